@@ -23,9 +23,11 @@ function Build-FromSource($feature) {
     $targetTokenizerFile = "neopilot_tokenizers.dll"
     $targetTemplatesFile = "neopilot_templates.dll"
     $targetRepoMapFile = "neopilot_repo_map.dll"
+    $targetCoreFile = "neopilot_core.dll"
     Copy-Item (Join-Path $SCRIPT_DIR "target\release\neopilot_tokenizers.dll") (Join-Path $BuildDir $targetTokenizerFile)
     Copy-Item (Join-Path $SCRIPT_DIR "target\release\neopilot_templates.dll") (Join-Path $BuildDir $targetTemplatesFile)
     Copy-Item (Join-Path $SCRIPT_DIR "target\release\neopilot_repo_map.dll") (Join-Path $BuildDir $targetRepoMapFile)
+    Copy-Item (Join-Path $SCRIPT_DIR "target\release\neopilot_core.dll") (Join-Path $BuildDir $targetCoreFile)
 
     Remove-Item -Recurse -Force "target"
 }
@@ -0,0 +1,265 @@
+//! Events for reactive front ends
+//!
+//! Long-running or background work reports what happened as [`Event`]s
+//! instead of through return values the caller has to poll for: scan
+//! progress, configuration reloads, finished downloads and cache evictions.
+//! Events are queued on an [`EventBus`] until the front end drains them;
+//! crates emit into the process-wide [`EVENTS`] bus with [`emit`].
+//!
+//! Each Lua module is its own library with its own bus, so the plugin polls
+//! every module it wants events from.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Something the plugin UI may want to react to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A scan entered a new phase, or handled another percent of its files
+    ScanProgress {
+        phase: &'static str,
+        files_done: u64,
+        files_total: u64,
+    },
+    /// A new configuration was applied
+    ConfigReloaded,
+    /// A download ended, with the error if it failed
+    DownloadFinished { url: String, error: Option<String> },
+    /// Entries were dropped from a cache
+    CacheEvicted { cache: &'static str, entries: usize },
+}
+
+impl Event {
+    /// Name of the event, as used by the Lua API
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::ScanProgress { .. } => "scan_progress",
+            Event::ConfigReloaded => "config_reloaded",
+            Event::DownloadFinished { .. } => "download_finished",
+            Event::CacheEvicted { .. } => "cache_evicted",
+        }
+    }
+}
+
+/// Events queued until the front end drains them
+///
+/// Keeps the most recent `capacity` events; older ones are dropped and
+/// counted, so a front end that stopped polling does not grow the queue.
+#[derive(Debug)]
+pub struct EventBus {
+    queue: Mutex<Queue>,
+}
+
+#[derive(Debug)]
+struct Queue {
+    events: VecDeque<Event>,
+    capacity: usize,
+    dropped: u64,
+}
+
+/// Capacity of [`EVENTS`]
+pub const DEFAULT_CAPACITY: usize = 256;
+
+impl EventBus {
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            queue: Mutex::new(Queue {
+                events: VecDeque::new(),
+                capacity,
+                dropped: 0,
+            }),
+        }
+    }
+
+    /// Queue `event`, dropping the oldest one if the queue is full
+    pub fn emit(&self, event: Event) {
+        let Ok(mut queue) = self.queue.lock() else {
+            return;
+        };
+        if queue.capacity == 0 {
+            queue.dropped += 1;
+            return;
+        }
+        if queue.events.len() == queue.capacity {
+            queue.events.pop_front();
+            queue.dropped += 1;
+        }
+        queue.events.push_back(event);
+    }
+
+    /// Take the queued events, oldest first
+    pub fn drain(&self) -> Vec<Event> {
+        self.queue
+            .lock()
+            .map(|mut queue| queue.events.drain(..).collect())
+            .unwrap_or_default()
+    }
+
+    /// Events dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.queue.lock().map_or(0, |queue| queue.dropped)
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+/// Events of this library, drained by its front end
+pub static EVENTS: EventBus = EventBus::new(DEFAULT_CAPACITY);
+
+/// Queue `event` on [`EVENTS`]
+pub fn emit(event: Event) {
+    log::trace!("Event {event:?}");
+    EVENTS.emit(event);
+}
+
+/// Lua support for events
+#[cfg(feature = "lua")]
+pub mod lua {
+    use mlua::{Function, Lua, Result, Table};
+
+    use super::{Event, EVENTS};
+
+    /// The Lua table of `event`: its `name` and its fields
+    pub fn event_to_lua(lua: &Lua, event: &Event) -> Result<Table> {
+        let table = lua.create_table()?;
+        table.set("name", event.name())?;
+        match event {
+            Event::ScanProgress {
+                phase,
+                files_done,
+                files_total,
+            } => {
+                table.set("phase", *phase)?;
+                table.set("files_done", *files_done)?;
+                table.set("files_total", *files_total)?;
+            },
+            Event::ConfigReloaded => {},
+            Event::DownloadFinished { url, error } => {
+                table.set("url", url.as_str())?;
+                table.set("error", error.as_deref())?;
+            },
+            Event::CacheEvicted { cache, entries } => {
+                table.set("cache", *cache)?;
+                table.set("entries", *entries)?;
+            },
+        }
+        Ok(table)
+    }
+
+    /// Add the event exports to a module
+    ///
+    /// - `poll_events()` drains the queued events and returns them as tables.
+    /// - `on_event(name, callback)` attaches `callback` to events named
+    ///   `name`, or to every event for `"*"`, and returns a function that
+    ///   detaches it.
+    /// - `dispatch_events()` drains the queued events, calls the attached
+    ///   callbacks with each and returns how many events there were.
+    ///
+    /// Callbacks only run inside `dispatch_events`, on the Lua thread, so the
+    /// plugin calls it from a timer or after scheduling work.
+    pub fn install(lua: &Lua, exports: &Table) -> Result<()> {
+        exports.set(
+            "poll_events",
+            lua.create_function(|lua, ()| {
+                let table = lua.create_table()?;
+                for event in EVENTS.drain() {
+                    table.push(event_to_lua(lua, &event)?)?;
+                }
+                Ok(table)
+            })?,
+        )?;
+
+        // Callbacks by event name, each a list of functions
+        let callbacks = lua.create_table()?;
+        let on_callbacks = callbacks.clone();
+        exports.set(
+            "on_event",
+            lua.create_function(move |lua, (name, callback): (String, Function)| {
+                let list = match on_callbacks.get::<Option<Table>>(name.as_str())? {
+                    Some(list) => list,
+                    None => {
+                        let list = lua.create_table()?;
+                        on_callbacks.set(name.as_str(), &list)?;
+                        list
+                    },
+                };
+                list.push(&callback)?;
+                lua.create_function(move |_, ()| detach(&list, &callback))
+            })?,
+        )?;
+        exports.set(
+            "dispatch_events",
+            lua.create_function(move |lua, ()| {
+                let events = EVENTS.drain();
+                for event in &events {
+                    for name in [event.name(), "*"] {
+                        let Some(list) = callbacks.get::<Option<Table>>(name)? else {
+                            continue;
+                        };
+                        // Copied, so callbacks may detach themselves
+                        let list: Vec<Function> = list.sequence_values().collect::<Result<_>>()?;
+                        for callback in list {
+                            callback.call::<()>(event_to_lua(lua, event)?)?;
+                        }
+                    }
+                }
+                Ok(events.len())
+            })?,
+        )?;
+        Ok(())
+    }
+
+    /// Remove `callback` from `list`, returning whether it was attached
+    fn detach(list: &Table, callback: &Function) -> Result<bool> {
+        let callbacks: Vec<Function> = list.sequence_values().collect::<Result<_>>()?;
+        let Some(position) = callbacks.iter().position(|f| f == callback) else {
+            return Ok(false);
+        };
+        list.raw_remove(position + 1)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drain_in_order() {
+        let bus = EventBus::new(4);
+        bus.emit(Event::ConfigReloaded);
+        bus.emit(Event::CacheEvicted {
+            cache: "tokenizers",
+            entries: 2,
+        });
+        let names: Vec<_> = bus.drain().iter().map(Event::name).collect();
+        assert_eq!(names, ["config_reloaded", "cache_evicted"]);
+        assert!(bus.drain().is_empty());
+    }
+
+    #[test]
+    fn test_full_queue_drops_oldest() {
+        let bus = EventBus::new(2);
+        for files_done in 0..5 {
+            bus.emit(Event::ScanProgress {
+                phase: "parsing",
+                files_done,
+                files_total: 5,
+            });
+        }
+        let done: Vec<_> = bus
+            .drain()
+            .into_iter()
+            .map(|event| match event {
+                Event::ScanProgress { files_done, .. } => files_done,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(done, [3, 4]);
+        assert_eq!(bus.dropped(), 3);
+    }
+}
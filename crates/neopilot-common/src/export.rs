@@ -0,0 +1,89 @@
+//! Serialization of results for the RPC and CLI layers
+//!
+//! The tokenizers and the repo map offer the same wire formats, so they are
+//! defined once here. JSON is the most convenient format for humans and Lua;
+//! MessagePack and CBOR are offered for tools that exchange large results and
+//! care about overhead.
+
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use neopilot_error::{Error, ErrorCode, Result};
+
+/// Wire format for serialized results
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            "msgpack" | "messagepack" => Ok(OutputFormat::MessagePack),
+            "cbor" => Ok(OutputFormat::Cbor),
+            _ => Err(Error::new(
+                ErrorCode::InvalidInput,
+                format!("Unknown output format '{s}', expected json, msgpack or cbor"),
+            )),
+        }
+    }
+}
+
+fn serialization_error(e: impl std::fmt::Display) -> Error {
+    Error::new(ErrorCode::Internal, format!("Failed to serialize: {e}"))
+}
+
+/// Serialize `value` in `format`
+///
+/// MessagePack maps keep their field names so every format carries the same
+/// self-describing structure.
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T, format: OutputFormat) -> Result<Vec<u8>> {
+    match format {
+        OutputFormat::Json => serde_json::to_vec(value).map_err(serialization_error),
+        OutputFormat::MessagePack => rmp_serde::to_vec_named(value).map_err(serialization_error),
+        OutputFormat::Cbor => {
+            let mut bytes = Vec::new();
+            ciborium::into_writer(value, &mut bytes).map_err(serialization_error)?;
+            Ok(bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formats() {
+        assert_eq!("json".parse::<OutputFormat>().unwrap(), OutputFormat::Json);
+        assert_eq!("msgpack".parse::<OutputFormat>().unwrap(), OutputFormat::MessagePack);
+        assert_eq!("messagepack".parse::<OutputFormat>().unwrap(), OutputFormat::MessagePack);
+        assert_eq!("cbor".parse::<OutputFormat>().unwrap(), OutputFormat::Cbor);
+        let error = "yaml".parse::<OutputFormat>().unwrap_err();
+        assert_eq!(error.code(), ErrorCode::InvalidInput);
+    }
+
+    #[test]
+    fn test_roundtrip_all_formats() -> Result<()> {
+        let value = serde_json::json!({ "tokens": [1, 2, 3], "name": "test" });
+        let json: serde_json::Value =
+            serde_json::from_slice(&to_bytes(&value, OutputFormat::Json)?).unwrap();
+        assert_eq!(json, value);
+
+        let msgpack: serde_json::Value =
+            rmp_serde::from_slice(&to_bytes(&value, OutputFormat::MessagePack)?).unwrap();
+        assert_eq!(msgpack, value);
+
+        let cbor: serde_json::Value =
+            ciborium::from_reader(to_bytes(&value, OutputFormat::Cbor)?.as_slice()).unwrap();
+        assert_eq!(cbor, value);
+        Ok(())
+    }
+}
@@ -0,0 +1,14 @@
+//! # Neopilot Common
+//!
+//! Building blocks shared by the neopilot crates beyond their error type,
+//! which lives in `neopilot-error`. The [`trace`] module tracks the request
+//! trace ID that log records and Lua errors are tagged with, [`events`]
+//! queues the events front ends react to, [`text`] decodes files in any
+//! encoding, [`export`] serializes results in the wire formats the bindings
+//! offer and [`scheduler`] runs background work behind interactive requests.
+
+pub mod events;
+pub mod export;
+pub mod scheduler;
+pub mod text;
+pub mod trace;
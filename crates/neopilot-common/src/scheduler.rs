@@ -0,0 +1,403 @@
+//! Priority scheduling of background work
+//!
+//! Interactive requests, such as the outline of the current buffer or a
+//! token count, must not wait behind a full repository scan or a download.
+//! The [`Scheduler`] runs tasks on a fixed set of worker threads and always
+//! takes interactive tasks first. Running tasks are never interrupted;
+//! instead background tasks may only occupy all workers but one, so an
+//! interactive task starts right away unless other interactive tasks keep
+//! every worker busy. With a single worker nothing is held back and
+//! interactive tasks wait for the running task.
+//!
+//! The repo map scans and the tokenizers download on the scheduler in their
+//! [`SchedulerSlot`]. Used on their own, each starts one sized like the
+//! default `performance` configuration; an engine of `neopilot-core` installs
+//! one scheduler sized from the loaded configuration in both.
+
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+
+use neopilot_error::{Error, ErrorCode, Result};
+
+use crate::trace;
+
+/// Background tasks allowed to wait for a worker by default, as
+/// `performance.channel_capacity`
+pub const DEFAULT_MAX_QUEUED_BACKGROUND: usize = 1000;
+
+/// How urgently a task must run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Requests the user is waiting for
+    Interactive,
+    /// Work that may take long, e.g. scans and downloads
+    Background,
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+#[derive(Default)]
+struct Queues {
+    interactive: VecDeque<Job>,
+    background: VecDeque<Job>,
+    /// Background tasks currently running
+    running_background: usize,
+    shut_down: bool,
+}
+
+struct Shared {
+    queues: Mutex<Queues>,
+    available: Condvar,
+    /// Background tasks allowed to run at once
+    background_slots: usize,
+    /// Background tasks allowed to wait in the queue
+    max_queued_background: usize,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queues> {
+        // Tasks run outside the lock, so a poisoned lock still holds valid queues
+        self.queues.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The next task a worker may run, `None` once the scheduler is shut down
+    fn next_job(&self) -> Option<(Job, Priority)> {
+        let mut queues = self.lock();
+        loop {
+            if let Some(job) = queues.interactive.pop_front() {
+                return Some((job, Priority::Interactive));
+            }
+            if queues.running_background < self.background_slots {
+                if let Some(job) = queues.background.pop_front() {
+                    queues.running_background += 1;
+                    return Some((job, Priority::Background));
+                }
+            }
+            if queues.shut_down {
+                return None;
+            }
+            queues = self.available.wait(queues).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    fn run_worker(&self) {
+        while let Some((job, priority)) = self.next_job() {
+            job();
+            if priority == Priority::Background {
+                self.lock().running_background -= 1;
+                // A worker waiting for a background slot may take one now
+                self.available.notify_one();
+            }
+        }
+    }
+}
+
+/// Marks a task finished once its job ran or was dropped
+struct Finished(Arc<AtomicBool>);
+
+impl Drop for Finished {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+/// Result of a submitted task
+pub struct TaskHandle<T> {
+    receiver: mpsc::Receiver<thread::Result<T>>,
+    finished: Arc<AtomicBool>,
+}
+
+impl<T> TaskHandle<T> {
+    /// Whether [`TaskHandle::join`] returns without waiting
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+
+    /// Wait for the task to finish and return its result
+    ///
+    /// Fails when the task panicked or was dropped by
+    /// [`Scheduler::shutdown`] before it started.
+    pub fn join(self) -> Result<T> {
+        match self.receiver.recv() {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(_)) => Err(Error::new(ErrorCode::Internal, "Task panicked")),
+            Err(_) => Err(Error::new(ErrorCode::Internal, "Task cancelled by shutdown")),
+        }
+    }
+}
+
+/// Worker threads running tasks by priority
+pub struct Scheduler {
+    shared: Arc<Shared>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Scheduler {
+    /// Start `workers` threads; at most `max_queued_background` background
+    /// tasks wait for a worker at once
+    ///
+    /// Both are sized from `performance.worker_threads` and
+    /// `performance.channel_capacity`.
+    pub fn new(workers: usize, max_queued_background: usize) -> Result<Self> {
+        let workers = workers.max(1);
+        let shared = Arc::new(Shared {
+            queues: Mutex::new(Queues::default()),
+            available: Condvar::new(),
+            background_slots: (workers - 1).max(1),
+            max_queued_background,
+        });
+        let threads = (0..workers)
+            .map(|i| {
+                let shared = Arc::clone(&shared);
+                thread::Builder::new()
+                    .name(format!("neopilot-worker-{i}"))
+                    .spawn(move || shared.run_worker())
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        Ok(Self {
+            shared,
+            threads: Mutex::new(threads),
+        })
+    }
+
+    /// Queue `task` to run on a worker
+    ///
+    /// Background tasks are refused while `max_queued_background` of them
+    /// are waiting; interactive tasks are always accepted. The task runs with
+    /// the trace ID current here, see [`trace::scope`].
+    pub fn submit<T, F>(&self, priority: Priority, task: F) -> Result<TaskHandle<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let finished = Arc::new(AtomicBool::new(false));
+        let marker = Finished(Arc::clone(&finished));
+        let trace_id = trace::current();
+        let job: Job = Box::new(move || {
+            let _marker = marker;
+            let _trace = trace::scope(trace_id);
+            // The handle may have been dropped; nobody wants the result then
+            let _ = sender.send(panic::catch_unwind(AssertUnwindSafe(task)));
+        });
+
+        let mut queues = self.shared.lock();
+        if queues.shut_down {
+            return Err(Error::new(ErrorCode::Internal, "Scheduler is shut down"));
+        }
+        match priority {
+            Priority::Interactive => queues.interactive.push_back(job),
+            Priority::Background => {
+                if queues.background.len() >= self.shared.max_queued_background {
+                    return Err(Error::new(
+                        ErrorCode::Internal,
+                        format!(
+                            "Too many background tasks queued ({})",
+                            self.shared.max_queued_background
+                        ),
+                    ));
+                }
+                queues.background.push_back(job);
+            },
+        }
+        drop(queues);
+        // Wake every worker: the one woken by `notify_one` may be unable to
+        // take a background task while its slots are full
+        self.shared.available.notify_all();
+        Ok(TaskHandle { receiver, finished })
+    }
+
+    /// Tasks of `priority` waiting for a worker
+    pub fn queued(&self, priority: Priority) -> usize {
+        let queues = self.shared.lock();
+        match priority {
+            Priority::Interactive => queues.interactive.len(),
+            Priority::Background => queues.background.len(),
+        }
+    }
+
+    /// Drop the queued tasks and wait for the running ones to finish
+    ///
+    /// Handles of dropped tasks report them as cancelled. Called from a task,
+    /// it does not wait for that task's own worker.
+    pub fn shutdown(&self) {
+        let dropped = {
+            let mut queues = self.shared.lock();
+            queues.shut_down = true;
+            let mut dropped: Vec<Job> = queues.interactive.drain(..).collect();
+            dropped.extend(queues.background.drain(..));
+            dropped
+        };
+        // Dropped outside the lock, as dropping a task may take a while
+        drop(dropped);
+        self.shared.available.notify_all();
+
+        let threads = {
+            let mut threads = self.threads.lock().unwrap_or_else(PoisonError::into_inner);
+            std::mem::take(&mut *threads)
+        };
+        let current = thread::current().id();
+        for handle in threads {
+            if handle.thread().id() != current {
+                // Tasks catch their panics, so workers only end by returning
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// The scheduler of a library, started on first use unless one was installed
+#[derive(Default)]
+pub struct SchedulerSlot(Mutex<Option<Arc<Scheduler>>>);
+
+impl SchedulerSlot {
+    /// The installed scheduler, or a new one of `workers` threads and
+    /// `max_queued_background` queued background tasks, see [`Scheduler::new`]
+    pub fn get_or_start(
+        &self,
+        workers: usize,
+        max_queued_background: usize,
+    ) -> Result<Arc<Scheduler>> {
+        let mut slot = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(scheduler) = slot.as_ref() {
+            return Ok(Arc::clone(scheduler));
+        }
+        let scheduler = Arc::new(Scheduler::new(workers, max_queued_background)?);
+        *slot = Some(Arc::clone(&scheduler));
+        Ok(scheduler)
+    }
+
+    /// [`SchedulerSlot::get_or_start`] sized like the default `performance`
+    /// configuration: a worker per CPU
+    pub fn get_or_start_default(&self) -> Result<Arc<Scheduler>> {
+        let workers = thread::available_parallelism().map_or(1, usize::from);
+        self.get_or_start(workers, DEFAULT_MAX_QUEUED_BACKGROUND)
+    }
+
+    /// Run later tasks on `scheduler`; tasks already queued keep their own
+    pub fn install(&self, scheduler: Arc<Scheduler>) {
+        let previous = self.0.lock().unwrap_or_else(PoisonError::into_inner).replace(scheduler);
+        // Dropped outside the lock, as the last reference waits for its tasks
+        drop(previous);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A task blocking its worker until the returned sender is used
+    fn blocker(
+        scheduler: &Scheduler,
+        priority: Priority,
+    ) -> (mpsc::Sender<()>, TaskHandle<()>) {
+        let (release, released) = mpsc::channel();
+        let handle = scheduler
+            .submit(priority, move || {
+                let _ = released.recv();
+            })
+            .unwrap();
+        (release, handle)
+    }
+
+    /// Wait until workers took every queued task of `priority`
+    fn wait_until_started(scheduler: &Scheduler, priority: Priority) {
+        while scheduler.queued(priority) > 0 {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn test_interactive_first() -> Result<()> {
+        let scheduler = Scheduler::new(1, 10)?;
+        let (release, blocked) = blocker(&scheduler, Priority::Background);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let record = |name: &'static str| {
+            let order = Arc::clone(&order);
+            move || order.lock().unwrap().push(name)
+        };
+        let handles = [
+            scheduler.submit(Priority::Background, record("scan"))?,
+            scheduler.submit(Priority::Background, record("download"))?,
+            scheduler.submit(Priority::Interactive, record("outline"))?,
+        ];
+        release.send(()).unwrap();
+        blocked.join()?;
+        for handle in handles {
+            handle.join()?;
+        }
+        assert_eq!(*order.lock().unwrap(), ["outline", "scan", "download"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_worker_kept_for_interactive() -> Result<()> {
+        let scheduler = Scheduler::new(2, 10)?;
+        let (release_scan, scan) = blocker(&scheduler, Priority::Background);
+        let (release_download, download) = blocker(&scheduler, Priority::Background);
+        // The download waits for the scan, leaving a worker for the count
+        let count = scheduler.submit(Priority::Interactive, || 42)?;
+        assert_eq!(count.join()?, 42);
+        assert_eq!(scheduler.queued(Priority::Background), 1);
+
+        release_scan.send(()).unwrap();
+        release_download.send(()).unwrap();
+        scan.join()?;
+        download.join()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_trace_and_finished() -> Result<()> {
+        let scheduler = Scheduler::new(1, 10)?;
+        let (release, blocked) = blocker(&scheduler, Priority::Background);
+        let traced = {
+            let _scope = trace::scope(Some("req-1".to_string()));
+            scheduler.submit(Priority::Interactive, trace::current)?
+        };
+        assert!(!blocked.is_finished());
+        release.send(()).unwrap();
+        blocked.join()?;
+        assert_eq!(traced.join()?.as_deref(), Some("req-1"));
+
+        let slot = SchedulerSlot::default();
+        let started = slot.get_or_start(1, 10)?;
+        assert!(Arc::ptr_eq(&started, &slot.get_or_start_default()?));
+        let installed = Arc::new(Scheduler::new(1, 10)?);
+        slot.install(Arc::clone(&installed));
+        assert!(Arc::ptr_eq(&installed, &slot.get_or_start(4, 10)?));
+        Ok(())
+    }
+
+    #[test]
+    fn test_limits_and_shutdown() -> Result<()> {
+        let scheduler = Scheduler::new(1, 1)?;
+        let panicked = scheduler.submit(Priority::Interactive, || panic!("boom"))?;
+        assert!(panicked.join().is_err());
+
+        let (release, blocked) = blocker(&scheduler, Priority::Background);
+        wait_until_started(&scheduler, Priority::Background);
+        let queued = scheduler.submit(Priority::Background, || ())?;
+        assert!(scheduler.submit(Priority::Background, || ()).is_err());
+        assert!(scheduler.submit(Priority::Interactive, || ()).is_ok());
+
+        thread::scope(|scope| {
+            scope.spawn(|| scheduler.shutdown());
+            wait_until_started(&scheduler, Priority::Background);
+            release.send(()).unwrap();
+        });
+        blocked.join()?;
+        assert!(queued.join().is_err());
+        assert!(scheduler.submit(Priority::Interactive, || ()).is_err());
+        Ok(())
+    }
+}
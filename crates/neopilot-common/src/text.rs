@@ -0,0 +1,100 @@
+//! Text files that are not UTF-8
+//!
+//! The repo map parses files from disk and the tokenizers count them, so both
+//! detect encodings the same way here rather than each with its own rules.
+//! Legacy codebases still hold files saved as Latin-1, and Windows tools
+//! write UTF-16 with a byte order mark.
+//!
+//! Detection is deliberately simple: a byte order mark decides, then valid
+//! UTF-8 is taken as is, and anything else without NUL bytes is read as
+//! Latin-1, which maps every byte to a character. Files with NUL bytes and
+//! no byte order mark are binary.
+
+use std::borrow::Cow;
+
+/// Bytes searched for NUL to tell binary files from text
+const BINARY_SNIFF_LEN: usize = 8192;
+
+/// Encoding text was decoded from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextEncoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+}
+
+/// Text decoded by [`decode`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedText<'a> {
+    pub encoding: TextEncoding,
+    pub text: Cow<'a, str>,
+    /// Whether invalid sequences were replaced with U+FFFD, which only
+    /// happens after a byte order mark
+    pub lossy: bool,
+}
+
+fn decode_utf16(bytes: &[u8], from_bytes: fn([u8; 2]) -> u16) -> (Cow<'static, str>, bool) {
+    let units: Vec<u16> = bytes
+        .chunks(2)
+        .map(|pair| from_bytes([pair[0], pair.get(1).copied().unwrap_or(0)]))
+        .collect();
+    let lossy =
+        char::decode_utf16(units.iter().copied()).any(|c| c.is_err()) || bytes.len() % 2 != 0;
+    (Cow::Owned(String::from_utf16_lossy(&units)), lossy)
+}
+
+/// Detect the encoding of `bytes` and transcode them to UTF-8
+///
+/// Returns `None` for binary files. Byte order marks are dropped.
+pub fn decode(bytes: &[u8]) -> Option<DecodedText<'_>> {
+    let (encoding, (text, lossy)) = if let Some(rest) = bytes.strip_prefix(b"\xEF\xBB\xBF") {
+        let text = String::from_utf8_lossy(rest);
+        let lossy = matches!(text, Cow::Owned(_));
+        (TextEncoding::Utf8, (text, lossy))
+    } else if let Some(rest) = bytes.strip_prefix(b"\xFF\xFE") {
+        (TextEncoding::Utf16Le, decode_utf16(rest, u16::from_le_bytes))
+    } else if let Some(rest) = bytes.strip_prefix(b"\xFE\xFF") {
+        (TextEncoding::Utf16Be, decode_utf16(rest, u16::from_be_bytes))
+    } else if bytes[..bytes.len().min(BINARY_SNIFF_LEN)].contains(&0) {
+        return None;
+    } else {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => (TextEncoding::Utf8, (Cow::Borrowed(text), false)),
+            Err(_) => {
+                let latin1 = bytes.iter().map(|&byte| char::from(byte)).collect();
+                (TextEncoding::Latin1, (Cow::Owned(latin1), false))
+            },
+        }
+    };
+    Some(DecodedText {
+        encoding,
+        text,
+        lossy,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let decode = |bytes: &'static [u8]| {
+            let decoded = super::decode(bytes)?;
+            Some((decoded.encoding, decoded.text.into_owned(), decoded.lossy))
+        };
+        let text = |encoding, text: &str, lossy| Some((encoding, text.to_string(), lossy));
+        assert_eq!(decode(b"caf\xC3\xA9"), text(TextEncoding::Utf8, "café", false));
+        assert_eq!(decode(b"\xEF\xBB\xBFcaf\xC3\xA9"), text(TextEncoding::Utf8, "café", false));
+        assert_eq!(decode(b"\xEF\xBB\xBFok\xFF"), text(TextEncoding::Utf8, "ok\u{FFFD}", true));
+        assert_eq!(decode(b"caf\xE9"), text(TextEncoding::Latin1, "café", false));
+        assert_eq!(
+            decode(b"\xFF\xFEf\0n\0 \0\xE9\0"),
+            text(TextEncoding::Utf16Le, "fn é", false)
+        );
+        assert_eq!(decode(b"\xFE\xFF\0f\0n"), text(TextEncoding::Utf16Be, "fn", false));
+        assert_eq!(decode(b"\xFE\xFF\xD8\0"), text(TextEncoding::Utf16Be, "\u{FFFD}", true));
+        assert_eq!(decode(b"\x7FELF\0\0"), None);
+    }
+}
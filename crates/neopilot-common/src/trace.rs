@@ -0,0 +1,130 @@
+//! Request trace IDs
+//!
+//! The plugin can tag a user action with a trace ID. While the ID is active
+//! on a thread it is attached to log records and error messages of every
+//! crate, so a slow or failing action can be matched with the Rust-side
+//! operations it triggered. Work moved to another thread keeps the ID by
+//! capturing [`current`] and entering it again with [`scope`].
+
+use std::cell::RefCell;
+
+thread_local! {
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Trace ID active on the current thread
+pub fn current() -> Option<String> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Makes a trace ID current until dropped
+#[must_use = "the trace ID is only active while the scope is alive"]
+#[derive(Debug)]
+pub struct TraceScope {
+    previous: Option<String>,
+}
+
+/// Make `id` the current trace ID until the returned scope is dropped
+///
+/// Scopes nest; dropping one restores the ID that was current before. `None`
+/// clears the ID for the duration of the scope.
+pub fn scope(id: Option<String>) -> TraceScope {
+    let previous = CURRENT.with(|current| current.replace(id));
+    TraceScope { previous }
+}
+
+impl Drop for TraceScope {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// Lua support for trace IDs
+#[cfg(feature = "lua")]
+pub mod lua {
+    use std::time::Instant;
+
+    use mlua::{Error, Function, Lua, MultiValue, Result, Table, Value};
+
+    /// Target of the timing records logged for traced calls
+    pub const TIMING_TARGET: &str = "neopilot::trace";
+
+    /// Create the `traced(id)` export of a module
+    ///
+    /// `traced(id)` returns a table with the same functions as `exports` that
+    /// run with `id` as the current trace ID and log their duration, e.g.
+    /// `tokenizers.traced("req-42").encode(text)`. Their error messages end
+    /// with the trace ID.
+    pub fn traced_function(lua: &Lua, exports: &Table) -> Result<Function> {
+        let exports = exports.clone();
+        lua.create_function(move |lua, id: String| {
+            let traced = lua.create_table()?;
+            for pair in exports.pairs::<String, Value>() {
+                let (name, value) = pair?;
+                let Value::Function(function) = value else {
+                    continue;
+                };
+                let id = id.clone();
+                let label = name.clone();
+                let wrapped = lua.create_function(move |_, args: MultiValue| {
+                    let _scope = super::scope(Some(id.clone()));
+                    let started = Instant::now();
+                    let result = function.call::<MultiValue>(args);
+                    log::debug!(
+                        target: TIMING_TARGET,
+                        "[{id}] {label} {} in {:.1?}",
+                        if result.is_ok() { "finished" } else { "failed" },
+                        started.elapsed()
+                    );
+                    result.map_err(|err| with_trace(err, &id))
+                })?;
+                traced.set(name, wrapped)?;
+            }
+            Ok(traced)
+        })
+    }
+
+    /// `err` with its message ending in the trace ID `id`
+    fn with_trace(err: Error, id: &str) -> Error {
+        match err {
+            Error::CallbackError { cause, .. } => with_trace((*cause).clone(), id),
+            Error::RuntimeError(message) => Error::RuntimeError(format!("{message} (trace {id})")),
+            err => err,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scopes_nest() {
+        assert_eq!(current(), None);
+        {
+            let _outer = scope(Some("outer".to_string()));
+            assert_eq!(current().as_deref(), Some("outer"));
+            {
+                let _inner = scope(Some("inner".to_string()));
+                assert_eq!(current().as_deref(), Some("inner"));
+            }
+            assert_eq!(current().as_deref(), Some("outer"));
+        }
+        assert_eq!(current(), None);
+    }
+
+    #[test]
+    fn test_other_threads_do_not_inherit() {
+        let _scope = scope(Some("main".to_string()));
+        let id = current();
+        let seen = std::thread::spawn(move || {
+            let before = current();
+            let _scope = scope(id);
+            (before, current())
+        })
+        .join()
+        .unwrap();
+        assert_eq!(seen, (None, Some("main".to_string())));
+    }
+}
@@ -0,0 +1,282 @@
+//! # Neopilot Core
+//!
+//! Facade over the configuration, tokenizers and repo map. Each of those
+//! crates keeps its own state behind its own entry points; an [`Engine`]
+//! owns one of each instead: the loaded configuration, a tokenizer state and
+//! a repo map state holding the repository index. Front ends such as the Lua
+//! modules or a command line tool create one engine and drive its lifecycle
+//! with [`Engine::init`], [`Engine::reload_config`] and [`Engine::shutdown`],
+//! and run work on its [`scheduler`] with [`Engine::submit`]. Scans and
+//! tokenizer downloads run on the same scheduler. The `neopilot_core` Lua
+//! module is such a front end, see [`lua`].
+
+#[cfg(feature = "lua")]
+pub mod lua;
+
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use neopilot_common::events::{self, Event};
+use neopilot_common::scheduler::{Priority, Scheduler, TaskHandle};
+use neopilot_error::{Error, ErrorCode, Result};
+use neopilot_repo_map::index::RepoIndex;
+use neopilot_repo_map::scan::{self, ScanOptions, ScanProgress};
+use neopilot_repo_map::{logging, Config, ConfigLoader};
+use neopilot_tokenizers::Settings;
+
+pub use neopilot_common::scheduler;
+pub use neopilot_repo_map as repo_map;
+pub use neopilot_tokenizers as tokenizers;
+
+/// How an [`Engine`] loads its configuration and sets itself up
+#[derive(Debug, Clone, Default)]
+pub struct EngineOptions {
+    /// Source of the configuration, read on init and on every reload
+    pub loader: ConfigLoader,
+    /// Install the neopilot logger and configure it from `logging`
+    ///
+    /// Off by default, so embedders keep their own logger.
+    pub install_logger: bool,
+}
+
+/// The parts of `config` the tokenizers use
+pub fn tokenizer_settings(config: &Config) -> Settings {
+    let network = &config.network;
+    Settings {
+        network_enabled: network.enabled,
+        hf_token: network.hf_token.clone(),
+        user_agent: network.user_agent.clone(),
+        headers: network.headers.clone().into_iter().collect(),
+        mirrors: network.mirrors.clone().into_iter().collect(),
+        max_retries: network.max_retries,
+        max_input_bytes: config.tokenizer.max_input_bytes,
+    }
+}
+
+/// Configuration, tokenizers and repository index of one front end
+pub struct Engine {
+    options: EngineOptions,
+    config: RwLock<Arc<Config>>,
+    tokenizers: Arc<tokenizers::State>,
+    repo_map: Arc<repo_map::State>,
+    scheduler: Arc<Scheduler>,
+    shut_down: AtomicBool,
+}
+
+impl Engine {
+    /// Load the configuration from `options.loader` and apply it
+    pub fn init(options: EngineOptions) -> Result<Self> {
+        let config = options.loader.clone().load()?;
+        Self::with_config(options, config)
+    }
+
+    /// Like [`Engine::init`] with a configuration loaded elsewhere, e.g.
+    /// handed over by the plugin; reloads still read from `options.loader`
+    ///
+    /// The scheduler is sized from `performance` of this configuration and
+    /// keeps its size across reloads.
+    pub fn with_config(options: EngineOptions, config: Config) -> Result<Self> {
+        let performance = &config.performance;
+        let scheduler =
+            Arc::new(Scheduler::new(performance.worker_threads, performance.channel_capacity)?);
+        let engine = Self {
+            options,
+            config: RwLock::new(Arc::new(Config::default())),
+            tokenizers: Arc::new(tokenizers::State::with_settings(tokenizer_settings(&config))),
+            repo_map: Arc::default(),
+            scheduler,
+            shut_down: AtomicBool::new(false),
+        };
+        engine.tokenizers.set_scheduler(Arc::clone(&engine.scheduler));
+        engine.repo_map.set_scheduler(Arc::clone(&engine.scheduler));
+        engine.apply(config)?;
+        Ok(engine)
+    }
+
+    /// Apply `config` to every component and make it current
+    fn apply(&self, config: Config) -> Result<Arc<Config>> {
+        tokenizers::apply_settings(&self.tokenizers, tokenizer_settings(&config))?;
+        if self.options.install_logger {
+            logging::init(&config.logging)?;
+        }
+        self.repo_map.set_config(Some(config.clone()))?;
+        let config = Arc::new(config);
+        *self.config.write()? = Arc::clone(&config);
+        Ok(config)
+    }
+
+    fn ensure_running(&self) -> Result<()> {
+        if self.shut_down.load(Ordering::Acquire) {
+            return Err(Error::new(ErrorCode::Internal, "Engine is shut down"));
+        }
+        Ok(())
+    }
+
+    /// Load the configuration again and apply it
+    ///
+    /// A configuration that fails to load or validate leaves the current one
+    /// in place. A successful reload emits an [`Event::ConfigReloaded`].
+    pub fn reload_config(&self) -> Result<Arc<Config>> {
+        self.ensure_running()?;
+        let config = self.options.loader.clone().load()?;
+        let config = self.apply(config)?;
+        events::emit(Event::ConfigReloaded);
+        Ok(config)
+    }
+
+    /// The configuration in use
+    pub fn config(&self) -> Result<Arc<Config>> {
+        Ok(Arc::clone(&*self.config.read()?))
+    }
+
+    /// Tokenizer state, for the functions of [`tokenizers`]
+    pub fn tokenizers(&self) -> &Arc<tokenizers::State> {
+        &self.tokenizers
+    }
+
+    /// Repo map state, holding the index of [`Engine::build_index`]
+    pub fn repo_map(&self) -> &Arc<repo_map::State> {
+        &self.repo_map
+    }
+
+    /// Load the tokenizer of `tokenizer.model`
+    pub fn load_tokenizer(&self) -> Result<()> {
+        self.ensure_running()?;
+        let config = self.config()?;
+        tokenizers::from_pretrained(&self.tokenizers, &config.tokenizer.model)?;
+        Ok(())
+    }
+
+    /// Token count of `text` with the loaded tokenizer
+    pub fn count_tokens(&self, text: &str) -> Result<usize> {
+        self.ensure_running()?;
+        let (_, num_tokens, _) = tokenizers::encode(&self.tokenizers, text)?;
+        Ok(num_tokens)
+    }
+
+    /// Scan `root` into a new repository index, replacing the current one
+    ///
+    /// Progress is reported through [`Engine::scan_progress`].
+    pub fn build_index(&self, root: &Path) -> Result<()> {
+        self.ensure_running()?;
+        let config = self.config()?;
+        let options = ScanOptions::from_config(&config);
+        let index = RepoIndex::build_with(root, &options, &scan::SCAN_PROGRESS)?;
+        self.repo_map.set_index(index, Config::clone(&config), options)
+    }
+
+    /// Run `f` on the repository index built by [`Engine::build_index`]
+    pub fn with_index<R>(&self, f: impl FnOnce(&mut RepoIndex) -> R) -> Result<R> {
+        self.ensure_running()?;
+        self.repo_map.with_index(f)
+    }
+
+    /// Progress of the current or last scan, shared with the repo map's
+    /// `scan_progress`
+    pub fn scan_progress(&self) -> &'static ScanProgress {
+        &scan::SCAN_PROGRESS
+    }
+
+    /// Run `task` on a worker thread
+    ///
+    /// Interactive tasks, e.g. the outline of the current buffer or a token
+    /// count, run before queued background tasks such as
+    /// [`Engine::build_index`] or downloads.
+    pub fn submit<T, F>(self: &Arc<Self>, priority: Priority, task: F) -> Result<TaskHandle<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Engine) -> T + Send + 'static,
+    {
+        self.ensure_running()?;
+        let engine = Arc::clone(self);
+        self.scheduler.submit(priority, move || task(&engine))
+    }
+
+    /// Task scheduler of [`Engine::submit`], scans and tokenizer downloads
+    pub fn scheduler(&self) -> &Arc<Scheduler> {
+        &self.scheduler
+    }
+
+    /// Cancel the queued tasks, wait for the running ones, then release the
+    /// index and flush the logs
+    ///
+    /// Later calls fail, except `shutdown` itself, which does nothing once
+    /// the engine is shut down.
+    pub fn shutdown(&self) -> Result<()> {
+        if self.shut_down.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        self.scheduler.shutdown();
+        self.repo_map.clear_index()?;
+        log::logger().flush();
+        Ok(())
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lifecycle() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config_path = dir.path().join("neopilot.toml");
+        std::fs::write(&config_path, "[network]\nenabled = false\n")?;
+        std::fs::create_dir(dir.path().join("src"))?;
+        std::fs::write(dir.path().join("src/lib.rs"), "pub struct Foo {}\n")?;
+
+        let options = EngineOptions {
+            loader: ConfigLoader::new().with_config_path(&config_path),
+            ..Default::default()
+        };
+        let engine = Arc::new(Engine::init(options)?);
+        assert!(!engine.config()?.repo_map.include_vendored);
+
+        engine.load_tokenizer()?;
+        let count =
+            engine.submit(Priority::Interactive, |engine| engine.count_tokens("Hello world"))?;
+        assert_eq!(count.join()??, 2);
+        let root = dir.path().to_path_buf();
+        engine.submit(Priority::Background, move |engine| engine.build_index(&root))?.join()??;
+        assert_eq!(engine.with_index(|index| index.files.len())?, 1);
+
+        std::fs::write(
+            &config_path,
+            "[network]\nenabled = false\n[repo_map]\ninclude_vendored = true\n",
+        )?;
+        assert!(engine.reload_config()?.repo_map.include_vendored);
+        // A broken file keeps the configuration that was loaded
+        std::fs::write(&config_path, "[repo_map\n")?;
+        assert!(engine.reload_config().is_err());
+        assert!(engine.config()?.repo_map.include_vendored);
+
+        engine.shutdown()?;
+        engine.shutdown()?;
+        assert!(engine.is_shut_down());
+        assert!(engine.count_tokens("Hello").is_err());
+        assert!(engine.with_index(|_| ()).is_err());
+        assert!(engine.submit(Priority::Interactive, |_| ()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tokenizer_settings() {
+        let mut config = Config::for_tests();
+        config.network.hf_token = Some("hf_secret".to_string());
+        config.network.max_retries = 5;
+        config.network.mirrors.insert(
+            "gpt2".to_string(),
+            vec!["https://mirror.example.com".to_string()],
+        );
+        let settings = tokenizer_settings(&config);
+        assert!(!settings.network_enabled);
+        assert_eq!(settings.hf_token.as_deref(), Some("hf_secret"));
+        assert_eq!(settings.max_retries, 5);
+        assert_eq!(settings.mirrors.len(), 1);
+    }
+}
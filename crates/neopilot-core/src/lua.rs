@@ -0,0 +1,37 @@
+//! The `neopilot_core` Lua module
+//!
+//! Its `tokenizers` and `repo_map` tables hold the functions of the
+//! `neopilot_tokenizers` and `neopilot_repo_map` modules, both working on
+//! one [`Engine`]: the plugin loads the configuration once and
+//! `reload_config()` applies a new one to both. Being one library, the two
+//! tables queue their events on one bus.
+
+use std::sync::Arc;
+
+use mlua::prelude::*;
+
+use crate::{repo_map, tokenizers, Engine, EngineOptions};
+
+#[mlua::lua_module]
+fn neopilot_core(lua: &Lua) -> LuaResult<LuaTable> {
+    lua_exports(lua, Arc::new(Engine::init(EngineOptions::default())?))
+}
+
+/// The functions of the `neopilot_core` module, working on `engine`
+pub fn lua_exports(lua: &Lua, engine: Arc<Engine>) -> LuaResult<LuaTable> {
+    let exports = lua.create_table()?;
+    exports.set("tokenizers", tokenizers::lua_exports(lua, Arc::clone(engine.tokenizers()))?)?;
+    exports.set("repo_map", repo_map::lua_exports(lua, Arc::clone(engine.repo_map()))?)?;
+    let reload_engine = Arc::clone(&engine);
+    exports.set(
+        "reload_config",
+        lua.create_function(move |_, ()| {
+            reload_engine.reload_config()?;
+            Ok(())
+        })?,
+    )?;
+    let shutdown_engine = Arc::clone(&engine);
+    exports.set("shutdown", lua.create_function(move |_, ()| Ok(shutdown_engine.shutdown()?))?)?;
+    exports.set("is_shut_down", lua.create_function(move |_, ()| Ok(engine.is_shut_down()))?)?;
+    Ok(exports)
+}
@@ -0,0 +1,244 @@
+//! # Neopilot Error
+//!
+//! Error type shared by the neopilot crates. Every error carries a stable
+//! [`ErrorCode`], an optional chain of context messages and an optional source
+//! error, and converts to a Lua error (or Python or JavaScript exception) in
+//! exactly one place.
+
+use std::error::Error as StdError;
+use std::fmt;
+
+/// Stable classification of an error, exposed to Lua and other bindings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorCode {
+    /// Unexpected internal failure
+    Internal,
+    /// File system or other I/O failure
+    Io,
+    /// Invalid argument passed by the caller
+    InvalidInput,
+    /// Language, model or format is not supported
+    Unsupported,
+    /// Source code or data could not be parsed
+    Parse,
+    /// Invalid or unreadable configuration
+    Config,
+    /// Network request failed
+    Network,
+    /// Operation rejected for security reasons
+    Security,
+    /// Tokenizer failed to load or run
+    Tokenizer,
+    /// Requested item does not exist
+    NotFound,
+    /// Shared state could not be locked
+    Lock,
+}
+
+impl ErrorCode {
+    /// String form of the code, e.g. `"io"`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::Internal => "internal",
+            ErrorCode::Io => "io",
+            ErrorCode::InvalidInput => "invalid_input",
+            ErrorCode::Unsupported => "unsupported",
+            ErrorCode::Parse => "parse",
+            ErrorCode::Config => "config",
+            ErrorCode::Network => "network",
+            ErrorCode::Security => "security",
+            ErrorCode::Tokenizer => "tokenizer",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::Lock => "lock",
+        }
+    }
+
+    /// Numeric form of the code
+    pub fn as_u16(&self) -> u16 {
+        match self {
+            ErrorCode::Internal => 1,
+            ErrorCode::Io => 2,
+            ErrorCode::InvalidInput => 3,
+            ErrorCode::Unsupported => 4,
+            ErrorCode::Parse => 5,
+            ErrorCode::Config => 6,
+            ErrorCode::Network => 7,
+            ErrorCode::Security => 8,
+            ErrorCode::Tokenizer => 9,
+            ErrorCode::NotFound => 10,
+            ErrorCode::Lock => 11,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Error shared by all neopilot crates
+#[derive(Debug)]
+pub struct Error {
+    code: ErrorCode,
+    message: String,
+    /// Context added while the error propagated, innermost first
+    context: Vec<String>,
+    source: Option<Box<dyn StdError + Send + Sync + 'static>>,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Create a new error with the given code and message
+    pub fn new<M: Into<String>>(code: ErrorCode, message: M) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            context: Vec::new(),
+            source: None,
+        }
+    }
+
+    /// Attach the underlying error that caused this one
+    pub fn with_source<E>(mut self, source: E) -> Self
+    where
+        E: StdError + Send + Sync + 'static,
+    {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    /// Add a context message describing what was being done
+    pub fn context<C: Into<String>>(mut self, context: C) -> Self {
+        self.context.push(context.into());
+        self
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    /// The original message, without any context
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Context messages, innermost first
+    pub fn contexts(&self) -> &[String] {
+        &self.context
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for context in self.context.iter().rev() {
+            write!(f, "{context}: ")?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.source
+            .as_ref()
+            .map(|source| source.as_ref() as &(dyn StdError + 'static))
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        let code = match err.kind() {
+            std::io::ErrorKind::NotFound => ErrorCode::NotFound,
+            _ => ErrorCode::Io,
+        };
+        Error::new(code, err.to_string()).with_source(err)
+    }
+}
+
+impl<T> From<std::sync::PoisonError<T>> for Error {
+    fn from(err: std::sync::PoisonError<T>) -> Self {
+        Error::new(ErrorCode::Lock, err.to_string())
+    }
+}
+
+/// Adds context to the error of a `Result`
+pub trait ResultExt<T> {
+    /// Add a fixed context message
+    fn context<C: Into<String>>(self, context: C) -> Result<T>;
+
+    /// Add a lazily built context message
+    fn with_context<C: Into<String>, F: FnOnce() -> C>(self, f: F) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> ResultExt<T> for std::result::Result<T, E> {
+    fn context<C: Into<String>>(self, context: C) -> Result<T> {
+        self.map_err(|e| e.into().context(context))
+    }
+
+    fn with_context<C: Into<String>, F: FnOnce() -> C>(self, f: F) -> Result<T> {
+        self.map_err(|e| e.into().context(f()))
+    }
+}
+
+/// The single conversion from neopilot errors to Lua errors
+///
+/// The message is prefixed with the error code so the plugin can match on it.
+#[cfg(feature = "lua")]
+impl From<Error> for mlua::Error {
+    fn from(err: Error) -> Self {
+        mlua::Error::RuntimeError(format!("[{}] {}", err.code, err))
+    }
+}
+
+/// The single conversion from neopilot errors to Python exceptions
+#[cfg(feature = "python")]
+impl From<Error> for pyo3::PyErr {
+    fn from(err: Error) -> Self {
+        pyo3::exceptions::PyRuntimeError::new_err(format!("[{}] {}", err.code, err))
+    }
+}
+
+/// The single conversion from neopilot errors to JavaScript errors
+#[cfg(feature = "node")]
+impl From<Error> for napi::Error {
+    fn from(err: Error) -> Self {
+        napi::Error::from_reason(format!("[{}] {}", err.code, err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_with_context() {
+        let err = Error::new(ErrorCode::Parse, "unexpected token")
+            .context("parsing src/lib.rs")
+            .context("building index");
+        assert_eq!(err.to_string(), "building index: parsing src/lib.rs: unexpected token");
+        assert_eq!(err.message(), "unexpected token");
+        assert_eq!(err.code(), ErrorCode::Parse);
+    }
+
+    #[test]
+    fn test_result_context() {
+        let result: std::result::Result<(), std::io::Error> = Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "missing",
+        ));
+        let err = result.context("reading index").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        assert_eq!(err.to_string(), "reading index: missing");
+        assert!(err.source().is_some());
+    }
+
+    #[test]
+    fn test_codes() {
+        assert_eq!(ErrorCode::Network.as_str(), "network");
+        assert_eq!(ErrorCode::Network.as_u16(), 7);
+        assert_eq!(ErrorCode::Io.to_string(), "io");
+    }
+}
@@ -0,0 +1,5 @@
+fn main() {
+    // Node bindings need extra linker arguments on macOS and Windows
+    #[cfg(feature = "node")]
+    napi_build::setup();
+}
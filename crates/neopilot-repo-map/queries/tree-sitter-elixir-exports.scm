@@ -0,0 +1,11 @@
+;; Functions delegated to another module with `defdelegate`
+(call
+  target: (identifier) @_defdelegate
+  (arguments
+    [
+      ; zero-arity functions with no parentheses
+      (identifier) @export
+      (call target: (identifier) @export)
+    ])
+  (#eq? @_defdelegate "defdelegate")
+)
@@ -7,6 +7,10 @@
 (export_statement
   declaration: (function_declaration) @function
 )
+;; Top-level functions, kept when an export list names them
+(program
+  (function_declaration) @function
+)
 (export_statement
   declaration: (class_declaration
     body: (class_body
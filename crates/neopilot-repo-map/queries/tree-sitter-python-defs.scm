@@ -7,6 +7,9 @@
 (module
   (function_definition) @function
 )
+(module
+  (class_definition) @class
+)
 (module
   (class_definition
     body: (block
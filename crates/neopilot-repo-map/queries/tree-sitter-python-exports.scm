@@ -0,0 +1,23 @@
+;; Names listed in a module-level `__all__`, assigned or extended
+(module
+  (expression_statement
+    (assignment
+      left: (identifier) @_all
+      right: [
+        (list (string (string_content) @export))
+        (tuple (string (string_content) @export))
+      ])
+  )
+  (#eq? @_all "__all__")
+)
+(module
+  (expression_statement
+    (augmented_assignment
+      left: (identifier) @_all
+      right: [
+        (list (string (string_content) @export))
+        (tuple (string (string_content) @export))
+      ])
+  )
+  (#eq? @_all "__all__")
+)
@@ -0,0 +1,21 @@
+;; Bazel BUILD, WORKSPACE and .bzl files, parsed with the Python grammar
+
+;; Targets: top-level rule and macro calls, kept when they have a `name`
+(module
+  (expression_statement
+    (call) @target
+  )
+)
+;; Rules, macros, aspects and providers assigned to a top-level name
+(module
+  (expression_statement
+    (assignment
+      left: (identifier)
+      right: (call)
+    ) @rule
+  )
+)
+;; Macros: top-level functions
+(module
+  (function_definition) @function
+)
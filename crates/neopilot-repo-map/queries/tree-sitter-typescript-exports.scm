@@ -0,0 +1,38 @@
+;; `export { name, name as alias }`, by local name
+(export_statement
+  (export_clause
+    (export_specifier name: (identifier) @export)
+  )
+)
+;; `export function name`, `export class Name` and exported types
+(export_statement
+  declaration: [
+    (function_declaration name: (identifier) @export)
+    (class_declaration name: (type_identifier) @export)
+    (interface_declaration name: (type_identifier) @export)
+    (type_alias_declaration name: (type_identifier) @export)
+  ]
+)
+;; `module.exports = { name, key: name }` and `module.exports = name`
+(assignment_expression
+  left: (member_expression
+    object: (identifier) @_module
+    property: (property_identifier) @_exports)
+  right: [
+    (object (shorthand_property_identifier) @export)
+    (object (pair value: (identifier) @export))
+    (identifier) @export
+  ]
+  (#eq? @_module "module")
+  (#eq? @_exports "exports")
+)
+;; `exports.key = name` and `module.exports.key = name`
+(assignment_expression
+  left: (member_expression
+    object: [
+      (identifier) @_exports
+      (member_expression property: (property_identifier) @_exports)
+    ])
+  right: (identifier) @export
+  (#eq? @_exports "exports")
+)
@@ -4,6 +4,8 @@ use std::path::PathBuf;
 use std::io;
 use thiserror::Error;
 
+use super::span::ConfigSpan;
+
 /// Errors that can occur during configuration loading and processing
 #[derive(Debug, Error)]
 pub enum ConfigError {
@@ -34,6 +36,39 @@ pub enum ConfigError {
     /// Missing required configuration
     #[error("Missing required configuration: {0}")]
     MissingValue(String),
+
+    /// Invalid configuration file, pointing at the offending line
+    #[error("{span}: {message}")]
+    InFile { message: String, span: ConfigSpan },
+}
+
+impl ConfigError {
+    /// Dotted path of the offending key, if known
+    ///
+    /// Validation messages start with the key they are about, e.g.
+    /// `tokenizer.max_tokens must be greater than 0`.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            ConfigError::InFile { span, .. } => span.key.as_deref(),
+            ConfigError::ValidationError(message) => {
+                let is_key_char = |c: char| c.is_ascii_alphanumeric() || c == '.' || c == '_';
+                message
+                    .split_whitespace()
+                    .next()
+                    .filter(|word| word.contains('.') && word.chars().all(is_key_char))
+            },
+            ConfigError::InvalidPath(path) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Message for showing to users, quoting the offending line when known
+    pub fn render(&self) -> String {
+        match self {
+            ConfigError::InFile { span, .. } => format!("{self}\n{}", span.render()),
+            _ => self.to_string(),
+        }
+    }
 }
 
 impl From<ConfigError> for std::io::Error {
@@ -45,6 +80,20 @@ impl From<ConfigError> for std::io::Error {
     }
 }
 
+impl From<ConfigError> for neopilot_error::Error {
+    fn from(err: ConfigError) -> Self {
+        use neopilot_error::ErrorCode;
+
+        let code = match &err {
+            ConfigError::IoError(e, _) if e.kind() == io::ErrorKind::NotFound => ErrorCode::NotFound,
+            ConfigError::IoError(..) => ErrorCode::Io,
+            ConfigError::TomlError(..) => ErrorCode::Parse,
+            _ => ErrorCode::Config,
+        };
+        neopilot_error::Error::new(code, err.render()).with_source(err)
+    }
+}
+
 impl From<toml::de::Error> for ConfigError {
     fn from(err: toml::de::Error) -> Self {
         ConfigError::TomlError(err, PathBuf::from("<unknown>"))
@@ -82,6 +131,36 @@ mod tests {
         );
     }
     
+    #[test]
+    fn test_into_shared_error() {
+        let error: neopilot_error::Error =
+            ConfigError::ValidationError("invalid value".to_string()).into();
+        assert_eq!(error.code(), neopilot_error::ErrorCode::Config);
+        assert_eq!(error.to_string(), "Configuration validation error: invalid value");
+    }
+
+    #[test]
+    fn test_in_file_render() {
+        let content = "[tokenizer]\nmax_tokens = 0\n";
+        let file = std::path::Path::new("neopilot.toml");
+        let span = ConfigSpan::for_key(file, content, "tokenizer.max_tokens").unwrap();
+        let error = ConfigError::InFile {
+            message: "tokenizer.max_tokens must be greater than 0".to_string(),
+            span,
+        };
+        assert_eq!(error.key(), Some("tokenizer.max_tokens"));
+        assert_eq!(
+            error.render(),
+            "neopilot.toml:2:1: tokenizer.max_tokens must be greater than 0\n\
+             \x20 |\n\
+             2 | max_tokens = 0\n\
+             \x20 | ^"
+        );
+        let validation =
+            ConfigError::ValidationError("network.max_retries cannot exceed 10".to_string());
+        assert_eq!(validation.key(), Some("network.max_retries"));
+    }
+
     #[test]
     fn test_from_toml_error() {
         let toml_str = "invalid toml";
@@ -7,6 +7,7 @@ use std::collections::HashMap;
 use crate::config::{Config, ConfigError};
 
 /// Loads and merges configuration from multiple sources
+#[derive(Debug, Clone)]
 pub struct ConfigLoader {
     config_path: Option<PathBuf>,
     env_prefix: String,
@@ -52,8 +53,9 @@ impl ConfigLoader {
         let mut config = Config::default();
         
         // Load from file if specified or find default config file
-        if let Some(path) = self.get_config_path()? {
-            config.merge_from_file(&path)?;
+        let path = self.get_config_path()?;
+        if let Some(path) = &path {
+            config.merge_from_file(path)?;
         }
         
         // Apply environment variable overrides
@@ -62,8 +64,17 @@ impl ConfigLoader {
         // Apply manual overrides
         self.apply_manual_overrides(&mut config)?;
         
-        // Validate the final configuration
-        crate::config::validation::validate_config(&config)?;
+        // Validate the final configuration, pointing at the file where possible
+        if let Err(err) = crate::config::validation::validate_config(&config) {
+            return Err(match &path {
+                Some(path) => crate::config::span::locate(err, path),
+                None => err,
+            });
+        }
+        
+        for warning in &config.warnings {
+            log::warn!("{}", warning);
+        }
         
         Ok(config)
     }
@@ -101,8 +112,8 @@ impl ConfigLoader {
                 .ok_or(ConfigError::NoConfigDir)?
                 .join(".config")
                 .join("neopilot.toml"),
-            // System-wide configuration
-            PathBuf::from("/etc/neopilot/config.toml"),
+            // System-wide configuration (/etc or %ProgramData%)
+            super::paths::system_config_file(),
         ];
         
         for path in &possible_paths {
@@ -115,14 +126,20 @@ impl ConfigLoader {
     }
     
     /// Apply environment variable overrides to the configuration
-    fn apply_env_overrides(&self, config: &mut Config) -> Result<(), ConfigError> {
+    ///
+    /// Other tools share the prefix, e.g. `NEOPILOT_VIM_BRANCH`, so variables
+    /// naming no configuration key are skipped. Bad values of known keys
+    /// still fail the load.
+    pub(super) fn apply_env_overrides(&self, config: &mut Config) -> Result<(), ConfigError> {
         for (key, value) in env::vars() {
             if let Some(rest) = key.strip_prefix(&self.env_prefix) {
-                // Convert NEOPILOT_TOKENIZER_MODEL to tokenizer.model
-                let path = rest
-                    .to_lowercase()
-                    .replace("__", ".");
-                config.set_from_str(&path, &value)?;
+                let path = env_key_path(rest);
+                match config.set_from_str(&path, &value) {
+                    Err(ConfigError::InvalidPath(_)) => {
+                        log::debug!("Ignoring {key}: '{path}' is not a configuration key");
+                    }
+                    result => result?,
+                }
             }
         }
         
@@ -139,6 +156,20 @@ impl ConfigLoader {
     }
 }
 
+/// Convert the part of a variable name after the prefix to a dotted path
+///
+/// `__` separates levels, `NETWORK__MAX_RETRIES` is `network.max_retries`;
+/// without it the first `_` ends the section, `TOKENIZER_MODEL` is
+/// `tokenizer.model`.
+fn env_key_path(name: &str) -> String {
+    let name = name.to_lowercase();
+    if name.contains("__") {
+        name.replace("__", ".")
+    } else {
+        name.replacen('_', ".", 1)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -176,6 +207,45 @@ mod tests {
         Ok(())
     }
     
+    #[test]
+    fn test_errors_point_at_the_file() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let config_path = dir.path().join("neopilot.toml");
+
+        std::fs::write(&config_path, "[tokenizer]\nmodel = \"gpt-4o\"\nmax_tokens = 0\n")?;
+        let err = ConfigLoader::new().with_config_path(&config_path).load().unwrap_err();
+        assert!(matches!(err, ConfigError::InFile { .. }));
+        assert_eq!(err.key(), Some("tokenizer.max_tokens"));
+        assert!(err.to_string().contains("neopilot.toml:3:1: tokenizer.max_tokens"));
+
+        std::fs::write(&config_path, "[network]\nmax_retries = \"three\"\n")?;
+        let err = ConfigLoader::new().with_config_path(&config_path).load().unwrap_err();
+        assert_eq!(err.key(), Some("network.max_retries"));
+        assert!(err.render().contains("2 | max_retries = \"three\""));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_overrides_are_validated() {
+        let result = ConfigLoader::new().with_override("network.max_retrys", "2").load();
+        assert!(matches!(result, Err(ConfigError::InvalidPath(_))));
+        let result = ConfigLoader::new().with_override("network.max_retries", "many").load();
+        assert!(matches!(
+            result,
+            Err(ConfigError::InvalidValue(message)) if message.starts_with("network.max_retries")
+        ));
+
+        // Overrides keep the other settings
+        let config = ConfigLoader::new()
+            .with_override("tokenizer.model", "overridden-model")
+            .with_override("tokenizer.max_tokens", "2048")
+            .load()
+            .unwrap();
+        assert_eq!(config.tokenizer.model, "overridden-model");
+        assert_eq!(config.tokenizer.max_tokens, 2048);
+    }
+
     #[test]
     fn test_env_overrides() -> Result<(), Box<dyn std::error::Error>> {
         env::set_var("NEOPILOT_TOKENIZER_MODEL", "env-model");
@@ -192,4 +262,23 @@ mod tests {
         
         Ok(())
     }
+
+    #[test]
+    fn test_unknown_env_keys_are_skipped() -> Result<(), Box<dyn std::error::Error>> {
+        let loader = ConfigLoader::new().with_env_prefix("NEOPILOT_TEST_SKIP_");
+        env::set_var("NEOPILOT_TEST_SKIP_VIM_BRANCH", "main");
+        env::set_var("NEOPILOT_TEST_SKIP_TOKENIZER__MAX_TOKENS", "1024");
+        let config = loader.clone().load()?;
+        assert_eq!(config.tokenizer.max_tokens, 1024);
+
+        // Bad values of known keys still fail
+        env::set_var("NEOPILOT_TEST_SKIP_TOKENIZER__MAX_TOKENS", "many");
+        assert!(matches!(loader.load(), Err(ConfigError::InvalidValue(_))));
+
+        env::remove_var("NEOPILOT_TEST_SKIP_VIM_BRANCH");
+        env::remove_var("NEOPILOT_TEST_SKIP_TOKENIZER__MAX_TOKENS");
+        assert_eq!(env_key_path("TOKENIZER_MAX_TOKENS"), "tokenizer.max_tokens");
+        assert_eq!(env_key_path("NETWORK__MAX_RETRIES"), "network.max_retries");
+        Ok(())
+    }
 }
@@ -5,15 +5,20 @@
 
 mod error;
 mod loader;
+pub mod paths;
+mod span;
 mod validation;
+mod warning;
 
 use std::path::PathBuf;
 use std::time::Duration;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use serde::{Deserialize, Serialize};
 pub use error::ConfigError;
 pub use loader::ConfigLoader;
+pub use span::ConfigSpan;
 pub use validation::validate_config;
+pub use warning::ConfigWarning;
 
 /// Main configuration structure containing all configuration options
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,9 +34,14 @@ pub struct Config {
     pub performance: PerformanceConfig,
     /// Logging configuration
     pub logging: LoggingConfig,
+    /// Repo map configuration
+    pub repo_map: RepoMapConfig,
     /// Internal field for storing raw configuration values
     #[serde(skip_serializing, skip_deserializing)]
     pub overrides: HashMap<String, toml::Value>,
+    /// Problems found while loading that did not prevent loading
+    #[serde(skip_serializing, skip_deserializing)]
+    pub warnings: Vec<ConfigWarning>,
 }
 
 /// Configuration for tokenizer-related settings
@@ -50,12 +60,18 @@ pub struct TokenizerConfig {
     pub batch_size: usize,
     /// Timeout for tokenizer operations
     pub timeout: Duration,
+    /// Largest text in bytes encoded at once; larger input fails with an
+    /// input-too-large error instead of stalling the editor (0 disables it)
+    pub max_input_bytes: usize,
 }
 
 /// Network-related configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct NetworkConfig {
+    /// Master switch for network access; when false, anything that would
+    /// download fails with a "network disabled" error instead
+    pub enabled: bool,
     /// Maximum number of retry attempts
     pub max_retries: u32,
     /// Connection timeout in seconds
@@ -66,6 +82,21 @@ pub struct NetworkConfig {
     pub allowed_domains: Vec<String>,
     /// Maximum download size in bytes
     pub max_download_size: u64,
+    /// Hugging Face access token for gated repositories such as Llama; the
+    /// `HF_TOKEN` environment variable is used when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hf_token: Option<String>,
+    /// `User-Agent` sent with downloads, instead of the tokenizers' own
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    /// Headers sent to mirrors and tokenizer URLs, e.g. the credentials of
+    /// an artifact mirror
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// Mirror URLs of each model, tried in order when downloading its
+    /// tokenizer from the primary source fails
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub mirrors: BTreeMap<String, Vec<String>>,
 }
 
 /// Caching configuration
@@ -102,12 +133,50 @@ pub struct PerformanceConfig {
 pub struct LoggingConfig {
     /// Logging level (error, warn, info, debug, trace)
     pub level: String,
+    /// Per-target levels overriding `level`, e.g. `network = "debug"`
+    pub targets: BTreeMap<String, String>,
     /// Optional path to log file
     pub file: Option<PathBuf>,
     /// Maximum number of log files to keep
     pub max_files: usize,
     /// Maximum size of each log file in MB
     pub max_size_mb: u64,
+    /// Number of recent log records kept in memory (0 disables the buffer)
+    pub ring_buffer_size: usize,
+}
+
+/// Repo map configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RepoMapConfig {
+    /// Weights of the signals used to rank files
+    pub ranking: RankingConfig,
+    /// Include per-function line counts and branch counts in the output
+    pub include_metrics: bool,
+    /// Scan vendored and third-party directories such as `vendor/` or `node_modules/`
+    pub include_vendored: bool,
+    /// List private definitions of the focus files too, other files stay
+    /// public-only; see [`crate::render::private_focus_definitions`]
+    pub include_private_in_focus: bool,
+    /// Glob patterns mapped to languages, consulted before file extensions,
+    /// e.g. `"*.inc" = "php"`; see [`crate::languages`]
+    pub languages: BTreeMap<String, String>,
+}
+
+/// Weights of the ranking signals, see [`crate::rank`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RankingConfig {
+    /// Weight of references to a file's definitions from other files
+    pub reference_weight: f64,
+    /// Weight of how recently a file was modified
+    pub recency_weight: f64,
+    /// Penalty applied to large files, 0 disables it
+    pub size_penalty: f64,
+    /// Multiplier applied to the score of focus files
+    pub focus_boost: f64,
+    /// Weight of each reference from a focus file to another file's symbols
+    pub focus_reference_weight: f64,
 }
 
 // Implement default values for all configuration structs
@@ -119,7 +188,9 @@ impl Default for Config {
             cache: CacheConfig::default(),
             performance: PerformanceConfig::default(),
             logging: LoggingConfig::default(),
+            repo_map: RepoMapConfig::default(),
             overrides: HashMap::new(),
+            warnings: Vec::new(),
         }
     }
 }
@@ -128,12 +199,12 @@ impl Default for TokenizerConfig {
     fn default() -> Self {
         Self {
             model: "gpt-4o".to_string(),
-            cache_dir: dirs::cache_dir()
-                .unwrap_or_else(|| PathBuf::from("/tmp/neopilot")),
+            cache_dir: paths::cache_dir(),
             max_tokens: 4096,
             chunk_size: 1000,
             batch_size: 10,
             timeout: Duration::from_secs(30),
+            max_input_bytes: 10 * 1024 * 1024,
         }
     }
 }
@@ -141,6 +212,7 @@ impl Default for TokenizerConfig {
 impl Default for NetworkConfig {
     fn default() -> Self {
         Self {
+            enabled: true,
             max_retries: 3,
             connect_timeout: Duration::from_secs(10),
             request_timeout: Duration::from_secs(30),
@@ -149,13 +221,17 @@ impl Default for NetworkConfig {
                 "cdn-lfs.huggingface.co".to_string(),
             ],
             max_download_size: 100 * 1024 * 1024, // 100MB
+            hf_token: None,
+            user_agent: None,
+            headers: BTreeMap::new(),
+            mirrors: BTreeMap::new(),
         }
     }
 }
 
 impl Default for CacheConfig {
     fn default() -> Self {
-        let mut cache_path = dirs::cache_dir().unwrap_or_else(|| PathBuf::from("/tmp/neopilot"));
+        let mut cache_path = paths::cache_dir();
         cache_path.push("cache");
         
         Self {
@@ -180,14 +256,28 @@ impl Default for PerformanceConfig {
 
 impl Default for LoggingConfig {
     fn default() -> Self {
-        let mut log_path = dirs::cache_dir().unwrap_or_else(|| PathBuf::from("/tmp/neopilot"));
+        let mut log_path = paths::cache_dir();
         log_path.push("neopilot.log");
         
         Self {
             level: "info".to_string(),
+            targets: BTreeMap::new(),
             file: Some(log_path),
             max_files: 5,
             max_size_mb: 50,
+            ring_buffer_size: 500,
+        }
+    }
+}
+
+impl Default for RankingConfig {
+    fn default() -> Self {
+        Self {
+            reference_weight: 1.0,
+            recency_weight: 0.0,
+            size_penalty: 0.0,
+            focus_boost: crate::rank::FOCUS_FILE_BOOST,
+            focus_reference_weight: crate::rank::FOCUS_REFERENCE_WEIGHT,
         }
     }
 }
@@ -206,72 +296,149 @@ impl Config {
         config.apply_env_overrides()?;
         
         // Validate the configuration
-        validate_config(&config)?;
+        if let Err(err) = validate_config(&config) {
+            return Err(match ConfigLoader::find_config_file()? {
+                Some(path) => span::locate(err, &path),
+                None => err,
+            });
+        }
         
         Ok(config)
     }
+
+    /// Default configuration for tests, without reading files or the environment
+    ///
+    /// Network access and the cache are disabled, so tests neither download
+    /// nor share state on disk. Tests running in parallel can each build one
+    /// instead of setting process-wide `NEOPILOT_` variables or changing the
+    /// working directory.
+    pub fn for_tests() -> Self {
+        let mut config = Self::default();
+        config.network.enabled = false;
+        config.cache.enabled = false;
+        config
+    }
+
+    /// Build a configuration from a TOML value, e.g. one handed over by a plugin
+    ///
+    /// Keys left out take their default values. Unlike configuration files,
+    /// unknown keys fail with [`ConfigError::InvalidPath`], as the value comes
+    /// from code rather than from a user.
+    pub fn from_value(value: toml::Value) -> Result<Self, ConfigError> {
+        let mut unknown = None;
+        let config: Self = serde_ignored::deserialize(value, |key| {
+            unknown.get_or_insert_with(|| key.to_string());
+        })
+        .map_err(|e| ConfigError::InvalidValue(e.message().to_string()))?;
+        if let Some(key) = unknown {
+            return Err(ConfigError::InvalidPath(key));
+        }
+        validate_config(&config)?;
+        Ok(config)
+    }
     
     /// Merge configuration from a file
+    ///
+    /// Unknown keys do not fail the merge; they are recorded in
+    /// [`Config::warnings`] so callers can report them. Syntax errors and
+    /// values of the wrong type fail with [`ConfigError::InFile`], pointing at
+    /// the offending key.
     pub fn merge_from_file(&mut self, path: &std::path::Path) -> Result<(), ConfigError> {
         let content = std::fs::read_to_string(path)
             .map_err(|e| ConfigError::IoError(e, path.to_path_buf()))?;
             
-        let new_config: Self = toml::from_str(&content)
-            .map_err(|e| ConfigError::TomlError(e, path.to_path_buf()))?;
+        let mut unknown_keys = Vec::new();
+        let mut new_config: Self =
+            serde_ignored::deserialize(toml::Deserializer::new(&content), |key| {
+                unknown_keys.push(key.to_string())
+            })
+            .map_err(|e| match e.span() {
+                Some(range) => {
+                    let span = ConfigSpan::at_offset(path, &content, range.start);
+                    let message = match &span.key {
+                        Some(key) => format!("{key}: {}", e.message()),
+                        None => e.message().to_string(),
+                    };
+                    ConfigError::InFile { message, span }
+                },
+                None => ConfigError::TomlError(e, path.to_path_buf()),
+            })?;
+            
+        let mut warnings = std::mem::take(&mut self.warnings);
+        warnings.extend(unknown_keys.into_iter().map(|key| ConfigWarning::UnknownKey {
+            key,
+            file: path.to_path_buf(),
+        }));
+        new_config.warnings = warnings;
             
         *self = new_config;
         Ok(())
     }
     
     /// Apply environment variable overrides
+    ///
+    /// Variables are named like for [`ConfigLoader`], e.g.
+    /// `NEOPILOT_TOKENIZER_MODEL` or `NEOPILOT_NETWORK__MAX_RETRIES`. Those
+    /// that do not name a configuration key are ignored, so other `NEOPILOT_`
+    /// variables in the environment do not break loading.
     pub fn apply_env_overrides(&mut self) -> Result<(), ConfigError> {
-        for (key, value) in std::env::vars() {
-            if let Some(rest) = key.strip_prefix("NEOPILOT_") {
-                let path = rest.to_lowercase().replace("__", ".");
-                self.set_from_str(&path, &value)?;
-            }
-        }
-        Ok(())
+        ConfigLoader::new().apply_env_overrides(self)
     }
     
     /// Set a configuration value from a string path
+    ///
+    /// The value is merged into the current configuration and checked against
+    /// its schema: unknown keys fail with [`ConfigError::InvalidPath`] and
+    /// values of the wrong type with [`ConfigError::InvalidValue`] naming the
+    /// key. Other settings are kept.
     pub fn set_from_str(&mut self, path: &str, value: &str) -> Result<(), ConfigError> {
-        // Store the raw value for later deserialization
-        let mut current = toml::value::Table::new();
-        let mut keys: Vec<&str> = path.split('.').collect();
-        
-        if keys.is_empty() {
+        let keys: Vec<&str> = path.split('.').collect();
+        if keys.iter().any(|key| key.is_empty()) {
             return Err(ConfigError::InvalidPath(path.to_string()));
         }
-        
-        let last_key = keys.pop().unwrap();
-        let mut current_table = &mut current;
-        
-        for key in keys {
-            let nested = toml::value::Table::new();
-            current_table.insert(key.to_string(), toml::Value::Table(nested));
-            current_table = match current_table.get_mut(key) {
-                Some(toml::Value::Table(t)) => t,
+        let (last_key, parents) = keys.split_last().expect("split always yields a key");
+
+        let mut current = toml::Value::try_from(&*self)
+            .map_err(|e| ConfigError::InvalidValue(format!("Failed to serialize config: {e}")))?;
+        let mut table = match &mut current {
+            toml::Value::Table(table) => table,
+            _ => return Err(ConfigError::InvalidPath(path.to_string())),
+        };
+        for key in parents {
+            let nested = table
+                .entry(key.to_string())
+                .or_insert_with(|| toml::Value::Table(toml::value::Table::new()));
+            table = match nested {
+                toml::Value::Table(nested) => nested,
                 _ => return Err(ConfigError::InvalidPath(path.to_string())),
             };
         }
         
         // Try to parse the value as different types
-        if let Ok(bool_val) = value.parse::<bool>() {
-            current_table.insert(last_key.to_string(), toml::Value::Boolean(bool_val));
+        let value = if let Ok(bool_val) = value.parse::<bool>() {
+            toml::Value::Boolean(bool_val)
         } else if let Ok(int_val) = value.parse::<i64>() {
-            current_table.insert(last_key.to_string(), toml::Value::Integer(int_val));
+            toml::Value::Integer(int_val)
         } else if let Ok(float_val) = value.parse::<f64>() {
-            current_table.insert(last_key.to_string(), toml::Value::Float(float_val));
+            toml::Value::Float(float_val)
         } else {
             // Default to string
-            current_table.insert(last_key.to_string(), toml::Value::String(value.to_string()));
-        }
+            toml::Value::String(value.to_string())
+        };
+        table.insert(last_key.to_string(), value);
         
-        // Merge with existing config
-        let new_config: Config = toml::Value::Table(current).try_into()
-            .map_err(|e| ConfigError::InvalidValue(format!("Failed to convert TOML to config: {}", e)))?;
+        let mut unknown = false;
+        let mut new_config: Config = serde_ignored::deserialize(current, |ignored| {
+            let ignored = ignored.to_string();
+            unknown |= path == ignored || path.starts_with(&format!("{ignored}."));
+        })
+        .map_err(|e| ConfigError::InvalidValue(format!("{path}: {}", e.message())))?;
+        if unknown {
+            return Err(ConfigError::InvalidPath(path.to_string()));
+        }
             
+        new_config.overrides = std::mem::take(&mut self.overrides);
+        new_config.warnings = std::mem::take(&mut self.warnings);
         *self = new_config;
         Ok(())
     }
@@ -289,9 +456,36 @@ mod tests {
         let config = Config::default();
         assert_eq!(config.tokenizer.model, "gpt-4o");
         assert_eq!(config.network.max_retries, 3);
+        assert!(config.network.enabled);
+        assert!(config.network.hf_token.is_none());
+        assert!(config.network.headers.is_empty());
         assert!(config.cache.enabled);
     }
     
+    #[test]
+    fn test_for_tests() {
+        let config = Config::for_tests();
+        assert!(!config.network.enabled);
+        assert!(!config.cache.enabled);
+        assert!(validate_config(&config).is_ok());
+    }
+
+    #[test]
+    fn test_from_value() {
+        let value: toml::Value = toml::from_str("[tokenizer]\nmodel = \"injected\"").unwrap();
+        let config = Config::from_value(value).unwrap();
+        assert_eq!(config.tokenizer.model, "injected");
+        assert_eq!(config.network.max_retries, 3);
+
+        let value: toml::Value = toml::from_str("[tokenizer]\nmodle = \"typo\"").unwrap();
+        assert!(matches!(
+            Config::from_value(value),
+            Err(ConfigError::InvalidPath(key)) if key == "tokenizer.modle"
+        ));
+        let value: toml::Value = toml::from_str("[network]\nmax_retries = 99").unwrap();
+        assert!(matches!(Config::from_value(value), Err(ConfigError::ValidationError(_))));
+    }
+    
     #[test]
     fn test_env_override() -> Result<(), Box<dyn std::error::Error>> {
         env::set_var("NEOPILOT_TOKENIZER_MODEL", "gpt-4");
@@ -331,6 +525,23 @@ mod tests {
         assert_eq!(config.tokenizer.model, "custom-model");
         assert_eq!(config.tokenizer.max_tokens, 2048);
         assert_eq!(config.network.max_retries, 2);
+        assert!(config.warnings.is_empty());
+        
+        Ok(())
+    }
+    
+    #[test]
+    fn test_unknown_keys_are_reported() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("config.toml");
+        fs::write(&file_path, "[tokenizer]\nmax_token = 10\n\n[typo_section]\nkey = 1\n")?;
+        
+        let mut config = Config::default();
+        config.merge_from_file(&file_path)?;
+        
+        let keys: Vec<String> = config.warnings.iter().map(|w| w.key().to_string()).collect();
+        assert_eq!(keys, vec!["tokenizer.max_token", "typo_section"]);
+        assert_eq!(config.tokenizer.max_tokens, 4096);
         
         Ok(())
     }
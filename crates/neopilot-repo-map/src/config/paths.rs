@@ -0,0 +1,74 @@
+//! Platform-specific default locations
+//!
+//! | Purpose        | Linux                     | macOS                         | Windows                          |
+//! |----------------|---------------------------|-------------------------------|----------------------------------|
+//! | cache          | `$XDG_CACHE_HOME/neopilot` | `~/Library/Caches/neopilot`   | `%LOCALAPPDATA%\neopilot`        |
+//! | system config  | `/etc/neopilot`           | `/etc/neopilot`               | `%ProgramData%\neopilot`         |
+//!
+//! When the platform directory cannot be determined, the system temporary
+//! directory is used instead of a hard-coded POSIX path.
+
+use std::path::PathBuf;
+
+const APP_DIR: &str = "neopilot";
+
+/// Base directory for everything neopilot caches
+pub fn cache_dir() -> PathBuf {
+    dirs::cache_dir()
+        .map(|dir| dir.join(APP_DIR))
+        .unwrap_or_else(fallback_dir)
+}
+
+/// Directory used when no platform directory is available
+pub fn fallback_dir() -> PathBuf {
+    std::env::temp_dir().join(APP_DIR)
+}
+
+/// Directory holding the machine-wide configuration
+#[cfg(windows)]
+pub fn system_config_dir() -> PathBuf {
+    std::env::var_os("ProgramData")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(r"C:\ProgramData"))
+        .join(APP_DIR)
+}
+
+/// Directory holding the machine-wide configuration
+#[cfg(not(windows))]
+pub fn system_config_dir() -> PathBuf {
+    PathBuf::from("/etc").join(APP_DIR)
+}
+
+/// Machine-wide configuration file
+pub fn system_config_file() -> PathBuf {
+    system_config_dir().join("config.toml")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_dir_is_app_specific() {
+        assert!(cache_dir().ends_with(APP_DIR));
+        assert!(fallback_dir().starts_with(std::env::temp_dir()));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_windows_paths() {
+        let config = system_config_file();
+        assert!(config.ends_with(r"neopilot\config.toml"));
+        assert!(!config.starts_with("/etc"));
+        if let Some(local) = std::env::var_os("LOCALAPPDATA") {
+            assert!(cache_dir().starts_with(local));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_paths() {
+        assert_eq!(system_config_file(), PathBuf::from("/etc/neopilot/config.toml"));
+        assert!(cache_dir().is_absolute());
+    }
+}
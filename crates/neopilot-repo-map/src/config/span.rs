@@ -0,0 +1,195 @@
+//! Locating keys in configuration files, for error messages
+//!
+//! The TOML parser reports byte offsets and validation reports dotted key
+//! paths; [`ConfigSpan`] turns either into a file position and the key found
+//! there, so errors point at the offending line. Files are scanned line by
+//! line, which is enough for the flat tables configuration files use.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use super::ConfigError;
+
+/// A position in a configuration file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigSpan {
+    pub file: PathBuf,
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column, in characters
+    pub column: usize,
+    /// Dotted path of the key at this position, if any
+    pub key: Option<String>,
+    /// Text of the line, for rendering
+    pub source_line: String,
+}
+
+impl fmt::Display for ConfigSpan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file.display(), self.line, self.column)
+    }
+}
+
+/// Strip a trailing comment that is not inside a string
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            ('#', None) => return &line[..i],
+            _ => {},
+        }
+    }
+    line
+}
+
+/// Dotted key path with quotes and whitespace removed, e.g. `a."b".c` to `a.b.c`
+fn normalize_key(key: &str) -> String {
+    key.split('.')
+        .map(|part| part.trim().trim_matches(|c| c == '"' || c == '\''))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Table named by a `[table]` or `[[table]]` header line
+fn table_header(line: &str) -> Option<String> {
+    let line = strip_comment(line).trim();
+    let inner = line.strip_prefix('[')?.strip_suffix(']')?;
+    let inner = inner.strip_prefix('[').and_then(|s| s.strip_suffix(']')).unwrap_or(inner);
+    Some(normalize_key(inner))
+}
+
+/// Key assigned on a `key = value` line, relative to its table
+fn assigned_key(line: &str) -> Option<String> {
+    let (key, _) = strip_comment(line).split_once('=')?;
+    let key = key.trim();
+    (!key.is_empty() && !key.starts_with('[')).then(|| normalize_key(key))
+}
+
+fn join_key(table: &str, key: &str) -> String {
+    if table.is_empty() {
+        key.to_string()
+    } else {
+        format!("{table}.{key}")
+    }
+}
+
+impl ConfigSpan {
+    fn new(file: &Path, line_index: usize, column: usize, key: Option<String>, line: &str) -> Self {
+        Self {
+            file: file.to_path_buf(),
+            line: line_index + 1,
+            column: column + 1,
+            key,
+            source_line: line.to_string(),
+        }
+    }
+
+    /// The span of byte `offset` in `content`, with the key assigned there
+    ///
+    /// Values spanning several lines, such as arrays, are attributed to the
+    /// key on the closest line above.
+    pub fn at_offset(file: &Path, content: &str, offset: usize) -> Self {
+        let offset = offset.min(content.len());
+        let line_index = content[..offset].matches('\n').count();
+        let line_start = content[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let column = content[line_start..offset].chars().count();
+
+        let mut table = String::new();
+        let mut key = None;
+        for line in content.lines().take(line_index + 1) {
+            if let Some(header) = table_header(line) {
+                key = Some(header.clone());
+                table = header;
+            } else if let Some(assigned) = assigned_key(line) {
+                key = Some(join_key(&table, &assigned));
+            }
+        }
+        let line = content.lines().nth(line_index).unwrap_or("");
+        Self::new(file, line_index, column, key, line)
+    }
+
+    /// The span of the line assigning `key`, or of its table header
+    pub fn for_key(file: &Path, content: &str, key: &str) -> Option<Self> {
+        let mut table = String::new();
+        for (line_index, line) in content.lines().enumerate() {
+            let found = if let Some(header) = table_header(line) {
+                table = header;
+                table == key
+            } else {
+                assigned_key(line).is_some_and(|assigned| join_key(&table, &assigned) == key)
+            };
+            if found {
+                let column = line.chars().take_while(|c| c.is_whitespace()).count();
+                return Some(Self::new(file, line_index, column, Some(key.to_string()), line));
+            }
+        }
+        None
+    }
+
+    /// The line with a caret under the column, indented for an error message
+    pub fn render(&self) -> String {
+        let number = self.line.to_string();
+        let gutter = " ".repeat(number.len());
+        let caret = " ".repeat(self.column.saturating_sub(1));
+        format!("{gutter} |\n{number} | {}\n{gutter} | {caret}^", self.source_line)
+    }
+}
+
+/// Point a validation error at the line of the file at `path` setting its key
+///
+/// Errors about keys the file does not set, e.g. ones coming from the
+/// environment, are returned unchanged.
+pub(crate) fn locate(err: ConfigError, path: &Path) -> ConfigError {
+    if !matches!(err, ConfigError::ValidationError(_)) {
+        return err;
+    }
+    let span = err.key().and_then(|key| {
+        let content = std::fs::read_to_string(path).ok()?;
+        ConfigSpan::for_key(path, &content, key)
+    });
+    match (err, span) {
+        (ConfigError::ValidationError(message), Some(span)) => {
+            ConfigError::InFile { message, span }
+        },
+        (err, _) => err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENT: &str = "\
+# Neopilot Configuration
+[tokenizer]
+model = \"gpt-4o\"  # the default
+max_tokens = \"many\"
+
+[repo_map.languages]
+\"*.inc\" = \"cpp\"
+";
+
+    #[test]
+    fn test_at_offset() {
+        let offset = CONTENT.find("\"many\"").unwrap();
+        let span = ConfigSpan::at_offset(Path::new("neopilot.toml"), CONTENT, offset);
+        assert_eq!((span.line, span.column), (4, 14));
+        assert_eq!(span.key.as_deref(), Some("tokenizer.max_tokens"));
+        assert_eq!(span.to_string(), "neopilot.toml:4:14");
+        assert_eq!(span.render(), "  |\n4 | max_tokens = \"many\"\n  |              ^");
+    }
+
+    #[test]
+    fn test_for_key() {
+        let file = Path::new("neopilot.toml");
+        let span = ConfigSpan::for_key(file, CONTENT, "tokenizer.model").unwrap();
+        assert_eq!((span.line, span.column), (3, 1));
+        let span = ConfigSpan::for_key(file, CONTENT, "repo_map.languages.*.inc").unwrap();
+        assert_eq!(span.line, 7);
+        let span = ConfigSpan::for_key(file, CONTENT, "repo_map.languages").unwrap();
+        assert_eq!(span.line, 6);
+        assert!(ConfigSpan::for_key(file, CONTENT, "network.max_retries").is_none());
+    }
+}
@@ -6,9 +6,11 @@ use super::{Config, ConfigError};
 pub fn validate_config(config: &Config) -> Result<(), ConfigError> {
     validate_tokenizer_config(&config.tokenizer)?;
     validate_network_config(&config.network)?;
+    validate_tokenizer_source(&config.tokenizer, &config.network)?;
     validate_cache_config(&config.cache)?;
     validate_performance_config(&config.performance)?;
     validate_logging_config(&config.logging)?;
+    validate_repo_map_config(&config.repo_map)?;
     
     Ok(())
 }
@@ -67,10 +69,89 @@ fn validate_network_config(config: &super::NetworkConfig) -> Result<(), ConfigEr
             "network.max_download_size cannot exceed 1GB".to_string(),
         ));
     }
+
+    // network.user_agent and network.headers are checked once, by the
+    // tokenizers when they are applied, with the rules of the HTTP client
+
+    for (model, mirrors) in &config.mirrors {
+        for mirror in mirrors {
+            let host = url::Url::parse(mirror)
+                .ok()
+                .filter(|url| url.scheme() == "https")
+                .and_then(|url| url.host_str().map(str::to_string))
+                .ok_or_else(|| {
+                    ConfigError::ValidationError(format!(
+                        "network.mirrors.{model} has '{mirror}', which is not an https URL"
+                    ))
+                })?;
+            if !is_domain_allowed(&host, &config.allowed_domains) {
+                return Err(ConfigError::ValidationError(format!(
+                    "network.mirrors.{model} host '{host}' is not in network.allowed_domains"
+                )));
+            }
+        }
+    }
     
     Ok(())
 }
 
+/// Check whether `host` is one of `allowed_domains` or a subdomain of one
+fn is_domain_allowed(host: &str, allowed_domains: &[String]) -> bool {
+    allowed_domains.iter().any(|domain| {
+        let domain = domain.trim_start_matches('.');
+        host == domain || host.ends_with(&format!(".{domain}"))
+    })
+}
+
+/// Validate a tokenizer model given as a URL against the network settings
+///
+/// This surfaces misconfigured downloads at startup instead of on first use.
+fn validate_tokenizer_source(
+    tokenizer: &super::TokenizerConfig,
+    network: &super::NetworkConfig,
+) -> Result<(), ConfigError> {
+    if !tokenizer.model.contains("://") {
+        return Ok(());
+    }
+
+    let url = url::Url::parse(&tokenizer.model).map_err(|e| {
+        ConfigError::ValidationError(format!(
+            "tokenizer.model is not a valid URL '{}': {}",
+            tokenizer.model, e
+        ))
+    })?;
+
+    if url.scheme() != "https" {
+        return Err(ConfigError::ValidationError(format!(
+            "tokenizer.model must use https, got '{}'",
+            url.scheme()
+        )));
+    }
+
+    let host = url.host_str().ok_or_else(|| {
+        ConfigError::ValidationError(format!(
+            "tokenizer.model URL has no host: {}",
+            tokenizer.model
+        ))
+    })?;
+    if !is_domain_allowed(host, &network.allowed_domains) {
+        return Err(ConfigError::ValidationError(format!(
+            "tokenizer.model host '{}' is not in network.allowed_domains ({})",
+            host,
+            network.allowed_domains.join(", ")
+        )));
+    }
+
+    if network.max_download_size == 0 {
+        return Err(ConfigError::ValidationError(
+            "network.max_download_size must be greater than 0 when tokenizer.model is a URL"
+                .to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
 /// Validate cache configuration
 fn validate_cache_config(config: &super::CacheConfig) -> Result<(), ConfigError> {
     if config.enabled {
@@ -155,6 +236,17 @@ fn validate_logging_config(config: &super::LoggingConfig) -> Result<(), ConfigEr
         )));
     }
     
+    for (target, level) in &config.targets {
+        if !valid_levels.contains(&level.to_lowercase().as_str()) {
+            return Err(ConfigError::ValidationError(format!(
+                "Invalid log level '{}' for target '{}'. Must be one of: {}",
+                level,
+                target,
+                valid_levels.join(", ")
+            )));
+        }
+    }
+    
     // Validate log file configuration if logging to file is enabled
     if let Some(log_file) = &config.file {
         if let Some(parent) = log_file.parent() {
@@ -196,6 +288,37 @@ fn validate_logging_config(config: &super::LoggingConfig) -> Result<(), ConfigEr
     Ok(())
 }
 
+/// Validate repo map configuration
+fn validate_repo_map_config(config: &super::RepoMapConfig) -> Result<(), ConfigError> {
+    let ranking = &config.ranking;
+    let weights = [
+        ("reference_weight", ranking.reference_weight),
+        ("recency_weight", ranking.recency_weight),
+        ("size_penalty", ranking.size_penalty),
+        ("focus_boost", ranking.focus_boost),
+        ("focus_reference_weight", ranking.focus_reference_weight),
+    ];
+    for (name, weight) in weights {
+        if !weight.is_finite() || weight < 0.0 {
+            return Err(ConfigError::ValidationError(format!(
+                "repo_map.ranking.{} must be a non-negative number, got {}",
+                name, weight
+            )));
+        }
+    }
+
+    for (pattern, language) in &config.languages {
+        if !crate::SUPPORTED_LANGUAGES.contains(&language.as_str()) {
+            return Err(ConfigError::ValidationError(format!(
+                "repo_map.languages.\"{}\" maps to unsupported language '{}'",
+                pattern, language
+            )));
+        }
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -244,8 +367,46 @@ mod tests {
         // Invalid max_download_size
         config.max_download_size = 2 * 1024 * 1024 * 1024; // 2GB
         assert!(validate_network_config(&config).is_err());
+        config.max_download_size = 1024;
+
+        // Mirrors must be https and allowed
+        let model = "meta-llama/Llama-3.1-8B".to_string();
+        config.mirrors.insert(model.clone(), vec!["https://hf-mirror.com".to_string()]);
+        assert!(validate_network_config(&config).is_err());
+        config.allowed_domains.push("hf-mirror.com".to_string());
+        assert!(validate_network_config(&config).is_ok());
+        config.mirrors.insert(model, vec!["http://hf-mirror.com".to_string()]);
+        assert!(validate_network_config(&config).is_err());
     }
     
+    #[test]
+    fn test_validate_tokenizer_source() {
+        let mut tokenizer = TokenizerConfig::default();
+        let network = NetworkConfig::default();
+
+        // Model names are not URLs and are always accepted
+        assert!(validate_tokenizer_source(&tokenizer, &network).is_ok());
+
+        tokenizer.model =
+            "https://huggingface.co/Qwen/Qwen2.5-7B/resolve/main/tokenizer.json".to_string();
+        assert!(validate_tokenizer_source(&tokenizer, &network).is_ok());
+
+        // Subdomains of allowed domains are accepted
+        tokenizer.model = "https://cdn-lfs.huggingface.co/repo/tokenizer.json".to_string();
+        assert!(validate_tokenizer_source(&tokenizer, &network).is_ok());
+
+        tokenizer.model = "https://evil.example.com/tokenizer.json".to_string();
+        assert!(validate_tokenizer_source(&tokenizer, &network).is_err());
+
+        tokenizer.model = "http://huggingface.co/tokenizer.json".to_string();
+        assert!(validate_tokenizer_source(&tokenizer, &network).is_err());
+
+        tokenizer.model = "https://huggingface.co/tokenizer.json".to_string();
+        let mut network = NetworkConfig::default();
+        network.max_download_size = 0;
+        assert!(validate_tokenizer_source(&tokenizer, &network).is_err());
+    }
+
     #[test]
     fn test_validate_logging_config() {
         let mut config = LoggingConfig::default();
@@ -258,8 +419,33 @@ mod tests {
         assert!(validate_logging_config(&config).is_err());
         config.level = "info".to_string();
         
+        // Invalid per-target level
+        config.targets.insert("network".to_string(), "verbose".to_string());
+        assert!(validate_logging_config(&config).is_err());
+        config.targets.insert("network".to_string(), "debug".to_string());
+        assert!(validate_logging_config(&config).is_ok());
+        
         // Test with invalid file path (should fail on non-existent parent)
         config.file = Some(Path::new("/nonexistent/path/to/logfile.log").to_path_buf());
         assert!(validate_logging_config(&config).is_err());
     }
+
+    #[test]
+    fn test_validate_repo_map_config() {
+        let mut config = RepoMapConfig::default();
+        assert!(validate_repo_map_config(&config).is_ok());
+
+        config.ranking.recency_weight = -1.0;
+        assert!(validate_repo_map_config(&config).is_err());
+        config.ranking.recency_weight = 0.5;
+
+        config.ranking.size_penalty = f64::NAN;
+        assert!(validate_repo_map_config(&config).is_err());
+        config.ranking.size_penalty = 0.0;
+
+        config.languages.insert("*.inc".to_string(), "php".to_string());
+        assert!(validate_repo_map_config(&config).is_ok());
+        config.languages.insert("*.cbl".to_string(), "cobol".to_string());
+        assert!(validate_repo_map_config(&config).is_err());
+    }
 }
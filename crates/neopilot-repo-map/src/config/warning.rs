@@ -0,0 +1,30 @@
+//! Non-fatal problems found while loading configuration
+
+use std::fmt;
+use std::path::PathBuf;
+
+/// A problem in a configuration file that did not prevent loading it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigWarning {
+    /// A key that does not correspond to any configuration option
+    UnknownKey { key: String, file: PathBuf },
+}
+
+impl ConfigWarning {
+    /// Dotted path of the offending key
+    pub fn key(&self) -> &str {
+        match self {
+            ConfigWarning::UnknownKey { key, .. } => key,
+        }
+    }
+}
+
+impl fmt::Display for ConfigWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigWarning::UnknownKey { key, file } => {
+                write!(f, "Unknown configuration key '{}' in {:?}", key, file)
+            }
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! Context selection around a cursor position
+//!
+//! Given a position in a file, find the enclosing function or class and the
+//! definitions it references elsewhere in the repository, trimmed to a token
+//! budget. This is the core of "smart context" selection for prompts.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use neopilot_error::{Error, ErrorCode, Result, ResultExt};
+use tree_sitter::{Node, Point};
+
+use crate::index::RepoIndex;
+use crate::scan::{count_identifiers, language_for_path};
+use crate::{parse_source, stringify_definition};
+
+/// Node kinds that enclose code on their own (Ruby, JavaScript)
+const ENCLOSING_KINDS: &[&str] = &["method", "singleton_method", "class", "module", "function"];
+
+/// Prefix and suffix of node kinds such as `function_item` or `class_declaration`
+const ENCLOSING_PREFIXES: &[&str] = &[
+    "function",
+    "method",
+    "constructor",
+    "class",
+    "impl",
+    "struct",
+    "trait",
+    "interface",
+    "module",
+    "enum",
+    "namespace",
+];
+const ENCLOSING_SUFFIXES: &[&str] = &["_definition", "_declaration", "_item", "_specifier"];
+
+/// Rough token estimate used when no tokenizer is available
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// A definition referenced from the enclosing code
+#[derive(Debug, Clone)]
+pub struct ContextDefinition {
+    /// Path of the defining file, relative to the index root
+    pub path: String,
+    pub name: String,
+    /// Stringified definition as it appears in the repo map
+    pub text: String,
+}
+
+/// Context selected for a cursor position
+#[derive(Debug, Clone)]
+pub struct PositionContext {
+    pub language: String,
+    /// Kind of the enclosing node, `None` when the position is at top level
+    pub enclosing_kind: Option<String>,
+    /// First line of `source` (0-based)
+    pub start_line: usize,
+    /// Last line of `source` (0-based, inclusive)
+    pub end_line: usize,
+    /// Source of the enclosing function or class
+    pub source: String,
+    pub definitions: Vec<ContextDefinition>,
+    /// Estimated tokens used by `source` and `definitions`
+    pub tokens: usize,
+}
+
+pub(crate) fn is_enclosing_kind(kind: &str) -> bool {
+    ENCLOSING_KINDS.contains(&kind)
+        || (ENCLOSING_PREFIXES.iter().any(|p| kind.starts_with(p))
+            && ENCLOSING_SUFFIXES.iter().any(|s| kind.ends_with(s)))
+}
+
+fn find_enclosing<'a>(node: Node<'a>) -> Option<Node<'a>> {
+    let mut current = Some(node);
+    while let Some(node) = current {
+        if is_enclosing_kind(node.kind()) {
+            return Some(node);
+        }
+        current = node.parent();
+    }
+    None
+}
+
+/// Keep whole lines of `source` while they fit in `budget_tokens`
+fn truncate_lines(source: &str, budget_tokens: usize) -> String {
+    let mut res = String::new();
+    let mut tokens = 0;
+    for line in source.split_inclusive('\n') {
+        tokens += estimate_tokens(line);
+        if tokens > budget_tokens {
+            break;
+        }
+        res.push_str(line);
+    }
+    res
+}
+
+/// Select context for `source` at `line`/`col` (both 0-based, `col` in bytes)
+///
+/// Referenced definitions are looked up in `index` when one is available and
+/// added, most used first, while they fit in `budget_tokens`.
+pub fn context_for_source(
+    index: Option<&RepoIndex>,
+    path: &Path,
+    source: &str,
+    line: usize,
+    col: usize,
+    budget_tokens: usize,
+) -> Result<PositionContext> {
+    // Indexed files keep the language they were scanned with, which honours
+    // the configured overrides
+    let indexed_language = index.and_then(|index| {
+        let relative = path.strip_prefix(&index.root).unwrap_or(path).to_string_lossy();
+        index.files.get(relative.as_ref()).map(|file| file.language.as_str())
+    });
+    let language = indexed_language.or_else(|| language_for_path(path)).ok_or_else(|| {
+        Error::new(
+            ErrorCode::Unsupported,
+            format!("Unsupported file type: {}", path.display()),
+        )
+    })?;
+    let tree = parse_source(language, source)?;
+    let point = Point::new(line, col);
+    let node = tree
+        .root_node()
+        .named_descendant_for_point_range(point, point)
+        .unwrap_or_else(|| tree.root_node());
+
+    let (enclosing_kind, start_byte, end_byte, start_line, end_line) = match find_enclosing(node) {
+        Some(enclosing) => (
+            Some(enclosing.kind().to_string()),
+            enclosing.start_byte(),
+            enclosing.end_byte(),
+            enclosing.start_position().row,
+            enclosing.end_position().row,
+        ),
+        None => (None, 0, source.len(), 0, source.lines().count().saturating_sub(1)),
+    };
+    let enclosing_source = &source[start_byte..end_byte];
+    let source = truncate_lines(enclosing_source, budget_tokens);
+    let mut tokens = estimate_tokens(&source);
+
+    let mut definitions = Vec::new();
+    if let Some(index) = index {
+        let own_path = path.strip_prefix(&index.root).unwrap_or(path).to_string_lossy();
+        let mut by_name: BTreeMap<&str, Vec<(&str, &crate::Definition)>> = BTreeMap::new();
+        for (file_path, file) in &index.files {
+            for definition in &file.definitions {
+                by_name
+                    .entry(definition.name())
+                    .or_default()
+                    .push((file_path.as_str(), definition));
+            }
+        }
+
+        let mut used: Vec<(String, u32)> = count_identifiers(&source).into_iter().collect();
+        used.sort_by(|(a_name, a), (b_name, b)| b.cmp(a).then_with(|| a_name.cmp(b_name)));
+        for (name, _) in used {
+            let Some(candidates) = by_name.get(name.as_str()) else {
+                continue;
+            };
+            for (file_path, definition) in candidates {
+                if *file_path == own_path {
+                    continue;
+                }
+                let text = stringify_definition(definition);
+                let cost = estimate_tokens(&text);
+                if tokens + cost > budget_tokens {
+                    continue;
+                }
+                tokens += cost;
+                definitions.push(ContextDefinition {
+                    path: file_path.to_string(),
+                    name: name.clone(),
+                    text,
+                });
+            }
+        }
+    }
+
+    Ok(PositionContext {
+        language: language.to_string(),
+        enclosing_kind,
+        start_line,
+        end_line,
+        source,
+        definitions,
+        tokens,
+    })
+}
+
+/// Read `path`, from its buffer if it has one, and select context at
+/// `line`/`col`
+pub fn context_for_position(
+    index: Option<&RepoIndex>,
+    path: &Path,
+    line: usize,
+    col: usize,
+    budget_tokens: usize,
+) -> Result<PositionContext> {
+    let source = crate::overlay::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    context_for_source(index, path, &source, line, col, budget_tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::ScannedFile;
+    use crate::{Definition, Variable};
+    use std::path::PathBuf;
+
+    const SOURCE: &str = "fn helper() -> u32 {\n    1\n}\n\nfn main() {\n    let x = LIMIT;\n    println!(\"{}\", x);\n}\n";
+
+    #[test]
+    fn test_enclosing_function() -> Result<()> {
+        let context = context_for_source(None, Path::new("main.rs"), SOURCE, 5, 8, 1000)?;
+        assert_eq!(context.enclosing_kind.as_deref(), Some("function_item"));
+        assert_eq!(context.start_line, 4);
+        assert_eq!(context.end_line, 7);
+        assert!(context.source.starts_with("fn main()"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_referenced_definitions_within_budget() -> Result<()> {
+        let index = RepoIndex::from_scan(
+            Path::new("/project"),
+            vec![ScannedFile {
+                path: PathBuf::from("consts.rs"),
+                language: "rust".to_string(),
+                definitions: vec![Definition::Variable(Variable {
+                    name: "LIMIT".to_string(),
+                    value_type: "u32".to_string(),
+                })],
+                size: 0,
+                identifiers: BTreeMap::new(),
+                modified: None,
+                metrics: vec![],
+                encoding: Default::default(),
+            }],
+        );
+        let path = Path::new("/project/main.rs");
+
+        let context = context_for_source(Some(&index), path, SOURCE, 5, 8, 1000)?;
+        assert_eq!(context.definitions.len(), 1);
+        assert_eq!(context.definitions[0].path, "consts.rs");
+        assert_eq!(context.definitions[0].text, "var LIMIT:u32;");
+
+        let tight = context_for_source(Some(&index), path, SOURCE, 5, 8, context.tokens - 1)?;
+        assert!(tight.definitions.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncate_lines() {
+        let source = "fn a() {\n    1\n}\n";
+        assert_eq!(truncate_lines(source, 100), source);
+        assert_eq!(truncate_lines(source, 5), "fn a() {\n    1\n");
+        assert_eq!(truncate_lines(source, 0), "");
+    }
+
+    #[test]
+    fn test_unsupported_file() {
+        assert!(context_for_source(None, Path::new("notes.txt"), "hello", 0, 0, 100).is_err());
+    }
+}
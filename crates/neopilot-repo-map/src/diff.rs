@@ -0,0 +1,241 @@
+//! Differences between two repo maps
+//!
+//! Comparing the map of the previous scan with the current one tells callers
+//! whether cached prompts are still valid, and lets the plugin show users
+//! which context changed between requests. [`MapDiff`] serializes for tools
+//! and implements `Display` for people.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::slice;
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use crate::config::RankingConfig;
+use crate::index::{IndexedFile, RepoIndex};
+use crate::rank::rank_files_with;
+use crate::stringify_definitions;
+
+/// Definitions of a file whose listing changed
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FileDiff {
+    pub path: String,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Definitions that kept their name but changed their signature
+    pub changed: Vec<String>,
+}
+
+/// A file whose position in the ranked map changed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RankChange {
+    pub path: String,
+    pub old_rank: usize,
+    pub new_rank: usize,
+}
+
+/// Delta between two repo maps
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MapDiff {
+    pub added_files: Vec<String>,
+    pub removed_files: Vec<String>,
+    pub changed_files: Vec<FileDiff>,
+    /// Files present in both maps that moved in the ranking, 0 is the top
+    pub rank_changes: Vec<RankChange>,
+}
+
+impl MapDiff {
+    /// Whether the rendered map is identical, including the file order
+    pub fn is_empty(&self) -> bool {
+        self.added_files.is_empty()
+            && self.removed_files.is_empty()
+            && self.changed_files.is_empty()
+            && self.rank_changes.is_empty()
+    }
+}
+
+impl fmt::Display for MapDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "Repo map unchanged");
+        }
+        for path in &self.added_files {
+            writeln!(f, "+ {path}")?;
+        }
+        for path in &self.removed_files {
+            writeln!(f, "- {path}")?;
+        }
+        for file in &self.changed_files {
+            writeln!(f, "~ {}", file.path)?;
+            for name in &file.added {
+                writeln!(f, "    + {name}")?;
+            }
+            for name in &file.removed {
+                writeln!(f, "    - {name}")?;
+            }
+            for name in &file.changed {
+                writeln!(f, "    ~ {name}")?;
+            }
+        }
+        for change in &self.rank_changes {
+            writeln!(f, "^ {}: {} -> {}", change.path, change.old_rank, change.new_rank)?;
+        }
+        Ok(())
+    }
+}
+
+/// Rendered signature of each definition, keyed by name
+fn signatures(file: &IndexedFile) -> BTreeMap<&str, String> {
+    file.definitions
+        .iter()
+        .map(|definition| {
+            let listing = stringify_definitions(slice::from_ref(definition));
+            (definition.name(), listing)
+        })
+        .collect()
+}
+
+fn diff_file(path: &str, old: &IndexedFile, new: &IndexedFile) -> Option<FileDiff> {
+    let old = signatures(old);
+    let new = signatures(new);
+    let mut diff = FileDiff {
+        path: path.to_string(),
+        ..Default::default()
+    };
+    for (name, signature) in &new {
+        match old.get(name) {
+            None => diff.added.push(name.to_string()),
+            Some(previous) if previous != signature => diff.changed.push(name.to_string()),
+            Some(_) => {}
+        }
+    }
+    diff.removed = old
+        .keys()
+        .filter(|name| !new.contains_key(*name))
+        .map(|name| name.to_string())
+        .collect();
+
+    let unchanged = diff.added.is_empty() && diff.removed.is_empty() && diff.changed.is_empty();
+    (!unchanged).then_some(diff)
+}
+
+/// Compare the repo maps of two scans
+///
+/// Files are ranked without focus files, so the delta only reflects changes
+/// in the repository. The output is sorted and stable for identical inputs.
+pub fn diff_maps(old: &RepoIndex, new: &RepoIndex) -> MapDiff {
+    diff_maps_with(old, new, &RankingConfig::default())
+}
+
+/// Compare the repo maps of two scans like [`diff_maps`], ranking both with
+/// `weights`
+pub fn diff_maps_with(old: &RepoIndex, new: &RepoIndex, weights: &RankingConfig) -> MapDiff {
+    let now = SystemTime::now();
+    let mut diff = MapDiff::default();
+    for (path, file) in &new.files {
+        match old.files.get(path) {
+            None => diff.added_files.push(path.clone()),
+            Some(previous) => diff.changed_files.extend(diff_file(path, previous, file)),
+        }
+    }
+    diff.removed_files = old
+        .files
+        .keys()
+        .filter(|path| !new.files.contains_key(*path))
+        .cloned()
+        .collect();
+
+    let old_ranks: BTreeMap<&str, usize> = rank_files_with(old, &[], weights, now)
+        .into_iter()
+        .enumerate()
+        .map(|(rank, ranked)| (ranked.path, rank))
+        .collect();
+    for (new_rank, ranked) in rank_files_with(new, &[], weights, now).into_iter().enumerate() {
+        match old_ranks.get(ranked.path) {
+            Some(&old_rank) if old_rank != new_rank => diff.rank_changes.push(RankChange {
+                path: ranked.path.to_string(),
+                old_rank,
+                new_rank,
+            }),
+            _ => {}
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Definition, Variable};
+    use std::path::PathBuf;
+
+    fn file(definitions: &[(&str, &str)]) -> IndexedFile {
+        IndexedFile {
+            language: "rust".to_string(),
+            definitions: definitions
+                .iter()
+                .map(|(name, value_type)| {
+                    Definition::Variable(Variable {
+                        name: name.to_string(),
+                        value_type: value_type.to_string(),
+                    })
+                })
+                .collect(),
+            size: 0,
+            identifiers: Default::default(),
+            modified: None,
+            metrics: vec![],
+            encoding: Default::default(),
+        }
+    }
+
+    fn index(files: Vec<(&str, IndexedFile)>) -> RepoIndex {
+        let mut index = RepoIndex {
+            root: PathBuf::from("/project"),
+            ..Default::default()
+        };
+        for (path, file) in files {
+            index.files.insert(path.to_string(), file);
+        }
+        index.recompute_rankings();
+        index
+    }
+
+    #[test]
+    fn test_identical_maps() {
+        let old = index(vec![("a.rs", file(&[("A", "u32")]))]);
+        let diff = diff_maps(&old, &old.clone());
+        assert!(diff.is_empty());
+        assert_eq!(diff.to_string(), "Repo map unchanged\n");
+    }
+
+    #[test]
+    fn test_file_and_definition_changes() {
+        let old = index(vec![
+            ("a.rs", file(&[("A", "u32"), ("B", "u32")])),
+            ("gone.rs", file(&[])),
+        ]);
+        let new = index(vec![
+            ("a.rs", file(&[("A", "u64"), ("C", "u32")])),
+            ("new.rs", file(&[])),
+        ]);
+
+        let diff = diff_maps(&old, &new);
+        assert_eq!(diff.added_files, vec!["new.rs"]);
+        assert_eq!(diff.removed_files, vec!["gone.rs"]);
+        assert_eq!(
+            diff.changed_files,
+            vec![FileDiff {
+                path: "a.rs".to_string(),
+                added: vec!["C".to_string()],
+                removed: vec!["B".to_string()],
+                changed: vec!["A".to_string()],
+            }]
+        );
+        assert_eq!(diff.to_string(), "+ new.rs\n- gone.rs\n~ a.rs\n    + C\n    - B\n    ~ A\n");
+
+        let json = serde_json::to_value(&diff).unwrap();
+        assert_eq!(json["changed_files"][0]["changed"][0], "A");
+    }
+}
@@ -0,0 +1,80 @@
+//! Source files that are not UTF-8
+//!
+//! Legacy codebases still hold files saved as Latin-1, and Windows tools
+//! write UTF-16 with a byte order mark. Tree-sitter parses UTF-8, so such
+//! files are transcoded before parsing rather than skipped or parsed into
+//! garbage identifiers, and the encoding that was detected is kept with the
+//! file. Detection is shared with the tokenizers, see [`neopilot_common::text`].
+
+use neopilot_common::text::{self, TextEncoding};
+use serde::{Deserialize, Serialize};
+
+/// Encoding a source file was decoded from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceEncoding {
+    #[default]
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+}
+
+impl SourceEncoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SourceEncoding::Utf8 => "utf8",
+            SourceEncoding::Utf16Le => "utf16le",
+            SourceEncoding::Utf16Be => "utf16be",
+            SourceEncoding::Latin1 => "latin1",
+        }
+    }
+
+    pub(crate) fn is_utf8(&self) -> bool {
+        *self == SourceEncoding::Utf8
+    }
+}
+
+impl From<TextEncoding> for SourceEncoding {
+    fn from(encoding: TextEncoding) -> Self {
+        match encoding {
+            TextEncoding::Utf8 => SourceEncoding::Utf8,
+            TextEncoding::Utf16Le => SourceEncoding::Utf16Le,
+            TextEncoding::Utf16Be => SourceEncoding::Utf16Be,
+            TextEncoding::Latin1 => SourceEncoding::Latin1,
+        }
+    }
+}
+
+/// Detect the encoding of `bytes` and transcode them to UTF-8
+///
+/// Returns `None` for binary files. Byte order marks are dropped.
+pub fn decode_source(bytes: Vec<u8>) -> Option<(SourceEncoding, String)> {
+    let decoded = text::decode(&bytes)?;
+    Some((decoded.encoding.into(), decoded.text.into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_source() {
+        let decode = |bytes: &[u8]| decode_source(bytes.to_vec());
+        assert_eq!(decode(b"caf\xC3\xA9"), Some((SourceEncoding::Utf8, "café".to_string())));
+        assert_eq!(
+            decode(b"\xEF\xBB\xBFcaf\xC3\xA9"),
+            Some((SourceEncoding::Utf8, "café".to_string()))
+        );
+        assert_eq!(decode(b"caf\xE9"), Some((SourceEncoding::Latin1, "café".to_string())));
+        assert_eq!(
+            decode(b"\xFF\xFEf\0n\0 \0\xE9\0"),
+            Some((SourceEncoding::Utf16Le, "fn é".to_string()))
+        );
+        assert_eq!(
+            decode(b"\xFE\xFF\0f\0n"),
+            Some((SourceEncoding::Utf16Be, "fn".to_string()))
+        );
+        assert_eq!(decode(b"\x7FELF\0\0"), None);
+    }
+}
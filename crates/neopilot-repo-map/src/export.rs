@@ -0,0 +1,124 @@
+//! Serialization of repo map results for the RPC and CLI layers
+//!
+//! The wire formats are shared with the tokenizers, see
+//! [`neopilot_common::export`].
+
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+pub use neopilot_common::export::{to_bytes, OutputFormat};
+
+use crate::config::RankingConfig;
+use crate::index::RepoIndex;
+use crate::metrics::FunctionMetrics;
+use crate::rank::{order_files, rank_files_with, MapOrder};
+use crate::Definition;
+
+/// One file of the repo map, in rank order
+#[derive(Debug, Clone, Serialize)]
+pub struct RepoMapEntry<'a> {
+    pub path: &'a str,
+    pub language: &'a str,
+    pub score: f64,
+    pub focus: bool,
+    pub definitions: &'a [Definition],
+    /// Function metrics, only present when requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<&'a [FunctionMetrics]>,
+}
+
+/// Ranked repo map entries for `index`, see [`crate::rank::rank_files`]
+pub fn repo_map<'a>(index: &'a RepoIndex, focus_files: &[String]) -> Vec<RepoMapEntry<'a>> {
+    repo_map_with(index, focus_files, false, MapOrder::Rank, &RankingConfig::default())
+}
+
+/// Repo map entries like [`repo_map`], optionally with function metrics,
+/// listed in `order` and ranked with `weights`
+pub fn repo_map_with<'a>(
+    index: &'a RepoIndex,
+    focus_files: &[String],
+    include_metrics: bool,
+    order: MapOrder,
+    weights: &RankingConfig,
+) -> Vec<RepoMapEntry<'a>> {
+    let mut ranked = rank_files_with(index, focus_files, weights, SystemTime::now());
+    order_files(&mut ranked, order);
+    ranked
+        .into_iter()
+        .map(|ranked| RepoMapEntry {
+            path: ranked.path,
+            language: &ranked.file.language,
+            score: ranked.score,
+            focus: ranked.is_focus,
+            definitions: &ranked.file.definitions,
+            metrics: include_metrics.then_some(ranked.file.metrics.as_slice()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::IndexedFile;
+    use crate::{Definition, Variable};
+    use neopilot_error::Result;
+    use std::path::PathBuf;
+
+    fn sample_index() -> RepoIndex {
+        let mut index = RepoIndex {
+            root: PathBuf::from("/project"),
+            ..Default::default()
+        };
+        index.files.insert(
+            "src/lib.rs".to_string(),
+            IndexedFile {
+                language: "rust".to_string(),
+                definitions: vec![Definition::Variable(Variable {
+                    name: "LIMIT".to_string(),
+                    value_type: "u32".to_string(),
+                })],
+                size: 20,
+                identifiers: Default::default(),
+                modified: None,
+                metrics: vec![],
+                encoding: Default::default(),
+            },
+        );
+        index.recompute_rankings();
+        index
+    }
+
+    #[test]
+    fn test_roundtrip_all_formats() -> Result<()> {
+        let index = sample_index();
+        let entries = repo_map(&index, &[]);
+
+        let json: serde_json::Value =
+            serde_json::from_slice(&to_bytes(&entries, OutputFormat::Json)?).unwrap();
+        assert_eq!(json[0]["path"], "src/lib.rs");
+
+        let msgpack: serde_json::Value =
+            rmp_serde::from_slice(&to_bytes(&entries, OutputFormat::MessagePack)?).unwrap();
+        assert_eq!(msgpack, json);
+
+        let cbor: serde_json::Value =
+            ciborium::from_reader(to_bytes(&entries, OutputFormat::Cbor)?.as_slice()).unwrap();
+        assert_eq!(cbor, json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_metrics_are_opt_in() -> Result<()> {
+        let index = sample_index();
+        let json: serde_json::Value =
+            serde_json::from_slice(&to_bytes(&repo_map(&index, &[]), OutputFormat::Json)?).unwrap();
+        assert!(json[0].get("metrics").is_none());
+
+        let entries = repo_map_with(&index, &[], true, MapOrder::Rank, &RankingConfig::default());
+        let json: serde_json::Value =
+            serde_json::from_slice(&to_bytes(&entries, OutputFormat::Json)?).unwrap();
+        assert_eq!(json[0]["metrics"], serde_json::json!([]));
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+//! Export lists of dynamic languages
+//!
+//! Without visibility keywords, modules of dynamic languages spell out their
+//! public API in lists: Python's `__all__`, CommonJS `module.exports`, ES
+//! `export` statements and Elixir's `defdelegate`. The extractor keeps the
+//! top-level functions these lists name and marks the definitions they
+//! export with the [`EXPORT_MODIFIER`].
+//!
+//! Only local names are collected: `export { a as b }` exports `a`, and
+//! `exports.key = function () {}` names no local definition at all.
+
+use std::collections::BTreeSet;
+
+use neopilot_error::Result;
+use tree_sitter::{Node, Query};
+
+use crate::{get_ts_language, health, parse_source, query_captures, unsupported_language};
+
+const PYTHON_QUERY: &str = include_str!("../queries/tree-sitter-python-exports.scm");
+const JAVASCRIPT_QUERY: &str = include_str!("../queries/tree-sitter-javascript-exports.scm");
+const TYPESCRIPT_QUERY: &str = include_str!("../queries/tree-sitter-typescript-exports.scm");
+const ELIXIR_QUERY: &str = include_str!("../queries/tree-sitter-elixir-exports.scm");
+
+/// Modifier of definitions named in an export list
+pub(crate) const EXPORT_MODIFIER: &str = "export";
+
+/// Whether the export list of `language` is the whole public API of a file
+///
+/// `__all__` and ES or CommonJS exports are; `defdelegate` only adds
+/// functions to a module whose other definitions are public as well.
+pub(crate) fn lists_public_api(language: &str) -> bool {
+    matches!(language, "python" | "javascript" | "typescript")
+}
+
+/// The exports query of `language`, `None` for languages without export lists
+///
+/// A query that fails to compile disables the language, like its definitions
+/// query.
+fn get_exports_query(language: &str) -> Result<Option<Query>> {
+    let contents = match language {
+        "python" => PYTHON_QUERY,
+        "javascript" => JAVASCRIPT_QUERY,
+        "typescript" => TYPESCRIPT_QUERY,
+        "elixir" => ELIXIR_QUERY,
+        _ => return Ok(None),
+    };
+    let ts_language = get_ts_language(language).ok_or_else(|| unsupported_language(language))?;
+    let query = Query::new(&ts_language.into(), contents).map_err(|e| {
+        health::disable(language, format!("Failed to parse exports query for {language}: {e}"))
+    })?;
+    Ok(Some(query))
+}
+
+/// Names exported by the tree under `root`
+pub(crate) fn exported_names(
+    language: &str,
+    root: Node,
+    source: &[u8],
+) -> Result<BTreeSet<String>> {
+    let Some(query) = get_exports_query(language)? else {
+        return Ok(BTreeSet::new());
+    };
+    Ok(query_captures(&query, root, source)
+        .into_iter()
+        .filter(|(index, _)| query.capture_names()[*index as usize] == "export")
+        .filter_map(|(_, node)| node.utf8_text(source).ok().map(str::to_string))
+        .collect())
+}
+
+/// Names explicitly exported by `source`, empty when it has no export list
+pub fn export_list(language: &str, source: &str) -> Result<BTreeSet<String>> {
+    let tree = parse_source(language, source)?;
+    exported_names(language, tree.root_node(), source.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(language: &str, source: &str) -> Vec<String> {
+        export_list(language, source).unwrap().into_iter().collect()
+    }
+
+    #[test]
+    fn test_python() {
+        let source = "__all__ = ['load', \"dump\"]\n__all__ += ('Error',)\n\
+            def load(): pass\n\
+            def helper():\n    __all__ = ['nested']\n";
+        assert_eq!(names("python", source), ["Error", "dump", "load"]);
+        assert!(names("python", "def load(): pass\n").is_empty());
+    }
+
+    #[test]
+    fn test_javascript() {
+        let source = "function a() {}\n\
+            export { b, c as renamed };\n\
+            export function d() {}\n\
+            module.exports = { e, key: f };\n\
+            exports.key = g;\n\
+            module.exports.other = function () {};\n";
+        assert_eq!(names("javascript", source), ["b", "c", "d", "e", "f", "g"]);
+        assert_eq!(names("typescript", "export interface Options {}\n"), ["Options"]);
+    }
+
+    #[test]
+    fn test_elixir() {
+        let source = "defmodule MyApp do\n\
+            defdelegate fetch(id), to: MyApp.Repo\n\
+            defdelegate all, to: MyApp.Repo\n\
+            def local, do: :ok\n\
+            end\n";
+        assert_eq!(names("elixir", source), ["all", "fetch"]);
+        assert!(names("rust", "pub fn f() {}").is_empty());
+        assert!(export_list("cobol", "").is_err());
+    }
+}
@@ -0,0 +1,111 @@
+//! Languages disabled at runtime and why, for health checks
+//!
+//! A grammar built against an incompatible tree-sitter version, or a query
+//! that no longer compiles against its grammar, fails the same way for every
+//! file. The first failure disables the language for the rest of the
+//! session: its files are skipped instead of failing one by one, other
+//! languages keep working, and [`health`] reports what went wrong.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use neopilot_error::{Error, ErrorCode, Result};
+
+/// Languages disabled in this session, with the error that disabled them
+static DISABLED: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// A language disabled because its grammar or query failed to load
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrammarDiagnostic {
+    pub language: String,
+    pub message: String,
+}
+
+/// State of the repo map for health checks
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Health {
+    /// Languages disabled for the session, by name
+    pub disabled_languages: Vec<GrammarDiagnostic>,
+}
+
+impl Health {
+    /// Whether every language works
+    pub fn is_ok(&self) -> bool {
+        self.disabled_languages.is_empty()
+    }
+}
+
+fn disabled_error(language: &str, message: &str) -> Error {
+    Error::new(
+        ErrorCode::Unsupported,
+        format!("Language {language} is disabled for this session: {message}"),
+    )
+}
+
+/// Disable `language` for the session because of `message`
+///
+/// Returns the error to report for the failing call.
+pub(crate) fn disable(language: &str, message: String) -> Error {
+    let error = disabled_error(language, &message);
+    // A poisoned lock only means another thread panicked; the map is still valid
+    let mut disabled = DISABLED.lock().unwrap_or_else(|e| e.into_inner());
+    if !disabled.contains_key(language) {
+        log::warn!("Disabling {language} for this session: {message}");
+        disabled.insert(language.to_string(), message);
+    }
+    error
+}
+
+/// The error to report if `language` is disabled
+pub(crate) fn check(language: &str) -> Result<()> {
+    let disabled = DISABLED.lock().unwrap_or_else(|e| e.into_inner());
+    match disabled.get(language) {
+        Some(message) => Err(disabled_error(language, message)),
+        None => Ok(()),
+    }
+}
+
+/// Whether `language` was disabled in this session
+pub fn is_disabled(language: &str) -> bool {
+    check(language).is_err()
+}
+
+/// Languages disabled so far and why
+pub fn health() -> Health {
+    let disabled = DISABLED.lock().unwrap_or_else(|e| e.into_inner());
+    Health {
+        disabled_languages: disabled
+            .iter()
+            .map(|(language, message)| GrammarDiagnostic {
+                language: language.clone(),
+                message: message.clone(),
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disable() {
+        // Made-up language, as the registry is shared with the other tests
+        let language = "test-broken-grammar";
+        assert!(!is_disabled(language));
+
+        let error = disable(language, "Incompatible language version 99".to_string());
+        assert_eq!(error.code(), ErrorCode::Unsupported);
+        assert!(is_disabled(language));
+        disable(language, "second failure".to_string());
+
+        let health = health();
+        assert!(!health.is_ok());
+        let diagnostic = health
+            .disabled_languages
+            .iter()
+            .find(|diagnostic| diagnostic.language == language)
+            .unwrap();
+        assert_eq!(diagnostic.message, "Incompatible language version 99");
+    }
+}
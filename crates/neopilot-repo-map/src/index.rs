@@ -0,0 +1,677 @@
+//! Persistent repository index
+//!
+//! The index keeps the definitions of every scanned file together with
+//! cross-file reference counts and file rankings. It can be saved to a compact
+//! MessagePack file so that reopening a project loads the map instantly instead
+//! of rescanning. The token cost of every definition is cached in the index as
+//! well, tagged with the fingerprint of the tokenizer that counted it.
+//!
+//! When a single file changes, [`RepoIndex::update_file`] adjusts only the
+//! references and rankings it affects instead of recomputing the whole graph.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use neopilot_error::{Error, ErrorCode, Result, ResultExt};
+use serde::{Deserialize, Serialize};
+
+use crate::context::estimate_tokens;
+use crate::encoding::SourceEncoding;
+use crate::metrics::FunctionMetrics;
+use crate::scan::{scan_directory_with, ScanOptions, ScanProgress, ScannedFile};
+use crate::{stringify_definition, Definition};
+
+/// Version of the on-disk format, bumped whenever the layout changes
+pub const INDEX_VERSION: u32 = 6;
+
+const INDEX_MAGIC: &[u8; 4] = b"NPRM";
+const HEADER_LEN: usize = INDEX_MAGIC.len() + 4;
+
+/// Indexed information about a single file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexedFile {
+    pub language: String,
+    pub definitions: Vec<Definition>,
+    /// Size of the file in bytes
+    pub size: u64,
+    /// Occurrence count of every identifier-like word in the file
+    pub identifiers: BTreeMap<String, u32>,
+    /// Last modification time in seconds since the Unix epoch, if known
+    pub modified: Option<u64>,
+    /// Size and complexity of each function
+    pub metrics: Vec<FunctionMetrics>,
+    /// Encoding the file was transcoded from
+    #[serde(default)]
+    pub encoding: SourceEncoding,
+}
+
+impl From<ScannedFile> for IndexedFile {
+    fn from(file: ScannedFile) -> Self {
+        Self {
+            language: file.language,
+            definitions: file.definitions,
+            size: file.size,
+            identifiers: file.identifiers,
+            modified: file.modified,
+            metrics: file.metrics,
+            encoding: file.encoding,
+        }
+    }
+}
+
+/// Counts the tokens of stringified definitions
+pub trait TokenCounter {
+    /// Identifies the tokenizer; cached costs with another fingerprint are discarded
+    fn fingerprint(&self) -> &str;
+
+    fn count(&self, text: &str) -> Result<usize>;
+}
+
+/// Counter based on [`estimate_tokens`], used when no tokenizer is loaded
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EstimatedTokens;
+
+impl TokenCounter for EstimatedTokens {
+    fn fingerprint(&self) -> &str {
+        "estimate"
+    }
+
+    fn count(&self, text: &str) -> Result<usize> {
+        Ok(estimate_tokens(text))
+    }
+}
+
+/// Token cost of each definition, as counted by one tokenizer
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenCosts {
+    /// Fingerprint of the tokenizer the costs were counted with
+    pub fingerprint: String,
+    /// Costs of the definitions of each file, keyed by path
+    pub files: BTreeMap<String, FileCosts>,
+}
+
+impl TokenCosts {
+    /// Cached cost of the definition listing of `path`
+    pub fn file_cost(&self, path: &str) -> Option<usize> {
+        self.files.get(path).map(|file| file.costs.iter().sum())
+    }
+}
+
+/// Token costs of the definitions of one file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileCosts {
+    /// Hash of the stringified definitions the costs were counted for, see
+    /// [`listing_hash`]
+    pub hash: u64,
+    /// Cost of each stringified definition, in definition order
+    pub costs: Vec<usize>,
+}
+
+/// FNV-1a hash of stringified definitions
+///
+/// The hash is saved with the index, so it must not change between builds
+/// the way the standard library's hasher may.
+fn listing_hash(listings: &[String]) -> u64 {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+    // 0xff never occurs in UTF-8, so it separates the listings unambiguously
+    listings
+        .iter()
+        .flat_map(|listing| listing.bytes().chain([0xff]))
+        .fold(OFFSET, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(PRIME))
+}
+
+/// Lookup tables for updating references one file at a time
+#[derive(Debug, Clone, Default)]
+struct ReferenceGraph {
+    /// Files defining each symbol
+    definers: BTreeMap<String, BTreeSet<String>>,
+    /// Occurrences of each defined symbol across all files
+    occurrences: BTreeMap<String, u32>,
+}
+
+impl ReferenceGraph {
+    fn build(files: &BTreeMap<String, IndexedFile>) -> Self {
+        let mut graph = Self::default();
+        for (path, file) in files {
+            for definition in &file.definitions {
+                graph
+                    .definers
+                    .entry(definition.name().to_string())
+                    .or_default()
+                    .insert(path.clone());
+            }
+        }
+        for file in files.values() {
+            for (identifier, count) in &file.identifiers {
+                if graph.definers.contains_key(identifier) {
+                    *graph.occurrences.entry(identifier.clone()).or_insert(0) += count;
+                }
+            }
+        }
+        graph
+    }
+
+    /// References to `name` from files that do not define it, `None` if
+    /// nothing defines it
+    fn references(&self, name: &str, files: &BTreeMap<String, IndexedFile>) -> Option<u32> {
+        let definers = self.definers.get(name)?;
+        let own: u32 = definers
+            .iter()
+            .filter_map(|path| files.get(path)?.identifiers.get(name))
+            .sum();
+        Some(self.occurrences.get(name).copied().unwrap_or(0) - own)
+    }
+}
+
+/// Definitions, references and rankings for a whole repository
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RepoIndex {
+    /// Root directory the index was built from
+    pub root: PathBuf,
+    /// Indexed files keyed by their path relative to `root`
+    pub files: BTreeMap<String, IndexedFile>,
+    /// Number of references to each defined symbol from other files
+    pub references: BTreeMap<String, u32>,
+    /// Rank of each file, higher is more relevant
+    pub rankings: BTreeMap<String, f64>,
+    /// Cached token costs of the definitions
+    pub token_costs: TokenCosts,
+    /// Built on the first incremental update, dropped by a full recompute
+    #[serde(skip)]
+    graph: Option<ReferenceGraph>,
+    /// Defining file and position in its definitions of every defined name
+    #[serde(skip)]
+    symbols: BTreeMap<String, Vec<(String, usize)>>,
+}
+
+impl RepoIndex {
+    /// Scan `root` and build a fresh index
+    pub fn build(root: &Path, progress: &ScanProgress) -> Result<Self> {
+        Self::build_with(root, &ScanOptions::default(), progress)
+    }
+
+    /// Scan `root` with `options` and build a fresh index
+    pub fn build_with(root: &Path, options: &ScanOptions, progress: &ScanProgress) -> Result<Self> {
+        let files = scan_directory_with(root, options, progress)?;
+        Ok(Self::from_scan(root, files))
+    }
+
+    /// Build an index from already scanned files
+    pub fn from_scan(root: &Path, files: Vec<ScannedFile>) -> Self {
+        let mut index = Self {
+            root: root.to_path_buf(),
+            ..Default::default()
+        };
+        for file in files {
+            let path = file.path.to_string_lossy().to_string();
+            index.files.insert(path, file.into());
+        }
+        index.recompute_rankings();
+        index
+    }
+
+    /// Recompute reference counts and file rankings from the indexed files
+    pub fn recompute_rankings(&mut self) {
+        let mut defined_in: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for (path, file) in &self.files {
+            for definition in &file.definitions {
+                defined_in
+                    .entry(definition.name())
+                    .or_default()
+                    .push(path.as_str());
+            }
+        }
+
+        // Only occurrences outside of the defining file count as references
+        let mut references: BTreeMap<String, u32> = defined_in
+            .keys()
+            .map(|name| (name.to_string(), 0))
+            .collect();
+        for (path, file) in &self.files {
+            for (identifier, count) in &file.identifiers {
+                let Some(definers) = defined_in.get(identifier.as_str()) else {
+                    continue;
+                };
+                if !definers.contains(&path.as_str()) {
+                    *references.entry(identifier.clone()).or_insert(0) += count;
+                }
+            }
+        }
+
+        let rankings = self
+            .files
+            .iter()
+            .map(|(path, file)| {
+                let rank: u32 = file
+                    .definitions
+                    .iter()
+                    .map(|d| references.get(d.name()).copied().unwrap_or(0))
+                    .sum();
+                (path.clone(), f64::from(rank))
+            })
+            .collect();
+
+        self.references = references;
+        self.rankings = rankings;
+        self.graph = None;
+        self.rebuild_symbols();
+    }
+
+    fn rebuild_symbols(&mut self) {
+        self.symbols.clear();
+        for (path, file) in &self.files {
+            for (i, definition) in file.definitions.iter().enumerate() {
+                let entries = self.symbols.entry(definition.name().to_string()).or_default();
+                entries.push((path.clone(), i));
+            }
+        }
+    }
+
+    /// Files defining `name` with the definition, in path order
+    pub fn definitions_named<'a>(
+        &'a self,
+        name: &str,
+    ) -> impl Iterator<Item = (&'a str, &'a IndexedFile, &'a Definition)> + 'a {
+        self.symbols.get(name).into_iter().flatten().filter_map(|(path, i)| {
+            let file = self.files.get(path)?;
+            Some((path.as_str(), file, file.definitions.get(*i)?))
+        })
+    }
+
+    /// Add or replace a single file, e.g. one reported by a file watcher
+    ///
+    /// Reference counts and rankings are updated only for the symbols the old
+    /// and new versions of the file define or mention, and for the files
+    /// defining them, with the same result as [`RepoIndex::recompute_rankings`].
+    /// Call that instead after modifying `files` directly.
+    pub fn update_file(&mut self, file: ScannedFile) {
+        let path = file.path.to_string_lossy().to_string();
+        self.replace_file(&path, Some(file.into()));
+    }
+
+    /// Remove a single file, updating references and rankings like
+    /// [`RepoIndex::update_file`]; returns whether the file was indexed
+    pub fn remove_file(&mut self, path: &str) -> bool {
+        if !self.files.contains_key(path) {
+            return false;
+        }
+        self.replace_file(path, None);
+        true
+    }
+
+    fn replace_file(&mut self, path: &str, new: Option<IndexedFile>) {
+        let mut graph = self
+            .graph
+            .take()
+            .unwrap_or_else(|| ReferenceGraph::build(&self.files));
+        let old = self.files.remove(path);
+        self.token_costs.files.remove(path);
+        if let Some(old) = &old {
+            for definition in &old.definitions {
+                if let Some(entries) = self.symbols.get_mut(definition.name()) {
+                    entries.retain(|(defined_in, _)| defined_in != path);
+                    if entries.is_empty() {
+                        self.symbols.remove(definition.name());
+                    }
+                }
+            }
+        }
+
+        // Symbols whose reference count may change
+        let mut changed: BTreeSet<String> = BTreeSet::new();
+        if let Some(old) = &old {
+            for definition in &old.definitions {
+                let name = definition.name();
+                if let Some(definers) = graph.definers.get_mut(name) {
+                    definers.remove(path);
+                    if definers.is_empty() {
+                        graph.definers.remove(name);
+                        graph.occurrences.remove(name);
+                    }
+                }
+                changed.insert(name.to_string());
+            }
+            for (identifier, count) in &old.identifiers {
+                if let Some(occurrences) = graph.occurrences.get_mut(identifier) {
+                    *occurrences -= count;
+                    changed.insert(identifier.clone());
+                }
+            }
+        }
+        if let Some(new) = new {
+            for (identifier, count) in &new.identifiers {
+                if let Some(occurrences) = graph.occurrences.get_mut(identifier) {
+                    *occurrences += count;
+                    changed.insert(identifier.clone());
+                }
+            }
+            self.files.insert(path.to_string(), new);
+            let new = &self.files[path];
+            for (i, definition) in new.definitions.iter().enumerate() {
+                let entries = self.symbols.entry(definition.name().to_string()).or_default();
+                let at = entries.partition_point(|(defined_in, _)| defined_in.as_str() <= path);
+                entries.insert(at, (path.to_string(), i));
+            }
+            for definition in &new.definitions {
+                let name = definition.name();
+                if !graph.definers.contains_key(name) {
+                    // Newly defined: count its occurrences in every file once
+                    let occurrences = self
+                        .files
+                        .values()
+                        .filter_map(|file| file.identifiers.get(name))
+                        .sum();
+                    graph.occurrences.insert(name.to_string(), occurrences);
+                }
+                graph
+                    .definers
+                    .entry(name.to_string())
+                    .or_default()
+                    .insert(path.to_string());
+                changed.insert(name.to_string());
+            }
+        }
+
+        // Files whose rank may change: the definers of every changed symbol
+        let mut affected: BTreeSet<String> = BTreeSet::new();
+        for name in &changed {
+            match graph.references(name, &self.files) {
+                Some(references) => {
+                    self.references.insert(name.clone(), references);
+                    affected.extend(graph.definers[name].iter().cloned());
+                }
+                None => {
+                    self.references.remove(name);
+                }
+            }
+        }
+        self.rankings.remove(path);
+        if self.files.contains_key(path) {
+            affected.insert(path.to_string());
+        }
+        for path in affected {
+            let rank: u32 = self.files[&path]
+                .definitions
+                .iter()
+                .map(|d| self.references.get(d.name()).copied().unwrap_or(0))
+                .sum();
+            self.rankings.insert(path, f64::from(rank));
+        }
+        self.graph = Some(graph);
+    }
+
+    /// Count the tokens of every definition not yet in the cache
+    ///
+    /// The whole cache is discarded when `counter` has a different fingerprint
+    /// than the one it was filled with. Files whose definitions changed since
+    /// they were counted, going by the hash of their listing, are counted
+    /// again. Returns the number of definitions counted.
+    pub fn update_token_costs(&mut self, counter: &dyn TokenCounter) -> Result<usize> {
+        let costs = &mut self.token_costs;
+        if costs.fingerprint != counter.fingerprint() {
+            costs.fingerprint = counter.fingerprint().to_string();
+            costs.files.clear();
+        }
+        costs.files.retain(|path, _| self.files.contains_key(path));
+
+        let mut counted = 0;
+        for (path, file) in &self.files {
+            let listings: Vec<String> = file.definitions.iter().map(stringify_definition).collect();
+            let hash = listing_hash(&listings);
+            if costs.files.get(path).is_some_and(|cached| cached.hash == hash) {
+                continue;
+            }
+            let file_costs = listings
+                .iter()
+                .map(|listing| counter.count(listing))
+                .collect::<Result<Vec<usize>>>()
+                .with_context(|| format!("Failed to count tokens of {path}"))?;
+            counted += file_costs.len();
+            costs.files.insert(
+                path.clone(),
+                FileCosts {
+                    hash,
+                    costs: file_costs,
+                },
+            );
+        }
+        Ok(counted)
+    }
+
+    /// Files ordered by descending rank, ties broken by path
+    pub fn ranked_files(&self) -> Vec<(&str, &IndexedFile)> {
+        let mut files: Vec<(&str, &IndexedFile)> = self
+            .files
+            .iter()
+            .map(|(path, file)| (path.as_str(), file))
+            .collect();
+        files.sort_by(|(a, _), (b, _)| {
+            let rank_a = self.rankings.get(*a).copied().unwrap_or(0.0);
+            let rank_b = self.rankings.get(*b).copied().unwrap_or(0.0);
+            rank_b.total_cmp(&rank_a).then_with(|| a.cmp(b))
+        });
+        files
+    }
+
+    /// Serialize the index, prefixed with a magic number and format version
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let payload = rmp_serde::to_vec(self).map_err(|e| {
+            Error::new(ErrorCode::Internal, format!("Failed to serialize index: {e}"))
+        })?;
+        let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+        bytes.extend_from_slice(INDEX_MAGIC);
+        bytes.extend_from_slice(&INDEX_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        Ok(bytes)
+    }
+
+    /// Deserialize an index written by [`RepoIndex::to_bytes`]
+    ///
+    /// Fails if the data was written by an incompatible format version, in
+    /// which case callers should rebuild the index.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_LEN || &bytes[..INDEX_MAGIC.len()] != INDEX_MAGIC {
+            return Err(Error::new(ErrorCode::Parse, "Not a repo map index file"));
+        }
+        let mut version = [0u8; 4];
+        version.copy_from_slice(&bytes[INDEX_MAGIC.len()..HEADER_LEN]);
+        let version = u32::from_le_bytes(version);
+        if version != INDEX_VERSION {
+            return Err(Error::new(
+                ErrorCode::Unsupported,
+                format!("Unsupported index version {version} (expected {INDEX_VERSION})"),
+            ));
+        }
+        let mut index: Self = rmp_serde::from_slice(&bytes[HEADER_LEN..]).map_err(|e| {
+            Error::new(ErrorCode::Parse, format!("Failed to deserialize index: {e}"))
+        })?;
+        index.rebuild_symbols();
+        Ok(index)
+    }
+
+    /// Write the index to `path` atomically
+    pub fn save(&self, path: &Path) -> Result<()> {
+        write_atomically(path, &self.to_bytes()?)
+    }
+
+    /// Read an index previously written with [`RepoIndex::save`]
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes =
+            std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::from_bytes(&bytes).with_context(|| format!("Failed to load {}", path.display()))
+    }
+}
+
+/// Write `bytes` to a temporary file next to `path` and rename it into place,
+/// creating the parent directories
+pub(crate) fn write_atomically(path: &Path, bytes: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    let temp_path = path.with_extension("tmp");
+    std::fs::write(&temp_path, bytes)
+        .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+    std::fs::rename(&temp_path, path)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::count_identifiers;
+    use crate::{Class, Func};
+
+    fn scanned(path: &str, definitions: Vec<Definition>, source: &str) -> ScannedFile {
+        ScannedFile {
+            path: PathBuf::from(path),
+            language: "rust".to_string(),
+            definitions,
+            size: source.len() as u64,
+            identifiers: count_identifiers(source),
+            modified: None,
+            metrics: vec![],
+            encoding: Default::default(),
+        }
+    }
+
+    fn class(name: &str) -> Definition {
+        Definition::Class(Class {
+            type_name: "class".to_string(),
+            name: name.to_string(),
+            methods: vec![Func {
+                name: "run".to_string(),
+                params: "(&self)".to_string(),
+                return_type: String::new(),
+                accessibility_modifier: None,
+            }],
+            properties: vec![],
+            visibility_modifier: Some("pub".to_string()),
+        })
+    }
+
+    fn sample_index() -> RepoIndex {
+        RepoIndex::from_scan(
+            Path::new("/project"),
+            vec![
+                scanned("a.rs", vec![class("Engine")], "pub struct Engine {}"),
+                scanned("b.rs", vec![class("Car")], "struct Car { engine: Engine }"),
+                scanned("c.rs", vec![], "fn main() { Engine::new(); Car::new(); Engine::stop(); }"),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_references_and_rankings() {
+        let index = sample_index();
+        assert_eq!(index.references.get("Engine"), Some(&3));
+        assert_eq!(index.references.get("Car"), Some(&1));
+
+        let ranked: Vec<&str> = index.ranked_files().into_iter().map(|(p, _)| p).collect();
+        assert_eq!(ranked, vec!["a.rs", "b.rs", "c.rs"]);
+    }
+
+    /// Rebuild `index` from scratch and compare its references and rankings
+    fn assert_matches_recompute(index: &RepoIndex) {
+        let mut expected = index.clone();
+        expected.recompute_rankings();
+        assert_eq!(index.references, expected.references);
+        assert_eq!(index.rankings, expected.rankings);
+        assert_eq!(index.symbols, expected.symbols);
+    }
+
+    #[test]
+    fn test_incremental_updates() {
+        let mut index = sample_index();
+
+        // A new file referencing Car
+        index.update_file(scanned("d.rs", vec![], "fn drive(car: Car) { Car::park(car) }"));
+        assert_eq!(index.references.get("Car"), Some(&3));
+        assert_matches_recompute(&index);
+
+        // c.rs starts defining Engine too, so its own mentions stop counting
+        index.update_file(scanned("c.rs", vec![class("Engine")], "fn main() { Engine::new(); }"));
+        assert_eq!(index.references.get("Engine"), Some(&1));
+        assert_matches_recompute(&index);
+
+        // A new symbol that other files already mention
+        index.update_file(scanned("e.rs", vec![class("main")], "struct main;"));
+        assert_eq!(index.references.get("main"), Some(&1));
+        assert_matches_recompute(&index);
+
+        assert!(index.remove_file("a.rs"));
+        assert!(!index.remove_file("a.rs"));
+        assert!(!index.rankings.contains_key("a.rs"));
+        assert_matches_recompute(&index);
+
+        index.update_file(scanned("b.rs", vec![], "fn unused() {}"));
+        assert_eq!(index.references.get("Car"), None);
+        assert_matches_recompute(&index);
+    }
+
+    #[test]
+    fn test_roundtrip() -> Result<()> {
+        let index = sample_index();
+        let restored = RepoIndex::from_bytes(&index.to_bytes()?)?;
+        assert_eq!(restored.root, index.root);
+        assert_eq!(restored.files.len(), 3);
+        assert_eq!(restored.references, index.references);
+        assert_eq!(restored.rankings, index.rankings);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_other_versions() -> Result<()> {
+        let mut bytes = sample_index().to_bytes()?;
+        bytes[INDEX_MAGIC.len()..HEADER_LEN].copy_from_slice(&(INDEX_VERSION + 1).to_le_bytes());
+        assert!(RepoIndex::from_bytes(&bytes).is_err());
+        assert!(RepoIndex::from_bytes(b"garbage").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_and_load() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("cache").join("index.bin");
+        sample_index().save(&path)?;
+        let loaded = RepoIndex::load(&path)?;
+        assert_eq!(loaded.files.len(), 3);
+        Ok(())
+    }
+
+    struct CharCounter;
+
+    impl TokenCounter for CharCounter {
+        fn fingerprint(&self) -> &str {
+            "chars"
+        }
+
+        fn count(&self, text: &str) -> Result<usize> {
+            Ok(text.len())
+        }
+    }
+
+    #[test]
+    fn test_token_costs() -> Result<()> {
+        let mut index = sample_index();
+        assert_eq!(index.update_token_costs(&EstimatedTokens)?, 2);
+        assert_eq!(index.update_token_costs(&EstimatedTokens)?, 0);
+        let listing = stringify_definition(&class("Engine"));
+        assert_eq!(index.token_costs.file_cost("a.rs"), Some(estimate_tokens(&listing)));
+        assert_eq!(index.token_costs.file_cost("c.rs"), Some(0));
+
+        // The cache survives a save and is invalidated by another tokenizer
+        let mut index = RepoIndex::from_bytes(&index.to_bytes()?)?;
+        assert_eq!(index.token_costs.fingerprint, "estimate");
+        assert_eq!(index.update_token_costs(&CharCounter)?, 2);
+        assert_eq!(index.token_costs.file_cost("a.rs"), Some(listing.len()));
+
+        // Definitions changed in place are counted again, even at the same count
+        index.files.get_mut("a.rs").unwrap().definitions = vec![class("Motorcycle")];
+        assert_eq!(index.update_token_costs(&CharCounter)?, 1);
+        let listing = stringify_definition(&class("Motorcycle"));
+        assert_eq!(index.token_costs.file_cost("a.rs"), Some(listing.len()));
+        Ok(())
+    }
+}
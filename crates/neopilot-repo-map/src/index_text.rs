@@ -0,0 +1,272 @@
+//! The index as a text file to commit
+//!
+//! Huge repositories take a while to scan, so a team may commit a pre-built
+//! index and let CI and teammates start from it. The binary cache of
+//! [`RepoIndex::save`] is unfit for that, so the index can also be exported
+//! as JSON Lines: a header line with the format version, then one line per
+//! file, sorted by path. Exporting the same files gives the same bytes, and a
+//! change to one file changes one line, which keeps diffs and merges small.
+//!
+//! Only what the files define and mention is exported. The root, the
+//! modification times and the token costs depend on the checkout and the
+//! tokenizer, and the references and rankings are recomputed on import.
+//! Files changed since the export are rescanned on import.
+
+use std::collections::BTreeMap;
+use std::path::{Component, Path};
+
+use neopilot_error::{Error, ErrorCode, Result, ResultExt};
+use serde::{Deserialize, Serialize};
+
+use crate::encoding::SourceEncoding;
+use crate::index::{write_atomically, IndexedFile, RepoIndex, INDEX_VERSION};
+use crate::metrics::FunctionMetrics;
+use crate::scan::{modified_secs, scan_single_file, ScanOptions};
+use crate::Definition;
+
+/// Value of `format` in the header line
+const FORMAT: &str = "neopilot-repo-map";
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    format: String,
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FileLine {
+    path: String,
+    language: String,
+    size: u64,
+    definitions: Vec<Definition>,
+    identifiers: BTreeMap<String, u32>,
+    #[serde(default)]
+    metrics: Vec<FunctionMetrics>,
+    /// Left out for UTF-8 files, so most lines do not mention it
+    #[serde(default, skip_serializing_if = "SourceEncoding::is_utf8")]
+    encoding: SourceEncoding,
+}
+
+fn serialization_error(e: serde_json::Error) -> Error {
+    Error::new(ErrorCode::Internal, format!("Failed to serialize index: {e}"))
+}
+
+/// The index as JSON Lines, see the module documentation
+pub fn to_jsonl(index: &RepoIndex) -> Result<String> {
+    let header = Header {
+        format: FORMAT.to_string(),
+        version: INDEX_VERSION,
+    };
+    let mut text = serde_json::to_string(&header).map_err(serialization_error)?;
+    text.push('\n');
+    // `files` is a BTreeMap, so the lines come sorted by path
+    for (path, file) in &index.files {
+        let line = FileLine {
+            path: path.clone(),
+            language: file.language.clone(),
+            size: file.size,
+            definitions: file.definitions.clone(),
+            identifiers: file.identifiers.clone(),
+            metrics: file.metrics.clone(),
+            encoding: file.encoding,
+        };
+        text.push_str(&serde_json::to_string(&line).map_err(serialization_error)?);
+        text.push('\n');
+    }
+    Ok(text)
+}
+
+/// Read an index exported by [`to_jsonl`] for the checkout at `root`
+///
+/// Fails on another format version, in which case the index must be rebuilt
+/// and exported again, and on paths that are absolute or leave `root`. The
+/// modification times are read from the files below `root`.
+pub fn from_jsonl(root: &Path, text: &str) -> Result<RepoIndex> {
+    let mut lines = text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+    let parse_error = |number: usize, e: serde_json::Error| {
+        Error::new(ErrorCode::Parse, format!("Invalid index line {}: {e}", number + 1))
+    };
+
+    let (number, header) = lines
+        .next()
+        .ok_or_else(|| Error::new(ErrorCode::Parse, "Empty index file"))?;
+    let header: Header = serde_json::from_str(header).map_err(|e| parse_error(number, e))?;
+    if header.format != FORMAT {
+        return Err(Error::new(ErrorCode::Parse, "Not a repo map index file"));
+    }
+    if header.version != INDEX_VERSION {
+        return Err(Error::new(
+            ErrorCode::Unsupported,
+            format!(
+                "Unsupported index version {} (expected {INDEX_VERSION})",
+                header.version
+            ),
+        ));
+    }
+
+    let mut index = RepoIndex {
+        root: root.to_path_buf(),
+        ..Default::default()
+    };
+    for (number, line) in lines {
+        let line: FileLine = serde_json::from_str(line).map_err(|e| parse_error(number, e))?;
+        let relative = Path::new(&line.path);
+        if !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(Error::new(
+                ErrorCode::Parse,
+                format!("Invalid index line {}: {} is not below the root", number + 1, line.path),
+            ));
+        }
+        let modified = modified_secs(&root.join(relative));
+        index.files.insert(
+            line.path,
+            IndexedFile {
+                language: line.language,
+                definitions: line.definitions,
+                size: line.size,
+                identifiers: line.identifiers,
+                modified,
+                metrics: line.metrics,
+                encoding: line.encoding,
+            },
+        );
+    }
+    index.recompute_rankings();
+    Ok(index)
+}
+
+/// Write `index` to `path` as JSON Lines, atomically
+pub fn export(index: &RepoIndex, path: &Path) -> Result<()> {
+    write_atomically(path, to_jsonl(index)?.as_bytes())
+}
+
+/// Read an index exported to `path` for the checkout at `root`
+///
+/// Files that are gone or were modified after `path` was written are
+/// scanned again with `options`, see [`rescan_stale`].
+pub fn import(root: &Path, path: &Path, options: &ScanOptions) -> Result<RepoIndex> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut index =
+        from_jsonl(root, &text).with_context(|| format!("Failed to import {}", path.display()))?;
+    rescan_stale(&mut index, modified_secs(path), options);
+    Ok(index)
+}
+
+/// Scan the files of an imported index again that changed since the export
+/// written at `exported`; returns how many were rescanned
+///
+/// Files that are gone, were modified after `exported` or are no longer
+/// accepted by `options` leave the index or are replaced by their new scan.
+/// Without a time of export only missing files count as changed.
+pub fn rescan_stale(index: &mut RepoIndex, exported: Option<u64>, options: &ScanOptions) -> usize {
+    let stale: Vec<String> = index
+        .files
+        .keys()
+        .filter(|path| match (modified_secs(&index.root.join(path)), exported) {
+            (None, _) => true,
+            (Some(modified), Some(exported)) => modified > exported,
+            (Some(_), None) => false,
+        })
+        .cloned()
+        .collect();
+    let root = index.root.clone();
+    for path in &stale {
+        match scan_single_file(&root, Path::new(path), options) {
+            Some(file) => index.update_file(file),
+            None => {
+                index.remove_file(path);
+            },
+        }
+    }
+    stale.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::{scan_directory, ScanProgress};
+    use std::fs;
+
+    fn sample_index(root: &Path) -> Result<RepoIndex> {
+        fs::write(root.join("b.rs"), "pub struct Car { engine: Engine }\n")?;
+        fs::write(root.join("a.rs"), "pub struct Engine {}\nfn start() {}\n")?;
+        Ok(RepoIndex::from_scan(root, scan_directory(root, &ScanProgress::new())?))
+    }
+
+    #[test]
+    fn test_roundtrip() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let index = sample_index(dir.path())?;
+        let text = to_jsonl(&index)?;
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with(r#"{"path":"a.rs""#));
+        assert!(lines[2].starts_with(r#"{"path":"b.rs""#));
+
+        let imported = from_jsonl(Path::new("/elsewhere"), &text)?;
+        assert_eq!(imported.root, Path::new("/elsewhere"));
+        assert_eq!(imported.references, index.references);
+        assert_eq!(imported.rankings, index.rankings);
+        // Modification times are left out, so exports are deterministic
+        assert_eq!(to_jsonl(&imported)?, text);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_other_versions() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let text = to_jsonl(&sample_index(dir.path())?)?;
+        let other = text.replacen(
+            &format!(r#""version":{INDEX_VERSION}"#),
+            &format!(r#""version":{}"#, INDEX_VERSION + 1),
+            1,
+        );
+        assert!(from_jsonl(dir.path(), &other).is_err());
+        assert!(from_jsonl(dir.path(), "").is_err());
+        assert!(from_jsonl(dir.path(), "{\"files\":[]}\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_paths_outside_the_root() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let text = to_jsonl(&sample_index(dir.path())?)?;
+        for path in ["../a.rs", "/etc/a.rs", "src/../../a.rs"] {
+            let outside = text.replacen(r#""path":"a.rs""#, &format!(r#""path":"{path}""#), 1);
+            assert!(from_jsonl(dir.path(), &outside).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_rescan_stale() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let index = sample_index(dir.path())?;
+        let mut imported = from_jsonl(dir.path(), &to_jsonl(&index)?)?;
+        assert!(imported.files.values().all(|file| file.modified.is_some()));
+        let exported = imported.files["a.rs"].modified;
+
+        // Unchanged files are kept, gone ones leave the index
+        fs::remove_file(dir.path().join("b.rs"))?;
+        assert_eq!(rescan_stale(&mut imported, exported, &ScanOptions::default()), 1);
+        assert_eq!(imported.files.keys().collect::<Vec<_>>(), ["a.rs"]);
+
+        // Files modified after the export are scanned again
+        fs::write(dir.path().join("a.rs"), "pub struct Motor {}\n")?;
+        let earlier = exported.map(|exported| exported - 1);
+        assert_eq!(rescan_stale(&mut imported, earlier, &ScanOptions::default()), 1);
+        assert_eq!(imported.files["a.rs"].definitions[0].name(), "Motor");
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_and_import() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let index = sample_index(dir.path())?;
+        let path = dir.path().join(".neopilot").join("repo-map.jsonl");
+        export(&index, &path)?;
+        assert_eq!(import(dir.path(), &path, &ScanOptions::default())?.files.len(), 2);
+        Ok(())
+    }
+}
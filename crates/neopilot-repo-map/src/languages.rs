@@ -0,0 +1,128 @@
+//! Language detection with configurable overrides
+//!
+//! Extensions are not always enough: `*.inc` files may be PHP, and SCons'
+//! `SConstruct` files have no extension at all. Overrides map glob patterns to
+//! languages and are consulted before the extension, see
+//! [`LanguageOverrides::language_for`].
+
+use std::cmp::Reverse;
+use std::path::Path;
+
+use crate::scan::language_for_path;
+
+/// Glob patterns mapped to tree-sitter language names
+///
+/// Patterns without a `/` match the file name, others match the whole path
+/// relative to the scan root. `*` and `?` do not cross `/`, `**` does. Exact
+/// names win over wildcards, and longer patterns over shorter ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LanguageOverrides {
+    rules: Vec<(String, String)>,
+}
+
+impl LanguageOverrides {
+    pub fn new(rules: impl IntoIterator<Item = (String, String)>) -> Self {
+        let mut rules: Vec<(String, String)> = rules.into_iter().collect();
+        rules.sort_by_key(|(pattern, _)| {
+            (pattern.contains(['*', '?']), Reverse(pattern.len()), pattern.clone())
+        });
+        Self { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Language of the first pattern matching `path`, if any
+    pub fn lookup(&self, path: &Path) -> Option<&str> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        let name = path.file_name()?.to_str()?;
+        let full: Vec<&str> = path.iter().filter_map(|part| part.to_str()).collect();
+        let full = full.join("/");
+        self.rules
+            .iter()
+            .find(|(pattern, _)| {
+                let target = if pattern.contains('/') { full.as_str() } else { name };
+                glob_match(pattern, target)
+            })
+            .map(|(_, language)| language.as_str())
+    }
+
+    /// Language of `path`, relative to the scan root, from the overrides or
+    /// its extension
+    pub fn language_for<'a>(&'a self, path: &Path) -> Option<&'a str> {
+        self.lookup(path).or_else(|| language_for_path(path))
+    }
+}
+
+/// Whether `text` matches the glob `pattern`
+fn glob_match(pattern: &str, text: &str) -> bool {
+    if let Some(rest) = pattern.strip_prefix("**/") {
+        // Zero or more leading directories
+        return glob_match(rest, text)
+            || text
+                .char_indices()
+                .any(|(i, c)| c == '/' && glob_match(rest, &text[i + 1..]));
+    }
+    if let Some(rest) = pattern.strip_prefix("**") {
+        return (0..=text.len())
+            .filter(|&i| text.is_char_boundary(i))
+            .any(|i| glob_match(rest, &text[i..]));
+    }
+    if let Some(rest) = pattern.strip_prefix('*') {
+        let limit = text.find('/').unwrap_or(text.len());
+        return (0..=limit)
+            .filter(|&i| text.is_char_boundary(i))
+            .any(|i| glob_match(rest, &text[i..]));
+    }
+    let mut pattern_chars = pattern.chars();
+    let mut text_chars = text.chars();
+    match (pattern_chars.next(), text_chars.next()) {
+        (None, None) => true,
+        (Some('?'), Some(c)) if c != '/' => glob_match(pattern_chars.as_str(), text_chars.as_str()),
+        (Some(p), Some(c)) if p == c && p != '?' => {
+            glob_match(pattern_chars.as_str(), text_chars.as_str())
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overrides(rules: &[(&str, &str)]) -> LanguageOverrides {
+        LanguageOverrides::new(rules.iter().map(|(p, l)| (p.to_string(), l.to_string())))
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.inc", "header.inc"));
+        assert!(!glob_match("*.inc", "header.inc.bak"));
+        assert!(glob_match("BUILD", "BUILD"));
+        assert!(glob_match("BUILD.?azel", "BUILD.bazel"));
+        assert!(!glob_match("src/*.inc", "src/nested/a.inc"));
+        assert!(glob_match("src/**/*.inc", "src/nested/deep/a.inc"));
+        assert!(glob_match("**/templates/*.inc", "templates/a.inc"));
+        assert!(glob_match("**/templates/*.inc", "web/templates/a.inc"));
+    }
+
+    #[test]
+    fn test_overrides_win_over_extension() {
+        let overrides = overrides(&[("*.inc", "php"), ("BUILD", "python"), ("legacy/*.h", "cpp")]);
+        assert_eq!(overrides.language_for(Path::new("lib/header.inc")), Some("php"));
+        assert_eq!(overrides.language_for(Path::new("pkg/BUILD")), Some("python"));
+        assert_eq!(overrides.language_for(Path::new("legacy/api.h")), Some("cpp"));
+        assert_eq!(overrides.language_for(Path::new("src/api.h")), Some("c"));
+        assert_eq!(overrides.language_for(Path::new("README.md")), None);
+    }
+
+    #[test]
+    fn test_exact_names_win_over_wildcards() {
+        let overrides = overrides(&[("*", "ruby"), ("Rakefile.rs", "rust")]);
+        assert_eq!(overrides.lookup(Path::new("Rakefile.rs")), Some("rust"));
+        assert_eq!(overrides.lookup(Path::new("Gemfile")), Some("ruby"));
+    }
+}
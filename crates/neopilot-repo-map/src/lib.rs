@@ -2,16 +2,50 @@
 
 // Re-export the Config type for easy access
 pub mod config;
+pub mod context;
+pub mod diff;
+pub mod encoding;
+pub mod export;
+pub mod export_lists;
+pub mod health;
+pub mod index;
+pub mod index_text;
+pub mod languages;
+pub mod logging;
+pub mod lookup;
+pub mod metrics;
+pub mod overlay;
+pub mod profile;
+pub mod rank;
+pub mod references;
+pub mod render;
+pub mod scan;
+pub mod sexp;
+
+#[cfg(feature = "node")]
+mod node;
+#[cfg(feature = "python")]
+mod python;
+
 pub use config::{Config, ConfigLoader};
 
 use mlua::prelude::*;
+use neopilot_common::events::{self, Event};
+use neopilot_common::scheduler::{Scheduler, SchedulerSlot};
+use neopilot_error::{Error, ErrorCode, Result};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
-use std::collections::BTreeMap;
-use tree_sitter::{Node, Parser, Query, QueryCursor};
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+use tree_sitter::{Node, Parser, Query, QueryCursor, Tree};
 use tree_sitter_language::LanguageFn;
 
 /// Represents a function or method definition.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Func {
     pub name: String,
     pub params: String,
@@ -20,7 +54,7 @@ pub struct Func {
 }
 
 /// Represents a class or module definition.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Class {
     pub type_name: String,
     pub name: String,
@@ -30,28 +64,40 @@ pub struct Class {
 }
 
 /// Represents an enum definition.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Enum {
     pub name: String,
     pub items: Vec<Variable>,
 }
 
 /// Represents a union definition.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Union {
     pub name: String,
     pub items: Vec<Variable>,
 }
 
 /// Represents a variable definition.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Variable {
     pub name: String,
     pub value_type: String,
 }
 
+/// Which definitions the extractor keeps
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Visibility {
+    /// What other files can use: `pub` Rust items, exported Go types,
+    /// definitions named in export lists
+    #[default]
+    Public,
+    /// Private and internal definitions as well, e.g. for the focus files
+    /// of the map, see [`render::private_focus_definitions`]
+    All,
+}
+
 /// Represents a top-level code definition (function, class, module, etc.).
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Definition {
     Func(Func),
     Class(Class),
@@ -62,10 +108,25 @@ pub enum Definition {
     // TODO: Namespace support
 }
 
+impl Definition {
+    /// Name of the defined symbol
+    pub fn name(&self) -> &str {
+        match self {
+            Definition::Func(func) => &func.name,
+            Definition::Class(class) | Definition::Module(class) => &class.name,
+            Definition::Enum(enum_def) => &enum_def.name,
+            Definition::Variable(variable) => &variable.name,
+            Definition::Union(union_def) => &union_def.name,
+        }
+    }
+}
+
 fn get_ts_language(language: &str) -> Option<LanguageFn> {
     match language {
         "rust" => Some(tree_sitter_rust::LANGUAGE),
         "python" => Some(tree_sitter_python::LANGUAGE),
+        // Starlark is a dialect of Python and parses with its grammar
+        "starlark" => Some(tree_sitter_python::LANGUAGE),
         "php" => Some(tree_sitter_php::LANGUAGE_PHP),
         "java" => Some(tree_sitter_java::LANGUAGE),
         "javascript" => Some(tree_sitter_javascript::LANGUAGE),
@@ -84,6 +145,25 @@ fn get_ts_language(language: &str) -> Option<LanguageFn> {
     }
 }
 
+/// Parse `source` with the tree-sitter grammar for `language`
+///
+/// A grammar that fails to load disables its language, see [`health`].
+fn parse_source(language: &str, source: &str) -> Result<Tree> {
+    let ts_language = get_ts_language(language).ok_or_else(|| unsupported_language(language))?;
+    health::check(language)?;
+    let mut parser = Parser::new();
+    parser.set_language(&ts_language.into()).map_err(|e| {
+        health::disable(language, format!("Failed to set language for {language}: {e}"))
+    })?;
+    parser.parse(source, None).ok_or_else(|| {
+        Error::new(ErrorCode::Parse, format!("Failed to parse source code for {language}"))
+    })
+}
+
+fn unsupported_language(language: &str) -> Error {
+    Error::new(ErrorCode::Unsupported, format!("Unsupported language: {language}"))
+}
+
 const C_QUERY: &str = include_str!("../queries/tree-sitter-c-defs.scm");
 const CPP_QUERY: &str = include_str!("../queries/tree-sitter-cpp-defs.scm");
 const GO_QUERY: &str = include_str!("../queries/tree-sitter-go-defs.scm");
@@ -91,6 +171,7 @@ const JAVA_QUERY: &str = include_str!("../queries/tree-sitter-java-defs.scm");
 const JAVASCRIPT_QUERY: &str = include_str!("../queries/tree-sitter-javascript-defs.scm");
 const LUA_QUERY: &str = include_str!("../queries/tree-sitter-lua-defs.scm");
 const PYTHON_QUERY: &str = include_str!("../queries/tree-sitter-python-defs.scm");
+const STARLARK_QUERY: &str = include_str!("../queries/tree-sitter-starlark-defs.scm");
 const PHP_QUERY: &str = include_str!("../queries/tree-sitter-php-defs.scm");
 const RUST_QUERY: &str = include_str!("../queries/tree-sitter-rust-defs.scm");
 const ZIG_QUERY: &str = include_str!("../queries/tree-sitter-zig-defs.scm");
@@ -101,9 +182,10 @@ const SWIFT_QUERY: &str = include_str!("../queries/tree-sitter-swift-defs.scm");
 const ELIXIR_QUERY: &str = include_str!("../queries/tree-sitter-elixir-defs.scm");
 const CSHARP_QUERY: &str = include_str!("../queries/tree-sitter-c-sharp-defs.scm");
 
-fn get_definitions_query(language: &str) -> Result<Query, String> {
-    let ts_language =
-        get_ts_language(language).ok_or_else(|| format!("Unsupported language: {language}"))?;
+/// The definitions query of `language`; a query that fails to compile disables the language
+fn get_definitions_query(language: &str) -> Result<Query> {
+    let ts_language = get_ts_language(language).ok_or_else(|| unsupported_language(language))?;
+    health::check(language)?;
     let contents = match language {
         "c" => C_QUERY,
         "cpp" => CPP_QUERY,
@@ -113,6 +195,7 @@ fn get_definitions_query(language: &str) -> Result<Query, String> {
         "lua" => LUA_QUERY,
         "php" => PHP_QUERY,
         "python" => PYTHON_QUERY,
+        "starlark" => STARLARK_QUERY,
         "rust" => RUST_QUERY,
         "zig" => ZIG_QUERY,
         "typescript" => TYPESCRIPT_QUERY,
@@ -121,10 +204,90 @@ fn get_definitions_query(language: &str) -> Result<Query, String> {
         "swift" => SWIFT_QUERY,
         "elixir" => ELIXIR_QUERY,
         "csharp" => CSHARP_QUERY,
-        _ => return Err(format!("Unsupported language: {language}")),
+        _ => return Err(unsupported_language(language)),
     };
-    Query::new(&ts_language.into(), contents)
-        .map_err(|e| format!("Failed to parse query for {language}: {e}"))
+    Query::new(&ts_language.into(), contents).map_err(|e| {
+        health::disable(language, format!("Failed to parse query for {language}: {e}"))
+    })
+}
+
+/// Languages with a tree-sitter grammar and a definitions query
+pub const SUPPORTED_LANGUAGES: &[&str] = &[
+    "c",
+    "cpp",
+    "csharp",
+    "elixir",
+    "go",
+    "java",
+    "javascript",
+    "lua",
+    "php",
+    "python",
+    "ruby",
+    "rust",
+    "scala",
+    "starlark",
+    "swift",
+    "typescript",
+    "zig",
+];
+
+/// Symbol kinds extracted for a language
+///
+/// Derived from the capture names of the language's definitions query that
+/// [`extract_definitions_with`] turns into definitions, so it changes together
+/// with the queries. Kinds a query captures but the extractor drops, such as
+/// enums and variables today, are not advertised.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LanguageSupport {
+    pub language: &'static str,
+    pub classes: bool,
+    pub functions: bool,
+    pub methods: bool,
+    pub enums: bool,
+    pub unions: bool,
+    pub variables: bool,
+    pub modules: bool,
+    pub imports: bool,
+    pub docstrings: bool,
+    /// Build targets and rule declarations, e.g. in Bazel files
+    pub targets: bool,
+}
+
+impl LanguageSupport {
+    /// Kept in step with the captures matched in [`extract_definitions_timed`]
+    fn from_captures(language: &'static str, captures: &[&str]) -> Self {
+        let has = |name: &str| captures.contains(&name);
+        // Elixir modules are captured as classes, and only its delegated
+        // functions are read from method captures
+        let elixir = language == "elixir";
+        Self {
+            language,
+            classes: has("class") && !elixir,
+            functions: has("function"),
+            methods: has("method") && elixir,
+            enums: false,
+            unions: false,
+            variables: false,
+            modules: has("module") || (has("class") && elixir),
+            imports: false,
+            docstrings: false,
+            targets: has("target") || has("rule"),
+        }
+    }
+}
+
+/// Extracted symbol kinds of every supported language
+///
+/// Languages whose query fails to load are left out, see [`health`].
+pub fn supported_languages() -> Result<Vec<LanguageSupport>> {
+    Ok(SUPPORTED_LANGUAGES
+        .iter()
+        .filter_map(|&language| {
+            let query = get_definitions_query(language).ok()?;
+            Some(LanguageSupport::from_captures(language, query.capture_names()))
+        })
+        .collect())
 }
 
 #[allow(dead_code)]
@@ -263,7 +426,6 @@ fn csharp_find_parent_type_node<'a>(node: &'a Node) -> Option<Node<'a>> {
     find_first_ancestor_by_types(node, &["class_declaration", "record_declaration"])
 }
 
-#[allow(dead_code)]
 fn ex_find_parent_module_declaration_name<'a>(node: &'a Node, source: &'a [u8]) -> Option<String> {
     let mut parent = node.parent();
     while let Some(parent_node) = parent {
@@ -305,6 +467,33 @@ fn ruby_find_parent_module_declaration_name<'a>(
     }
 }
 
+/// Functions whose result, assigned to a name in a `.bzl` file, declares a
+/// rule-like symbol
+const STARLARK_RULE_KINDS: &[&str] = &[
+    "rule",
+    "repository_rule",
+    "macro",
+    "aspect",
+    "provider",
+    "module_extension",
+    "tag_class",
+];
+
+/// Value of the `name = "..."` argument of a Starlark call
+fn starlark_target_name(call: &Node, source: &[u8]) -> Option<String> {
+    let arguments = call.child_by_field_name("arguments")?;
+    let mut cursor = arguments.walk();
+    let argument = arguments.named_children(&mut cursor).find(|argument| {
+        argument.kind() == "keyword_argument"
+            && argument
+                .child_by_field_name("name")
+                .map_or(false, |name| name.utf8_text(source) == Ok("name"))
+    })?;
+    let value = argument.child_by_field_name("value")?;
+    let content = find_child_by_type(&value, "string_content")?;
+    Some(get_node_text(&content, source))
+}
+
 fn get_node_text<'a>(node: &'a Node, source: &'a [u8]) -> String {
     node.utf8_text(source).unwrap_or_default().to_string()
 }
@@ -328,27 +517,105 @@ fn is_first_letter_uppercase(name: &str) -> bool {
     name.chars().next().unwrap().is_uppercase()
 }
 
-// Given a language, parse the given source code and return exported definitions.
-fn extract_definitions(language: &str, source: &str) -> Result<Vec<Definition>, String> {
-    let ts_language = get_ts_language(language);
-    if ts_language.is_none() {
-        return Ok(vec![]);
+/// Files with at least this many top-level nodes are matched in parallel
+const PARALLEL_QUERY_MIN_NODES: usize = 1024;
+/// Top-level nodes matched by each parallel task
+const PARALLEL_QUERY_CHUNK_NODES: usize = 256;
+
+/// Nodes captured by `query` within `range` of the source, in document order
+fn captures_in_range<'tree>(
+    query: &Query,
+    root: Node<'tree>,
+    source: &[u8],
+    range: Range<usize>,
+) -> Vec<(u32, Node<'tree>)> {
+    let mut query_cursor = QueryCursor::new();
+    query_cursor.set_byte_range(range);
+    query_cursor
+        .captures(query, root, source)
+        .flat_map(|(m, _)| m.captures.iter().map(|c| (c.index, c.node)).collect::<Vec<_>>())
+        .collect()
+}
+
+/// Nodes captured by `query` in the tree under `root`, in document order
+///
+/// Generated files can hold tens of thousands of top-level definitions. Their
+/// top-level nodes are split into chunks matched on separate threads, which
+/// keeps outlining them from stalling the editor; captures come back in the
+/// same order as matching the whole tree at once.
+fn query_captures<'tree>(
+    query: &Query,
+    root: Node<'tree>,
+    source: &[u8],
+) -> Vec<(u32, Node<'tree>)> {
+    if root.child_count() < PARALLEL_QUERY_MIN_NODES {
+        return captures_in_range(query, root, source, 0..usize::MAX);
     }
-    let ts_language = ts_language.unwrap();
+    let mut tree_cursor = root.walk();
+    let mut starts: Vec<usize> = root
+        .children(&mut tree_cursor)
+        .step_by(PARALLEL_QUERY_CHUNK_NODES)
+        .map(|node| node.start_byte())
+        .collect();
+    // The first chunk also covers anything before the first node, the last
+    // one anything after the last node
+    starts[0] = 0;
+    starts.push(usize::MAX);
+    starts
+        .par_windows(2)
+        .map(|bounds| captures_in_range(query, root, source, bounds[0]..bounds[1]))
+        .collect::<Vec<_>>()
+        .concat()
+}
 
-    let mut parser = Parser::new();
-    parser
-        .set_language(&ts_language.into())
-        .unwrap_or_else(|_| panic!("Failed to set language for {language}"));
-    let tree = parser
-        .parse(source, None)
-        .unwrap_or_else(|| panic!("Failed to parse source code for {language}"));
+// Given a language, parse the given source code and return exported definitions.
+fn extract_definitions(language: &str, source: &str) -> Result<Vec<Definition>> {
+    extract_definitions_with(language, source, Visibility::Public)
+}
+
+/// Extract the definitions of `source` with the given `visibility`
+pub(crate) fn extract_definitions_with(
+    language: &str,
+    source: &str,
+    visibility: Visibility,
+) -> Result<Vec<Definition>> {
+    extract_definitions_timed(language, source, visibility, false)
+        .map(|(definitions, _, _)| definitions)
+}
+
+/// Extract definitions like [`extract_definitions_with`], timing the parse
+/// and the query for [`profile`]
+///
+/// With `with_metrics`, the [`metrics::FunctionMetrics`] of the functions are
+/// computed from the same parse and query.
+fn extract_definitions_timed(
+    language: &str,
+    source: &str,
+    visibility: Visibility,
+    with_metrics: bool,
+) -> Result<(Vec<Definition>, Vec<metrics::FunctionMetrics>, profile::Timings)> {
+    if get_ts_language(language).is_none() {
+        return Ok((vec![], vec![], profile::Timings::default()));
+    }
+    let started = Instant::now();
+    let tree = parse_source(language, source)?;
+    let parsed = Instant::now();
     let root_node = tree.root_node();
+    let exported = export_lists::exported_names(language, root_node, source.as_bytes())?;
+    let export_modifier = || Some(export_lists::EXPORT_MODIFIER.to_string());
+    let include_private = visibility == Visibility::All;
 
     let query = get_definitions_query(language)?;
-    let mut query_cursor = QueryCursor::new();
-    let captures = query_cursor.captures(&query, root_node, source.as_bytes());
+    let captures = query_captures(&query, root_node, source.as_bytes());
+    let metrics_started = Instant::now();
+    let function_metrics = if with_metrics {
+        metrics::metrics_from_captures(&query, &captures, source.as_bytes())
+    } else {
+        vec![]
+    };
+    let metrics_time = metrics_started.elapsed();
     let mut definitions = Vec::new();
+    let mut func_defs: Vec<Func> = Vec::new();
     let mut class_def_map: BTreeMap<String, RefCell<Class>> = BTreeMap::new();
     let enum_def_map: BTreeMap<String, RefCell<Enum>> = BTreeMap::new();
     let union_def_map: BTreeMap<String, RefCell<Union>> = BTreeMap::new();
@@ -384,146 +651,241 @@ fn extract_definitions(language: &str, source: &str) -> Result<Vec<Definition>,
 
     // Sometimes, multiple queries capture the same node with the same capture name.
     // We need to ensure that we only add the node to the definition map once.
-    let mut captured_nodes: BTreeMap<String, Vec<usize>> = BTreeMap::new();
-
-    for (m, _) in captures {
-        for capture in m.captures {
-            let capture_name = &query.capture_names()[capture.index as usize];
-            let node = capture.node;
-            let node_text = node.utf8_text(source.as_bytes()).unwrap();
-
-            let node_id = node.id();
-            if captured_nodes
-                .get(*capture_name)
-                .map_or(false, |v| v.contains(&node_id))
-            {
-                continue;
-            }
-            captured_nodes
-                .entry(String::from(*capture_name))
-                .or_default()
-                .push(node_id);
-
-            let name = match language {
-                "cpp" => {
-                    if *capture_name == "class" {
-                        node.child_by_field_name("name")
-                            .map(|n| n.utf8_text(source.as_bytes()).unwrap())
-                            .unwrap_or(node_text)
-                            .to_string()
-                    } else {
-                        let ident = find_descendant_by_type(&node, "field_identifier")
-                            .or_else(|| find_descendant_by_type(&node, "operator_name"))
-                            .or_else(|| find_descendant_by_type(&node, "identifier"))
-                            .map(|n| n.utf8_text(source.as_bytes()).unwrap());
-                        if let Some(ident) = ident {
-                            let scope = node
-                                .child_by_field_name("declarator")
-                                .and_then(|n| n.child_by_field_name("declarator"))
-                                .and_then(|n| n.child_by_field_name("scope"));
-                            if let Some(scope_node) = scope {
-                                format!(
-                                    "{}::{}",
-                                    scope_node.utf8_text(source.as_bytes()).unwrap(),
-                                    ident
-                                )
-                            } else {
-                                ident.to_string()
-                            }
-                        } else {
-                            node_text.to_string()
-                        }
-                    }
-                }
-                "scala" => node
-                    .child_by_field_name("name")
-                    .or_else(|| node.child_by_field_name("pattern"))
-                    .map(|n| n.utf8_text(source.as_bytes()).unwrap())
-                    .unwrap_or(node_text)
-                    .to_string(),
-                "csharp" => {
-                    let mut identifier = node;
-                    // Handle primary constructors (they are direct children of *_declaration)
-                    if *capture_name == "method" && csharp_is_primary_constructor(&node) {
-                        identifier = node.parent().unwrap_or(node);
-                    } else if *capture_name == "class_variable" {
-                        identifier =
-                            find_descendant_by_type(&node, "variable_declarator").unwrap_or(node);
-                    }
+    let mut captured_nodes: HashSet<(u32, usize)> = HashSet::new();
 
-                    identifier
-                        .child_by_field_name("name")
+    for (capture_index, node) in captures {
+        if !captured_nodes.insert((capture_index, node.id())) {
+            continue;
+        }
+        let capture_name = &query.capture_names()[capture_index as usize];
+        let node_text = node.utf8_text(source.as_bytes()).unwrap();
+
+        let name = match language {
+            "cpp" => {
+                if *capture_name == "class" {
+                    node.child_by_field_name("name")
                         .map(|n| n.utf8_text(source.as_bytes()).unwrap())
                         .unwrap_or(node_text)
                         .to_string()
-                }
-                "ruby" => {
-                    let name = node
-                        .child_by_field_name("name")
-                        .map(|n| n.utf8_text(source.as_bytes()).unwrap())
-                        .unwrap_or(node_text)
-                        .to_string();
-                    if *capture_name == "class" || *capture_name == "module" {
-                        ruby_find_parent_module_declaration_name(&node, source.as_bytes())
-                            .unwrap_or(name)
+                } else {
+                    let ident = find_descendant_by_type(&node, "field_identifier")
+                        .or_else(|| find_descendant_by_type(&node, "operator_name"))
+                        .or_else(|| find_descendant_by_type(&node, "identifier"))
+                        .map(|n| n.utf8_text(source.as_bytes()).unwrap());
+                    if let Some(ident) = ident {
+                        let scope = node
+                            .child_by_field_name("declarator")
+                            .and_then(|n| n.child_by_field_name("declarator"))
+                            .and_then(|n| n.child_by_field_name("scope"));
+                        if let Some(scope_node) = scope {
+                            format!(
+                                "{}::{}",
+                                scope_node.utf8_text(source.as_bytes()).unwrap(),
+                                ident
+                            )
+                        } else {
+                            ident.to_string()
+                        }
                     } else {
-                        name
+                        node_text.to_string()
                     }
                 }
-                _ => node
+            }
+            "scala" => node
+                .child_by_field_name("name")
+                .or_else(|| node.child_by_field_name("pattern"))
+                .map(|n| n.utf8_text(source.as_bytes()).unwrap())
+                .unwrap_or(node_text)
+                .to_string(),
+            "csharp" => {
+                let mut identifier = node;
+                // Handle primary constructors (they are direct children of *_declaration)
+                if *capture_name == "method" && csharp_is_primary_constructor(&node) {
+                    identifier = node.parent().unwrap_or(node);
+                } else if *capture_name == "class_variable" {
+                    identifier =
+                        find_descendant_by_type(&node, "variable_declarator").unwrap_or(node);
+                }
+
+                identifier
                     .child_by_field_name("name")
                     .map(|n| n.utf8_text(source.as_bytes()).unwrap())
                     .unwrap_or(node_text)
-                    .to_string(),
-            };
+                    .to_string()
+            }
+            "ruby" => {
+                let name = node
+                    .child_by_field_name("name")
+                    .map(|n| n.utf8_text(source.as_bytes()).unwrap())
+                    .unwrap_or(node_text)
+                    .to_string();
+                if *capture_name == "class" || *capture_name == "module" {
+                    ruby_find_parent_module_declaration_name(&node, source.as_bytes())
+                        .unwrap_or(name)
+                } else {
+                    name
+                }
+            }
+            _ => node
+                .child_by_field_name("name")
+                .map(|n| n.utf8_text(source.as_bytes()).unwrap())
+                .unwrap_or(node_text)
+                .to_string(),
+        };
 
-            match *capture_name {
-                "class" => {
-                    if !name.is_empty() {
-                        if language == "go" && !is_first_letter_uppercase(&name) {
-                            continue;
-                        }
-                        ensure_class_def(language, &name, &mut class_def_map);
-                        let visibility_modifier_node =
-                            find_child_by_type(&node, "visibility_modifier");
-                        let visibility_modifier = visibility_modifier_node
-                            .map(|n| n.utf8_text(source.as_bytes()).unwrap())
-                            .unwrap_or("");
-                        let class_def = class_def_map.get_mut(&name).unwrap();
-                        class_def.borrow_mut().visibility_modifier =
-                            if visibility_modifier.is_empty() {
-                                None
-                            } else {
-                                Some(visibility_modifier.to_string())
-                            };
+        match *capture_name {
+            "class" => {
+                if !name.is_empty() {
+                    if language == "go" && !is_first_letter_uppercase(&name) && !include_private {
+                        continue;
                     }
+                    ensure_class_def(language, &name, &mut class_def_map);
+                    let visibility_modifier_node =
+                        find_child_by_type(&node, "visibility_modifier");
+                    let visibility_modifier = visibility_modifier_node
+                        .map(|n| n.utf8_text(source.as_bytes()).unwrap())
+                        .unwrap_or("");
+                    let class_def = class_def_map.get_mut(&name).unwrap();
+                    class_def.borrow_mut().visibility_modifier =
+                        if !visibility_modifier.is_empty() {
+                            Some(visibility_modifier.to_string())
+                        } else if exported.contains(&name) {
+                            export_modifier()
+                        } else {
+                            None
+                        };
                 }
-                "module" => {
-                    if !name.is_empty() {
-                        ensure_module_def(&name, &mut class_def_map);
-                    }
+            }
+            "module" => {
+                if !name.is_empty() {
+                    ensure_module_def(&name, &mut class_def_map);
                 }
-                _ => {
-                    // Handle other capture types (functions, variables, etc.) as needed
-                    // This is a simplified version - you'd need to add more cases here
+            }
+            // A target is shown as its rule followed by its name, e.g. `cc_library foo`
+            "target" => {
+                let Some(rule) = node.child_by_field_name("function") else {
+                    continue;
+                };
+                let Some(name) = starlark_target_name(&node, source.as_bytes()) else {
+                    continue;
+                };
+                class_def_map.entry(name.clone()).or_insert_with(|| {
+                    RefCell::new(Class {
+                        type_name: get_node_text(&rule, source.as_bytes()),
+                        name,
+                        methods: vec![],
+                        properties: vec![],
+                        visibility_modifier: None,
+                    })
+                });
+            }
+            "rule" => {
+                let kind = node
+                    .child_by_field_name("right")
+                    .and_then(|call| call.child_by_field_name("function"))
+                    .map(|function| get_node_text(&function, source.as_bytes()))
+                    .unwrap_or_default();
+                let name = node
+                    .child_by_field_name("left")
+                    .map(|left| get_node_text(&left, source.as_bytes()))
+                    .unwrap_or_default();
+                // Names starting with `_` are private to the .bzl file
+                let private = name.starts_with('_') && !include_private;
+                if !STARLARK_RULE_KINDS.contains(&kind.as_str()) || private {
+                    continue;
                 }
+                class_def_map.entry(name.clone()).or_insert_with(|| {
+                    RefCell::new(Class {
+                        type_name: kind,
+                        name,
+                        methods: vec![],
+                        properties: vec![],
+                        visibility_modifier: None,
+                    })
+                });
+            }
+            "function" if language == "starlark" => {
+                if name.is_empty() || (name.starts_with('_') && !include_private) {
+                    continue;
+                }
+                func_defs.push(Func {
+                    name,
+                    params: node
+                        .child_by_field_name("parameters")
+                        .map(|params| get_node_text(&params, source.as_bytes()))
+                        .unwrap_or_default(),
+                    return_type: String::new(),
+                    accessibility_modifier: None,
+                });
+            }
+            // Top-level functions of dynamic languages are shown when exported
+            "function" if include_private || exported.contains(&name) => {
+                let exported = exported.contains(&name);
+                func_defs.push(Func {
+                    name,
+                    params: node
+                        .child_by_field_name("parameters")
+                        .map(|params| get_node_text(&params, source.as_bytes()))
+                        .unwrap_or_default(),
+                    return_type: node
+                        .child_by_field_name("return_type")
+                        .map(|return_type| get_node_text(&return_type, source.as_bytes()))
+                        .map(|return_type| return_type.trim_start_matches(':').trim().to_string())
+                        .unwrap_or_default(),
+                    accessibility_modifier: if exported { export_modifier() } else { None },
+                });
+            }
+            // Functions delegated with `defdelegate` are shown in their module
+            "method" if language == "elixir" && exported.contains(&name) => {
+                let Some(module) = ex_find_parent_module_declaration_name(&node, source.as_bytes())
+                else {
+                    continue;
+                };
+                ensure_class_def(language, &module, &mut class_def_map);
+                let params = node
+                    .parent()
+                    .filter(|parent| parent.kind() == "call")
+                    .and_then(|call| find_child_by_type(&call, "arguments"))
+                    .map(|arguments| get_node_text(&arguments, source.as_bytes()))
+                    .unwrap_or_default();
+                class_def_map[&module].borrow_mut().methods.push(Func {
+                    name,
+                    params,
+                    return_type: String::new(),
+                    accessibility_modifier: export_modifier(),
+                });
+            }
+            _ => {
+                // Handle other capture types (functions, variables, etc.) as needed
+                // This is a simplified version - you'd need to add more cases here
             }
         }
     }
 
+    // An explicit export list hides the classes it leaves out
+    let lists_public_api = export_lists::lists_public_api(language) && !exported.is_empty();
     for (_, def) in class_def_map {
         let class_def = def.into_inner();
-        if language == "rust" {
+        if include_private {
+            definitions.push(Definition::Class(class_def));
+        } else if language == "rust" {
             if let Some(visibility_modifier) = &class_def.visibility_modifier {
                 if visibility_modifier.contains("pub") {
                     definitions.push(Definition::Class(class_def));
                 }
             }
+        } else if lists_public_api {
+            if exported.contains(&class_def.name) {
+                definitions.push(Definition::Class(class_def));
+            }
         } else {
             definitions.push(Definition::Class(class_def));
         }
     }
 
+    for func in func_defs {
+        definitions.push(Definition::Func(func));
+    }
     for (_, def) in enum_def_map {
         definitions.push(Definition::Enum(def.into_inner()));
     }
@@ -531,7 +893,12 @@ fn extract_definitions(language: &str, source: &str) -> Result<Vec<Definition>,
         definitions.push(Definition::Union(def.into_inner()));
     }
 
-    Ok(definitions)
+    let timings = profile::Timings {
+        parse: parsed - started,
+        query: parsed.elapsed().saturating_sub(metrics_time),
+        metrics: metrics_time,
+    };
+    Ok((definitions, function_metrics, timings))
 }
 
 fn stringify_function(func: &Func) -> String {
@@ -605,40 +972,1048 @@ fn stringify_union(union_def: &Union) -> String {
     format!("{res}}};")
 }
 
-fn stringify_definitions(definitions: &Vec<Definition>) -> String {
+fn stringify_definition(definition: &Definition) -> String {
+    match definition {
+        Definition::Class(class) => stringify_class(class),
+        Definition::Module(module) => stringify_class(module),
+        Definition::Enum(enum_def) => stringify_enum(enum_def),
+        Definition::Union(union_def) => stringify_union(union_def),
+        Definition::Func(func) => stringify_function(func),
+        Definition::Variable(variable) => stringify_variable(variable),
+    }
+}
+
+fn stringify_definitions(definitions: &[Definition]) -> String {
     let mut res = String::new();
     for definition in definitions {
-        match definition {
-            Definition::Class(class) => res = format!("{res}{}", stringify_class(class)),
-            Definition::Module(module) => res = format!("{res}{}", stringify_class(module)),
-            Definition::Enum(enum_def) => res = format!("{res}{}", stringify_enum(enum_def)),
-            Definition::Union(union_def) => res = format!("{res}{}", stringify_union(union_def)),
-            Definition::Func(func) => res = format!("{res}{}", stringify_function(func)),
-            Definition::Variable(variable) => {
-                let variable_str = stringify_variable(variable);
-                res = format!("{res}{variable_str}");
-            }
+        res = format!("{res}{}", stringify_definition(definition));
+    }
+    res
+}
+
+/// Marker ending output cut by [`stringify_definitions_capped`]
+fn omitted_marker(omitted: usize) -> String {
+    format!("\u{2026} {omitted} more symbols omitted\n")
+}
+
+/// Like [`stringify_definitions`], cut at a definition boundary to fit in `max_bytes`
+///
+/// Definitions that do not fit are replaced by a "… N more symbols omitted"
+/// line, counted in `max_bytes`. Only when `max_bytes` is smaller than that
+/// line is the result longer than `max_bytes`.
+///
+/// This caps the listing of a single source. Whole maps are bounded by the
+/// token budget of [`render::render_map`] instead.
+fn stringify_definitions_capped(definitions: &[Definition], max_bytes: Option<usize>) -> String {
+    let Some(max_bytes) = max_bytes else {
+        return stringify_definitions(definitions);
+    };
+    let mut res = String::new();
+    for (i, definition) in definitions.iter().enumerate() {
+        let stringified = stringify_definition(definition);
+        let remaining = definitions.len() - i - 1;
+        let marker_len = if remaining > 0 { omitted_marker(remaining).len() } else { 0 };
+        if res.len() + stringified.len() + marker_len > max_bytes {
+            res.push_str(&omitted_marker(definitions.len() - i));
+            break;
         }
+        res.push_str(&stringified);
     }
     res
 }
 
-pub fn get_definitions_string(language: &str, source: &str) -> LuaResult<String> {
-    let definitions =
-        extract_definitions(language, source).map_err(|e| LuaError::RuntimeError(e.to_string()))?;
-    let stringified = stringify_definitions(&definitions);
+pub fn get_definitions_string(
+    language: &str,
+    source: &str,
+    max_output_bytes: Option<usize>,
+) -> LuaResult<String> {
+    let definitions = extract_definitions(language, source)?;
+    let stringified = stringify_definitions_capped(&definitions, max_output_bytes);
     Ok(stringified)
 }
 
+/// State shared by the Lua module functions
+///
+/// The `neopilot_repo_map` module keeps its own; an engine of `neopilot-core`
+/// shares one between its Lua exports and its own calls, see [`lua_exports`].
+#[derive(Default)]
+pub struct State {
+    index: Mutex<Option<index::RepoIndex>>,
+    /// Configuration injected with `set_config`, used instead of loading one
+    config: Mutex<Option<Config>>,
+    /// Options the index was scanned with, reused to rescan single files
+    scan_options: Mutex<Option<scan::ScanOptions>>,
+    /// Configuration loaded when the index was set, reused to render its map
+    index_config: Mutex<Option<Config>>,
+    /// Runs background scans, sized from `performance` on first use
+    scheduler: SchedulerSlot,
+}
+
+impl State {
+    /// Use `config` instead of loading one from files and the environment,
+    /// or load one again with `None`
+    pub fn set_config(&self, config: Option<Config>) -> Result<()> {
+        *self.config.lock()? = config;
+        Ok(())
+    }
+
+    /// Make `index` current, scanned from `config` with `options`
+    pub fn set_index(
+        &self,
+        index: index::RepoIndex,
+        config: Config,
+        options: scan::ScanOptions,
+    ) -> Result<()> {
+        set_index(self, index, config, options)
+    }
+
+    /// Run `f` on the current index
+    pub fn with_index<R>(&self, f: impl FnOnce(&mut index::RepoIndex) -> R) -> Result<R> {
+        let mut index = lock_index(self)?;
+        Ok(f(index.as_mut().ok_or_else(index_not_built)?))
+    }
+
+    /// Drop the current index
+    pub fn clear_index(&self) -> Result<()> {
+        lock_index(self)?.take();
+        Ok(())
+    }
+
+    /// Run later background scans on `scheduler`
+    pub fn set_scheduler(&self, scheduler: Arc<Scheduler>) {
+        self.scheduler.install(scheduler);
+    }
+
+    /// The scheduler of background scans, started from `config` if none was set
+    fn scheduler(&self, config: &Config) -> Result<Arc<Scheduler>> {
+        let performance = &config.performance;
+        self.scheduler.get_or_start(performance.worker_threads, performance.channel_capacity)
+    }
+}
+
+fn lock_index(state: &State) -> Result<std::sync::MutexGuard<'_, Option<index::RepoIndex>>> {
+    Ok(state.index.lock()?)
+}
+
+/// The injected configuration, or the one from files and the environment
+fn load_config(state: &State) -> Result<Config> {
+    match state.config.lock()?.as_ref() {
+        Some(config) => Ok(config.clone()),
+        None => ConfigLoader::new().load().map_err(Error::from),
+    }
+}
+
+/// Configuration for rendering the map of the current index
+///
+/// Like [`load_config`], but reuses the configuration loaded with the index
+/// instead of reading the config files on every render.
+fn map_config(state: &State) -> Result<Config> {
+    if let Some(config) = state.config.lock()?.as_ref() {
+        return Ok(config.clone());
+    }
+    match state.index_config.lock()?.as_ref() {
+        Some(config) => Ok(config.clone()),
+        None => load_config(state),
+    }
+}
+
+/// Convert a Lua value to TOML; tables with a sequence part become arrays
+fn lua_to_toml(value: LuaValue) -> LuaResult<toml::Value> {
+    Ok(match value {
+        LuaValue::Boolean(b) => toml::Value::Boolean(b),
+        LuaValue::Integer(i) => toml::Value::Integer(i),
+        LuaValue::Number(n) => toml::Value::Float(n),
+        LuaValue::String(s) => toml::Value::String(s.to_str()?.to_string()),
+        LuaValue::Table(table) if table.raw_len() > 0 => toml::Value::Array(
+            table.sequence_values().map(|v| lua_to_toml(v?)).collect::<LuaResult<_>>()?,
+        ),
+        LuaValue::Table(table) => {
+            let mut map = toml::value::Table::new();
+            for pair in table.pairs::<String, LuaValue>() {
+                let (key, value) = pair?;
+                map.insert(key, lua_to_toml(value)?);
+            }
+            toml::Value::Table(map)
+        },
+        other => {
+            return Err(LuaError::FromLuaConversionError {
+                from: other.type_name(),
+                to: "TOML value".to_string(),
+                message: None,
+            })
+        },
+    })
+}
+
+/// Remember the scan options of a newly built or loaded index
+fn set_index(
+    state: &State,
+    index: index::RepoIndex,
+    config: Config,
+    options: scan::ScanOptions,
+) -> Result<()> {
+    let mut guard = lock_index(state)?;
+    *state.scan_options.lock()? = Some(options);
+    *state.index_config.lock()? = Some(config);
+    *guard = Some(index);
+    Ok(())
+}
+
+/// Rescan `path` into `index`; deleted, unparsable or filtered files leave it
+///
+/// Uses the options `index` was scanned with. Returns whether the file is in
+/// the index afterwards.
+fn refresh_file(state: &State, index: &mut index::RepoIndex, path: &Path) -> LuaResult<bool> {
+    let options = state.scan_options.lock().map_err(Error::from)?.clone();
+    let options = match options {
+        Some(options) => options,
+        None => scan_options_from_lua(load_config(state)?, None)?,
+    };
+    let root = index.root.clone();
+    match scan::scan_single_file(&root, path, &options) {
+        Some(file) => {
+            index.update_file(file);
+            Ok(true)
+        }
+        None => {
+            let relative = path.strip_prefix(&root).unwrap_or(path).to_string_lossy();
+            index.remove_file(&relative);
+            Ok(false)
+        }
+    }
+}
+
+fn index_not_built() -> Error {
+    Error::new(ErrorCode::NotFound, "Index not built")
+}
+
+/// Private definitions of the focus files when the config lists them
+///
+/// The index is only locked to look the files up; reading and parsing them
+/// happens after the lock is released.
+fn focus_overrides(
+    state: &State,
+    focus_files: &[String],
+    config: &config::RepoMapConfig,
+) -> Result<BTreeMap<String, Vec<Definition>>> {
+    if !config.include_private_in_focus {
+        return Ok(BTreeMap::new());
+    }
+    let sources = match lock_index(state)?.as_ref() {
+        Some(index) => render::focus_sources(index, focus_files),
+        None => return Ok(BTreeMap::new()),
+    };
+    Ok(render::private_focus_definitions(&sources))
+}
+
+fn index_to_lua(
+    lua: &Lua,
+    index: &index::RepoIndex,
+    focus_files: &[String],
+    config: &config::RepoMapConfig,
+    order: rank::MapOrder,
+    overrides: &BTreeMap<String, Vec<Definition>>,
+) -> LuaResult<LuaTable> {
+    let table = lua.create_table()?;
+    let mut ranked = rank::rank_files_with(index, focus_files, &config.ranking, SystemTime::now());
+    rank::order_files(&mut ranked, order);
+    for ranked in ranked {
+        let definitions = overrides.get(ranked.path).unwrap_or(&ranked.file.definitions);
+        let entry = lua.create_table()?;
+        entry.set("path", ranked.path)?;
+        entry.set("lang", ranked.file.language.as_str())?;
+        entry.set("defs", stringify_definitions(definitions))?;
+        entry.set("encoding", ranked.file.encoding.as_str())?;
+        entry.set("score", ranked.score)?;
+        entry.set("focus", ranked.is_focus)?;
+        if config.include_metrics {
+            entry.set("metrics", function_metrics_to_lua(lua, &ranked.file.metrics)?)?;
+        }
+        table.push(entry)?;
+    }
+    Ok(table)
+}
+
+fn function_metrics_to_lua(lua: &Lua, metrics: &[metrics::FunctionMetrics]) -> LuaResult<LuaTable> {
+    let table = lua.create_table()?;
+    for function in metrics {
+        let entry = lua.create_table()?;
+        entry.set("name", function.name.as_str())?;
+        entry.set("start_line", function.start_line)?;
+        entry.set("body_lines", function.body_lines)?;
+        entry.set("branches", function.branches)?;
+        table.push(entry)?;
+    }
+    Ok(table)
+}
+
+fn map_diff_to_lua(lua: &Lua, diff: &diff::MapDiff) -> LuaResult<LuaTable> {
+    let table = lua.create_table()?;
+    table.set("unchanged", diff.is_empty())?;
+    table.set("text", diff.to_string())?;
+    table.set("added_files", diff.added_files.clone())?;
+    table.set("removed_files", diff.removed_files.clone())?;
+    let changed_files = lua.create_table()?;
+    for file in &diff.changed_files {
+        let entry = lua.create_table()?;
+        entry.set("path", file.path.as_str())?;
+        entry.set("added", file.added.clone())?;
+        entry.set("removed", file.removed.clone())?;
+        entry.set("changed", file.changed.clone())?;
+        changed_files.push(entry)?;
+    }
+    table.set("changed_files", changed_files)?;
+    let rank_changes = lua.create_table()?;
+    for change in &diff.rank_changes {
+        let entry = lua.create_table()?;
+        entry.set("path", change.path.as_str())?;
+        entry.set("old_rank", change.old_rank)?;
+        entry.set("new_rank", change.new_rank)?;
+        rank_changes.push(entry)?;
+    }
+    table.set("rank_changes", rank_changes)?;
+    Ok(table)
+}
+
+fn position_context_to_lua(lua: &Lua, context: &context::PositionContext) -> LuaResult<LuaTable> {
+    let table = lua.create_table()?;
+    table.set("language", context.language.as_str())?;
+    table.set("enclosing_kind", context.enclosing_kind.as_deref())?;
+    table.set("start_line", context.start_line)?;
+    table.set("end_line", context.end_line)?;
+    table.set("source", context.source.as_str())?;
+    table.set("tokens", context.tokens)?;
+    let definitions = lua.create_table()?;
+    for definition in &context.definitions {
+        let entry = lua.create_table()?;
+        entry.set("path", definition.path.as_str())?;
+        entry.set("name", definition.name.as_str())?;
+        entry.set("text", definition.text.as_str())?;
+        definitions.push(entry)?;
+    }
+    table.set("definitions", definitions)?;
+    Ok(table)
+}
+
+fn symbol_location_to_lua(lua: &Lua, location: &lookup::SymbolLocation) -> LuaResult<LuaTable> {
+    let table = lua.create_table()?;
+    table.set("path", location.path.as_str())?;
+    table.set("language", location.language.as_str())?;
+    table.set("definition", definition_to_lua(lua, &location.definition)?)?;
+    table.set("text", location.text.as_str())?;
+    if let Some(source) = &location.source {
+        table.set("start_line", source.start_line)?;
+        table.set("end_line", source.end_line)?;
+        table.set("snippet", source.snippet.as_str())?;
+    }
+    Ok(table)
+}
+
+fn timings_to_lua(table: &LuaTable, timings: &profile::Timings) -> LuaResult<()> {
+    let millis = |duration: std::time::Duration| duration.as_secs_f64() * 1000.0;
+    table.set("parse_ms", millis(timings.parse))?;
+    table.set("query_ms", millis(timings.query))?;
+    table.set("metrics_ms", millis(timings.metrics))?;
+    table.set("total_ms", millis(timings.total()))?;
+    Ok(())
+}
+
+fn profile_to_lua(lua: &Lua, profile: &profile::Profile) -> LuaResult<LuaTable> {
+    let languages = lua.create_table()?;
+    for (language, totals) in &profile.languages {
+        let entry = lua.create_table()?;
+        entry.set("files", totals.files)?;
+        entry.set("bytes", totals.bytes)?;
+        timings_to_lua(&entry, &totals.timings)?;
+        languages.set(language.as_str(), entry)?;
+    }
+    let slowest_files = lua.create_table()?;
+    for file in &profile.slowest_files {
+        let entry = lua.create_table()?;
+        entry.set("path", file.path.as_str())?;
+        entry.set("language", file.language.as_str())?;
+        entry.set("bytes", file.bytes)?;
+        timings_to_lua(&entry, &file.timings)?;
+        slowest_files.push(entry)?;
+    }
+    let table = lua.create_table()?;
+    table.set("languages", languages)?;
+    table.set("slowest_files", slowest_files)?;
+    table.set("text", profile.to_string())?;
+    Ok(table)
+}
+
+fn reference_to_lua(lua: &Lua, reference: &references::Reference) -> LuaResult<LuaTable> {
+    let table = lua.create_table()?;
+    table.set("start_line", reference.start_line)?;
+    table.set("start_col", reference.start_col)?;
+    table.set("end_line", reference.end_line)?;
+    table.set("end_col", reference.end_col)?;
+    table.set("start_byte", reference.start_byte)?;
+    table.set("end_byte", reference.end_byte)?;
+    Ok(table)
+}
+
+fn func_to_lua(lua: &Lua, func: &Func) -> LuaResult<LuaTable> {
+    let table = lua.create_table()?;
+    table.set("name", func.name.as_str())?;
+    table.set("params", func.params.as_str())?;
+    table.set("return_type", func.return_type.as_str())?;
+    table.set("modifier", func.accessibility_modifier.as_deref())?;
+    Ok(table)
+}
+
+fn variable_to_lua(lua: &Lua, variable: &Variable) -> LuaResult<LuaTable> {
+    let table = lua.create_table()?;
+    table.set("name", variable.name.as_str())?;
+    table.set("value_type", variable.value_type.as_str())?;
+    Ok(table)
+}
+
+/// A definition as a table whose `kind` says which fields it has
+fn definition_to_lua(lua: &Lua, definition: &Definition) -> LuaResult<LuaTable> {
+    let variables_to_lua = |variables: &[Variable]| -> LuaResult<LuaTable> {
+        let table = lua.create_table()?;
+        for variable in variables {
+            table.push(variable_to_lua(lua, variable)?)?;
+        }
+        Ok(table)
+    };
+    let (kind, table) = match definition {
+        Definition::Func(func) => ("func", func_to_lua(lua, func)?),
+        Definition::Variable(variable) => ("variable", variable_to_lua(lua, variable)?),
+        Definition::Class(class) | Definition::Module(class) => {
+            let table = lua.create_table()?;
+            table.set("name", class.name.as_str())?;
+            table.set("type_name", class.type_name.as_str())?;
+            table.set("modifier", class.visibility_modifier.as_deref())?;
+            let methods = lua.create_table()?;
+            for method in &class.methods {
+                methods.push(func_to_lua(lua, method)?)?;
+            }
+            table.set("methods", methods)?;
+            table.set("properties", variables_to_lua(&class.properties)?)?;
+            let kind = if matches!(definition, Definition::Class(_)) { "class" } else { "module" };
+            (kind, table)
+        },
+        Definition::Enum(Enum { name, items }) | Definition::Union(Union { name, items }) => {
+            let table = lua.create_table()?;
+            table.set("name", name.as_str())?;
+            table.set("items", variables_to_lua(items)?)?;
+            let kind = if matches!(definition, Definition::Enum(_)) { "enum" } else { "union" };
+            (kind, table)
+        },
+    };
+    table.set("kind", kind)?;
+    Ok(table)
+}
+
+fn func_from_lua(table: &LuaTable) -> LuaResult<Func> {
+    Ok(Func {
+        name: table.get("name")?,
+        params: table.get::<Option<String>>("params")?.unwrap_or_default(),
+        return_type: table.get::<Option<String>>("return_type")?.unwrap_or_default(),
+        accessibility_modifier: table.get("modifier")?,
+    })
+}
+
+fn variable_from_lua(table: &LuaTable) -> LuaResult<Variable> {
+    Ok(Variable {
+        name: table.get("name")?,
+        value_type: table.get::<Option<String>>("value_type")?.unwrap_or_default(),
+    })
+}
+
+/// Read a definition written by [`definition_to_lua`]; only `kind` and
+/// `name` are required
+fn definition_from_lua(table: &LuaTable) -> LuaResult<Definition> {
+    let list = |key: &str| -> LuaResult<Vec<LuaTable>> {
+        Ok(table.get::<Option<Vec<LuaTable>>>(key)?.unwrap_or_default())
+    };
+    let variables = |key: &str| -> LuaResult<Vec<Variable>> {
+        list(key)?.iter().map(variable_from_lua).collect()
+    };
+    let kind: String = table.get("kind")?;
+    Ok(match kind.as_str() {
+        "func" => Definition::Func(func_from_lua(table)?),
+        "variable" => Definition::Variable(variable_from_lua(table)?),
+        "class" | "module" => {
+            let type_name: Option<String> = table.get("type_name")?;
+            let class = Class {
+                type_name: type_name.unwrap_or_else(|| kind.clone()),
+                name: table.get("name")?,
+                methods: list("methods")?.iter().map(func_from_lua).collect::<LuaResult<_>>()?,
+                properties: variables("properties")?,
+                visibility_modifier: table.get("modifier")?,
+            };
+            if kind == "class" {
+                Definition::Class(class)
+            } else {
+                Definition::Module(class)
+            }
+        },
+        "enum" => Definition::Enum(Enum {
+            name: table.get("name")?,
+            items: variables("items")?,
+        }),
+        "union" => Definition::Union(Union {
+            name: table.get("name")?,
+            items: variables("items")?,
+        }),
+        _ => {
+            return Err(LuaError::RuntimeError(format!(
+                "Unknown definition kind '{kind}', expected func, class, module, enum, union \
+                 or variable"
+            )))
+        },
+    })
+}
+
+/// Pass `definitions` through `transform(defs, lang)`
+///
+/// The callback returns the definitions to render, or nothing to render the
+/// table it was given, e.g. after editing it in place.
+fn transform_definitions(
+    lua: &Lua,
+    definitions: &[Definition],
+    language: &str,
+    transform: &LuaFunction,
+) -> LuaResult<Vec<Definition>> {
+    let table = lua.create_table()?;
+    for definition in definitions {
+        table.push(definition_to_lua(lua, definition)?)?;
+    }
+    let transformed: Option<LuaTable> = transform.call((table.clone(), language))?;
+    transformed
+        .unwrap_or(table)
+        .sequence_values::<LuaTable>()
+        .map(|entry| definition_from_lua(&entry?))
+        .collect()
+}
+
+/// Arguments of `get_repo_map`: focus files and order
+type MapArgs = (Option<Vec<String>>, Option<String>);
+/// Arguments of `get_repo_map_encoded`: format, focus files and order
+type EncodedMapArgs = (String, Option<Vec<String>>, Option<String>);
+/// Arguments of `render_repo_map`: budget, focus files, summarizer and order
+type RenderArgs = (usize, Option<Vec<String>>, Option<LuaFunction>, Option<String>);
+
+/// Map order from an optional Lua string, by rank if missing
+fn map_order_from_lua(order: Option<String>) -> Result<rank::MapOrder> {
+    order.map_or(Ok(rank::MapOrder::Rank), |order| order.parse())
+}
+
+/// Summarizer calling `fun(path, defs, names): string|nil`
+struct LuaSummarizer(LuaFunction);
+
+impl render::Summarizer for LuaSummarizer {
+    fn summarize(&self, path: &str, definitions: &[Definition]) -> Result<Option<String>> {
+        let names: Vec<&str> = definitions.iter().map(Definition::name).collect();
+        self.0
+            .call((path, stringify_definitions(definitions), names))
+            .map_err(|e| {
+                Error::new(ErrorCode::Internal, format!("Summarizer failed for {path}: {e}"))
+            })
+    }
+}
+
+/// Token counter backed by a Lua function
+struct LuaTokenCounter {
+    fingerprint: String,
+    count: LuaFunction,
+}
+
+impl index::TokenCounter for LuaTokenCounter {
+    fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    fn count(&self, text: &str) -> Result<usize> {
+        self.count
+            .call(text)
+            .map_err(|e| Error::new(ErrorCode::Tokenizer, format!("Token counter failed: {e}")))
+    }
+}
+
+fn rendered_map_to_lua(lua: &Lua, map: &render::RenderedMap) -> LuaResult<LuaTable> {
+    let files = lua.create_table()?;
+    for file in &map.files {
+        let entry = lua.create_table()?;
+        entry.set("path", file.path)?;
+        entry.set("lang", file.language)?;
+        entry.set("defs", file.text.as_str())?;
+        entry.set("summarized", file.summarized)?;
+        entry.set("score", file.score)?;
+        entry.set("focus", file.focus)?;
+        entry.set("tokens", file.tokens)?;
+        files.push(entry)?;
+    }
+    let table = lua.create_table()?;
+    table.set("files", files)?;
+    table.set("tokens", map.tokens)?;
+    table.set("omitted", map.omitted)?;
+    Ok(table)
+}
+
+fn scanned_files_to_lua(lua: &Lua, files: &[scan::ScannedFile]) -> LuaResult<LuaTable> {
+    let table = lua.create_table()?;
+    for file in files {
+        let entry = lua.create_table()?;
+        entry.set("path", file.path.to_string_lossy().to_string())?;
+        entry.set("lang", file.language.as_str())?;
+        entry.set("defs", stringify_definitions(&file.definitions))?;
+        entry.set("encoding", file.encoding.as_str())?;
+        table.push(entry)?;
+    }
+    Ok(table)
+}
+
+fn progress_to_lua(lua: &Lua, progress: &scan::ProgressSnapshot) -> LuaResult<LuaTable> {
+    let table = lua.create_table()?;
+    table.set("phase", progress.phase.as_str())?;
+    table.set("files_discovered", progress.files_discovered)?;
+    table.set("files_parsed", progress.files_parsed)?;
+    table.set("files_skipped", progress.files_skipped)?;
+    table.set("bytes_total", progress.bytes_total)?;
+    table.set("bytes_processed", progress.bytes_processed)?;
+    table.set("percent", progress.percent())?;
+    Ok(table)
+}
+
+/// Read `{ sandboxed = bool, max_bytes = integer, include_vendored = bool }`, all optional
+///
+/// `include_vendored` defaults to `repo_map.include_vendored` from the config.
+fn scan_options_from_lua(
+    config: Config,
+    options: Option<LuaTable>,
+) -> LuaResult<scan::ScanOptions> {
+    let Some(options) = options else {
+        return Ok(scan::ScanOptions::from_config(&config));
+    };
+    let sandboxed: Option<bool> = options.get("sandboxed")?;
+    let max_bytes: Option<u64> = options.get("max_bytes")?;
+    let include_vendored: Option<bool> = options.get("include_vendored")?;
+    let mut scan_options = scan::ScanOptions::with_config(&config, sandboxed.unwrap_or(false));
+    if max_bytes.is_some() {
+        scan_options.max_total_bytes = max_bytes;
+    }
+    if let Some(include_vendored) = include_vendored {
+        scan_options.include_vendored = include_vendored;
+    }
+    Ok(scan_options)
+}
+
+fn language_support_to_lua(lua: &Lua, support: &LanguageSupport) -> LuaResult<LuaTable> {
+    let table = lua.create_table()?;
+    table.set("language", support.language)?;
+    table.set("classes", support.classes)?;
+    table.set("functions", support.functions)?;
+    table.set("methods", support.methods)?;
+    table.set("enums", support.enums)?;
+    table.set("unions", support.unions)?;
+    table.set("variables", support.variables)?;
+    table.set("modules", support.modules)?;
+    table.set("imports", support.imports)?;
+    table.set("docstrings", support.docstrings)?;
+    table.set("targets", support.targets)?;
+    Ok(table)
+}
+
 #[mlua::lua_module]
 fn neopilot_repo_map(lua: &Lua) -> LuaResult<LuaTable> {
+    lua_exports(lua, Arc::new(State::default()))
+}
+
+/// The functions of the `neopilot_repo_map` module, working on `state`
+pub fn lua_exports(lua: &Lua, state: Arc<State>) -> LuaResult<LuaTable> {
     let exports = lua.create_table()?;
     exports.set(
         "stringify_definitions",
+        lua.create_function(
+            move |lua, (language, source, options): (String, String, Option<LuaTable>)| {
+                let (max_output_bytes, transform) = match options {
+                    Some(options) => (
+                        options.get::<Option<usize>>("max_output_bytes")?,
+                        options.get::<Option<LuaFunction>>("transform")?,
+                    ),
+                    None => (None, None),
+                };
+                let Some(transform) = transform else {
+                    return get_definitions_string(&language, &source, max_output_bytes);
+                };
+                let definitions = extract_definitions(&language, &source)?;
+                let definitions = transform_definitions(lua, &definitions, &language, &transform)?;
+                Ok(stringify_definitions_capped(&definitions, max_output_bytes))
+            },
+        )?,
+    )?;
+    exports.set(
+        "sexp",
+        lua.create_function(
+            move |_, (language, source, options): (String, String, Option<LuaTable>)| {
+                let options = match options {
+                    Some(options) => sexp::SexpOptions {
+                        max_depth: options.get("max_depth")?,
+                        max_len: options.get("max_len")?,
+                    },
+                    None => sexp::SexpOptions::default(),
+                };
+                Ok(sexp::sexp(&language, &source, options)?)
+            },
+        )?,
+    )?;
+    exports.set(
+        "export_list",
         lua.create_function(move |_, (language, source): (String, String)| {
-            get_definitions_string(language.as_str(), source.as_str())
+            Ok(export_lists::export_list(&language, &source)?.into_iter().collect::<Vec<_>>())
+        })?,
+    )?;
+    exports.set(
+        "local_references",
+        lua.create_function(
+            move |lua, (source, language, position): (String, String, LuaTable)| {
+                let line: usize = position.get("line")?;
+                let col: usize = position.get("col")?;
+                let table = lua.create_table()?;
+                for reference in references::local_references(&source, &language, line, col)? {
+                    table.push(reference_to_lua(lua, &reference)?)?;
+                }
+                Ok(table)
+            },
+        )?,
+    )?;
+    exports.set(
+        "supported_languages",
+        lua.create_function(move |lua, ()| {
+            let table = lua.create_table()?;
+            for support in supported_languages()? {
+                table.push(language_support_to_lua(lua, &support)?)?;
+            }
+            Ok(table)
+        })?,
+    )?;
+    let logging_state = Arc::clone(&state);
+    exports.set(
+        "health",
+        lua.create_function(move |lua, ()| {
+            let health = health::health();
+            let disabled = lua.create_table()?;
+            for diagnostic in health.disabled_languages.iter() {
+                let entry = lua.create_table()?;
+                entry.set("language", diagnostic.language.as_str())?;
+                entry.set("message", diagnostic.message.as_str())?;
+                disabled.push(entry)?;
+            }
+            let table = lua.create_table()?;
+            table.set("ok", health.is_ok())?;
+            table.set("disabled_languages", disabled)?;
+            Ok(table)
+        })?,
+    )?;
+    exports.set(
+        "profile",
+        lua.create_function(move |lua, ()| profile_to_lua(lua, &profile::report()))?,
+    )?;
+    exports.set(
+        "reset_profile",
+        lua.create_function(move |_, ()| {
+            profile::reset();
+            Ok(())
+        })?,
+    )?;
+    exports.set(
+        "disable_language",
+        lua.create_function(move |_, language: String| {
+            health::disable(&language, "Disabled by the user".to_string());
+            Ok(())
+        })?,
+    )?;
+    exports.set(
+        "init_logging",
+        lua.create_function(move |_, ()| {
+            let config = load_config(&logging_state)?;
+            Ok(logging::init(&config.logging)?)
+        })?,
+    )?;
+    exports.set(
+        "recent_logs",
+        lua.create_function(move |lua, limit: Option<usize>| {
+            let table = lua.create_table()?;
+            for entry in logging::recent_entries(limit) {
+                let record = lua.create_table()?;
+                record.set("timestamp", entry.timestamp)?;
+                record.set("level", entry.level)?;
+                record.set("target", entry.target)?;
+                record.set("message", entry.message)?;
+                record.set("trace_id", entry.trace_id)?;
+                table.push(record)?;
+            }
+            Ok(table)
+        })?,
+    )?;
+    let config_state = Arc::clone(&state);
+    exports.set(
+        "set_config",
+        lua.create_function(move |_, config: Option<LuaValue>| {
+            let config = match config {
+                Some(value) => {
+                    Some(Config::from_value(lua_to_toml(value)?).map_err(Error::from)?)
+                },
+                None => None,
+            };
+            config_state.set_config(config)?;
+            events::emit(Event::ConfigReloaded);
+            Ok(())
+        })?,
+    )?;
+    let scan_state = Arc::clone(&state);
+    exports.set(
+        "scan_directory",
+        lua.create_function(move |lua, (root, options): (String, Option<LuaTable>)| {
+            let options = scan_options_from_lua(load_config(&scan_state)?, options)?;
+            let files =
+                scan::scan_directory_with(Path::new(&root), &options, &scan::SCAN_PROGRESS)?;
+            scanned_files_to_lua(lua, &files)
+        })?,
+    )?;
+    let start_state = Arc::clone(&state);
+    exports.set(
+        "start_scan",
+        lua.create_function(move |_, (root, options): (String, Option<LuaTable>)| {
+            let config = load_config(&start_state)?;
+            let scheduler = start_state.scheduler(&config)?;
+            let options = scan_options_from_lua(config, options)?;
+            Ok(scan::start_background_scan(root.into(), options, &scheduler)?)
+        })?,
+    )?;
+    exports.set(
+        "take_scan_result",
+        lua.create_function(move |lua, ()| match scan::take_background_result() {
+            Some(Ok(files)) => Ok(Some(scanned_files_to_lua(lua, &files)?)),
+            Some(Err(e)) => Err(e.into()),
+            None => Ok(None),
+        })?,
+    )?;
+    exports.set(
+        "scan_progress",
+        lua.create_function(move |lua, ()| {
+            progress_to_lua(lua, &scan::SCAN_PROGRESS.snapshot())
+        })?,
+    )?;
+    let build_state = Arc::clone(&state);
+    exports.set(
+        "build_index",
+        lua.create_function(move |_, (root, options): (String, Option<LuaTable>)| {
+            let config = load_config(&build_state)?;
+            let options = scan_options_from_lua(config.clone(), options)?;
+            let index =
+                index::RepoIndex::build_with(Path::new(&root), &options, &scan::SCAN_PROGRESS)?;
+            let num_files = index.files.len();
+            set_index(&build_state, index, config, options)?;
+            Ok(num_files)
+        })?,
+    )?;
+    let save_state = Arc::clone(&state);
+    exports.set(
+        "save_index",
+        lua.create_function(move |_, path: String| match lock_index(&save_state)?.as_ref() {
+            Some(index) => Ok(index.save(Path::new(&path))?),
+            None => Err(index_not_built().into()),
         })?,
     )?;
+    let load_state = Arc::clone(&state);
+    exports.set(
+        "load_index",
+        lua.create_function(move |_, path: String| {
+            let config = load_config(&load_state)?;
+            let options = scan_options_from_lua(config.clone(), None)?;
+            let index = index::RepoIndex::load(Path::new(&path))?;
+            let num_files = index.files.len();
+            set_index(&load_state, index, config, options)?;
+            Ok(num_files)
+        })?,
+    )?;
+    let export_state = Arc::clone(&state);
+    exports.set(
+        "export_index",
+        lua.create_function(move |_, path: String| match lock_index(&export_state)?.as_ref() {
+            Some(index) => Ok(index_text::export(index, Path::new(&path))?),
+            None => Err(index_not_built().into()),
+        })?,
+    )?;
+    let import_state = Arc::clone(&state);
+    exports.set(
+        "import_index",
+        lua.create_function(move |_, (path, root): (String, String)| {
+            let config = load_config(&import_state)?;
+            let options = scan_options_from_lua(config.clone(), None)?;
+            let index = index_text::import(Path::new(&root), Path::new(&path), &options)?;
+            let num_files = index.files.len();
+            set_index(&import_state, index, config, options)?;
+            Ok(num_files)
+        })?,
+    )?;
+    let update_state = Arc::clone(&state);
+    exports.set(
+        "update_file",
+        lua.create_function(move |_, path: String| {
+            let mut index = lock_index(&update_state)?;
+            let index = index.as_mut().ok_or_else(index_not_built)?;
+            refresh_file(&update_state, index, Path::new(&path))
+        })?,
+    )?;
+    let overlay_state = Arc::clone(&state);
+    exports.set(
+        "set_buffer_overlay",
+        lua.create_function(move |_, (path, contents): (String, Option<String>)| {
+            let path = Path::new(&path);
+            match contents {
+                Some(contents) => overlay::set_buffer_overlay(path, contents),
+                None => {
+                    overlay::remove_buffer_overlay(path);
+                },
+            }
+            // Keep a built index in sync, as `update_file` would
+            match lock_index(&overlay_state)?.as_mut() {
+                Some(index) if overlay::is_below(path, &index.root) => {
+                    refresh_file(&overlay_state, index, path)
+                },
+                _ => Ok(false),
+            }
+        })?,
+    )?;
+    let map_state = Arc::clone(&state);
+    exports.set(
+        "get_repo_map",
+        lua.create_function(move |lua, (focus_files, order): MapArgs| {
+            let order = map_order_from_lua(order)?;
+            let config = map_config(&map_state)?;
+            let focus_files = focus_files.unwrap_or_default();
+            let overrides = focus_overrides(&map_state, &focus_files, &config.repo_map)?;
+            match lock_index(&map_state)?.as_ref() {
+                Some(index) => {
+                    index_to_lua(lua, index, &focus_files, &config.repo_map, order, &overrides)
+                }
+                None => Err(index_not_built().into()),
+            }
+        })?,
+    )?;
+    let encoded_state = Arc::clone(&state);
+    exports.set(
+        "get_repo_map_encoded",
+        lua.create_function(move |lua, (format, focus_files, order): EncodedMapArgs| {
+            let format: export::OutputFormat = format.parse()?;
+            let order = map_order_from_lua(order)?;
+            let config = map_config(&encoded_state)?;
+            let index = lock_index(&encoded_state)?;
+            let index = index.as_ref().ok_or_else(index_not_built)?;
+            let entries = export::repo_map_with(
+                index,
+                &focus_files.unwrap_or_default(),
+                config.repo_map.include_metrics,
+                order,
+                &config.repo_map.ranking,
+            );
+            lua.create_string(export::to_bytes(&entries, format)?)
+        })?,
+    )?;
+    let diff_state = Arc::clone(&state);
+    exports.set(
+        "diff_index",
+        lua.create_function(move |lua, path: String| {
+            let previous = index::RepoIndex::load(Path::new(&path))?;
+            let config = map_config(&diff_state)?;
+            let index = lock_index(&diff_state)?;
+            let index = index.as_ref().ok_or_else(index_not_built)?;
+            let diff = diff::diff_maps_with(&previous, index, &config.repo_map.ranking);
+            map_diff_to_lua(lua, &diff)
+        })?,
+    )?;
+    let costs_state = Arc::clone(&state);
+    exports.set(
+        "update_token_costs",
+        lua.create_function(
+            move |_, (fingerprint, count): (Option<String>, Option<LuaFunction>)| {
+                let mut index = lock_index(&costs_state)?;
+                let index = index.as_mut().ok_or_else(index_not_built)?;
+                let counted = match (fingerprint, count) {
+                    (Some(fingerprint), Some(count)) => {
+                        index.update_token_costs(&LuaTokenCounter { fingerprint, count })?
+                    }
+                    _ => index.update_token_costs(&index::EstimatedTokens)?,
+                };
+                Ok(counted)
+            },
+        )?,
+    )?;
+    let render_state = Arc::clone(&state);
+    exports.set(
+        "render_repo_map",
+        lua.create_function(move |lua, args: RenderArgs| {
+            let (budget_tokens, focus_files, summarize, order) = args;
+            let order = map_order_from_lua(order)?;
+            let summarizer = summarize.map(LuaSummarizer);
+            let focus_files = focus_files.unwrap_or_default();
+            let config = map_config(&render_state)?;
+            let overrides = focus_overrides(&render_state, &focus_files, &config.repo_map)?;
+            let render = |index: &index::RepoIndex| -> LuaResult<LuaTable> {
+                let map = render::render_map_with_overrides(
+                    index,
+                    &focus_files,
+                    budget_tokens,
+                    summarizer.as_ref().map(|s| s as &dyn render::Summarizer),
+                    order,
+                    &config.repo_map.ranking,
+                    &overrides,
+                )?;
+                rendered_map_to_lua(lua, &map)
+            };
+            // The summarizer runs Lua that may call back into this module, so
+            // it works on a copy of the index rather than under the lock
+            if summarizer.is_some() {
+                let snapshot = lock_index(&render_state)?.clone();
+                render(snapshot.as_ref().ok_or_else(index_not_built)?)
+            } else {
+                let index = lock_index(&render_state)?;
+                render(index.as_ref().ok_or_else(index_not_built)?)
+            }
+        })?,
+    )?;
+    let context_state = Arc::clone(&state);
+    exports.set(
+        "context_for_position",
+        lua.create_function(
+            move |lua, (path, line, col, budget_tokens): (String, usize, usize, usize)| {
+                let index = lock_index(&context_state)?;
+                let context = context::context_for_position(
+                    index.as_ref(),
+                    Path::new(&path),
+                    line,
+                    col,
+                    budget_tokens,
+                )?;
+                position_context_to_lua(lua, &context)
+            },
+        )?,
+    )?;
+    let lookup_state = Arc::clone(&state);
+    exports.set(
+        "lookup",
+        lua.create_function(move |lua, qualified_name: String| {
+            let found = {
+                let index = lock_index(&lookup_state)?;
+                let index = index.as_ref().ok_or_else(index_not_built)?;
+                lookup::find(index, &qualified_name)
+            };
+            // Read the file without holding the index
+            found
+                .and_then(lookup::SymbolMatch::resolve)
+                .map(|location| symbol_location_to_lua(lua, &location))
+                .transpose()
+        })?,
+    )?;
+    events::lua::install(lua, &exports)?;
+    exports.set("traced", neopilot_common::trace::lua::traced_function(lua, &exports)?)?;
     Ok(exports)
 }
 
@@ -704,6 +2079,137 @@ mod tests {
         assert!(!stringified.is_empty());
     }
 
+    #[test]
+    fn test_starlark() {
+        let source = r#"
+load("@rules_cc//cc:defs.bzl", "cc_library")
+
+package(default_visibility = ["//visibility:public"])
+
+cc_library(
+    name = "core",
+    srcs = ["core.cc"],
+)
+
+MyInfo = provider(fields = ["files"])
+_helper_rule = rule(implementation = _impl)
+my_rule = rule(implementation = _impl)
+
+def my_macro(name, srcs = []):
+    native.genrule(name = name + "_gen")
+
+def _impl(ctx):
+    pass
+"#;
+        let definitions = extract_definitions("starlark", source).unwrap();
+        assert_eq!(
+            stringify_definitions(&definitions),
+            "provider MyInfo{};cc_library core{};rule my_rule{};func my_macro(name, srcs = []);"
+        );
+    }
+
+    #[test]
+    fn test_export_lists() {
+        let source = r#"
+__all__ = ["public", "Visible"]
+
+def public(a, b) -> int:
+    pass
+
+def _private():
+    pass
+
+class Visible:
+    pass
+
+class Hidden:
+    pass
+"#;
+        let definitions = extract_definitions("python", source).unwrap();
+        assert_eq!(
+            stringify_definitions(&definitions),
+            "class Visible{};export func public(a, b) -> int;"
+        );
+
+        let all = extract_definitions_with("python", source, Visibility::All).unwrap();
+        let all = stringify_definitions(&all);
+        for expected in ["class Hidden{}", "func _private()", "export func public(a, b) -> int"] {
+            assert!(all.contains(expected), "{all}");
+        }
+
+        let source = "function helper() {}\nfunction run(task) {}\nmodule.exports = { run };\n";
+        let definitions = extract_definitions("javascript", source).unwrap();
+        assert_eq!(stringify_definitions(&definitions), "export func run(task);");
+
+        let source = "defmodule MyApp do\n  defdelegate fetch(id), to: MyApp.Repo\nend\n";
+        let definitions = extract_definitions("elixir", source).unwrap();
+        assert_eq!(stringify_definitions(&definitions), "module MyApp{export func fetch(id);};");
+    }
+
+    #[test]
+    fn test_supported_languages() {
+        let languages = supported_languages().unwrap();
+        assert_eq!(languages.len(), SUPPORTED_LANGUAGES.len());
+        for support in &languages {
+            assert!(get_ts_language(support.language).is_some());
+        }
+        let rust = languages.iter().find(|s| s.language == "rust").unwrap();
+        assert!(rust.classes && rust.functions);
+        // Captured by the query but not extracted
+        assert!(!rust.enums && !rust.variables && !rust.methods);
+        assert!(!rust.imports && !rust.docstrings);
+        let elixir = languages.iter().find(|s| s.language == "elixir").unwrap();
+        assert!(elixir.modules && elixir.methods && !elixir.classes);
+        let lua = languages.iter().find(|s| s.language == "lua").unwrap();
+        assert!(lua.functions && !lua.classes);
+        let starlark = languages.iter().find(|s| s.language == "starlark").unwrap();
+        assert!(starlark.targets && starlark.functions && !rust.targets);
+    }
+
+    #[test]
+    fn test_parallel_query() {
+        let source: String = (0..PARALLEL_QUERY_MIN_NODES * 3)
+            .map(|i| format!("pub struct Generated{i} {{ pub field: u32 }}\n"))
+            .collect();
+        let tree = parse_source("rust", &source).unwrap();
+        let query = get_definitions_query("rust").unwrap();
+        let root = tree.root_node();
+        assert!(root.child_count() >= PARALLEL_QUERY_MIN_NODES);
+
+        let ids = |captures: Vec<(u32, Node)>| -> Vec<(u32, usize)> {
+            captures.into_iter().map(|(index, node)| (index, node.id())).collect()
+        };
+        let sequential = captures_in_range(&query, root, source.as_bytes(), 0..usize::MAX);
+        let parallel = query_captures(&query, root, source.as_bytes());
+        assert_eq!(ids(parallel), ids(sequential));
+
+        let definitions = extract_definitions("rust", &source).unwrap();
+        assert_eq!(definitions.len(), PARALLEL_QUERY_MIN_NODES * 3);
+    }
+
+    #[test]
+    fn test_max_output_bytes() {
+        let source = "
+            pub struct FirstStruct { pub argument: u32, pub other_argument: u32 }
+            pub struct SecondStruct { pub argument: u32, pub other_argument: u32 }
+            pub struct ThirdStruct { pub argument: u32, pub other_argument: u32 }
+        ";
+        let definitions = extract_definitions("rust", source).unwrap();
+        assert_eq!(definitions.len(), 3);
+        let full = stringify_definitions(&definitions);
+        assert_eq!(stringify_definitions_capped(&definitions, None), full);
+        assert_eq!(stringify_definitions_capped(&definitions, Some(full.len())), full);
+
+        let first = stringify_definition(&definitions[0]);
+        let capped = stringify_definitions_capped(&definitions, Some(full.len() - 1));
+        assert!(capped.len() < full.len());
+        assert!(capped.starts_with(&first));
+        assert!(capped.ends_with("more symbols omitted\n"));
+
+        let capped = stringify_definitions_capped(&definitions, Some(0));
+        assert_eq!(capped, "\u{2026} 3 more symbols omitted\n");
+    }
+
     #[test]
     fn test_unsupported_language() {
         let source = "print(\"Hello, world!\")";
@@ -714,4 +2220,53 @@ mod tests {
         let expected = "";
         assert_eq!(stringified, expected);
     }
+
+    #[test]
+    fn test_injected_config() {
+        let state = State::default();
+        let mut config = Config::for_tests();
+        config.repo_map.include_vendored = true;
+        *state.config.lock().unwrap() = Some(config);
+        let config = load_config(&state).unwrap();
+        assert!(config.repo_map.include_vendored);
+        assert!(!config.network.enabled);
+        let options = scan_options_from_lua(config, None).unwrap();
+        assert!(options.include_vendored);
+    }
+
+    #[test]
+    fn test_map_config_reuses_index_config() {
+        let state = State::default();
+        let mut config = Config::for_tests();
+        config.repo_map.include_metrics = !config.repo_map.include_metrics;
+        let include_metrics = config.repo_map.include_metrics;
+        let index = index::RepoIndex::default();
+        set_index(&state, index, config, scan::ScanOptions::default()).unwrap();
+        assert_eq!(map_config(&state).unwrap().repo_map.include_metrics, include_metrics);
+
+        // Injected configuration still wins
+        *state.config.lock().unwrap() = Some(Config::for_tests());
+        assert_ne!(map_config(&state).unwrap().repo_map.include_metrics, include_metrics);
+    }
+
+    #[test]
+    fn test_refresh_file_uses_index_options() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("vendor"))?;
+        std::fs::write(dir.path().join("vendor/lib.rs"), "pub struct Vendored {}\n")?;
+        let state = State::default();
+        *state.config.lock().unwrap() = Some(Config::for_tests());
+
+        let options = scan::ScanOptions {
+            include_vendored: true,
+            ..Default::default()
+        };
+        let index = index::RepoIndex::from_scan(dir.path(), vec![]);
+        set_index(&state, index, Config::for_tests(), options).unwrap();
+        let mut guard = lock_index(&state).unwrap();
+        let index = guard.as_mut().unwrap();
+        assert!(refresh_file(&state, index, Path::new("vendor/lib.rs")).unwrap());
+        assert!(!refresh_file(&state, index, Path::new("../vendor/lib.rs")).unwrap());
+        Ok(())
+    }
 }
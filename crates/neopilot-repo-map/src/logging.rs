@@ -0,0 +1,324 @@
+//! Logger backing the `log` macros used throughout the crate
+//!
+//! Records are filtered by a default level plus per-target overrides from
+//! [`LoggingConfig::targets`], so e.g. network activity can be debugged
+//! without enabling debug output for the parser. The most recent records are
+//! also kept in memory so they can be shown even when file logging is off.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+
+use log::{LevelFilter, Log, Metadata, Record};
+use neopilot_common::trace;
+use neopilot_error::{Error, ErrorCode, Result, ResultExt};
+
+use crate::config::LoggingConfig;
+
+/// Default level plus per-target overrides
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelFilters {
+    default: LevelFilter,
+    /// Target overrides, longest (most specific) first
+    targets: Vec<(String, LevelFilter)>,
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter> {
+    LevelFilter::from_str(level)
+        .map_err(|_| Error::new(ErrorCode::Config, format!("Invalid log level '{level}'")))
+}
+
+/// Whether a filter `key` applies to a record `target`
+///
+/// A key matches the target itself, any module below it (`key::...`) and any
+/// path segment named after it, so `network` matches both
+/// `log::debug!(target: "network", ...)` and `neopilot_tokenizers::network`.
+fn target_matches(key: &str, target: &str) -> bool {
+    target == key
+        || target
+            .strip_prefix(key)
+            .map_or(false, |rest| rest.starts_with("::"))
+        || target.split("::").any(|segment| segment == key)
+}
+
+impl LevelFilters {
+    pub const fn new(default: LevelFilter) -> Self {
+        Self {
+            default,
+            targets: Vec::new(),
+        }
+    }
+
+    pub fn from_config(config: &LoggingConfig) -> Result<Self> {
+        let mut filters = Self::new(parse_level(&config.level)?);
+        for (target, level) in &config.targets {
+            filters.targets.push((target.clone(), parse_level(level)?));
+        }
+        filters
+            .targets
+            .sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        Ok(filters)
+    }
+
+    /// Level that applies to records logged with `target`
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .find(|(key, _)| target_matches(key, target))
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+
+    /// Most verbose level of any filter, used as the global `log` max level
+    pub fn max_level(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, std::cmp::max)
+    }
+}
+
+/// A log record kept in the in-memory ring buffer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    pub timestamp: String,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+    /// Trace ID that was current when the record was logged
+    pub trace_id: Option<String>,
+}
+
+/// Fixed-capacity buffer of the most recent log entries
+#[derive(Debug)]
+struct RingBuffer {
+    entries: VecDeque<LogEntry>,
+    capacity: usize,
+}
+
+impl RingBuffer {
+    const fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    fn push(&mut self, entry: LogEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    /// Up to `limit` most recent entries, oldest first
+    fn recent(&self, limit: Option<usize>) -> Vec<LogEntry> {
+        let limit = limit.unwrap_or(self.entries.len()).min(self.entries.len());
+        self.entries
+            .iter()
+            .skip(self.entries.len() - limit)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Logger writing filtered records to the configured log file and ring buffer
+pub struct Logger {
+    filters: RwLock<LevelFilters>,
+    file: Mutex<Option<File>>,
+    recent: Mutex<RingBuffer>,
+}
+
+impl Logger {
+    const fn new() -> Self {
+        Self {
+            filters: RwLock::new(LevelFilters::new(LevelFilter::Info)),
+            file: Mutex::new(None),
+            recent: Mutex::new(RingBuffer::new(0)),
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filters
+            .read()
+            .map_or(false, |filters| metadata.level() <= filters.level_for(metadata.target()))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let entry = LogEntry {
+            timestamp: chrono::Local::now()
+                .format("%Y-%m-%d %H:%M:%S%.3f")
+                .to_string(),
+            level: record.level().to_string(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+            trace_id: trace::current(),
+        };
+        if let Ok(mut file) = self.file.lock() {
+            if let Some(file) = file.as_mut() {
+                let trace_id = entry.trace_id.as_deref().map(|id| format!(" [{id}]"));
+                let _ = writeln!(
+                    file,
+                    "{} {:<5}{} {}: {}",
+                    entry.timestamp,
+                    entry.level,
+                    trace_id.unwrap_or_default(),
+                    entry.target,
+                    entry.message
+                );
+            }
+        }
+        if let Ok(mut recent) = self.recent.lock() {
+            recent.push(entry);
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut file) = self.file.lock() {
+            if let Some(file) = file.as_mut() {
+                let _ = file.flush();
+            }
+        }
+    }
+}
+
+static LOGGER: Logger = Logger::new();
+
+/// Install the logger (once) and apply `config`
+///
+/// Calling this again, e.g. after the configuration was reloaded, only
+/// updates the filters and log file.
+pub fn init(config: &LoggingConfig) -> Result<()> {
+    let filters = LevelFilters::from_config(config)?;
+    let file = match &config.file {
+        Some(path) => Some(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open log file {}", path.display()))?,
+        ),
+        None => None,
+    };
+
+    log::set_max_level(filters.max_level());
+    *LOGGER.filters.write().context("Failed to update log filters")? = filters;
+    *LOGGER.file.lock().context("Failed to update log file")? = file;
+    LOGGER
+        .recent
+        .lock()
+        .context("Failed to update log buffer")?
+        .set_capacity(config.ring_buffer_size);
+
+    // Only the first call installs the logger; later calls just reconfigure it
+    let _ = log::set_logger(&LOGGER);
+    Ok(())
+}
+
+/// Up to `limit` most recent log entries (all buffered ones if `None`), oldest first
+pub fn recent_entries(limit: Option<usize>) -> Vec<LogEntry> {
+    LOGGER
+        .recent
+        .lock()
+        .map(|recent| recent.recent(limit))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn config(level: &str, targets: &[(&str, &str)]) -> LoggingConfig {
+        LoggingConfig {
+            level: level.to_string(),
+            targets: targets
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<BTreeMap<_, _>>(),
+            ..LoggingConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_target_matches() {
+        assert!(target_matches("network", "network"));
+        assert!(target_matches("network", "neopilot_tokenizers::network"));
+        assert!(target_matches("neopilot_repo_map", "neopilot_repo_map::scan"));
+        assert!(!target_matches("network", "networking"));
+        assert!(!target_matches("scan", "neopilot_repo_map::scanner"));
+    }
+
+    #[test]
+    fn test_per_target_levels() {
+        let filters =
+            LevelFilters::from_config(&config("info", &[("network", "debug"), ("parser", "warn")]))
+                .unwrap();
+        assert_eq!(filters.level_for("neopilot::network"), LevelFilter::Debug);
+        assert_eq!(filters.level_for("parser"), LevelFilter::Warn);
+        assert_eq!(filters.level_for("neopilot_repo_map::scan"), LevelFilter::Info);
+        assert_eq!(filters.max_level(), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn test_most_specific_target_wins() {
+        let filters = LevelFilters::from_config(&config(
+            "warn",
+            &[("neopilot_repo_map", "info"), ("neopilot_repo_map::scan", "trace")],
+        ))
+        .unwrap();
+        assert_eq!(filters.level_for("neopilot_repo_map::scan"), LevelFilter::Trace);
+        assert_eq!(filters.level_for("neopilot_repo_map::index"), LevelFilter::Info);
+    }
+
+    fn entry(message: &str) -> LogEntry {
+        LogEntry {
+            timestamp: String::new(),
+            level: "INFO".to_string(),
+            target: "test".to_string(),
+            message: message.to_string(),
+            trace_id: None,
+        }
+    }
+
+    #[test]
+    fn test_ring_buffer_keeps_latest() {
+        let mut buffer = RingBuffer::new(2);
+        buffer.push(entry("a"));
+        buffer.push(entry("b"));
+        buffer.push(entry("c"));
+        let messages: Vec<String> = buffer.recent(None).into_iter().map(|e| e.message).collect();
+        assert_eq!(messages, vec!["b", "c"]);
+        assert_eq!(buffer.recent(Some(1))[0].message, "c");
+        assert_eq!(buffer.recent(Some(10)).len(), 2);
+
+        buffer.set_capacity(1);
+        assert_eq!(buffer.recent(None)[0].message, "c");
+
+        buffer.set_capacity(0);
+        buffer.push(entry("d"));
+        assert!(buffer.recent(None).is_empty());
+    }
+
+    #[test]
+    fn test_invalid_level() {
+        assert!(LevelFilters::from_config(&config("info", &[("network", "loud")])).is_err());
+    }
+}
@@ -0,0 +1,320 @@
+//! Single-symbol lookup in the index
+//!
+//! Completing and resolving an `@mention` of a symbol needs its definition,
+//! the file defining it and where in that file it is. The index already knows
+//! which file defines every symbol, so only that file is read and parsed to
+//! find the span, instead of scanning anything. [`find`] picks the file from
+//! the index and [`SymbolMatch::resolve`] reads it, so callers can release
+//! the index before touching the disk.
+//!
+//! Names are qualified with `::` or `.`, e.g. `Engine::new` or
+//! `Engine.new` for the method `new` of the class `Engine`; unqualified names
+//! match top-level definitions only. The index keeps few methods, so methods
+//! of an indexed class are otherwise found by parsing its file.
+
+use std::path::PathBuf;
+
+use tree_sitter::{Node, Tree};
+
+use crate::context::is_enclosing_kind;
+use crate::index::RepoIndex;
+use crate::{get_node_text, parse_source, stringify_definition, Definition, Func};
+
+/// Lines of the definition kept in [`SymbolSource::snippet`]
+pub const SNIPPET_LINES: usize = 20;
+
+/// Where a definition is in its file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolSource {
+    /// First line of the definition (0-based)
+    pub start_line: usize,
+    /// Last line of the definition (0-based, inclusive)
+    pub end_line: usize,
+    /// The first [`SNIPPET_LINES`] lines of the definition
+    pub snippet: String,
+}
+
+/// A symbol found by [`lookup`]
+#[derive(Debug, Clone)]
+pub struct SymbolLocation {
+    /// Path of the defining file, relative to the index root
+    pub path: String,
+    pub language: String,
+    pub definition: Definition,
+    /// Stringified definition as it appears in the repo map
+    pub text: String,
+    /// `None` when the file could not be read or no longer has the definition
+    pub source: Option<SymbolSource>,
+}
+
+/// Container and member of `qualified_name`, the container empty if unqualified
+fn split_qualified(qualified_name: &str) -> (&str, &str) {
+    let separator = qualified_name
+        .rfind("::")
+        .map(|i| (i, 2))
+        .or_else(|| qualified_name.rfind('.').map(|i| (i, 1)));
+    match separator {
+        Some((i, len)) => {
+            let container = &qualified_name[..i];
+            // Only the innermost container is matched, e.g. `Engine` of `core::Engine::new`
+            let container = container.rsplit(['.', ':']).next().unwrap_or(container);
+            (container, &qualified_name[i + len..])
+        },
+        None => ("", qualified_name),
+    }
+}
+
+/// The method `member` of `definition` if that is a class or module listing
+/// it, `Some(None)` if it does not list it and `None` for other definitions
+fn find_method(definition: &Definition, member: &str) -> Option<Option<Definition>> {
+    match definition {
+        Definition::Class(class) | Definition::Module(class) => Some(
+            class
+                .methods
+                .iter()
+                .find(|method| method.name == member)
+                .map(|method| Definition::Func(method.clone())),
+        ),
+        _ => None,
+    }
+}
+
+/// Name of a definition node: its `name` field, the innermost `declarator`
+/// (C and C++ functions) or the `type` (Rust `impl` blocks)
+fn node_name<'a>(node: Node, source: &'a str) -> Option<&'a str> {
+    let named = node.child_by_field_name("name").or_else(|| {
+        let mut declarator = node.child_by_field_name("declarator")?;
+        while let Some(inner) = declarator
+            .child_by_field_name("declarator")
+            .or_else(|| declarator.child_by_field_name("name"))
+        {
+            declarator = inner;
+        }
+        Some(declarator)
+    });
+    named
+        .or_else(|| node.child_by_field_name("type"))?
+        .utf8_text(source.as_bytes())
+        .ok()
+}
+
+/// Functions, classes and the like enclosing `node`, innermost first
+fn enclosing_ancestors<'t>(node: Node<'t>) -> impl Iterator<Item = Node<'t>> {
+    std::iter::successors(node.parent(), Node::parent)
+        // The root is a Python `module`, not a definition
+        .filter(|node| node.parent().is_some() && is_enclosing_kind(node.kind()))
+}
+
+/// Definition node of `member` in `tree`
+///
+/// Prefers a definition inside `container`, or a top-level one if that is
+/// empty; otherwise takes the first with the name, as Go methods are declared
+/// outside their type. The flag tells whether the preferred one was found.
+fn find_node<'t>(
+    tree: &'t Tree,
+    source: &str,
+    container: &str,
+    member: &str,
+) -> Option<(Node<'t>, bool)> {
+    let mut candidates = Vec::new();
+    let mut stack = vec![tree.root_node()];
+    while let Some(node) = stack.pop() {
+        if is_enclosing_kind(node.kind()) && node_name(node, source) == Some(member) {
+            candidates.push(node);
+        }
+        let mut cursor = node.walk();
+        stack.extend(node.named_children(&mut cursor));
+    }
+    candidates.sort_by_key(Node::start_byte);
+    let preferred = candidates.iter().find(|node| {
+        let mut ancestors = enclosing_ancestors(**node);
+        if container.is_empty() {
+            ancestors.next().is_none()
+        } else {
+            ancestors.any(|ancestor| node_name(ancestor, source) == Some(container))
+        }
+    });
+    match preferred {
+        Some(node) => Some((*node, true)),
+        None => Some((*candidates.first()?, false)),
+    }
+}
+
+fn symbol_source(node: Node, source: &str) -> SymbolSource {
+    let text = &source[node.start_byte()..node.end_byte()];
+    let snippet: String = text.split_inclusive('\n').take(SNIPPET_LINES).collect();
+    SymbolSource {
+        start_line: node.start_position().row,
+        end_line: node.end_position().row,
+        snippet,
+    }
+}
+
+/// A method the index does not list, from its definition node
+fn method_from_node(node: Node, source: &str, name: &str) -> Func {
+    let field = |field: &str| {
+        node.child_by_field_name(field)
+            .map(|child| get_node_text(&child, source.as_bytes()))
+            .unwrap_or_default()
+    };
+    Func {
+        name: name.to_string(),
+        params: field("parameters"),
+        return_type: field("return_type").trim_start_matches(':').trim().to_string(),
+        accessibility_modifier: None,
+    }
+}
+
+/// Span of the definition of `member` in `source`, see [`find_node`]
+#[cfg(test)]
+fn find_span(language: &str, source: &str, container: &str, member: &str) -> Option<SymbolSource> {
+    let tree = parse_source(language, source).ok()?;
+    let (node, _) = find_node(&tree, source, container, member)?;
+    Some(symbol_source(node, source))
+}
+
+/// A symbol picked from the index by [`find`], not yet read from its file
+#[derive(Debug, Clone)]
+pub struct SymbolMatch {
+    path: String,
+    absolute_path: PathBuf,
+    language: String,
+    container: String,
+    member: String,
+    /// `None` for a method its class does not list
+    definition: Option<Definition>,
+}
+
+/// Pick the file defining `qualified_name` from the index
+///
+/// When several files define the symbol, the highest ranked one wins.
+/// Returns `None` when nothing in the index defines it, or no class or
+/// module of that name for a qualified name.
+pub fn find(index: &RepoIndex, qualified_name: &str) -> Option<SymbolMatch> {
+    let (container, member) = split_qualified(qualified_name);
+    let name = if container.is_empty() { member } else { container };
+    let mut best: Option<(&str, &str, Option<Definition>, f64)> = None;
+    for (path, file, definition) in index.definitions_named(name) {
+        let definition = if container.is_empty() {
+            Some(definition.clone())
+        } else {
+            let Some(method) = find_method(definition, member) else {
+                continue;
+            };
+            method
+        };
+        let rank = index.rankings.get(path).copied().unwrap_or(0.0);
+        if best.as_ref().map_or(true, |(_, _, _, best_rank)| rank > *best_rank) {
+            best = Some((path, file.language.as_str(), definition, rank));
+        }
+    }
+    let (path, language, definition, _) = best?;
+    Some(SymbolMatch {
+        path: path.to_string(),
+        absolute_path: index.root.join(path),
+        language: language.to_string(),
+        container: container.to_string(),
+        member: member.to_string(),
+        definition,
+    })
+}
+
+impl SymbolMatch {
+    /// Read the span of the symbol from disk, or from the buffer overlay of
+    /// its file
+    ///
+    /// Returns `None` for a method its class does not list when the class
+    /// has no such method in the file either.
+    pub fn resolve(self) -> Option<SymbolLocation> {
+        let source = crate::overlay::read_to_string(&self.absolute_path).ok();
+        let tree = source
+            .as_deref()
+            .and_then(|source| parse_source(&self.language, source).ok());
+        let found = source.as_deref().zip(tree.as_ref()).and_then(|(source, tree)| {
+            let (node, preferred) = find_node(tree, source, &self.container, &self.member)?;
+            Some((node, preferred, source))
+        });
+        let definition = match self.definition {
+            Some(definition) => definition,
+            // Only a definition inside the class makes it a method of the class
+            None => {
+                let (node, _, source) = found.filter(|(_, preferred, _)| *preferred)?;
+                Definition::Func(method_from_node(node, source, &self.member))
+            },
+        };
+        Some(SymbolLocation {
+            path: self.path,
+            language: self.language,
+            text: stringify_definition(&definition),
+            definition,
+            source: found.map(|(node, _, source)| symbol_source(node, source)),
+        })
+    }
+}
+
+/// Find the definition of `qualified_name` and read its span, see [`find`]
+/// and [`SymbolMatch::resolve`]
+pub fn lookup(index: &RepoIndex, qualified_name: &str) -> Option<SymbolLocation> {
+    find(index, qualified_name)?.resolve()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::{scan_directory, ScanProgress};
+    use neopilot_error::Result;
+    use std::fs;
+
+    #[test]
+    fn test_split_qualified() {
+        assert_eq!(split_qualified("Engine"), ("", "Engine"));
+        assert_eq!(split_qualified("Engine::new"), ("Engine", "new"));
+        assert_eq!(split_qualified("Engine.new"), ("Engine", "new"));
+        assert_eq!(split_qualified("core::Engine::new"), ("Engine", "new"));
+    }
+
+    #[test]
+    fn test_lookup() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source = concat!(
+            "__all__ = [\"start\", \"Engine\"]\n\n",
+            "def start():\n    pass\n\nclass Engine:\n    def start(self):\n        return 1\n",
+        );
+        fs::write(dir.path().join("engine.py"), source)?;
+        let files = scan_directory(dir.path(), &ScanProgress::new())?;
+        let index = RepoIndex::from_scan(dir.path(), files);
+
+        let function = lookup(&index, "start").unwrap();
+        assert_eq!(function.path, "engine.py");
+        assert!(matches!(function.definition, Definition::Func(_)));
+        assert_eq!(function.source.unwrap().start_line, 2);
+        let class = lookup(&index, "Engine").unwrap();
+        assert!(matches!(class.definition, Definition::Class(_)));
+        assert_eq!(class.source.unwrap().start_line, 5);
+
+        let method = lookup(&index, "Engine.start").unwrap();
+        assert!(matches!(method.definition, Definition::Func(_)));
+        assert_eq!(method.text, "func start(self);");
+        let source = method.source.unwrap();
+        assert_eq!((source.start_line, source.end_line), (6, 7));
+        assert!(source.snippet.starts_with("def start(self):"));
+        assert_eq!(lookup(&index, "Engine::start").unwrap().path, "engine.py");
+
+        assert!(lookup(&index, "Engine.stop").is_none());
+        assert!(lookup(&index, "Missing").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_span() {
+        let source = concat!(
+            "def start():\n    pass\n\n",
+            "class Engine:\n    def start(self):\n        return 1\n",
+        );
+        let method = find_span("python", source, "Engine", "start").unwrap();
+        assert_eq!((method.start_line, method.end_line), (4, 5));
+        assert!(method.snippet.starts_with("def start(self):"));
+        assert_eq!(find_span("python", source, "", "start").unwrap().start_line, 0);
+        assert!(find_span("python", source, "", "stop").is_none());
+    }
+}
@@ -0,0 +1,179 @@
+//! Size and complexity hints for functions
+//!
+//! Every function and method matched by the definitions query gets its line
+//! count and a rough branch count (conditionals, loops, match arms, catch
+//! clauses and short-circuit operators). These are not exact cyclomatic
+//! complexity, but they are cheap to compute during a scan and good enough to
+//! spot hotspots or to weigh files in the ranking.
+
+use std::collections::HashSet;
+
+use neopilot_error::Result;
+use serde::{Deserialize, Serialize};
+use tree_sitter::{Node, Query};
+
+use crate::{find_descendant_by_type, get_definitions_query, parse_source, query_captures};
+
+/// Node kinds that add a branch, across the supported grammars
+const BRANCH_KINDS: &[&str] = &[
+    "if_statement",
+    "if_expression",
+    "if_let_expression",
+    "elif_clause",
+    "else_if_clause",
+    "elsif",
+    "unless",
+    "for_statement",
+    "for_in_statement",
+    "for_expression",
+    "enhanced_for_statement",
+    "foreach_statement",
+    "while_statement",
+    "while_expression",
+    "loop_expression",
+    "do_statement",
+    "repeat_statement",
+    "match_arm",
+    "case_clause",
+    "switch_case",
+    "switch_section",
+    "expression_case",
+    "type_case",
+    "when",
+    "catch_clause",
+    "except_clause",
+    "rescue",
+    "conditional_expression",
+    "ternary_expression",
+    "&&",
+    "||",
+    "and",
+    "or",
+];
+
+/// Metrics of a single function or method
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FunctionMetrics {
+    pub name: String,
+    /// First line of the definition (0-based)
+    pub start_line: usize,
+    /// Number of lines spanned by the definition, including its signature
+    pub body_lines: usize,
+    /// Number of branching constructs inside the definition
+    pub branches: usize,
+}
+
+impl FunctionMetrics {
+    /// Branch count plus one, the usual starting point of cyclomatic complexity
+    pub fn complexity(&self) -> usize {
+        self.branches + 1
+    }
+}
+
+fn count_branches(node: &Node) -> usize {
+    let mut cursor = node.walk();
+    (1..node.descendant_count())
+        .filter(|&i| {
+            cursor.goto_descendant(i);
+            BRANCH_KINDS.contains(&cursor.node().kind())
+        })
+        .count()
+}
+
+fn function_name(node: &Node, source: &[u8]) -> String {
+    let name = node.child_by_field_name("name").or_else(|| {
+        ["field_identifier", "identifier", "operator_name"]
+            .iter()
+            .find_map(|kind| find_descendant_by_type(node, kind))
+    });
+    name.and_then(|n| n.utf8_text(source).ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Metrics of every function and method in `source`, in source order
+pub fn function_metrics(language: &str, source: &str) -> Result<Vec<FunctionMetrics>> {
+    let tree = parse_source(language, source)?;
+    let query = get_definitions_query(language)?;
+    let captures = query_captures(&query, tree.root_node(), source.as_bytes());
+    Ok(metrics_from_captures(&query, &captures, source.as_bytes()))
+}
+
+/// Metrics of the functions and methods among `captures` of the definitions
+/// query, in source order
+///
+/// Scans pass the captures they extracted definitions from, so each file is
+/// parsed and queried once.
+pub(crate) fn metrics_from_captures(
+    query: &Query,
+    captures: &[(u32, Node<'_>)],
+    source: &[u8],
+) -> Vec<FunctionMetrics> {
+    let mut seen = HashSet::new();
+    let mut metrics = Vec::new();
+    for &(capture_index, node) in captures {
+        let capture_name = query.capture_names()[capture_index as usize];
+        if !matches!(capture_name, "function" | "method") || !seen.insert(node.id()) {
+            continue;
+        }
+        metrics.push(FunctionMetrics {
+            name: function_name(&node, source),
+            start_line: node.start_position().row,
+            body_lines: node.end_position().row - node.start_position().row + 1,
+            branches: count_branches(&node),
+        });
+    }
+    metrics.sort_by_key(|m| m.start_line);
+    metrics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rust_metrics() -> Result<()> {
+        let source = r#"
+fn simple() -> u32 {
+    1
+}
+
+fn branchy(a: u32, b: bool) -> u32 {
+    if a > 1 && b {
+        return 1;
+    }
+    for i in 0..a {
+        match i {
+            0 => {}
+            _ => {}
+        }
+    }
+    0
+}
+"#;
+        let metrics = function_metrics("rust", source)?;
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics[0].name, "simple");
+        assert_eq!((metrics[0].start_line, metrics[0].body_lines), (1, 3));
+        assert_eq!(metrics[0].complexity(), 1);
+        assert_eq!(metrics[1].name, "branchy");
+        // if, &&, for and two match arms
+        assert_eq!(metrics[1].branches, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_python_methods() -> Result<()> {
+        let source = "class A:\n    def run(self, x):\n        return x if x else None\n";
+        let metrics = function_metrics("python", source)?;
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].name, "run");
+        assert_eq!(metrics[0].branches, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsupported_language() {
+        assert!(function_metrics("cobol", "").is_err());
+    }
+}
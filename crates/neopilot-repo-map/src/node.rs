@@ -0,0 +1,109 @@
+//! Node bindings, enabled with the `node` feature
+//!
+//! Used by the VS Code extension so both editors build identical repo maps.
+//! Entries have the same shape and order as the ones returned by the Lua
+//! `get_repo_map`.
+
+use std::path::Path;
+
+use napi_derive::napi;
+
+use crate::config::ConfigLoader;
+use crate::export::repo_map_with;
+use crate::index::RepoIndex;
+use crate::metrics::FunctionMetrics;
+use crate::rank::MapOrder;
+use crate::render::{focus_sources, private_focus_definitions};
+use crate::scan::{ScanOptions, ScanProgress};
+use crate::{extract_definitions, stringify_definitions, stringify_definitions_capped};
+
+/// Size and complexity of one function of a [`RepoMapEntry`]
+#[napi(object)]
+pub struct FunctionMetricsEntry {
+    pub name: String,
+    pub start_line: u32,
+    pub body_lines: u32,
+    pub branches: u32,
+}
+
+impl From<&FunctionMetrics> for FunctionMetricsEntry {
+    fn from(metrics: &FunctionMetrics) -> Self {
+        Self {
+            name: metrics.name.clone(),
+            start_line: metrics.start_line as u32,
+            body_lines: metrics.body_lines as u32,
+            branches: metrics.branches as u32,
+        }
+    }
+}
+
+/// One file of the repo map
+#[napi(object)]
+pub struct RepoMapEntry {
+    pub path: String,
+    pub lang: String,
+    pub defs: String,
+    pub encoding: String,
+    pub score: f64,
+    pub focus: bool,
+    /// Only present when `repo_map.include_metrics` is set
+    pub metrics: Option<Vec<FunctionMetricsEntry>>,
+}
+
+/// Definitions in `source`, rendered like the repo map
+///
+/// With `max_output_bytes`, the output is cut at a definition boundary and
+/// ends with a "… N more symbols omitted" line.
+#[napi(js_name = "stringifyDefinitions")]
+pub fn js_stringify_definitions(
+    language: String,
+    source: String,
+    max_output_bytes: Option<u32>,
+) -> napi::Result<String> {
+    let definitions = extract_definitions(&language, &source)?;
+    let max_output_bytes = max_output_bytes.map(|max| max as usize);
+    Ok(stringify_definitions_capped(&definitions, max_output_bytes))
+}
+
+/// Scan `root` and return the ranked repo map
+///
+/// The configuration is loaded like the plugin does, so `[repo_map]` decides
+/// which files are scanned and how they are ranked, and whether focus files
+/// list their private definitions. `order` is `"rank"`, `"path"`, `"recent"`
+/// or `"dependencies"`.
+#[napi(js_name = "repoMap")]
+pub fn js_repo_map(
+    root: String,
+    focus_files: Option<Vec<String>>,
+    sandboxed: Option<bool>,
+    order: Option<String>,
+) -> napi::Result<Vec<RepoMapEntry>> {
+    let config = ConfigLoader::new().load().map_err(neopilot_error::Error::from)?;
+    let options = ScanOptions::with_config(&config, sandboxed.unwrap_or(false));
+    let index = RepoIndex::build_with(Path::new(&root), &options, &ScanProgress::new())?;
+    let order: MapOrder = order.as_deref().unwrap_or("rank").parse()?;
+    let focus_files = focus_files.unwrap_or_default();
+    let repo_map = &config.repo_map;
+    let overrides = if repo_map.include_private_in_focus {
+        private_focus_definitions(&focus_sources(&index, &focus_files))
+    } else {
+        Default::default()
+    };
+    let entries =
+        repo_map_with(&index, &focus_files, repo_map.include_metrics, order, &repo_map.ranking);
+    Ok(entries
+        .into_iter()
+        .map(|entry| {
+            let overridden = overrides.get(entry.path).map(Vec::as_slice);
+            RepoMapEntry {
+                path: entry.path.to_string(),
+                lang: entry.language.to_string(),
+                defs: stringify_definitions(overridden.unwrap_or(entry.definitions)),
+                encoding: index.files[entry.path].encoding.as_str().to_string(),
+                score: entry.score,
+                focus: entry.focus,
+                metrics: entry.metrics.map(|metrics| metrics.iter().map(Into::into).collect()),
+            }
+        })
+        .collect())
+}
@@ -0,0 +1,135 @@
+//! Contents of unsaved editor buffers
+//!
+//! Neovim buffers often differ from the files on disk. Like an LSP server
+//! tracking open documents, the repo map reads an overlaid path from its
+//! buffer contents instead of from disk: scans, single file updates and
+//! context queries all see what the user is editing. Overlaid files that do
+//! not exist on disk yet are picked up by directory scans and single file
+//! updates, unless the scan filters would skip them, e.g. below vendored
+//! directories.
+//!
+//! Paths are made absolute against the current directory, without resolving
+//! symlinks, so they match the paths scans build from an absolute root.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::encoding::{decode_source, SourceEncoding};
+
+/// Buffer contents by absolute path
+static OVERLAYS: Mutex<BTreeMap<PathBuf, Overlay>> = Mutex::new(BTreeMap::new());
+
+/// Contents of a buffer and when they were set
+#[derive(Debug, Clone)]
+pub(crate) struct Overlay {
+    pub contents: Arc<str>,
+    /// Seconds since the Unix epoch, used as the modification time of the file
+    pub modified: Option<u64>,
+}
+
+fn key(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn overlays() -> std::sync::MutexGuard<'static, BTreeMap<PathBuf, Overlay>> {
+    // A poisoned lock only means another thread panicked; the map is still valid
+    OVERLAYS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Read `path` from `contents` instead of from disk until the overlay is removed
+pub fn set_buffer_overlay(path: &Path, contents: String) {
+    let modified = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|elapsed| elapsed.as_secs());
+    let overlay = Overlay {
+        contents: contents.into(),
+        modified,
+    };
+    overlays().insert(key(path), overlay);
+}
+
+/// Read `path` from disk again, e.g. once its buffer is saved or closed
+///
+/// Returns whether the path had an overlay.
+pub fn remove_buffer_overlay(path: &Path) -> bool {
+    overlays().remove(&key(path)).is_some()
+}
+
+/// Remove every overlay
+pub fn clear_buffer_overlays() {
+    overlays().clear();
+}
+
+/// The overlay of `path`, if any
+pub(crate) fn get(path: &Path) -> Option<Overlay> {
+    overlays().get(&key(path)).cloned()
+}
+
+/// Contents of `path`, from its overlay or from disk, transcoded to UTF-8
+pub(crate) fn read_to_string(path: &Path) -> std::io::Result<String> {
+    read_source(path).map(|(_, source)| source)
+}
+
+/// Contents of `path` like [`read_to_string`], with the encoding of the file
+///
+/// Buffers are always UTF-8. Fails with [`std::io::ErrorKind::InvalidData`]
+/// for binary files, see [`crate::encoding`].
+pub(crate) fn read_source(path: &Path) -> std::io::Result<(SourceEncoding, String)> {
+    match get(path) {
+        Some(overlay) => Ok((SourceEncoding::Utf8, overlay.contents.to_string())),
+        None => decode_source(std::fs::read(path)?).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "binary file")
+        }),
+    }
+}
+
+/// Whether `path` is below `root`, both made absolute like overlaid paths
+pub(crate) fn is_below(path: &Path, root: &Path) -> bool {
+    key(path).starts_with(key(root))
+}
+
+/// Overlaid paths below `root` that do not exist on disk, with their sizes
+///
+/// Paths are joined to `root` as given, like the paths of a directory walk.
+pub(crate) fn unsaved_below(root: &Path) -> Vec<(PathBuf, u64)> {
+    let absolute_root = key(root);
+    overlays()
+        .iter()
+        .filter(|(path, _)| !path.exists())
+        .filter_map(|(path, overlay)| {
+            let relative = path.strip_prefix(&absolute_root).ok()?;
+            Some((root.join(relative), overlay.contents.len() as u64))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overlay_replaces_disk() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("lib.rs");
+        std::fs::write(&path, "saved")?;
+
+        set_buffer_overlay(&path, "unsaved".to_string());
+        assert_eq!(read_to_string(&path)?, "unsaved");
+        assert!(unsaved_below(dir.path()).is_empty());
+
+        let new_file = dir.path().join("src/new.rs");
+        set_buffer_overlay(&new_file, "fn f() {}".to_string());
+        assert_eq!(unsaved_below(dir.path()), vec![(new_file.clone(), 9)]);
+        assert!(is_below(&new_file, dir.path()));
+        assert!(!is_below(dir.path(), &new_file));
+
+        assert!(remove_buffer_overlay(&path));
+        assert!(!remove_buffer_overlay(&path));
+        assert_eq!(read_to_string(&path)?, "saved");
+        remove_buffer_overlay(&new_file);
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+//! Where scans spend their time
+//!
+//! A slow scan is usually the fault of one grammar or query, or of a few
+//! huge generated files. Every scanned file records how long parsing, running
+//! the definitions query and computing the function metrics took, summed per
+//! language, and the slowest files are kept, so [`report`] points at the
+//! language to disable or the files to exclude from scans.
+//!
+//! Scans record from many threads at once, so each thread records into its
+//! own profile and [`report`] merges them.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// Files kept in [`Profile::slowest_files`]
+pub const SLOWEST_FILES: usize = 20;
+
+/// Time spent on each step of extracting a file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timings {
+    pub parse: Duration,
+    /// Running the definitions query and building the definitions
+    pub query: Duration,
+    pub metrics: Duration,
+}
+
+impl Timings {
+    pub fn total(&self) -> Duration {
+        self.parse + self.query + self.metrics
+    }
+
+    fn add(&mut self, other: &Timings) {
+        self.parse += other.parse;
+        self.query += other.query;
+        self.metrics += other.metrics;
+    }
+}
+
+/// Totals of the files of one language
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LanguageProfile {
+    pub files: u64,
+    pub bytes: u64,
+    pub timings: Timings,
+}
+
+/// Timings of a single file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileProfile {
+    pub path: String,
+    pub language: String,
+    pub bytes: u64,
+    pub timings: Timings,
+}
+
+/// Timings recorded since the start of the session or the last [`reset`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub languages: BTreeMap<String, LanguageProfile>,
+    /// The slowest files, slowest first
+    pub slowest_files: Vec<FileProfile>,
+}
+
+impl Profile {
+    fn record(&mut self, file: FileProfile) {
+        let language = self.languages.entry(file.language.clone()).or_default();
+        language.files += 1;
+        language.bytes += file.bytes;
+        language.timings.add(&file.timings);
+        self.keep_if_slow(file);
+    }
+
+    /// Add `file` to the slowest files if it is one of them
+    fn keep_if_slow(&mut self, file: FileProfile) {
+        let total = file.timings.total();
+        let position = self.slowest_files.partition_point(|f| f.timings.total() >= total);
+        if position < SLOWEST_FILES {
+            self.slowest_files.insert(position, file);
+            self.slowest_files.truncate(SLOWEST_FILES);
+        }
+    }
+
+    /// Add the timings recorded in `other`
+    fn merge(&mut self, other: &Profile) {
+        for (name, profile) in &other.languages {
+            let language = self.languages.entry(name.clone()).or_default();
+            language.files += profile.files;
+            language.bytes += profile.bytes;
+            language.timings.add(&profile.timings);
+        }
+        for file in &other.slowest_files {
+            self.keep_if_slow(file.clone());
+        }
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// A table of the languages, slowest first, and of the slowest files
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut languages: Vec<_> = self.languages.iter().collect();
+        languages.sort_by(|(_, a), (_, b)| b.timings.total().cmp(&a.timings.total()));
+        writeln!(f, "language        files      bytes   parse ms   query ms metrics ms")?;
+        for (language, profile) in languages {
+            writeln!(
+                f,
+                "{language:<12} {:>8} {:>10} {:>10.1} {:>10.1} {:>10.1}",
+                profile.files,
+                profile.bytes,
+                millis(profile.timings.parse),
+                millis(profile.timings.query),
+                millis(profile.timings.metrics),
+            )?;
+        }
+        if !self.slowest_files.is_empty() {
+            writeln!(f, "\nslowest files (total ms):")?;
+        }
+        for file in &self.slowest_files {
+            writeln!(
+                f,
+                "{:>10.1}  {} ({})",
+                millis(file.timings.total()),
+                file.path,
+                file.language
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The profile of one thread, only locked by others for [`report`]
+type ThreadProfile = Arc<Mutex<Profile>>;
+
+/// The profiles of the threads that recorded timings
+struct Registry {
+    /// Merged profiles of the threads that exited since
+    exited: Profile,
+    threads: Vec<ThreadProfile>,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    exited: Profile {
+        languages: BTreeMap::new(),
+        slowest_files: Vec::new(),
+    },
+    threads: Vec::new(),
+});
+
+thread_local! {
+    static THREAD_PROFILE: ThreadProfile = {
+        let profile = ThreadProfile::default();
+        lock(&REGISTRY).threads.push(Arc::clone(&profile));
+        profile
+    };
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // Only timings, still valid if a scanning thread panicked
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Add a scanned file to the profile of the calling thread
+pub(crate) fn record(path: String, language: &str, bytes: u64, timings: Timings) {
+    let file = FileProfile {
+        path,
+        language: language.to_string(),
+        bytes,
+        timings,
+    };
+    THREAD_PROFILE.with(|profile| lock(profile).record(file));
+}
+
+/// Timings recorded so far, by all threads
+pub fn report() -> Profile {
+    let mut registry = lock(&REGISTRY);
+    let Registry { exited, threads } = &mut *registry;
+    // The registry holds the last reference to the profile of an exited thread
+    threads.retain(|profile| {
+        if Arc::strong_count(profile) > 1 {
+            return true;
+        }
+        exited.merge(&lock(profile));
+        false
+    });
+    let mut report = exited.clone();
+    for profile in threads.iter() {
+        report.merge(&lock(profile));
+    }
+    report
+}
+
+/// Forget the recorded timings, e.g. before profiling a fresh scan
+pub fn reset() {
+    let mut registry = lock(&REGISTRY);
+    registry.exited = Profile::default();
+    for profile in &registry.threads {
+        *lock(profile) = Profile::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, language: &str, parse_ms: u64) -> FileProfile {
+        FileProfile {
+            path: path.to_string(),
+            language: language.to_string(),
+            bytes: 100,
+            timings: Timings {
+                parse: Duration::from_millis(parse_ms),
+                query: Duration::from_millis(1),
+                metrics: Duration::ZERO,
+            },
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let mut profile = Profile::default();
+        for i in 0..SLOWEST_FILES as u64 + 5 {
+            profile.record(file(&format!("{i}.rs"), "rust", i));
+        }
+        profile.record(file("slow.py", "python", 1000));
+
+        let rust = profile.languages["rust"];
+        assert_eq!(rust.files, SLOWEST_FILES as u64 + 5);
+        assert_eq!(rust.timings.query, Duration::from_millis(SLOWEST_FILES as u64 + 5));
+        assert_eq!(profile.slowest_files.len(), SLOWEST_FILES);
+        assert_eq!(profile.slowest_files[0].path, "slow.py");
+        assert_eq!(profile.slowest_files[1].path, "24.rs");
+
+        let text = profile.to_string();
+        assert!(text.lines().nth(1).unwrap().starts_with("python"));
+        assert!(text.contains("1001.0  slow.py (python)"));
+    }
+
+    #[test]
+    fn test_report_merges_threads() {
+        let language = "profile-test";
+        std::thread::scope(|scope| {
+            for i in 0..4 {
+                scope.spawn(move || record(format!("{i}.x"), language, 10, Timings::default()));
+            }
+        });
+        record("main.x".to_string(), language, 10, Timings::default());
+        let profile = report();
+        assert_eq!(profile.languages[language].files, 5);
+        assert_eq!(profile.languages[language].bytes, 50);
+        // Exited threads are folded into the registry and still reported
+        assert_eq!(report().languages[language], profile.languages[language]);
+    }
+}
@@ -0,0 +1,82 @@
+//! Python bindings, enabled with the `python` feature
+//!
+//! Exposes definition extraction and the ranked repo map so Python tooling
+//! sees exactly what the plugin sends to the model:
+//!
+//! ```python
+//! import json
+//! import neopilot_repo_map
+//!
+//! repo_map = json.loads(neopilot_repo_map.repo_map_json(".", ["src/main.rs"]))
+//! ```
+
+use std::path::Path;
+
+use pyo3::prelude::*;
+
+use crate::config::ConfigLoader;
+use crate::export::{repo_map_with, to_bytes, OutputFormat};
+use crate::index::RepoIndex;
+use crate::rank::MapOrder;
+use crate::scan::{ScanOptions, ScanProgress};
+use crate::{extract_definitions, stringify_definitions_capped};
+
+/// Definitions in `source`, rendered like the repo map
+///
+/// With `max_output_bytes`, the output is cut at a definition boundary and
+/// ends with a "… N more symbols omitted" line.
+#[pyfunction(
+    name = "stringify_definitions",
+    signature = (language, source, max_output_bytes = None),
+)]
+fn py_stringify_definitions(
+    language: &str,
+    source: &str,
+    max_output_bytes: Option<usize>,
+) -> PyResult<String> {
+    let definitions = extract_definitions(language, source)?;
+    Ok(stringify_definitions_capped(&definitions, max_output_bytes))
+}
+
+/// Scan `root` and return the ranked repo map as JSON
+///
+/// The configuration is loaded like the plugin does, so `[repo_map]` decides
+/// which files are scanned and how they are ranked. `order` is `"rank"`,
+/// `"path"`, `"recent"` or `"dependencies"`; `include_metrics` defaults to
+/// `repo_map.include_metrics`.
+#[pyfunction(signature = (
+    root,
+    focus_files = None,
+    sandboxed = false,
+    include_metrics = None,
+    order = "rank",
+))]
+fn repo_map_json(
+    py: Python<'_>,
+    root: &str,
+    focus_files: Option<Vec<String>>,
+    sandboxed: bool,
+    include_metrics: Option<bool>,
+    order: &str,
+) -> PyResult<String> {
+    let config = ConfigLoader::new().load().map_err(neopilot_error::Error::from)?;
+    let options = ScanOptions::with_config(&config, sandboxed);
+    // Scanning does not touch Python objects, so let other threads run
+    let index = py.allow_threads(|| {
+        RepoIndex::build_with(Path::new(root), &options, &ScanProgress::new())
+    })?;
+    let order: MapOrder = order.parse()?;
+    let focus_files = focus_files.unwrap_or_default();
+    let repo_map = &config.repo_map;
+    let include_metrics = include_metrics.unwrap_or(repo_map.include_metrics);
+    let entries = repo_map_with(&index, &focus_files, include_metrics, order, &repo_map.ranking);
+    let json = to_bytes(&entries, OutputFormat::Json)?;
+    Ok(String::from_utf8_lossy(&json).into_owned())
+}
+
+#[pymodule]
+fn neopilot_repo_map(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(py_stringify_definitions, m)?)?;
+    m.add_function(wrap_pyfunction!(repo_map_json, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,378 @@
+//! Ranking of indexed files for rendering the repo map
+//!
+//! The base rank of a file comes from how often other files reference its
+//! definitions. Files the user is working on ("focus files", e.g. open buffers
+//! and recently edited files) and files whose symbols they use are boosted so
+//! the map reflects the current task. Recently modified files can be favoured
+//! and large files penalized; all weights come from [`RankingConfig`].
+//!
+//! The ranking decides which files make it into the map; [`MapOrder`] then
+//! decides in which order they are listed.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use neopilot_error::{Error, ErrorCode, Result};
+
+use crate::config::RankingConfig;
+use crate::index::{IndexedFile, RepoIndex};
+
+/// Multiplier applied to the score of a focus file
+pub const FOCUS_FILE_BOOST: f64 = 10.0;
+
+/// Weight of each reference from a focus file to another file's symbols
+pub const FOCUS_REFERENCE_WEIGHT: f64 = 0.5;
+
+/// A file together with its final ranking score
+#[derive(Debug, Clone)]
+pub struct RankedFile<'a> {
+    pub path: &'a str,
+    pub file: &'a IndexedFile,
+    pub score: f64,
+    pub is_focus: bool,
+}
+
+/// Convert a focus path (absolute or relative) into an index key
+pub(crate) fn index_key(index: &RepoIndex, path: &str) -> String {
+    let path = Path::new(path);
+    path.strip_prefix(&index.root)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Rank all indexed files, boosting `focus_files` and the files they reference
+///
+/// Uses the default [`RankingConfig`], see [`rank_files_with`].
+pub fn rank_files<'a>(index: &'a RepoIndex, focus_files: &[String]) -> Vec<RankedFile<'a>> {
+    rank_files_with(index, focus_files, &RankingConfig::default(), SystemTime::now())
+}
+
+/// Recency signal in `(0, 1]`, halving after the first day
+fn recency(modified: Option<u64>, now: SystemTime) -> f64 {
+    let Some(modified) = modified else {
+        return 0.0;
+    };
+    let now = now.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let age_days = now.saturating_sub(modified) as f64 / 86_400.0;
+    1.0 / (1.0 + age_days)
+}
+
+/// Factor in `(0, 1]` that shrinks the score of large files
+fn size_factor(size: u64, penalty: f64) -> f64 {
+    1.0 / (1.0 + penalty * (size as f64 / 1024.0).ln_1p())
+}
+
+/// Rank all indexed files with the given signal weights
+///
+/// `now` is the reference time for the recency signal. The result is sorted
+/// by descending score; ties are broken by path so the output is stable.
+pub fn rank_files_with<'a>(
+    index: &'a RepoIndex,
+    focus_files: &[String],
+    weights: &RankingConfig,
+    now: SystemTime,
+) -> Vec<RankedFile<'a>> {
+    let focus: Vec<String> = focus_files
+        .iter()
+        .map(|path| index_key(index, path))
+        .filter(|key| index.files.contains_key(key))
+        .collect();
+
+    // How often each identifier is used by the focus files
+    let mut focus_usage: BTreeMap<&str, u32> = BTreeMap::new();
+    for key in &focus {
+        for (identifier, count) in &index.files[key].identifiers {
+            *focus_usage.entry(identifier.as_str()).or_insert(0) += count;
+        }
+    }
+
+    let mut ranked: Vec<RankedFile<'a>> = index
+        .files
+        .iter()
+        .map(|(path, file)| {
+            let is_focus = focus.contains(path);
+            let base = index.rankings.get(path).copied().unwrap_or(0.0);
+            let focus_references: u32 = if is_focus {
+                0
+            } else {
+                file.definitions
+                    .iter()
+                    .map(|d| focus_usage.get(d.name()).copied().unwrap_or(0))
+                    .sum()
+            };
+            let mut score = 1.0
+                + weights.reference_weight * base
+                + weights.recency_weight * recency(file.modified, now);
+            score *= 1.0 + weights.focus_reference_weight * f64::from(focus_references);
+            score *= size_factor(file.size, weights.size_penalty);
+            if is_focus {
+                score *= weights.focus_boost;
+            }
+            RankedFile {
+                path: path.as_str(),
+                file,
+                score,
+                is_focus,
+            }
+        })
+        .collect();
+
+    ranked.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.path.cmp(b.path)));
+    ranked
+}
+
+/// Order in which the files of the map are listed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MapOrder {
+    /// Highest score first
+    #[default]
+    Rank,
+    /// Alphabetically by path
+    Path,
+    /// Most recently modified first
+    Recent,
+    /// Files before the files that use their definitions
+    Dependencies,
+}
+
+impl FromStr for MapOrder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "rank" => Ok(MapOrder::Rank),
+            "path" => Ok(MapOrder::Path),
+            "recent" => Ok(MapOrder::Recent),
+            "dependencies" | "topological" => Ok(MapOrder::Dependencies),
+            _ => Err(Error::new(
+                ErrorCode::InvalidInput,
+                format!("Unknown map order '{s}', expected rank, path, recent or dependencies"),
+            )),
+        }
+    }
+}
+
+/// Positions of `ranked` in dependency order
+///
+/// A file depends on the other files defining identifiers it uses. Among the
+/// files whose dependencies are all listed, the best ranked comes first; a
+/// cycle is broken at its best ranked file.
+fn dependency_order(ranked: &[RankedFile<'_>]) -> Vec<usize> {
+    let mut definers: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+    for (i, ranked) in ranked.iter().enumerate() {
+        for definition in &ranked.file.definitions {
+            definers.entry(definition.name()).or_default().push(i);
+        }
+    }
+
+    let mut pending: Vec<BTreeSet<usize>> = ranked
+        .iter()
+        .enumerate()
+        .map(|(i, ranked)| {
+            ranked
+                .file
+                .identifiers
+                .keys()
+                .filter_map(|identifier| definers.get(identifier.as_str()))
+                .flatten()
+                .copied()
+                .filter(|&j| j != i)
+                .collect()
+        })
+        .collect();
+    let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); ranked.len()];
+    for (i, dependencies) in pending.iter().enumerate() {
+        for &j in dependencies {
+            dependents[j].push(i);
+        }
+    }
+
+    // Positions follow the ranking, so the smallest ready one is the best ranked
+    let mut ready: BTreeSet<usize> = (0..ranked.len()).filter(|&i| pending[i].is_empty()).collect();
+    let mut listed = vec![false; ranked.len()];
+    let mut order = Vec::with_capacity(ranked.len());
+    while order.len() < ranked.len() {
+        let next = match ready.pop_first() {
+            Some(next) if listed[next] => continue,
+            Some(next) => next,
+            None => (0..ranked.len()).find(|&i| !listed[i]).unwrap_or_default(),
+        };
+        listed[next] = true;
+        order.push(next);
+        for &dependent in &dependents[next] {
+            pending[dependent].remove(&next);
+            if pending[dependent].is_empty() && !listed[dependent] {
+                ready.insert(dependent);
+            }
+        }
+    }
+    order
+}
+
+/// Reorder files sorted by score, as returned by [`rank_files_with`]
+///
+/// Ties keep the rank order.
+pub fn order_files(ranked: &mut Vec<RankedFile<'_>>, order: MapOrder) {
+    match order {
+        MapOrder::Rank => {}
+        MapOrder::Path => ranked.sort_by(|a, b| a.path.cmp(b.path)),
+        // Files without a modification time go last
+        MapOrder::Recent => ranked.sort_by(|a, b| b.file.modified.cmp(&a.file.modified)),
+        MapOrder::Dependencies => {
+            *ranked = dependency_order(ranked)
+                .into_iter()
+                .map(|i| ranked[i].clone())
+                .collect();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::{count_identifiers, ScannedFile};
+    use crate::{Definition, Variable};
+    use std::path::PathBuf;
+
+    fn scanned(path: &str, names: &[&str], source: &str) -> ScannedFile {
+        ScannedFile {
+            path: PathBuf::from(path),
+            language: "rust".to_string(),
+            definitions: names
+                .iter()
+                .map(|name| {
+                    Definition::Variable(Variable {
+                        name: name.to_string(),
+                        value_type: String::new(),
+                    })
+                })
+                .collect(),
+            size: source.len() as u64,
+            identifiers: count_identifiers(source),
+            modified: None,
+            metrics: vec![],
+            encoding: Default::default(),
+        }
+    }
+
+    fn sample_index() -> RepoIndex {
+        RepoIndex::from_scan(
+            Path::new("/project"),
+            vec![
+                scanned("popular.rs", &["POPULAR"], "POPULAR"),
+                scanned("helper.rs", &["HELPER"], "HELPER"),
+                scanned("user1.rs", &[], "POPULAR POPULAR"),
+                scanned("editing.rs", &[], "HELPER HELPER HELPER"),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_rank_without_focus() {
+        let index = sample_index();
+        let ranked = rank_files(&index, &[]);
+        assert_eq!(ranked[0].path, "helper.rs");
+        assert!(ranked.iter().all(|r| !r.is_focus));
+    }
+
+    #[test]
+    fn test_focus_files_are_boosted() {
+        let index = sample_index();
+        let ranked = rank_files(&index, &["/project/user1.rs".to_string()]);
+        assert_eq!(ranked[0].path, "user1.rs");
+        assert!(ranked[0].is_focus);
+        // The file defining symbols used by the focus file comes next
+        assert_eq!(ranked[1].path, "popular.rs");
+    }
+
+    #[test]
+    fn test_unknown_focus_files_are_ignored() {
+        let index = sample_index();
+        let ranked = rank_files(&index, &["elsewhere/missing.rs".to_string()]);
+        assert_eq!(ranked.len(), 4);
+        assert!(ranked.iter().all(|r| !r.is_focus));
+    }
+
+    #[test]
+    fn test_recency_weight() {
+        let mut index = sample_index();
+        index.files.get_mut("user1.rs").unwrap().modified = Some(1_000_000);
+        let now = UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        let weights = RankingConfig {
+            recency_weight: 100.0,
+            ..Default::default()
+        };
+
+        let ranked = rank_files_with(&index, &[], &weights, now);
+        assert_eq!(ranked[0].path, "user1.rs");
+
+        let ranked = rank_files_with(&index, &[], &RankingConfig::default(), now);
+        assert_ne!(ranked[0].path, "user1.rs");
+    }
+
+    fn ordered(index: &RepoIndex, order: MapOrder) -> Vec<&str> {
+        let mut ranked = rank_files(index, &[]);
+        order_files(&mut ranked, order);
+        ranked.into_iter().map(|r| r.path).collect()
+    }
+
+    #[test]
+    fn test_map_orders() {
+        let mut index = sample_index();
+        index.files.get_mut("editing.rs").unwrap().modified = Some(20);
+        index.files.get_mut("popular.rs").unwrap().modified = Some(10);
+
+        assert_eq!(
+            ordered(&index, MapOrder::Rank),
+            vec!["helper.rs", "popular.rs", "editing.rs", "user1.rs"]
+        );
+        assert_eq!(
+            ordered(&index, MapOrder::Path),
+            vec!["editing.rs", "helper.rs", "popular.rs", "user1.rs"]
+        );
+        // Files without a modification time keep their rank order
+        assert_eq!(
+            ordered(&index, MapOrder::Recent),
+            vec!["editing.rs", "popular.rs", "helper.rs", "user1.rs"]
+        );
+        assert_eq!("topological".parse::<MapOrder>().ok(), Some(MapOrder::Dependencies));
+        assert!("sideways".parse::<MapOrder>().is_err());
+    }
+
+    #[test]
+    fn test_dependency_order() {
+        let index = RepoIndex::from_scan(
+            Path::new("/project"),
+            vec![
+                scanned("app.rs", &["APP"], "SERVICE SERVICE MODEL"),
+                scanned("service.rs", &["SERVICE"], "MODEL MODEL"),
+                scanned("model.rs", &["MODEL"], ""),
+                // A cycle is still listed, at its best ranked file
+                scanned("ping.rs", &["PING"], "PONG"),
+                scanned("pong.rs", &["PONG"], "PING PING"),
+            ],
+        );
+        let order = ordered(&index, MapOrder::Dependencies);
+        let position = |path: &str| order.iter().position(|p| *p == path).unwrap();
+        assert_eq!(order.len(), 5);
+        assert!(position("model.rs") < position("service.rs"));
+        assert!(position("service.rs") < position("app.rs"));
+        assert!(position("ping.rs") < position("pong.rs"));
+    }
+
+    #[test]
+    fn test_size_penalty() {
+        let mut index = sample_index();
+        index.files.get_mut("helper.rs").unwrap().size = 1024 * 1024;
+        let weights = RankingConfig {
+            size_penalty: 1.0,
+            ..Default::default()
+        };
+
+        let ranked = rank_files_with(&index, &[], &weights, SystemTime::now());
+        assert_eq!(ranked[0].path, "popular.rs");
+    }
+}
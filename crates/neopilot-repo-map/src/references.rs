@@ -0,0 +1,136 @@
+//! References to a symbol within a single file
+//!
+//! Rename and edit requests need every usage of the symbol under the cursor.
+//! Without a project index, usages are found syntactically: identifiers with
+//! the same name as the one at the position. Scopes are not resolved, so
+//! unrelated symbols sharing the name, such as shadowed locals, are included;
+//! for building context, showing a usage too many beats missing one.
+//! Comments and strings are never identifiers and are left out.
+
+use neopilot_error::Result;
+use tree_sitter::{Node, Point};
+
+use crate::parse_source;
+
+/// Node kinds naming a symbol that do not end in `identifier`, such as
+/// Ruby's `constant` and PHP's `name`
+const NAME_KINDS: &[&str] = &["name", "constant", "alias"];
+
+/// A reference to a symbol, as a range of the source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    /// Line of the first character (0-based)
+    pub start_line: usize,
+    /// Column of the first character (0-based, in bytes)
+    pub start_col: usize,
+    /// Line after the last character (0-based)
+    pub end_line: usize,
+    /// Column after the last character (0-based, in bytes)
+    pub end_col: usize,
+    pub start_byte: usize,
+    pub end_byte: usize,
+}
+
+impl Reference {
+    fn from_node(node: Node) -> Self {
+        Self {
+            start_line: node.start_position().row,
+            start_col: node.start_position().column,
+            end_line: node.end_position().row,
+            end_col: node.end_position().column,
+            start_byte: node.start_byte(),
+            end_byte: node.end_byte(),
+        }
+    }
+}
+
+/// Whether `node` is a single identifier
+fn is_identifier(node: Node) -> bool {
+    let kind = node.kind();
+    node.is_named()
+        && node.named_child_count() == 0
+        && (kind.ends_with("identifier") || NAME_KINDS.contains(&kind))
+}
+
+/// Every reference in `source` to the symbol at `line`/`col` (both 0-based,
+/// `col` in bytes), in source order
+///
+/// The reference at the position is included. Returns nothing when the
+/// position is not on an identifier.
+pub fn local_references(
+    source: &str,
+    language: &str,
+    line: usize,
+    col: usize,
+) -> Result<Vec<Reference>> {
+    let tree = parse_source(language, source)?;
+    let point = Point::new(line, col);
+    let Some(target) = tree
+        .root_node()
+        .named_descendant_for_point_range(point, point)
+        .filter(|node| is_identifier(*node))
+    else {
+        return Ok(Vec::new());
+    };
+    let name = &source.as_bytes()[target.byte_range()];
+
+    // Walked with a cursor, so deeply nested sources cannot overflow the stack
+    let mut references = Vec::new();
+    let mut cursor = tree.walk();
+    'nodes: loop {
+        let node = cursor.node();
+        if is_identifier(node) && source.as_bytes()[node.byte_range()] == *name {
+            references.push(Reference::from_node(node));
+        }
+        if cursor.goto_first_child() {
+            continue;
+        }
+        loop {
+            if cursor.goto_next_sibling() {
+                continue 'nodes;
+            }
+            if !cursor.goto_parent() {
+                return Ok(references);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "struct Counter { count: u32 }\n\
+        fn bump(counter: &mut Counter) -> u32 {\n    \
+            // counter is bumped\n    \
+            counter.count += 1;\n    \
+            let label = \"counter\";\n    \
+            counter.count\n\
+        }\n";
+
+    fn starts(references: &[Reference]) -> Vec<(usize, usize)> {
+        references.iter().map(|r| (r.start_line, r.start_col)).collect()
+    }
+
+    #[test]
+    fn test_local_references() -> Result<()> {
+        // `counter` on its declaration, skipping the comment and the string
+        let references = local_references(SOURCE, "rust", 1, 9)?;
+        assert_eq!(starts(&references), [(1, 8), (3, 4), (5, 4)]);
+        assert_eq!(&SOURCE[references[1].start_byte..references[1].end_byte], "counter");
+
+        // Type and field identifiers are references too
+        let references = local_references(SOURCE, "rust", 0, 8)?;
+        assert_eq!(starts(&references), [(0, 7), (1, 22)]);
+        let references = local_references(SOURCE, "rust", 5, 13)?;
+        assert_eq!(starts(&references), [(0, 17), (3, 12), (5, 12)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_not_on_identifier() -> Result<()> {
+        assert!(local_references(SOURCE, "rust", 3, 18)?.is_empty());
+        assert!(local_references(SOURCE, "cobol", 0, 0).is_err());
+        Ok(())
+    }
+}
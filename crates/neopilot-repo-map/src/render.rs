@@ -0,0 +1,403 @@
+//! Budget-aware rendering of the repo map
+//!
+//! Files are added in rank order with their full definition listing while it
+//! fits in the token budget. When a listing does not fit, a [`Summarizer`] can
+//! provide a one-line summary to use instead, so highly ranked but large files
+//! are still represented. Listing costs come from the index's token cost cache
+//! when it is filled, see [`RepoIndex::update_token_costs`].
+//!
+//! The index only holds public definitions. The files the user is working on
+//! can be listed with their private definitions too, see
+//! [`private_focus_definitions`], while every other file stays public-only;
+//! such listings are costed from their own text, since the cache only knows
+//! the public ones.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use neopilot_error::Result;
+
+use crate::config::RankingConfig;
+use crate::context::estimate_tokens;
+use crate::index::RepoIndex;
+use crate::overlay;
+use crate::rank::{index_key, order_files, rank_files_with, MapOrder};
+use crate::{extract_definitions_with, stringify_definitions, Definition, Visibility};
+
+/// Produces a short summary of a file from its definitions
+pub trait Summarizer {
+    /// One-line summary of the file at `path`, or `None` to omit the file
+    fn summarize(&self, path: &str, definitions: &[Definition]) -> Result<Option<String>>;
+}
+
+impl<F> Summarizer for F
+where
+    F: Fn(&str, &[Definition]) -> Result<Option<String>>,
+{
+    fn summarize(&self, path: &str, definitions: &[Definition]) -> Result<Option<String>> {
+        self(path, definitions)
+    }
+}
+
+/// A file as it appears in the rendered map
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderedFile<'a> {
+    pub path: &'a str,
+    pub language: &'a str,
+    /// Full definition listing, or the summary if `summarized` is set
+    pub text: String,
+    pub summarized: bool,
+    pub score: f64,
+    pub focus: bool,
+    pub tokens: usize,
+}
+
+/// Repo map trimmed to a token budget
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RenderedMap<'a> {
+    pub files: Vec<RenderedFile<'a>>,
+    /// Estimated tokens of all rendered files
+    pub tokens: usize,
+    /// Files with definitions that did not fit in the budget
+    pub omitted: usize,
+}
+
+/// Collapse a summary onto a single line
+fn one_line(summary: &str) -> String {
+    summary.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// An indexed focus file to list with its private definitions
+#[derive(Debug, Clone)]
+pub struct FocusSource {
+    /// Path of the file in the index
+    pub key: String,
+    /// Language the file was indexed as
+    pub language: String,
+    /// Absolute path to read the file from
+    pub path: PathBuf,
+}
+
+/// The indexed files among `focus_files`, for [`private_focus_definitions`]
+///
+/// This only looks the files up, so callers holding the index behind a lock
+/// can release it before the files are read and parsed.
+pub fn focus_sources(index: &RepoIndex, focus_files: &[String]) -> Vec<FocusSource> {
+    focus_files
+        .iter()
+        .filter_map(|focus| {
+            let key = index_key(index, focus);
+            let file = index.files.get(&key)?;
+            Some(FocusSource {
+                path: index.root.join(&key),
+                language: file.language.clone(),
+                key,
+            })
+        })
+        .collect()
+}
+
+/// Definitions of the focus `sources` including private ones, keyed by index
+/// path, for [`render_map_with_overrides`]
+///
+/// Focus files are read again, from their unsaved buffer if there is one.
+/// Files that cannot be read or parsed are left out and keep their indexed
+/// definitions.
+pub fn private_focus_definitions(sources: &[FocusSource]) -> BTreeMap<String, Vec<Definition>> {
+    let mut overrides = BTreeMap::new();
+    for source in sources {
+        let definitions = overlay::read_source(&source.path)
+            .map_err(|e| e.to_string())
+            .and_then(|(_, text)| {
+                extract_definitions_with(&source.language, &text, Visibility::All)
+                    .map_err(|e| e.to_string())
+            });
+        match definitions {
+            Ok(definitions) => {
+                overrides.insert(source.key.clone(), definitions);
+            }
+            Err(e) => log::debug!("Keeping public definitions of {}: {e}", source.key),
+        }
+    }
+    overrides
+}
+
+/// Render the ranked map of `index` within `budget_tokens`
+///
+/// Files without definitions are left out. `summarizer` is only consulted for
+/// files whose full listing does not fit. Uses the default [`RankingConfig`],
+/// see [`render_map_with`].
+pub fn render_map<'a>(
+    index: &'a RepoIndex,
+    focus_files: &[String],
+    budget_tokens: usize,
+    summarizer: Option<&dyn Summarizer>,
+) -> Result<RenderedMap<'a>> {
+    let weights = RankingConfig::default();
+    render_map_with(index, focus_files, budget_tokens, summarizer, MapOrder::Rank, &weights)
+}
+
+/// Render the map like [`render_map`], ranking with `weights` and listing the
+/// files in `order`
+///
+/// Files are still selected by rank, so the budget goes to the same files
+/// whatever the order.
+pub fn render_map_with<'a>(
+    index: &'a RepoIndex,
+    focus_files: &[String],
+    budget_tokens: usize,
+    summarizer: Option<&dyn Summarizer>,
+    order: MapOrder,
+    weights: &RankingConfig,
+) -> Result<RenderedMap<'a>> {
+    let overrides = BTreeMap::new();
+    render_map_with_overrides(
+        index,
+        focus_files,
+        budget_tokens,
+        summarizer,
+        order,
+        weights,
+        &overrides,
+    )
+}
+
+/// Render the map like [`render_map_with`], listing the files in `overrides`
+/// with the definitions given there instead of the indexed ones
+///
+/// Ranking still uses the indexed definitions, so the overrides only change
+/// what the selected files list and what that costs from the budget.
+pub fn render_map_with_overrides<'a>(
+    index: &'a RepoIndex,
+    focus_files: &[String],
+    budget_tokens: usize,
+    summarizer: Option<&dyn Summarizer>,
+    order: MapOrder,
+    weights: &RankingConfig,
+    overrides: &BTreeMap<String, Vec<Definition>>,
+) -> Result<RenderedMap<'a>> {
+    let ranked = rank_files_with(index, focus_files, weights, SystemTime::now());
+    let mut map = RenderedMap::default();
+    for ranked in ranked.iter().cloned() {
+        let overridden = overrides.get(ranked.path);
+        let definitions = overridden.unwrap_or(&ranked.file.definitions);
+        if definitions.is_empty() {
+            continue;
+        }
+
+        let listing = stringify_definitions(definitions);
+        let cached = match overridden {
+            Some(_) => None,
+            None => index.token_costs.file_cost(ranked.path),
+        };
+        let cost = cached.unwrap_or_else(|| estimate_tokens(&listing));
+        let (text, summarized, cost) = if map.tokens + cost <= budget_tokens {
+            (listing, false, cost)
+        } else {
+            let summary = match summarizer {
+                Some(summarizer) => summarizer.summarize(ranked.path, definitions)?,
+                None => None,
+            };
+            match summary.map(|summary| one_line(&summary)) {
+                Some(summary) if map.tokens + estimate_tokens(&summary) <= budget_tokens => {
+                    let cost = estimate_tokens(&summary);
+                    (summary, true, cost)
+                }
+                _ => {
+                    map.omitted += 1;
+                    continue;
+                }
+            }
+        };
+
+        map.tokens += cost;
+        map.files.push(RenderedFile {
+            path: ranked.path,
+            language: &ranked.file.language,
+            text,
+            summarized,
+            score: ranked.score,
+            focus: ranked.is_focus,
+            tokens: cost,
+        });
+    }
+
+    if order != MapOrder::Rank {
+        let mut rendered: Vec<_> = ranked
+            .into_iter()
+            .filter(|ranked| map.files.iter().any(|file| file.path == ranked.path))
+            .collect();
+        order_files(&mut rendered, order);
+        let position: BTreeMap<&str, usize> =
+            rendered.iter().enumerate().map(|(i, ranked)| (ranked.path, i)).collect();
+        map.files.sort_by_key(|file| position[file.path]);
+    }
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{FileCosts, IndexedFile};
+    use crate::Variable;
+    use std::path::PathBuf;
+
+    /// Cached costs of a file, whatever its definitions
+    fn costs(costs: Vec<usize>) -> FileCosts {
+        FileCosts { hash: 0, costs }
+    }
+
+    fn variables(names: &[&str]) -> Vec<Definition> {
+        names
+            .iter()
+            .map(|name| {
+                Definition::Variable(Variable {
+                    name: name.to_string(),
+                    value_type: "u32".to_string(),
+                })
+            })
+            .collect()
+    }
+
+    fn sample_index() -> RepoIndex {
+        let mut index = RepoIndex {
+            root: PathBuf::from("/project"),
+            ..Default::default()
+        };
+        let files = [
+            ("big.rs", variables(&["ALPHA", "BETA", "GAMMA", "DELTA", "EPSILON"])),
+            ("small.rs", variables(&["X"])),
+            ("empty.rs", vec![]),
+        ];
+        for (path, definitions) in files {
+            index.files.insert(
+                path.to_string(),
+                IndexedFile {
+                    language: "rust".to_string(),
+                    definitions,
+                    size: 0,
+                    identifiers: Default::default(),
+                    modified: None,
+                    metrics: vec![],
+                    encoding: Default::default(),
+                },
+            );
+        }
+        index.recompute_rankings();
+        index
+    }
+
+    #[test]
+    fn test_everything_fits() -> Result<()> {
+        let index = sample_index();
+        let map = render_map(&index, &[], 10_000, None)?;
+        assert_eq!(map.files.len(), 2);
+        assert_eq!(map.omitted, 0);
+        assert!(map.files.iter().all(|file| !file.summarized));
+        Ok(())
+    }
+
+    #[test]
+    fn test_summarizer_used_when_budget_is_tight() -> Result<()> {
+        let index = sample_index();
+        let small = estimate_tokens(&stringify_definitions(&variables(&["X"])));
+        let summarize = |path: &str, definitions: &[Definition]| -> Result<Option<String>> {
+            Ok(Some(format!("{path}:\n {} symbols", definitions.len())))
+        };
+
+        let map = render_map(&index, &[], small + 5, Some(&summarize))?;
+        let big = map.files.iter().find(|file| file.path == "big.rs").unwrap();
+        assert!(big.summarized);
+        assert_eq!(big.text, "big.rs: 5 symbols");
+        assert!(map.tokens <= small + 5);
+
+        let map = render_map(&index, &[], small, None)?;
+        assert_eq!(map.files.len(), 1);
+        assert_eq!(map.omitted, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_order() -> Result<()> {
+        let index = sample_index();
+        let focus = ["small.rs".to_string()];
+        let paths = |map: RenderedMap| map.files.iter().map(|f| f.path.to_string()).collect();
+
+        let map = render_map(&index, &focus, 10_000, None)?;
+        assert_eq!(paths(map), vec!["small.rs", "big.rs"]);
+        let weights = RankingConfig::default();
+        let map = render_map_with(&index, &focus, 10_000, None, MapOrder::Path, &weights)?;
+        assert_eq!(paths(map), vec!["big.rs", "small.rs"]);
+
+        // Without the focus boost both files tie and are ranked by path
+        let weights = RankingConfig {
+            focus_boost: 1.0,
+            ..Default::default()
+        };
+        let map = render_map_with(&index, &focus, 10_000, None, MapOrder::Rank, &weights)?;
+        assert_eq!(paths(map), vec!["big.rs", "small.rs"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_private_focus_definitions() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source = "__all__ = [\"Visible\"]\n\nclass Visible:\n    pass\n\n\
+                      class Hidden:\n    pass\n";
+        std::fs::write(dir.path().join("app.py"), source)?;
+        let mut index = RepoIndex {
+            root: dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        index.files.insert(
+            "app.py".to_string(),
+            IndexedFile {
+                language: "python".to_string(),
+                definitions: crate::extract_definitions("python", source)?,
+                size: source.len() as u64,
+                identifiers: Default::default(),
+                modified: None,
+                metrics: vec![],
+                encoding: Default::default(),
+            },
+        );
+        index.recompute_rankings();
+        index.token_costs.files.insert("app.py".to_string(), costs(vec![1]));
+
+        assert!(focus_sources(&index, &[]).is_empty());
+        let missing = ["missing.py".to_string()];
+        assert!(focus_sources(&index, &missing).is_empty());
+        let focus = [dir.path().join("app.py").to_string_lossy().to_string()];
+        let overrides = private_focus_definitions(&focus_sources(&index, &focus));
+        assert_eq!(overrides["app.py"].len(), 2);
+
+        let weights = RankingConfig::default();
+        let map = render_map_with_overrides(
+            &index,
+            &focus,
+            10_000,
+            None,
+            MapOrder::Rank,
+            &weights,
+            &overrides,
+        )?;
+        let app = &map.files[0];
+        assert!(app.text.contains("class Hidden{}"));
+        assert_eq!(app.tokens, estimate_tokens(&app.text));
+        let map = render_map(&index, &focus, 10_000, None)?;
+        assert!(!map.files[0].text.contains("Hidden"));
+        assert_eq!(map.files[0].tokens, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cached_token_costs() -> Result<()> {
+        let mut index = sample_index();
+        index.token_costs.files.insert("big.rs".to_string(), costs(vec![1; 5]));
+        let map = render_map(&index, &[], 10_000, None)?;
+        let big = map.files.iter().find(|file| file.path == "big.rs").unwrap();
+        assert_eq!(big.tokens, 5);
+        Ok(())
+    }
+}
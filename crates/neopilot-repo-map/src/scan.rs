@@ -0,0 +1,1091 @@
+//! Repository scanning with progress reporting
+//!
+//! Walks a project directory, extracts definitions from every supported source
+//! file and keeps counters that can be polled while the scan is running.
+//! Phase changes and every completed percent are also emitted as
+//! [`Event::ScanProgress`] events.
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use neopilot_common::events::{self, Event};
+use neopilot_common::scheduler::{Priority, Scheduler};
+use neopilot_error::{Error, ErrorCode, Result, ResultExt};
+
+use crate::config::Config;
+use crate::encoding::{decode_source, SourceEncoding};
+use crate::languages::LanguageOverrides;
+use crate::metrics::FunctionMetrics;
+use crate::overlay;
+use crate::{extract_definitions_timed, profile, Definition, Visibility};
+
+/// Phase of a scan, as reported by [`ProgressSnapshot::phase`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanPhase {
+    Idle,
+    Discovering,
+    Parsing,
+    Done,
+}
+
+impl ScanPhase {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => ScanPhase::Discovering,
+            2 => ScanPhase::Parsing,
+            3 => ScanPhase::Done,
+            _ => ScanPhase::Idle,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScanPhase::Idle => "idle",
+            ScanPhase::Discovering => "discovering",
+            ScanPhase::Parsing => "parsing",
+            ScanPhase::Done => "done",
+        }
+    }
+}
+
+/// Progress counters updated while a scan is running
+///
+/// All counters are atomics so they can be read from another thread (or from
+/// Lua through the module exports) without blocking the scan.
+#[derive(Debug)]
+pub struct ScanProgress {
+    phase: AtomicU8,
+    files_discovered: AtomicU64,
+    files_parsed: AtomicU64,
+    files_skipped: AtomicU64,
+    bytes_total: AtomicU64,
+    bytes_processed: AtomicU64,
+}
+
+/// Point-in-time copy of [`ScanProgress`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgressSnapshot {
+    pub phase: ScanPhase,
+    pub files_discovered: u64,
+    pub files_parsed: u64,
+    pub files_skipped: u64,
+    pub bytes_total: u64,
+    pub bytes_processed: u64,
+}
+
+impl ProgressSnapshot {
+    /// Percentage of discovered files that have been parsed or skipped
+    pub fn percent(&self) -> f64 {
+        match self.phase {
+            ScanPhase::Done => 100.0,
+            ScanPhase::Idle | ScanPhase::Discovering => 0.0,
+            ScanPhase::Parsing => {
+                if self.files_discovered == 0 {
+                    return 100.0;
+                }
+                let handled = self.files_parsed + self.files_skipped;
+                (handled as f64 / self.files_discovered as f64 * 100.0).min(100.0)
+            }
+        }
+    }
+}
+
+impl Default for ScanProgress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScanProgress {
+    pub const fn new() -> Self {
+        Self {
+            phase: AtomicU8::new(0),
+            files_discovered: AtomicU64::new(0),
+            files_parsed: AtomicU64::new(0),
+            files_skipped: AtomicU64::new(0),
+            bytes_total: AtomicU64::new(0),
+            bytes_processed: AtomicU64::new(0),
+        }
+    }
+
+    /// Reset all counters and enter the discovery phase
+    pub fn start(&self) {
+        self.files_discovered.store(0, Ordering::Relaxed);
+        self.files_parsed.store(0, Ordering::Relaxed);
+        self.files_skipped.store(0, Ordering::Relaxed);
+        self.bytes_total.store(0, Ordering::Relaxed);
+        self.bytes_processed.store(0, Ordering::Relaxed);
+        self.set_phase(ScanPhase::Discovering);
+    }
+
+    fn set_phase(&self, phase: ScanPhase) {
+        self.phase.store(phase as u8, Ordering::Release);
+        self.emit_event();
+    }
+
+    /// Report the progress as an [`Event::ScanProgress`]
+    fn emit_event(&self) {
+        let snapshot = self.snapshot();
+        events::emit(Event::ScanProgress {
+            phase: snapshot.phase.as_str(),
+            files_done: snapshot.files_parsed + snapshot.files_skipped,
+            files_total: snapshot.files_discovered,
+        });
+    }
+
+    /// Emit an event when handling a file completed another percent of the
+    /// discovered files, so large scans do not flood the event queue
+    fn handled(&self) {
+        let handled = self.files_parsed.load(Ordering::Relaxed)
+            + self.files_skipped.load(Ordering::Relaxed);
+        let total = self.files_discovered.load(Ordering::Relaxed).max(1);
+        if handled * 100 / total != handled.saturating_sub(1) * 100 / total {
+            self.emit_event();
+        }
+    }
+
+    fn discovered(&self, bytes: u64) {
+        self.files_discovered.fetch_add(1, Ordering::Relaxed);
+        self.bytes_total.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn parsed(&self, bytes: u64) {
+        self.files_parsed.fetch_add(1, Ordering::Relaxed);
+        self.bytes_processed.fetch_add(bytes, Ordering::Relaxed);
+        self.handled();
+    }
+
+    fn skipped(&self, bytes: u64) {
+        self.files_skipped.fetch_add(1, Ordering::Relaxed);
+        self.bytes_processed.fetch_add(bytes, Ordering::Relaxed);
+        self.handled();
+    }
+
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        ProgressSnapshot {
+            phase: ScanPhase::from_u8(self.phase.load(Ordering::Acquire)),
+            files_discovered: self.files_discovered.load(Ordering::Relaxed),
+            files_parsed: self.files_parsed.load(Ordering::Relaxed),
+            files_skipped: self.files_skipped.load(Ordering::Relaxed),
+            bytes_total: self.bytes_total.load(Ordering::Relaxed),
+            bytes_processed: self.bytes_processed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Progress of the scan driven through the Lua module
+pub static SCAN_PROGRESS: ScanProgress = ScanProgress::new();
+
+/// Definitions extracted from a single file
+#[derive(Debug, Clone)]
+pub struct ScannedFile {
+    /// Path relative to the scan root
+    pub path: PathBuf,
+    pub language: String,
+    pub definitions: Vec<Definition>,
+    /// Size of the file in bytes
+    pub size: u64,
+    /// Occurrence count of every identifier-like word in the file
+    pub identifiers: BTreeMap<String, u32>,
+    /// Last modification time in seconds since the Unix epoch, if known
+    pub modified: Option<u64>,
+    /// Size and complexity of each function, see [`crate::metrics`]
+    pub metrics: Vec<FunctionMetrics>,
+    /// Encoding the file was transcoded from, see [`crate::encoding`]
+    pub encoding: SourceEncoding,
+}
+
+/// Count identifier-like words in `source`
+///
+/// This is deliberately language agnostic: it is only used to estimate how
+/// often a symbol is referenced across the repository.
+pub fn count_identifiers(source: &str) -> BTreeMap<String, u32> {
+    let mut counts = BTreeMap::new();
+    let is_ident_char = |c: char| c.is_alphanumeric() || c == '_';
+    for word in source.split(|c: char| !is_ident_char(c)) {
+        if word.is_empty() || word.starts_with(|c: char| c.is_ascii_digit()) {
+            continue;
+        }
+        *counts.entry(word.to_string()).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Map a file path to the tree-sitter language used to parse it
+pub fn language_for_path(path: &Path) -> Option<&'static str> {
+    // Bazel files are recognised by name, most of them have no extension
+    let name = path.file_name()?.to_str()?;
+    if matches!(
+        name,
+        "BUILD" | "BUILD.bazel" | "WORKSPACE" | "WORKSPACE.bazel" | "MODULE.bazel"
+    ) {
+        return Some("starlark");
+    }
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    let language = match ext.as_str() {
+        "rs" => "rust",
+        "py" | "pyi" => "python",
+        "php" => "php",
+        "java" => "java",
+        "js" | "mjs" | "cjs" | "jsx" => "javascript",
+        "ts" | "mts" | "cts" | "tsx" => "typescript",
+        "go" => "go",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hh" | "hpp" | "hxx" => "cpp",
+        "lua" => "lua",
+        "rb" => "ruby",
+        "zig" => "zig",
+        "scala" | "sc" => "scala",
+        "swift" => "swift",
+        "ex" | "exs" => "elixir",
+        "cs" => "csharp",
+        "bzl" | "star" => "starlark",
+        _ => return None,
+    };
+    Some(language)
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map_or(false, |name| name.starts_with('.'))
+}
+
+/// Directory names that hold third-party code
+const VENDORED_DIRS: &[&str] = &[
+    "vendor",
+    "vendors",
+    "_vendor",
+    "third_party",
+    "third-party",
+    "thirdparty",
+    "3rdparty",
+    "node_modules",
+    "bower_components",
+    "jspm_packages",
+    "site-packages",
+    "__pypackages__",
+    "Pods",
+    "Carthage",
+    "Godeps",
+];
+
+/// Whether the directory at `path` looks like vendored or third-party code
+///
+/// Besides the usual package manager directories this matches checked-in
+/// SDKs, i.e. directories named like `google-cloud-sdk` or `nrf_sdk`.
+pub fn is_vendored_dir(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let lower = name.to_ascii_lowercase();
+    VENDORED_DIRS.contains(&name) || lower.ends_with("-sdk") || lower.ends_with("_sdk")
+}
+
+/// Total bytes read by a sandboxed scan unless configured otherwise
+pub const DEFAULT_SANDBOX_MAX_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Limits applied while scanning
+///
+/// The defaults scan everything reachable from the root except vendored
+/// directories, see [`is_vendored_dir`]. Sandboxed scans are meant for
+/// untrusted repositories, e.g. freshly cloned ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanOptions {
+    /// Refuse to follow symlinks that resolve outside of the scan root
+    pub sandboxed: bool,
+    /// Stop reading files once this many bytes have been read
+    pub max_total_bytes: Option<u64>,
+    /// Also scan vendored and third-party directories
+    pub include_vendored: bool,
+    /// Languages of files matching configured patterns
+    pub languages: LanguageOverrides,
+}
+
+impl ScanOptions {
+    /// Options for scanning an untrusted repository
+    pub fn sandboxed() -> Self {
+        Self {
+            sandboxed: true,
+            max_total_bytes: Some(DEFAULT_SANDBOX_MAX_BYTES),
+            include_vendored: false,
+            languages: LanguageOverrides::default(),
+        }
+    }
+
+    /// Unsandboxed options following `repo_map` of `config`
+    pub fn from_config(config: &Config) -> Self {
+        Self::with_config(config, false)
+    }
+
+    /// Options following `repo_map` of `config`, sandboxed if `sandboxed`
+    ///
+    /// Every binding builds its scan options here, so they all scan the same
+    /// files of a repository.
+    pub fn with_config(config: &Config, sandboxed: bool) -> Self {
+        let options = if sandboxed { Self::sandboxed() } else { Self::default() };
+        Self {
+            include_vendored: config.repo_map.include_vendored,
+            languages: LanguageOverrides::new(config.repo_map.languages.clone()),
+            ..options
+        }
+    }
+
+    /// Whether reading `size` more bytes after `bytes_read` would exceed the limit
+    fn exceeds_limit(&self, bytes_read: u64, size: u64) -> bool {
+        self.max_total_bytes.map_or(false, |max| bytes_read.saturating_add(size) > max)
+    }
+}
+
+/// Whether the symlink at `path` resolves to somewhere below `root`
+fn symlink_within(path: &Path, root: &Path) -> bool {
+    path.canonicalize().map_or(false, |target| target.starts_with(root))
+}
+
+/// Path and (symlink-following) metadata of a directory entry that should be
+/// scanned, or `None` for hidden entries, vendored directories unless
+/// included and, when sandboxed, symlinks leading out of `canonical_root`
+fn accept_entry(
+    entry: &std::fs::DirEntry,
+    options: &ScanOptions,
+    canonical_root: &Path,
+) -> Option<(PathBuf, std::fs::Metadata)> {
+    let path = entry.path();
+    if is_hidden(&path) {
+        return None;
+    }
+    let is_symlink = entry.file_type().map_or(false, |t| t.is_symlink());
+    if options.sandboxed && is_symlink && !symlink_within(&path, canonical_root) {
+        log::debug!("Not following {} outside of the scan root", path.display());
+        return None;
+    }
+    let metadata = std::fs::metadata(&path).ok()?;
+    if metadata.is_dir() && !options.include_vendored && is_vendored_dir(&path) {
+        log::debug!("Skipping vendored directory {}", path.display());
+        return None;
+    }
+    Some((path, metadata))
+}
+
+/// Whether the single file at `path` passes the filters of a full scan
+///
+/// Applies the rules of [`accept_entry`] to every component below `root`,
+/// rejects paths that leave `root` and files larger than
+/// [`ScanOptions::max_total_bytes`]. Unsaved buffers are only checked by name.
+fn accept_path(root: &Path, path: &Path, options: &ScanOptions) -> bool {
+    let Ok(relative) = path.strip_prefix(root) else {
+        log::debug!("Not scanning {} outside of the scan root", path.display());
+        return false;
+    };
+    let mut current = root.to_path_buf();
+    let mut components = relative.components().peekable();
+    while let Some(component) = components.next() {
+        let Component::Normal(name) = component else {
+            return false;
+        };
+        current.push(name);
+        let vendored = components.peek().is_some() && is_vendored_dir(&current);
+        if is_hidden(&current) || (vendored && !options.include_vendored) {
+            return false;
+        }
+    }
+    let size = match std::fs::metadata(path) {
+        Ok(metadata) if metadata.is_file() => {
+            if options.sandboxed {
+                let within = root.canonicalize().ok().zip(path.canonicalize().ok());
+                if !within.is_some_and(|(root, target)| target.starts_with(root)) {
+                    log::debug!("Not following {} outside of the scan root", path.display());
+                    return false;
+                }
+            }
+            metadata.len()
+        },
+        _ => match overlay::get(path) {
+            Some(overlay) => overlay.contents.len() as u64,
+            None => return false,
+        },
+    };
+    !options.exceeds_limit(0, size)
+}
+
+/// Modification time of `path` in seconds since the Unix epoch
+pub(crate) fn modified_secs(path: &Path) -> Option<u64> {
+    let modified = std::fs::metadata(path).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+/// Read and parse a single file, preferring the contents of its buffer
+///
+/// Files in another encoding are transcoded to UTF-8 first, see
+/// [`crate::encoding`]. Returns `None` if the file is binary, its definitions
+/// could not be extracted or its language was disabled, see [`crate::health`].
+/// Overlaid files count as modified when their buffer contents were set, see
+/// [`crate::overlay`].
+fn scan_file(root: &Path, path: &Path, language: &str) -> Option<ScannedFile> {
+    if crate::health::is_disabled(language) {
+        log::debug!("Skipping {}: {language} is disabled", path.display());
+        return None;
+    }
+    let (encoding, source, modified) = match overlay::get(path) {
+        Some(overlay) => (SourceEncoding::Utf8, overlay.contents.to_string(), overlay.modified),
+        None => {
+            let (encoding, source) = decode_source(std::fs::read(path).ok()?)?;
+            (encoding, source, modified_secs(path))
+        },
+    };
+    if !encoding.is_utf8() {
+        log::debug!("Transcoded {} from {}", path.display(), encoding.as_str());
+    }
+    match extract_definitions_timed(language, &source, Visibility::Public, true) {
+        Ok((definitions, metrics, timings)) => {
+            let relative = path.strip_prefix(root).unwrap_or(path).to_path_buf();
+            let size = source.len() as u64;
+            profile::record(relative.to_string_lossy().to_string(), language, size, timings);
+            Some(ScannedFile {
+                path: relative,
+                language: language.to_string(),
+                definitions,
+                size,
+                identifiers: count_identifiers(&source),
+                modified,
+                metrics,
+                encoding,
+            })
+        },
+        Err(e) => {
+            log::warn!("Failed to extract definitions from {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+/// Scan a single file below `root`, e.g. one reported by a file watcher
+///
+/// `path` is absolute or relative to `root`. Returns `None` when the file is
+/// gone, has no supported language, cannot be parsed or would be skipped by
+/// a full scan with `options`. Unsaved buffers count as files, see
+/// [`crate::overlay`].
+pub fn scan_single_file(root: &Path, path: &Path, options: &ScanOptions) -> Option<ScannedFile> {
+    let path = root.join(path);
+    if !accept_path(root, &path, options) {
+        return None;
+    }
+    let relative = path.strip_prefix(root).unwrap_or(&path);
+    let language = options.languages.language_for(relative)?;
+    scan_file(root, &path, language)
+}
+
+/// Recursively collect all regular files below `root`, skipping hidden entries
+fn discover_files(
+    root: &Path,
+    options: &ScanOptions,
+    progress: &ScanProgress,
+) -> Result<Vec<(PathBuf, u64)>> {
+    let mut files = Vec::new();
+    let mut visited = HashSet::new();
+    let mut pending = vec![root.to_path_buf()];
+    let canonical_root = root
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve {}", root.display()))?;
+
+    while let Some(dir) = pending.pop() {
+        // Symlinked directories can form cycles; only enter each directory once
+        let canonical = dir
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve {}", dir.display()))?;
+        if !visited.insert(canonical) {
+            continue;
+        }
+
+        let entries = std::fs::read_dir(&dir)
+            .with_context(|| format!("Failed to read directory {}", dir.display()))?;
+        for entry in entries.flatten() {
+            let Some((path, metadata)) = accept_entry(&entry, options, &canonical_root) else {
+                continue;
+            };
+            if metadata.is_dir() {
+                pending.push(path);
+            } else if metadata.is_file() {
+                progress.discovered(metadata.len());
+                files.push((path, metadata.len()));
+            }
+        }
+    }
+    for (path, size) in overlay::unsaved_below(root) {
+        if !accept_path(root, &path, options) {
+            continue;
+        }
+        progress.discovered(size);
+        files.push((path, size));
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Scan a directory and extract definitions from every supported file
+///
+/// Files in unsupported languages or that cannot be read as UTF-8 are counted
+/// as skipped.
+pub fn scan_directory(root: &Path, progress: &ScanProgress) -> Result<Vec<ScannedFile>> {
+    scan_directory_with(root, &ScanOptions::default(), progress)
+}
+
+/// Scan a directory like [`scan_directory`], applying `options`
+///
+/// Files that would exceed [`ScanOptions::max_total_bytes`] are skipped
+/// without being read.
+pub fn scan_directory_with(
+    root: &Path,
+    options: &ScanOptions,
+    progress: &ScanProgress,
+) -> Result<Vec<ScannedFile>> {
+    progress.start();
+    let files = match discover_files(root, options, progress) {
+        Ok(files) => files,
+        Err(e) => {
+            progress.set_phase(ScanPhase::Done);
+            return Err(e);
+        }
+    };
+    progress.set_phase(ScanPhase::Parsing);
+
+    let mut results = Vec::new();
+    let mut bytes_read = 0u64;
+    for (path, size) in files {
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        let Some(language) = options.languages.language_for(relative) else {
+            progress.skipped(size);
+            continue;
+        };
+        if options.exceeds_limit(bytes_read, size) {
+            log::debug!("Skipping {}: scan byte limit reached", path.display());
+            progress.skipped(size);
+            continue;
+        }
+        bytes_read += size;
+        match scan_file(root, &path, language) {
+            Some(file) => {
+                progress.parsed(size);
+                results.push(file);
+            }
+            None => progress.skipped(size),
+        }
+    }
+
+    progress.set_phase(ScanPhase::Done);
+    Ok(results)
+}
+
+/// Lazily scan `root`, yielding one result per parsed file
+///
+/// Unlike [`scan_directory`] nothing is collected up front: memory use is
+/// bounded by the directory structure rather than by the number of files, so
+/// this is suited to processing very large repositories. Files are yielded in
+/// directory traversal order, followed by unsaved buffers that do not exist on
+/// disk yet, and unsupported or unreadable files are skipped.
+pub fn scan_iter(root: &Path) -> Result<ScanIter> {
+    scan_iter_with(root, ScanOptions::default())
+}
+
+/// Lazily scan `root` like [`scan_iter`], applying `options`
+pub fn scan_iter_with(root: &Path, options: ScanOptions) -> Result<ScanIter> {
+    let canonical_root = root
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve {}", root.display()))?;
+    Ok(ScanIter {
+        root: root.to_path_buf(),
+        canonical_root,
+        options,
+        pending: vec![root.to_path_buf()],
+        entries: None,
+        visited: HashSet::new(),
+        unsaved: None,
+        bytes_read: 0,
+    })
+}
+
+/// Iterator returned by [`scan_iter`]
+///
+/// An `Err` item means a directory could not be read; iteration continues
+/// with the remaining directories.
+#[derive(Debug)]
+pub struct ScanIter {
+    root: PathBuf,
+    canonical_root: PathBuf,
+    options: ScanOptions,
+    pending: Vec<PathBuf>,
+    entries: Option<std::fs::ReadDir>,
+    visited: HashSet<PathBuf>,
+    /// Unsaved buffers below the root, listed once the directories are walked
+    unsaved: Option<std::vec::IntoIter<(PathBuf, u64)>>,
+    bytes_read: u64,
+}
+
+impl ScanIter {
+    /// Start reading `dir`, unless it was already visited through a symlink
+    fn enter(&mut self, dir: &Path) -> Result<()> {
+        let canonical = dir
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve {}", dir.display()))?;
+        if self.visited.insert(canonical) {
+            let entries = std::fs::read_dir(dir)
+                .with_context(|| format!("Failed to read directory {}", dir.display()))?;
+            self.entries = Some(entries);
+        }
+        Ok(())
+    }
+
+    /// Scan `path` of `size` bytes, unless it has no supported language or
+    /// would exceed the byte limit
+    fn scan(&mut self, path: &Path, size: u64) -> Option<ScannedFile> {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        let language = self.options.languages.language_for(relative)?;
+        if self.options.exceeds_limit(self.bytes_read, size) {
+            log::debug!("Skipping {}: scan byte limit reached", path.display());
+            return None;
+        }
+        self.bytes_read += size;
+        scan_file(&self.root, path, language)
+    }
+
+    /// The next unsaved buffer that is scanned like the files on disk, see
+    /// [`discover_files`]
+    fn next_unsaved(&mut self) -> Option<ScannedFile> {
+        loop {
+            let root = &self.root;
+            let unsaved = self
+                .unsaved
+                .get_or_insert_with(|| overlay::unsaved_below(root).into_iter());
+            let (path, size) = unsaved.next()?;
+            if !accept_path(&self.root, &path, &self.options) {
+                continue;
+            }
+            if let Some(file) = self.scan(&path, size) {
+                return Some(file);
+            }
+        }
+    }
+}
+
+impl Iterator for ScanIter {
+    type Item = Result<ScannedFile>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Some(entries) = self.entries.as_mut() else {
+                let Some(dir) = self.pending.pop() else {
+                    return self.next_unsaved().map(Ok);
+                };
+                if let Err(e) = self.enter(&dir) {
+                    return Some(Err(e));
+                }
+                continue;
+            };
+            let Some(entry) = entries.next() else {
+                self.entries = None;
+                continue;
+            };
+            let Ok(entry) = entry else {
+                continue;
+            };
+            let Some((path, metadata)) = accept_entry(&entry, &self.options, &self.canonical_root)
+            else {
+                continue;
+            };
+            if metadata.is_dir() {
+                self.pending.push(path);
+                continue;
+            }
+            if !metadata.is_file() {
+                continue;
+            }
+            if let Some(file) = self.scan(&path, metadata.len()) {
+                return Some(Ok(file));
+            }
+        }
+    }
+}
+
+type ScanResult = Result<Vec<ScannedFile>>;
+
+/// Result of a finished background scan, with the generation of its scan
+static BACKGROUND_RESULT: Mutex<Option<(u64, ScanResult)>> = Mutex::new(None);
+/// Generation of the last background scan started
+static BACKGROUND_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Start scanning `root` as a background task of `scheduler`, reporting into
+/// [`SCAN_PROGRESS`]
+///
+/// Returns an error if a background scan is already running or `scheduler`
+/// refuses the task. The result of an earlier scan that was not taken is
+/// dropped.
+pub fn start_background_scan(
+    root: PathBuf,
+    options: ScanOptions,
+    scheduler: &Scheduler,
+) -> Result<()> {
+    let phase = SCAN_PROGRESS.snapshot().phase;
+    if matches!(phase, ScanPhase::Discovering | ScanPhase::Parsing) {
+        return Err(Error::new(ErrorCode::InvalidInput, "A scan is already in progress"));
+    }
+    let generation = {
+        let mut slot = BACKGROUND_RESULT.lock()?;
+        slot.take();
+        BACKGROUND_GENERATION.fetch_add(1, Ordering::AcqRel) + 1
+    };
+    SCAN_PROGRESS.start();
+    let submitted = scheduler.submit(Priority::Background, move || {
+        let result = scan_directory_with(&root, &options, &SCAN_PROGRESS);
+        if let Ok(mut slot) = BACKGROUND_RESULT.lock() {
+            // A scan started since then replaces this one
+            if BACKGROUND_GENERATION.load(Ordering::Acquire) == generation {
+                *slot = Some((generation, result));
+            }
+        }
+    });
+    // The result is taken with `take_background_result`, not from the task
+    match submitted {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            SCAN_PROGRESS.set_phase(ScanPhase::Idle);
+            Err(e)
+        },
+    }
+}
+
+/// Take the result of the last background scan, if it has finished
+///
+/// Results of scans started before the last one are never returned.
+pub fn take_background_result() -> Option<ScanResult> {
+    let mut slot = BACKGROUND_RESULT.lock().ok()?;
+    let generation = BACKGROUND_GENERATION.load(Ordering::Acquire);
+    match slot.take() {
+        Some((finished, result)) if finished == generation => Some(result),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_language_for_path() {
+        assert_eq!(language_for_path(Path::new("src/lib.rs")), Some("rust"));
+        assert_eq!(language_for_path(Path::new("app.TSX")), Some("typescript"));
+        assert_eq!(language_for_path(Path::new("README.md")), None);
+        assert_eq!(language_for_path(Path::new("Makefile")), None);
+        assert_eq!(language_for_path(Path::new("pkg/BUILD.bazel")), Some("starlark"));
+        assert_eq!(language_for_path(Path::new("tools/defs.bzl")), Some("starlark"));
+    }
+
+    #[test]
+    fn test_count_identifiers() {
+        let counts = count_identifiers("let foo = bar(foo, 42); foo_bar");
+        assert_eq!(counts.get("foo"), Some(&2));
+        assert_eq!(counts.get("bar"), Some(&1));
+        assert_eq!(counts.get("foo_bar"), Some(&1));
+        assert_eq!(counts.get("42"), None);
+    }
+
+    #[test]
+    fn test_progress_percent() {
+        let progress = ScanProgress::new();
+        assert_eq!(progress.snapshot().percent(), 0.0);
+
+        progress.start();
+        progress.discovered(10);
+        progress.discovered(30);
+        progress.set_phase(ScanPhase::Parsing);
+        progress.parsed(10);
+
+        let snapshot = progress.snapshot();
+        assert_eq!(snapshot.files_discovered, 2);
+        assert_eq!(snapshot.bytes_processed, 10);
+        assert_eq!(snapshot.percent(), 50.0);
+    }
+
+    #[test]
+    fn test_scan_directory_counts() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::create_dir(dir.path().join("src"))?;
+        fs::write(dir.path().join("src/lib.rs"), "pub struct Foo {}\n")?;
+        fs::write(dir.path().join("notes.txt"), "not code")?;
+        fs::create_dir(dir.path().join(".git"))?;
+        fs::write(dir.path().join(".git/HEAD"), "ref: refs/heads/main")?;
+
+        let progress = ScanProgress::new();
+        let files = scan_directory(dir.path(), &progress)?;
+
+        let snapshot = progress.snapshot();
+        assert_eq!(snapshot.phase, ScanPhase::Done);
+        assert_eq!(snapshot.files_discovered, 2);
+        assert_eq!(snapshot.files_parsed, 1);
+        assert_eq!(snapshot.files_skipped, 1);
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, PathBuf::from("src/lib.rs"));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sandboxed_scan_stays_in_root() -> Result<()> {
+        let outside = tempfile::tempdir()?;
+        fs::write(outside.path().join("secret.rs"), "pub struct Secret {}\n")?;
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("lib.rs"), "pub struct Foo {}\n")?;
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("linked"))?;
+
+        let files = scan_directory(dir.path(), &ScanProgress::new())?;
+        assert_eq!(files.len(), 2);
+
+        let options = ScanOptions::sandboxed();
+        let files = scan_directory_with(dir.path(), &options, &ScanProgress::new())?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, PathBuf::from("lib.rs"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_byte_limit() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("a.rs"), "pub struct A {}\n")?;
+        fs::write(dir.path().join("b.rs"), "pub struct B {}\n")?;
+
+        let options = ScanOptions {
+            sandboxed: true,
+            max_total_bytes: Some(20),
+            ..Default::default()
+        };
+        let progress = ScanProgress::new();
+        let files = scan_directory_with(dir.path(), &options, &progress)?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(progress.snapshot().files_skipped, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_vendored_dir() {
+        assert!(is_vendored_dir(Path::new("web/node_modules")));
+        assert!(is_vendored_dir(Path::new("third_party")));
+        assert!(is_vendored_dir(Path::new("tools/google-cloud-sdk")));
+        assert!(!is_vendored_dir(Path::new("src")));
+        assert!(!is_vendored_dir(Path::new("sdk")));
+    }
+
+    #[test]
+    fn test_vendored_dirs_are_skipped() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::create_dir_all(dir.path().join("vendor/lib"))?;
+        fs::create_dir(dir.path().join("src"))?;
+        fs::write(dir.path().join("src/lib.rs"), "pub struct Foo {}\n")?;
+        fs::write(dir.path().join("vendor/lib/dep.rs"), "pub struct Dep {}\n")?;
+
+        let files = scan_directory(dir.path(), &ScanProgress::new())?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, PathBuf::from("src/lib.rs"));
+        assert_eq!(scan_iter(dir.path())?.count(), 1);
+
+        let options = ScanOptions {
+            include_vendored: true,
+            ..Default::default()
+        };
+        let files = scan_directory_with(dir.path(), &options, &ScanProgress::new())?;
+        assert_eq!(files.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_single_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::create_dir(dir.path().join("src"))?;
+        fs::write(dir.path().join("src/lib.rs"), "pub struct Foo {}\n")?;
+        fs::write(dir.path().join("notes.txt"), "Foo")?;
+
+        let options = ScanOptions::default();
+        let file = scan_single_file(dir.path(), Path::new("src/lib.rs"), &options).unwrap();
+        assert_eq!(file.path, PathBuf::from("src/lib.rs"));
+        let absolute = dir.path().join("src/lib.rs");
+        assert!(scan_single_file(dir.path(), &absolute, &options).is_some());
+        assert!(scan_single_file(dir.path(), Path::new("notes.txt"), &options).is_none());
+        assert!(scan_single_file(dir.path(), Path::new("gone.rs"), &options).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_computes_metrics() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source = "pub fn one() -> u32 {\n    if true { 1 } else { 2 }\n}\n";
+        fs::write(dir.path().join("lib.rs"), source)?;
+        let file = scan_single_file(dir.path(), Path::new("lib.rs"), &ScanOptions::default());
+        let file = file.unwrap();
+        assert_eq!(file.metrics, crate::metrics::function_metrics("rust", source)?);
+        assert_eq!(file.metrics.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_single_file_filters() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let outside = tempfile::tempdir()?;
+        fs::create_dir(dir.path().join(".hidden"))?;
+        fs::create_dir(dir.path().join("vendor"))?;
+        fs::write(dir.path().join(".hidden/lib.rs"), "pub struct Hidden {}\n")?;
+        fs::write(dir.path().join("vendor/lib.rs"), "pub struct Vendored {}\n")?;
+        fs::write(dir.path().join("large.rs"), "pub struct Large {}\n")?;
+        fs::write(outside.path().join("lib.rs"), "pub struct Outside {}\n")?;
+
+        let options = ScanOptions::default();
+        let scan = |path: &str, options: &ScanOptions| {
+            scan_single_file(dir.path(), Path::new(path), options)
+        };
+        assert!(scan(".hidden/lib.rs", &options).is_none());
+        assert!(scan("vendor/lib.rs", &options).is_none());
+        assert!(scan("../lib.rs", &options).is_none());
+        assert!(scan_single_file(dir.path(), &outside.path().join("lib.rs"), &options).is_none());
+        assert!(scan("large.rs", &options).is_some());
+
+        let limited = ScanOptions {
+            max_total_bytes: Some(4),
+            include_vendored: true,
+            ..Default::default()
+        };
+        assert!(scan("large.rs", &limited).is_none());
+        let vendored = ScanOptions {
+            include_vendored: true,
+            ..Default::default()
+        };
+        assert!(scan("vendor/lib.rs", &vendored).is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy_encodings() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("latin1.rs"), b"// caf\xE9\npub struct Engine {}\n")?;
+        let utf16: Vec<u8> = [0xFF, 0xFE]
+            .into_iter()
+            .chain("pub struct Car {}\n".encode_utf16().flat_map(u16::to_le_bytes))
+            .collect();
+        fs::write(dir.path().join("utf16.rs"), utf16)?;
+        fs::write(dir.path().join("binary.rs"), b"\x7FELF\0\0\0")?;
+
+        let options = ScanOptions::default();
+        let latin1 = scan_single_file(dir.path(), Path::new("latin1.rs"), &options).unwrap();
+        assert_eq!(latin1.encoding, SourceEncoding::Latin1);
+        assert_eq!(latin1.definitions[0].name(), "Engine");
+        assert_eq!(latin1.identifiers.get("café"), Some(&1));
+        let utf16 = scan_single_file(dir.path(), Path::new("utf16.rs"), &options).unwrap();
+        assert_eq!(utf16.encoding, SourceEncoding::Utf16Le);
+        assert_eq!(utf16.definitions[0].name(), "Car");
+        assert!(scan_single_file(dir.path(), Path::new("binary.rs"), &options).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_buffer_overlays() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("lib.rs"), "pub struct Saved {}\n")?;
+        overlay::set_buffer_overlay(&dir.path().join("lib.rs"), "pub struct Edited {}\n".into());
+        overlay::set_buffer_overlay(&dir.path().join("new.rs"), "pub struct New {}\n".into());
+
+        let files = scan_directory(dir.path(), &ScanProgress::new())?;
+        let names: Vec<&str> = files.iter().map(|file| file.definitions[0].name()).collect();
+        assert_eq!(names, ["Edited", "New"]);
+        let mut streamed: Vec<PathBuf> = scan_iter(dir.path())?
+            .map(|file| file.map(|file| file.path))
+            .collect::<Result<_>>()?;
+        streamed.sort();
+        assert_eq!(streamed, [PathBuf::from("lib.rs"), PathBuf::from("new.rs")]);
+        let options = ScanOptions::default();
+        assert!(scan_single_file(dir.path(), Path::new("new.rs"), &options).is_some());
+        let vendored = dir.path().join("vendor/new.rs");
+        overlay::set_buffer_overlay(&vendored, "pub struct Vendored {}\n".into());
+        assert_eq!(scan_directory(dir.path(), &ScanProgress::new())?.len(), 2);
+        overlay::remove_buffer_overlay(&vendored);
+
+        overlay::remove_buffer_overlay(&dir.path().join("lib.rs"));
+        overlay::remove_buffer_overlay(&dir.path().join("new.rs"));
+        let files = scan_directory(dir.path(), &ScanProgress::new())?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].definitions[0].name(), "Saved");
+        Ok(())
+    }
+
+    #[test]
+    fn test_language_overrides() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("BUILD"), "def build():\n    pass\n")?;
+        fs::write(dir.path().join("lib.rs"), "pub struct Foo {}\n")?;
+
+        let files = scan_directory(dir.path(), &ScanProgress::new())?;
+        assert_eq!(files.len(), 1);
+
+        let options = ScanOptions {
+            languages: LanguageOverrides::new([("BUILD".to_string(), "python".to_string())]),
+            ..Default::default()
+        };
+        let mut files = scan_directory_with(dir.path(), &options, &ScanProgress::new())?;
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(files.len(), 2);
+        assert_eq!((files[0].path.to_str(), files[0].language.as_str()), (Some("BUILD"), "python"));
+        assert_eq!(scan_iter_with(dir.path(), options)?.count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_iter_matches_scan_directory() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::create_dir_all(dir.path().join("src/nested"))?;
+        fs::write(dir.path().join("src/lib.rs"), "pub struct Foo {}\n")?;
+        fs::write(dir.path().join("src/nested/mod.rs"), "pub struct Bar {}\n")?;
+        fs::write(dir.path().join("README.md"), "# readme")?;
+
+        let mut streamed: Vec<PathBuf> = scan_iter(dir.path())?
+            .map(|file| file.map(|file| file.path))
+            .collect::<Result<_>>()?;
+        streamed.sort();
+        let collected: Vec<PathBuf> = scan_directory(dir.path(), &ScanProgress::new())?
+            .into_iter()
+            .map(|file| file.path)
+            .collect();
+        assert_eq!(streamed, collected);
+        assert_eq!(streamed.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_background_result_of_last_scan() -> Result<()> {
+        let wait_for_result = || loop {
+            if BACKGROUND_RESULT.lock().unwrap().is_some() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        };
+        let scheduler = Scheduler::new(2, 10)?;
+        let first = tempfile::tempdir()?;
+        fs::write(first.path().join("lib.rs"), "pub struct Foo {}\n")?;
+        start_background_scan(first.path().to_path_buf(), ScanOptions::default(), &scheduler)?;
+        wait_for_result();
+
+        let second = tempfile::tempdir()?;
+        fs::write(second.path().join("a.rs"), "pub struct A {}\n")?;
+        fs::write(second.path().join("b.rs"), "pub struct B {}\n")?;
+        start_background_scan(second.path().to_path_buf(), ScanOptions::default(), &scheduler)?;
+        // The first scan's result was not taken, and must not pass for the second's
+        let files = match take_background_result() {
+            Some(files) => files?,
+            None => {
+                wait_for_result();
+                take_background_result().unwrap()?
+            },
+        };
+        assert_eq!(files.len(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+//! S-expression dump of a syntax tree
+//!
+//! Useful when writing custom definition queries or attaching a parse tree to
+//! a bug report. The output matches tree-sitter's own `to_sexp`, but can be
+//! limited in depth and length so huge files stay readable.
+
+use neopilot_error::Result;
+use tree_sitter::Node;
+
+use crate::parse_source;
+
+/// Appended where nodes or text were left out
+const ELLIPSIS: &str = "…";
+
+/// Limits applied to [`sexp`] output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SexpOptions {
+    /// Levels of children shown below the root; deeper nodes are elided
+    pub max_depth: Option<usize>,
+    /// Maximum length of the output in characters
+    pub max_len: Option<usize>,
+}
+
+/// Render the tree below `root` as an S-expression
+///
+/// The tree is walked with a cursor rather than recursively, so deeply nested
+/// sources cannot overflow the stack.
+fn render(root: Node, max_depth: Option<usize>) -> String {
+    let mut out = String::new();
+    let mut cursor = root.walk();
+    let mut depth = 0;
+    'nodes: loop {
+        let node = cursor.node();
+        // Anonymous nodes are leaves; only named and missing ones are shown
+        if node.is_named() || node.is_missing() {
+            if !out.is_empty() {
+                out.push(' ');
+            }
+            if let Some(field) = cursor.field_name() {
+                out.push_str(field);
+                out.push_str(": ");
+            }
+            out.push('(');
+            if node.is_missing() {
+                out.push_str("MISSING ");
+            }
+            out.push_str(node.kind());
+
+            if max_depth.map_or(true, |max| depth < max) {
+                if cursor.goto_first_child() {
+                    depth += 1;
+                    continue;
+                }
+            } else if node.named_child_count() > 0 {
+                out.push(' ');
+                out.push_str(ELLIPSIS);
+            }
+            out.push(')');
+        }
+
+        while depth > 0 {
+            if cursor.goto_next_sibling() {
+                continue 'nodes;
+            }
+            cursor.goto_parent();
+            depth -= 1;
+            out.push(')');
+        }
+        return out;
+    }
+}
+
+/// S-expression of the syntax tree of `source`, limited by `options`
+pub fn sexp(language: &str, source: &str, options: SexpOptions) -> Result<String> {
+    let tree = parse_source(language, source)?;
+    let mut out = render(tree.root_node(), options.max_depth);
+    if let Some(max_len) = options.max_len {
+        if let Some((index, _)) = out.char_indices().nth(max_len) {
+            out.truncate(index);
+            out.push_str(ELLIPSIS);
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "fn main() { let x = 1; }\nstruct Foo;\n";
+
+    #[test]
+    fn test_matches_tree_sitter() -> Result<()> {
+        let tree = parse_source("rust", SOURCE)?;
+        assert_eq!(sexp("rust", SOURCE, SexpOptions::default())?, tree.root_node().to_sexp());
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_depth() -> Result<()> {
+        let options = SexpOptions {
+            max_depth: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            sexp("rust", SOURCE, options)?,
+            "(source_file (function_item …) (struct_item …))"
+        );
+        let options = SexpOptions {
+            max_depth: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(sexp("rust", SOURCE, options)?, "(source_file …)");
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_len() -> Result<()> {
+        let options = SexpOptions {
+            max_len: Some(12),
+            ..Default::default()
+        };
+        assert_eq!(sexp("rust", SOURCE, options)?, "(source_file…");
+        assert!(sexp("cobol", SOURCE, SexpOptions::default()).is_err());
+        Ok(())
+    }
+}
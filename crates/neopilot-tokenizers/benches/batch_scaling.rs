@@ -0,0 +1,59 @@
+// benches/batch_scaling.rs
+//
+// Encodes a batch on pools of 1 to 8 threads, through `encode_batch_parallel`
+// and `count_files`, with a tiktoken and a Hugging Face tokenizer. With
+// per-worker encoders the time per batch should fall roughly in proportion to
+// the threads, as long as the machine has as many cores; compare the reported
+// throughput across thread counts. The Hugging Face tokenizer is downloaded
+// on the first run.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use neopilot_tokenizers::{count_files, encode_batch_parallel, from_pretrained, State};
+use std::path::PathBuf;
+
+const BATCH_SIZE: usize = 256;
+
+/// A tiktoken model and a Hugging Face one, whose workers encode with copies
+const MODELS: &[&str] = &["gpt-4o", "gpt2"];
+
+fn sample_text(i: usize) -> String {
+    let line = "    println!(\"The quick brown fox jumps over the lazy dog\");";
+    format!("fn item_{i}() {{\n{line}\n}}\n").repeat(16)
+}
+
+fn batch_scaling(c: &mut Criterion) {
+    let texts: Vec<String> = (0..BATCH_SIZE).map(sample_text).collect();
+
+    let dir = tempfile::tempdir().expect("Failed to create directory");
+    let paths: Vec<PathBuf> = texts
+        .iter()
+        .enumerate()
+        .map(|(i, text)| {
+            let path = dir.path().join(format!("item_{i}.rs"));
+            std::fs::write(&path, text).expect("Failed to write file");
+            path
+        })
+        .collect();
+
+    for model in MODELS {
+        let state = State::new();
+        from_pretrained(&state, model).expect("Failed to load model");
+        let mut group = c.benchmark_group(format!("batch_scaling/{model}"));
+        group.throughput(Throughput::Elements(BATCH_SIZE as u64));
+        for threads in [1, 2, 4, 8] {
+            group.bench_with_input(BenchmarkId::new("encode_batch", threads), &threads, |b, &n| {
+                b.iter(|| encode_batch_parallel(&state, &texts, n).unwrap())
+            });
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .expect("Failed to start thread pool");
+            group.bench_with_input(BenchmarkId::new("count_files", threads), &threads, |b, _| {
+                b.iter(|| pool.install(|| count_files(&state, &paths).unwrap()))
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, batch_scaling);
+criterion_main!(benches);
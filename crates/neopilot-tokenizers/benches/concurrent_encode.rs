@@ -0,0 +1,53 @@
+// benches/concurrent_encode.rs
+//
+// Encodes from several threads at once, through the shared `State` and
+// through a `Mutex` held for the whole encode, as `State` used to do.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use neopilot_tokenizers::{encode, from_pretrained, load, State, TokenizerType};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const ENCODES_PER_THREAD: usize = 64;
+
+fn sample_text() -> String {
+    "fn main() {\n    println!(\"The quick brown fox jumps over the lazy dog\");\n}\n".repeat(32)
+}
+
+/// Run `encode_once` `ENCODES_PER_THREAD` times on each of `threads` threads
+fn run_concurrently(threads: usize, encode_once: impl Fn() + Sync) {
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                for _ in 0..ENCODES_PER_THREAD {
+                    encode_once();
+                }
+            });
+        }
+    });
+}
+
+fn concurrent_encode(c: &mut Criterion) {
+    let text = sample_text();
+    let state = State::new();
+    from_pretrained(&state, "gpt-4o").expect("Failed to load model");
+    let locked: Mutex<Arc<TokenizerType>> =
+        Mutex::new(load(&state, "gpt-4o").expect("Failed to load model"));
+
+    let mut group = c.benchmark_group("concurrent_encode");
+    for threads in [1, 2, 4, 8] {
+        group.bench_with_input(BenchmarkId::new("state", threads), &threads, |b, &threads| {
+            b.iter(|| run_concurrently(threads, || {
+                encode(&state, &text).unwrap();
+            }))
+        });
+        group.bench_with_input(BenchmarkId::new("mutex", threads), &threads, |b, &threads| {
+            b.iter(|| run_concurrently(threads, || {
+                locked.lock().unwrap().encode(&text).unwrap();
+            }))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_encode);
+criterion_main!(benches);
@@ -0,0 +1,103 @@
+//! Approximate token counts for Anthropic's Claude models
+//!
+//! Claude's tokenizer is not published, so text is encoded with cl100k_base
+//! and the count is scaled by a calibration ratio. Counts are estimates meant
+//! for budgeting prompts; the token IDs are cl100k_base IDs, so they only
+//! serve to cut or decode text, never to send to the API. Functions working
+//! on the tokens themselves, such as `truncate` and `chunk`, measure in
+//! cl100k_base tokens.
+
+use crate::error::Result;
+use crate::special::SpecialTokens;
+use crate::tiktoken::{Encoding, Tiktoken};
+
+/// Ratio of Claude tokens to cl100k_base tokens used when none is given
+///
+/// Claude usually needs more tokens than cl100k_base for the same text. The
+/// ratio errs on the side of overestimating, which keeps prompts within the
+/// context window.
+pub const DEFAULT_RATIO: f64 = 1.15;
+
+/// Calibrated approximation of a Claude tokenizer
+pub struct Anthropic {
+    base: Tiktoken,
+    ratio: f64,
+}
+
+impl Anthropic {
+    /// Approximate the tokenizer of Claude models with [`DEFAULT_RATIO`]
+    pub fn new() -> Result<Self> {
+        Self::with_ratio(DEFAULT_RATIO)
+    }
+
+    /// Approximate a tokenizer producing `ratio` tokens per cl100k_base token
+    ///
+    /// Ratios come from comparing cl100k_base counts with the token counting
+    /// API on representative prompts. Ratios that are not finite and
+    /// positive fall back to [`DEFAULT_RATIO`].
+    pub fn with_ratio(ratio: f64) -> Result<Self> {
+        let ratio = if ratio.is_finite() && ratio > 0.0 { ratio } else { DEFAULT_RATIO };
+        Ok(Self {
+            base: Tiktoken::with_encoding(Encoding::Cl100kBase)?,
+            ratio,
+        })
+    }
+
+    /// Approximate the tokenizer of Claude models with [`DEFAULT_RATIO`] on
+    /// top of `base`, which must use cl100k_base
+    pub(crate) fn with_base(base: Tiktoken) -> Self {
+        debug_assert_eq!(base.encoding(), Encoding::Cl100kBase);
+        Self {
+            base,
+            ratio: DEFAULT_RATIO,
+        }
+    }
+
+    pub fn ratio(&self) -> f64 {
+        self.ratio
+    }
+
+    /// The cl100k_base tokenizer the approximation is built on
+    pub fn base(&self) -> &Tiktoken {
+        &self.base
+    }
+
+    /// Estimated Claude token count for `base_tokens` cl100k_base tokens
+    pub fn estimate(&self, base_tokens: usize) -> usize {
+        (base_tokens as f64 * self.ratio).ceil() as usize
+    }
+
+    /// Encode text, returning cl100k_base tokens with the estimated count
+    ///
+    /// Special token strings are ordinary text: Claude prompts have no
+    /// tiktoken special tokens.
+    pub fn encode(&self, text: &str) -> (Vec<u32>, usize, usize) {
+        let (tokens, num_tokens, num_chars) =
+            self.base.encode_with_special(text, SpecialTokens::Ordinary);
+        (tokens, self.estimate(num_tokens), num_chars)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate() {
+        let tokenizer = Anthropic::with_ratio(1.5).unwrap();
+        assert_eq!(tokenizer.estimate(0), 0);
+        assert_eq!(tokenizer.estimate(3), 5);
+        assert_eq!(Anthropic::with_ratio(f64::NAN).unwrap().ratio(), DEFAULT_RATIO);
+    }
+
+    #[test]
+    fn test_encode() {
+        let tokenizer = Anthropic::new().unwrap();
+        let text = "Hello, world! <|endoftext|>";
+        let (tokens, num_tokens, num_chars) = tokenizer.encode(text);
+        assert!(num_tokens >= tokens.len());
+        assert_eq!(num_chars, text.chars().count());
+        assert_eq!(tokenizer.base().decode(&tokens).unwrap(), text);
+        assert!(tokens.iter().all(|&token| token < 100_256));
+    }
+}
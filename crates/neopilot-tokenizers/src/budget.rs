@@ -0,0 +1,100 @@
+//! Token budgets for assembling prompts
+//!
+//! Prompts are built from pieces of decreasing importance: instructions, the
+//! current file, related definitions, history. [`TokenBudget`] counts each
+//! piece as it is offered and only takes the ones that still fit, so callers
+//! offer pieces in priority order and know what is left for the rest.
+
+use std::sync::Arc;
+
+use crate::error::Result;
+use crate::TokenizerType;
+
+/// A budget of tokens filled one piece at a time
+///
+/// A piece that does not fit is rejected without reserving anything, so a
+/// smaller piece offered after it may still fit. The tokenizer is fixed when
+/// the budget is created, like [`crate::StreamDecoder`].
+#[derive(Clone)]
+pub struct TokenBudget {
+    tokenizer: Arc<TokenizerType>,
+    max_tokens: usize,
+    used: usize,
+}
+
+impl TokenBudget {
+    pub fn new(tokenizer: Arc<TokenizerType>, max_tokens: usize) -> Self {
+        Self {
+            tokenizer,
+            max_tokens,
+            used: 0,
+        }
+    }
+
+    /// Size of the budget
+    pub fn max_tokens(&self) -> usize {
+        self.max_tokens
+    }
+
+    /// Tokens reserved so far
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Tokens left to reserve
+    pub fn remaining(&self) -> usize {
+        self.max_tokens.saturating_sub(self.used)
+    }
+
+    /// Reserve the tokens of `text` if they fit in what is left
+    ///
+    /// Returns the number of tokens of `text` when it was reserved, `None`
+    /// when it did not fit.
+    pub fn try_reserve(&mut self, text: &str) -> Result<Option<usize>> {
+        let (_, num_tokens, _) = self.tokenizer.encode(text)?;
+        Ok(self.try_reserve_tokens(num_tokens).then_some(num_tokens))
+    }
+
+    /// Reserve `tokens` counted elsewhere, such as chat framing or images,
+    /// if they fit; returns whether they were reserved
+    pub fn try_reserve_tokens(&mut self, tokens: usize) -> bool {
+        if tokens > self.remaining() {
+            return false;
+        }
+        self.used += tokens;
+        true
+    }
+
+    /// Give back `tokens` reserved earlier, e.g. for a piece that was dropped
+    pub fn release(&mut self, tokens: usize) {
+        self.used = self.used.saturating_sub(tokens);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiktoken::Tiktoken;
+
+    #[test]
+    fn test_try_reserve() -> Result<()> {
+        let tokenizer = Arc::new(TokenizerType::Tiktoken(Tiktoken::new("gpt-4o")?));
+        let mut budget = TokenBudget::new(tokenizer, 5);
+
+        assert_eq!(budget.try_reserve("Hello world")?, Some(2));
+        assert_eq!(budget.remaining(), 3);
+        // Too large, nothing is reserved and smaller pieces still fit
+        assert_eq!(budget.try_reserve("one two three four")?, None);
+        assert_eq!(budget.used(), 2);
+        assert!(budget.try_reserve_tokens(3));
+        assert_eq!(budget.remaining(), 0);
+        assert_eq!(budget.try_reserve("")?, Some(0));
+        assert!(!budget.try_reserve_tokens(1));
+
+        budget.release(4);
+        assert_eq!((budget.used(), budget.remaining(), budget.max_tokens()), (1, 4, 5));
+        budget.release(10);
+        assert_eq!(budget.used(), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+//! Token streams of edited buffers
+//!
+//! Neovim reports every change to a buffer as a replaced byte range.
+//! Encoding a 10k-line buffer again after each keystroke is wasteful when
+//! only a few tokens change, so [`TokenizedBuffer`] encodes the lines around
+//! the edit again and splices the result into the previous token stream.
+//!
+//! The region encoded again runs between line starts where tokens never
+//! split, as in [`crate::incremental`]: from the start of the last non-blank
+//! line before the edited one, to the start of the first non-blank line after
+//! the edit. Both lines are left untouched by the edit, which is the safety
+//! margin keeping the splice equal to encoding the whole text. When the old
+//! stream has no token boundary at either end of the region, the whole text
+//! is encoded again.
+
+use std::sync::Arc;
+
+use crate::consistency;
+use crate::error::{Result, TokenizerError};
+use crate::incremental::last_split_point;
+use crate::offsets::OffsetUnit;
+use crate::special::SpecialTokens;
+use crate::TokenizerType;
+
+/// Text of a buffer with its tokens, kept up to date edit by edit
+///
+/// The tokenizer is fixed when the buffer is created, like
+/// [`crate::StreamDecoder`].
+#[derive(Clone)]
+pub struct TokenizedBuffer {
+    tokenizer: Arc<TokenizerType>,
+    text: String,
+    tokens: Vec<u32>,
+    /// Byte span of each token in `text`
+    spans: Vec<(usize, usize)>,
+    /// Whether spliced counts are compared with encoding the whole text
+    cross_check: bool,
+}
+
+/// Start of the first line after `from` that has a character other than
+/// whitespace
+fn next_split_point(text: &str, from: usize) -> Option<usize> {
+    text[from..].match_indices('\n').find_map(|(newline, _)| {
+        let line_start = from + newline + 1;
+        let line = text[line_start..].split('\n').next().unwrap_or_default();
+        line.chars().any(|c| !c.is_whitespace()).then_some(line_start)
+    })
+}
+
+impl TokenizedBuffer {
+    pub fn new(tokenizer: Arc<TokenizerType>, text: &str) -> Result<Self> {
+        let mut buffer = Self {
+            tokenizer,
+            text: String::new(),
+            tokens: Vec::new(),
+            spans: Vec::new(),
+            cross_check: false,
+        };
+        buffer.set_text(text)?;
+        Ok(buffer)
+    }
+
+    /// Compare the count after every spliced edit with encoding the whole
+    /// text, see [`crate::consistency`]
+    pub fn with_cross_check(mut self, enabled: bool) -> Self {
+        self.cross_check = enabled;
+        self
+    }
+
+    /// Tokens of `text` with their byte spans, starting at `offset`
+    fn encode(&self, text: &str, offset: usize) -> Result<(Vec<u32>, Vec<(usize, usize)>)> {
+        let (tokens, spans) =
+            self.tokenizer.encode_with_offsets(text, OffsetUnit::Byte, SpecialTokens::default())?;
+        let spans = spans.into_iter().map(|(start, end)| (start + offset, end + offset));
+        Ok((tokens, spans.collect()))
+    }
+
+    /// Replace the whole text, encoding it from scratch
+    pub fn set_text(&mut self, text: &str) -> Result<()> {
+        let (tokens, spans) = self.encode(text, 0)?;
+        self.text = text.to_string();
+        self.tokens = tokens;
+        self.spans = spans;
+        Ok(())
+    }
+
+    /// Replace the bytes `start..old_end` with `new_text` and return the new
+    /// token count
+    ///
+    /// Both offsets must fall on character boundaries of the current text.
+    pub fn edit(&mut self, start: usize, old_end: usize, new_text: &str) -> Result<usize> {
+        if start > old_end
+            || !self.text.is_char_boundary(start)
+            || !self.text.is_char_boundary(old_end)
+        {
+            return Err(TokenizerError::InvalidArgument(format!(
+                "Invalid edit range {start}..{old_end} of a {}-byte buffer",
+                self.text.len()
+            )));
+        }
+        let line_start = self.text[..start].rfind('\n').map_or(0, |newline| newline + 1);
+        let region_start = last_split_point(&self.text[..line_start]).unwrap_or(0);
+        self.text.replace_range(start..old_end, new_text);
+
+        let new_end = start + new_text.len();
+        let region_end = next_split_point(&self.text, new_end).unwrap_or(self.text.len());
+        let old_region_end = region_end - new_end + old_end;
+        let first = self.spans.partition_point(|&(token_start, _)| token_start < region_start);
+        let last = self.spans.partition_point(|&(token_start, _)| token_start < old_region_end);
+        let aligned = |index: usize, offset: usize| {
+            self.spans.get(index).map_or(true, |&(token_start, _)| token_start == offset)
+        };
+        if !aligned(first, region_start) || !aligned(last, old_region_end) {
+            let text = std::mem::take(&mut self.text);
+            self.set_text(&text)?;
+            return Ok(self.num_tokens());
+        }
+
+        let (tokens, spans) = self.encode(&self.text[region_start..region_end], region_start)?;
+        let shift = |offset: usize| offset + new_end - old_end;
+        for span in &mut self.spans[last..] {
+            *span = (shift(span.0), shift(span.1));
+        }
+        self.tokens.splice(first..last, tokens);
+        self.spans.splice(first..last, spans);
+        if self.cross_check {
+            consistency::cross_check(&self.tokenizer, "buffer", &self.text, self.num_tokens());
+        }
+        Ok(self.num_tokens())
+    }
+
+    /// Current text of the buffer
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Tokens of the current text
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    /// Token count of the current text
+    ///
+    /// Anthropic approximations estimate the count from the tokens, as
+    /// [`TokenizerType::encode`] does.
+    pub fn num_tokens(&self) -> usize {
+        match self.tokenizer.as_ref() {
+            TokenizerType::Anthropic(tokenizer) => tokenizer.estimate(self.tokens.len()),
+            _ => self.tokens.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiktoken::Tiktoken;
+
+    const SOURCE: &str = "fn main() {\n    let x = 1;\n\n    println!(\"{x}\");\n}\n";
+
+    #[test]
+    fn test_next_split_point() {
+        assert_eq!(next_split_point("a\n  \nb\n", 0), Some(5));
+        assert_eq!(next_split_point("a\nb", 2), None);
+        assert_eq!(next_split_point("a\n\n", 0), None);
+    }
+
+    #[test]
+    fn test_edits_match_full_encode() -> Result<()> {
+        let tokenizer = Arc::new(TokenizerType::Tiktoken(Tiktoken::new("gpt-4o")?));
+        let mut buffer = TokenizedBuffer::new(Arc::clone(&tokenizer), SOURCE)?;
+        let edits: &[(&str, &str)] = &[
+            // Typing in the middle of a line
+            ("= 1", "= 12"),
+            // Emptying a line, which then merges with the blank line after it
+            ("    let x = 12;", ""),
+            // Splitting and joining lines
+            ("println!", "\n\tprintln!"),
+            ("    \n\tprintln!", " println!"),
+            // Edits at both ends of the text
+            ("fn", "pub fn"),
+            ("}\n", "}\n\n// end\r\n"),
+        ];
+        for (old, new) in edits {
+            let start = buffer.text().find(old).unwrap();
+            let num_tokens = buffer.edit(start, start + old.len(), new)?;
+            let (expected, _, _) = tokenizer.encode(buffer.text())?;
+            assert_eq!(buffer.tokens(), expected, "after replacing {old:?} with {new:?}");
+            assert_eq!(num_tokens, expected.len());
+        }
+
+        assert!(buffer.edit(3, 2, "").is_err());
+        assert!(buffer.edit(0, buffer.text().len() + 1, "").is_err());
+        Ok(())
+    }
+}
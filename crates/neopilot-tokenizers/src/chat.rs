@@ -0,0 +1,341 @@
+//! Token counts of chat messages and their structured parts
+//!
+//! Chat APIs wrap every message in framing tokens the text does not contain,
+//! which [`ChatFraming`] adds per message, per name and for the reply.
+//!
+//! Text parts are encoded like any other text. Images are not text at all
+//! and tool calls are wrapped by the provider before the model sees them, so
+//! providers bill them by their own rules, which [`PartRules`] reproduces for
+//! each model family. Counts for non-text parts are estimates meant for
+//! budgeting prompts and err on the side of overestimating when the image
+//! size is unknown.
+
+use crate::error::{Result, TokenizerError};
+use crate::family::{detect_family, ModelFamily};
+
+/// Tokens added around every tool call for its name and argument wrapper
+pub const TOOL_CALL_OVERHEAD: usize = 3;
+
+/// Largest number of tokens an image costs on Claude models
+pub const ANTHROPIC_MAX_IMAGE_TOKENS: usize = 1600;
+
+/// Pixels per token on Claude models
+const ANTHROPIC_PIXELS_PER_TOKEN: f64 = 750.0;
+
+/// Longest edge of an image as seen by Claude models, larger ones are scaled down
+const ANTHROPIC_MAX_EDGE: f64 = 1568.0;
+
+/// Side of the square tiles OpenAI cuts high detail images into
+const OPENAI_TILE: f64 = 512.0;
+
+/// Tiles of the largest image after OpenAI's scaling, 768x2048
+const OPENAI_MAX_TILES: usize = 8;
+
+/// Detail level requested for an image, as in OpenAI's `image_url.detail`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ImageDetail {
+    /// A fixed low resolution version of the image
+    Low,
+    /// The image cut into tiles
+    High,
+    /// Chosen by the provider; counted as [`ImageDetail::High`]
+    #[default]
+    Auto,
+}
+
+impl std::str::FromStr for ImageDetail {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(Self::Low),
+            "high" => Ok(Self::High),
+            "auto" => Ok(Self::Auto),
+            _ => Err(format!("Unknown image detail '{s}', expected low, high or auto")),
+        }
+    }
+}
+
+/// A part of a chat message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessagePart {
+    Text(String),
+    /// An image of the given size in pixels, if known
+    Image {
+        width: Option<u32>,
+        height: Option<u32>,
+        detail: ImageDetail,
+    },
+    /// A call of tool `name` with its arguments as a JSON document
+    ToolCall { name: String, arguments: String },
+}
+
+/// A chat message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    pub role: String,
+    /// Name of the participant, as in OpenAI's `name` field
+    pub name: Option<String>,
+    pub content: Vec<MessagePart>,
+}
+
+impl ChatMessage {
+    /// A message whose content is a single text
+    pub fn text(role: &str, content: &str) -> Self {
+        Self {
+            role: role.to_string(),
+            name: None,
+            content: vec![MessagePart::Text(content.to_string())],
+        }
+    }
+}
+
+/// Tokens a chat API adds around messages
+///
+/// Values are OpenAI's, as documented for its chat models; they also stand
+/// in for providers that do not document theirs. Local models frame messages
+/// with their chat template instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChatFraming {
+    /// Tokens around every message
+    pub per_message: usize,
+    /// Tokens added, or saved when negative, by a message with a name
+    pub per_name: isize,
+    /// Tokens priming the reply, added once per conversation
+    pub reply: usize,
+}
+
+impl ChatFraming {
+    /// Framing used by the chat API of `model`
+    pub fn for_model(model: Option<&str>) -> Self {
+        match model {
+            // The name replaces the role in the first chat models
+            Some(model) if model.starts_with("gpt-3.5-turbo-0301") => Self {
+                per_message: 4,
+                per_name: -1,
+                reply: 3,
+            },
+            _ => Self {
+                per_message: 3,
+                per_name: 1,
+                reply: 3,
+            },
+        }
+    }
+}
+
+/// How a model family counts the parts of a message that are not text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartRules {
+    /// OpenAI vision models: `base` tokens per image, plus `per_tile` tokens
+    /// for each 512px tile of a high detail image
+    Tiles { base: usize, per_tile: usize },
+    /// Claude models: one token per 750 pixels
+    Pixels,
+}
+
+impl PartRules {
+    /// Rules of the family of `model`
+    ///
+    /// Families without published rules, such as local models, use the
+    /// rules of GPT-4o, as do models that are not known.
+    pub fn for_model(model: Option<&str>) -> Self {
+        let model = model.unwrap_or_default();
+        match detect_family(model) {
+            ModelFamily::Anthropic => Self::Pixels,
+            _ if model.contains("gpt-4o-mini") => Self::Tiles {
+                base: 2833,
+                per_tile: 5667,
+            },
+            _ => Self::Tiles {
+                base: 85,
+                per_tile: 170,
+            },
+        }
+    }
+
+    /// Tokens of an image of `width` by `height` pixels
+    ///
+    /// Images of unknown size count as the largest image the model accepts.
+    pub fn image_tokens(
+        &self,
+        width: Option<u32>,
+        height: Option<u32>,
+        detail: ImageDetail,
+    ) -> usize {
+        let size = match (width, height) {
+            (Some(width), Some(height)) if width > 0 && height > 0 => {
+                Some((f64::from(width), f64::from(height)))
+            },
+            _ => None,
+        };
+        match *self {
+            Self::Tiles { base, .. } if detail == ImageDetail::Low => base,
+            Self::Tiles { base, per_tile } => {
+                let tiles = size.map_or(OPENAI_MAX_TILES, |(width, height)| {
+                    openai_tiles(width, height)
+                });
+                base + per_tile * tiles
+            },
+            Self::Pixels => size.map_or(ANTHROPIC_MAX_IMAGE_TOKENS, |(width, height)| {
+                let scale = (ANTHROPIC_MAX_EDGE / width.max(height)).min(1.0);
+                let pixels = width * scale * height * scale;
+                let tokens = (pixels / ANTHROPIC_PIXELS_PER_TOKEN).ceil() as usize;
+                tokens.min(ANTHROPIC_MAX_IMAGE_TOKENS)
+            }),
+        }
+    }
+}
+
+/// Number of 512px tiles of an image once scaled as OpenAI does
+///
+/// Images are scaled down to fit in 2048x2048, then so that their shortest
+/// side is at most 768px.
+fn openai_tiles(width: f64, height: f64) -> usize {
+    let fit = (2048.0 / width.max(height)).min(1.0);
+    let (width, height) = (width * fit, height * fit);
+    let shorten = (768.0 / width.min(height)).min(1.0);
+    let (width, height) = (width * shorten, height * shorten);
+    ((width / OPENAI_TILE).ceil() * (height / OPENAI_TILE).ceil()) as usize
+}
+
+/// Tokens of each part of `parts`
+///
+/// `count` returns the number of tokens of a text. Tool call arguments are
+/// counted in their compact form, which is how they reach the model.
+pub(crate) fn count_parts<F>(
+    parts: &[MessagePart],
+    rules: PartRules,
+    count: F,
+) -> Result<Vec<usize>>
+where
+    F: Fn(&str) -> Result<usize>,
+{
+    parts
+        .iter()
+        .map(|part| match part {
+            MessagePart::Text(text) => count(text),
+            MessagePart::Image {
+                width,
+                height,
+                detail,
+            } => Ok(rules.image_tokens(*width, *height, *detail)),
+            MessagePart::ToolCall { name, arguments } => {
+                let arguments: serde_json::Value =
+                    serde_json::from_str(arguments).map_err(|e| {
+                        TokenizerError::InvalidArgument(format!(
+                            "Arguments of tool call '{name}' are not valid JSON: {e}"
+                        ))
+                    })?;
+                Ok(count(name)? + count(&arguments.to_string())? + TOOL_CALL_OVERHEAD)
+            },
+        })
+        .collect()
+}
+
+/// Tokens of a conversation as the chat API counts them
+///
+/// `count` returns the number of tokens of a text; the role and name of
+/// every message are counted like its content.
+pub(crate) fn count_messages<F>(
+    messages: &[ChatMessage],
+    framing: ChatFraming,
+    rules: PartRules,
+    count: F,
+) -> Result<usize>
+where
+    F: Fn(&str) -> Result<usize>,
+{
+    let mut total = framing.reply;
+    for message in messages {
+        total += framing.per_message + count(&message.role)?;
+        total += count_parts(&message.content, rules, &count)?.iter().sum::<usize>();
+        if let Some(name) = &message.name {
+            total = (total + count(name)?).saturating_add_signed(framing.per_name);
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count_words(text: &str) -> Result<usize> {
+        Ok(text.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()).count())
+    }
+
+    #[test]
+    fn test_openai_images() {
+        let rules = PartRules::for_model(Some("gpt-4o"));
+        assert_eq!(rules.image_tokens(Some(4096), Some(8192), ImageDetail::Low), 85);
+        // 1024x1024 is scaled to 768x768, four tiles
+        assert_eq!(rules.image_tokens(Some(1024), Some(1024), ImageDetail::High), 765);
+        // 2048x4096 is scaled to 768x1536, six tiles
+        assert_eq!(rules.image_tokens(Some(2048), Some(4096), ImageDetail::Auto), 1105);
+        assert_eq!(rules.image_tokens(Some(100), Some(100), ImageDetail::High), 255);
+        assert_eq!(rules.image_tokens(None, None, ImageDetail::High), 1445);
+
+        let mini = PartRules::for_model(Some("gpt-4o-mini-2024-07-18"));
+        assert_eq!(mini.image_tokens(Some(512), Some(512), ImageDetail::High), 8500);
+        assert_eq!(PartRules::for_model(None), rules);
+    }
+
+    #[test]
+    fn test_anthropic_images() {
+        let rules = PartRules::for_model(Some("claude-3-5-sonnet-20241022"));
+        assert_eq!(rules, PartRules::Pixels);
+        assert_eq!(rules.image_tokens(Some(200), Some(200), ImageDetail::Low), 54);
+        assert_eq!(rules.image_tokens(Some(1000), Some(1000), ImageDetail::Auto), 1334);
+        assert_eq!(
+            rules.image_tokens(Some(8000), Some(6000), ImageDetail::High),
+            ANTHROPIC_MAX_IMAGE_TOKENS
+        );
+        assert_eq!(rules.image_tokens(Some(0), Some(100), ImageDetail::High), 1600);
+    }
+
+    #[test]
+    fn test_count_messages() -> Result<()> {
+        let mut messages = vec![
+            ChatMessage::text("system", "You are a helpful assistant"),
+            ChatMessage::text("user", "Hello"),
+        ];
+        let rules = PartRules::for_model(Some("gpt-4o"));
+        let framing = ChatFraming::for_model(Some("gpt-4o"));
+        // 3 for the reply, then 3 + role + content for each message
+        assert_eq!(count_messages(&messages, framing, rules, count_words)?, 3 + 9 + 5);
+
+        messages[1].name = Some("alice".to_string());
+        assert_eq!(count_messages(&messages, framing, rules, count_words)?, 3 + 9 + 7);
+        let legacy = ChatFraming::for_model(Some("gpt-3.5-turbo-0301"));
+        assert_eq!(count_messages(&messages, legacy, rules, count_words)?, 3 + 10 + 6);
+        assert_eq!(count_messages(&[], framing, rules, count_words)?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_count_parts() -> Result<()> {
+        let parts = vec![
+            MessagePart::Text("What is in this picture?".to_string()),
+            MessagePart::Image {
+                width: Some(1024),
+                height: Some(1024),
+                detail: ImageDetail::Low,
+            },
+            MessagePart::ToolCall {
+                name: "get_weather".to_string(),
+                arguments: "{ \"city\": \"Paris\" }".to_string(),
+            },
+        ];
+        let counts = count_parts(&parts, PartRules::for_model(Some("gpt-4o")), count_words)?;
+        assert_eq!(counts, vec![5, 85, 2 + 2 + TOOL_CALL_OVERHEAD]);
+
+        let invalid = [MessagePart::ToolCall {
+            name: "get_weather".to_string(),
+            arguments: "{ city: Paris }".to_string(),
+        }];
+        let error = count_parts(&invalid, PartRules::Pixels, count_words).unwrap_err();
+        assert!(matches!(error, TokenizerError::InvalidArgument(_)));
+        Ok(())
+    }
+}
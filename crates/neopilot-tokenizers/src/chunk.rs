@@ -0,0 +1,123 @@
+//! Splitting text into overlapping chunks of a fixed token size
+//!
+//! Chunks are measured in tokens of the loaded tokenizer, so every chunk fits
+//! the same budget regardless of how dense the text is. Consecutive chunks
+//! share `overlap_tokens` tokens, which is what retrieval pipelines and
+//! sliding-window prompts expect.
+
+use crate::error::{Result, TokenizerError};
+use crate::truncate::{ceil_char_boundary, floor_char_boundary};
+
+/// A chunk of the input text
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Chunk {
+    pub text: String,
+    /// Character index of the first character of the chunk
+    pub start: usize,
+    /// Character index one past the last character of the chunk
+    pub end: usize,
+    /// Index of the first token of the chunk in the encoded text
+    pub token_start: usize,
+    /// Index one past the last token of the chunk in the encoded text
+    pub token_end: usize,
+}
+
+impl Chunk {
+    /// Number of tokens of the chunk, as encoded within the whole text
+    pub fn num_tokens(&self) -> usize {
+        self.token_end - self.token_start
+    }
+}
+
+/// Split `text` into chunks of at most `max_tokens` tokens
+///
+/// `encode` returns the tokens of a text and the byte span of each token.
+/// Chunks cover the whole text; a token that ends inside a multi-byte
+/// character pulls the whole character into both neighbouring chunks.
+pub(crate) fn chunk<F>(
+    text: &str,
+    max_tokens: usize,
+    overlap_tokens: usize,
+    encode: F,
+) -> Result<Vec<Chunk>>
+where
+    F: FnOnce(&str) -> Result<(Vec<u32>, Vec<(usize, usize)>)>,
+{
+    if max_tokens == 0 || overlap_tokens >= max_tokens {
+        return Err(TokenizerError::InvalidArgument(format!(
+            "overlap_tokens ({overlap_tokens}) must be smaller than max_tokens ({max_tokens})"
+        )));
+    }
+    let (tokens, spans) = encode(text)?;
+    let char_starts: Vec<usize> = text.char_indices().map(|(i, _)| i).collect();
+    let char_index = |byte: usize| char_starts.partition_point(|&start| start < byte);
+
+    let mut chunks = Vec::new();
+    let mut token_start = 0;
+    while token_start < tokens.len() {
+        let token_end = (token_start + max_tokens).min(tokens.len());
+        let byte_start = if token_start == 0 { 0 } else { spans[token_start].0 };
+        let byte_end = spans.get(token_end).map_or(text.len(), |span| span.0);
+        let byte_start = floor_char_boundary(text, byte_start);
+        let byte_end = ceil_char_boundary(text, byte_end.max(byte_start));
+        chunks.push(Chunk {
+            text: text[byte_start..byte_end].to_string(),
+            start: char_index(byte_start),
+            end: char_index(byte_end),
+            token_start,
+            token_end,
+        });
+        if token_end == tokens.len() {
+            break;
+        }
+        token_start += max_tokens - overlap_tokens;
+    }
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One token per byte, like a byte-level BPE without merges
+    fn encode_bytes(text: &str) -> Result<(Vec<u32>, Vec<(usize, usize)>)> {
+        let tokens = text.bytes().map(u32::from).collect();
+        let spans = (0..text.len()).map(|i| (i, i + 1)).collect();
+        Ok((tokens, spans))
+    }
+
+    #[test]
+    fn test_chunks_without_overlap() -> Result<()> {
+        let chunks = chunk("abcdefgh", 3, 0, encode_bytes)?;
+        let texts: Vec<&str> = chunks.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, vec!["abc", "def", "gh"]);
+        assert_eq!((chunks[1].start, chunks[1].end), (3, 6));
+        assert_eq!(chunks[2].num_tokens(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunks_with_overlap() -> Result<()> {
+        let chunks = chunk("abcdefgh", 4, 2, encode_bytes)?;
+        let texts: Vec<&str> = chunks.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, vec!["abcd", "cdef", "efgh"]);
+        assert!(chunk("", 4, 2, encode_bytes)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_characters_and_char_offsets() -> Result<()> {
+        // '€' is three byte-level tokens, split between the first two chunks
+        let chunks = chunk("a€b", 2, 0, encode_bytes)?;
+        assert_eq!(chunks[0].text, "a€");
+        assert_eq!(chunks[1].text, "€");
+        assert_eq!((chunks[2].text.as_str(), chunks[2].start, chunks[2].end), ("b", 2, 3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_overlap() {
+        assert!(chunk("abc", 2, 2, encode_bytes).is_err());
+        assert!(chunk("abc", 0, 0, encode_bytes).is_err());
+    }
+}
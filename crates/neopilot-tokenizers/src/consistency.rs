@@ -0,0 +1,171 @@
+//! Token counts that must agree whatever path computed them
+//!
+//! The same text is counted in several ways: encoded whole, appended line by
+//! line to an [`IncrementalEncoder`], kept in a [`TokenizedBuffer`], encoded
+//! by the workers of a batch, or as the content of a chat message. Budgets
+//! and the counts shown to the user mix these paths, so for identical input
+//! and the same tokenizer they must give identical totals. [`check`] counts
+//! a text through every path and reports where they disagree.
+//!
+//! While `State::cross_check` is set, which it is by default in development
+//! builds, the parallel batch and buffer paths also compare each count they
+//! return with encoding the text whole. A disagreement is logged as an error
+//! and never panics, since the counts are computed inside the editor.
+
+use std::sync::Arc;
+
+use rayon::ThreadPool;
+
+use crate::chat::{self, ChatFraming, ChatMessage, MessagePart, PartRules};
+use crate::error::Result;
+use crate::workers::WorkerEncoders;
+use crate::{IncrementalEncoder, TokenizedBuffer, TokenizerType};
+
+/// A counting path that disagrees with encoding the text whole
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// `"per_line"`, `"buffer"`, `"batch"`, `"parts"` or `"chat"`
+    pub path: &'static str,
+    /// Token count of the text encoded whole
+    pub expected: usize,
+    pub actual: usize,
+}
+
+/// Tokens of `text` appended line by line to an [`IncrementalEncoder`]
+fn count_per_line(tokenizer: &Arc<TokenizerType>, text: &str) -> Result<usize> {
+    let mut encoder = IncrementalEncoder::new(Arc::clone(tokenizer));
+    for line in text.split_inclusive('\n') {
+        encoder.append(line)?;
+    }
+    Ok(encoder.num_tokens())
+}
+
+/// Tokens of `text` encoded by a worker of the batch `pool`, with its own
+/// copy of the tokenizer where the backend needs one
+fn count_in_batch(pool: &ThreadPool, tokenizer: &Arc<TokenizerType>, text: &str) -> Result<usize> {
+    let encoders = WorkerEncoders::new(Arc::clone(tokenizer), pool.current_num_threads());
+    pool.install(|| encoders.get().encode(text).map(|(_, num_tokens, _)| num_tokens))
+}
+
+/// Tokens of `text` as the content of a chat message, without the framing
+fn count_in_chat(tokenizer: &TokenizerType, text: &str, model: Option<&str>) -> Result<usize> {
+    let framing = ChatFraming::for_model(model);
+    let rules = PartRules::for_model(model);
+    let count = |text: &str| tokenizer.encode(text).map(|(_, num_tokens, _)| num_tokens);
+    let total = |text: &str| {
+        chat::count_messages(&[ChatMessage::text("user", text)], framing, rules, count)
+    };
+    Ok(total(text)? - total("")?)
+}
+
+/// Count `text` through every path built on `tokenizer` and return the ones
+/// disagreeing with encoding it whole
+///
+/// `model` picks the chat rules, as in `count_chat_tokens`, and the `batch`
+/// path encodes on `pool`. SentencePiece tokenizers marking the start of the
+/// text are expected to disagree on the `per_line` path, see
+/// [`crate::incremental`].
+pub fn check(
+    tokenizer: &Arc<TokenizerType>,
+    text: &str,
+    model: Option<&str>,
+    pool: &ThreadPool,
+) -> Result<Vec<Mismatch>> {
+    let (_, expected, _) = tokenizer.encode(text)?;
+    let parts = chat::count_parts(
+        &[MessagePart::Text(text.to_string())],
+        PartRules::for_model(model),
+        |text| tokenizer.encode(text).map(|(_, num_tokens, _)| num_tokens),
+    )?;
+    let counts = [
+        ("per_line", count_per_line(tokenizer, text)?),
+        ("buffer", TokenizedBuffer::new(Arc::clone(tokenizer), text)?.num_tokens()),
+        ("batch", count_in_batch(pool, tokenizer, text)?),
+        ("parts", parts.iter().sum()),
+        ("chat", count_in_chat(tokenizer, text, model)?),
+    ];
+    Ok(counts
+        .into_iter()
+        .filter(|&(_, actual)| actual != expected)
+        .map(|(path, actual)| Mismatch {
+            path,
+            expected,
+            actual,
+        })
+        .collect())
+}
+
+/// Compare `num_tokens`, counted for `text` by the `path` path, with encoding
+/// `text` whole with `tokenizer`
+///
+/// Logs a disagreement and returns whether the counts agree.
+pub(crate) fn cross_check(
+    tokenizer: &TokenizerType,
+    path: &'static str,
+    text: &str,
+    num_tokens: usize,
+) -> bool {
+    let expected = match tokenizer.encode(text) {
+        Ok((_, expected, _)) => expected,
+        // The path itself would have failed on the same text
+        Err(_) => return true,
+    };
+    if expected != num_tokens {
+        log::error!(
+            "{} tokenizer counted {num_tokens} tokens on the {path} path, {expected} when \
+             encoding the {}-byte text whole",
+            tokenizer.backend(),
+            text.len()
+        );
+        return false;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anthropic::Anthropic;
+    use crate::tiktoken::Tiktoken;
+
+    const TEXTS: &[&str] = &[
+        "",
+        "hello world",
+        "fn main() {\n    let x = 1;\n  \n\n\tprintln!(\"{x}\");\r\n}\n\n",
+        "naïve café 日本語\n🦀 crab\n",
+        "trailing spaces   \n   leading\n\n\n",
+    ];
+
+    #[test]
+    fn test_paths_agree() -> Result<()> {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let tokenizers = [
+            TokenizerType::Tiktoken(Tiktoken::new("gpt-4o")?),
+            TokenizerType::Tiktoken(Tiktoken::new("gpt-4")?),
+            TokenizerType::Anthropic(Anthropic::new()?),
+        ];
+        for tokenizer in tokenizers.map(Arc::new) {
+            for text in TEXTS {
+                for model in [None, Some("gpt-4o")] {
+                    let mismatches = check(&tokenizer, text, model, &pool)?;
+                    assert!(mismatches.is_empty(), "{text:?}: {mismatches:?}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_cross_check_accepts_matching_counts() -> Result<()> {
+        let tokenizer = TokenizerType::Tiktoken(Tiktoken::new("gpt-4o")?);
+        let (_, num_tokens, _) = tokenizer.encode(TEXTS[2])?;
+        assert!(cross_check(&tokenizer, "batch", TEXTS[2], num_tokens));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cross_check_reports_mismatch() {
+        let tokenizer = TokenizerType::Tiktoken(Tiktoken::new("gpt-4o").unwrap());
+        assert!(!cross_check(&tokenizer, "buffer", "hello world", 3));
+    }
+}
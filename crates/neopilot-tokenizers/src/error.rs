@@ -1,5 +1,7 @@
 use std::path::PathBuf;
 
+use neopilot_error::ErrorCode;
+
 /// Error type for tokenizer operations
 #[derive(Debug, thiserror::Error)]
 pub enum TokenizerError {
@@ -67,15 +69,188 @@ pub enum TokenizerError {
     /// Path is not absolute
     #[error("Path is not absolute: {0:?}")]
     PathNotAbsolute(PathBuf),
-    
-    
+
+    /// Server answered with a non-success HTTP status
+    #[error("HTTP error {status} for {url}")]
+    HttpStatus {
+        /// The URL that was being downloaded
+        url: String,
+        /// HTTP status code returned by the server
+        status: u16,
+    },
+
+    /// Result could not be serialized in the requested format
+    #[error("Failed to serialize result: {0}")]
+    OutputFormatError(String),
+
+    /// Argument outside of its valid range
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+
+    /// Text contains a special token that the caller disallowed
+    #[error("Disallowed special token in text: {0}")]
+    DisallowedSpecialToken(String),
+
+    /// No tokenizer is registered under the name
+    #[error("No tokenizer registered as '{0}'")]
+    UnknownTokenizer(String),
+
+    /// A download was needed but network access is disabled
+    #[error("Network access is disabled (network.enabled = false), cannot fetch {0}")]
+    NetworkDisabled(String),
+
+    /// A chat template is missing, or failed to render the messages
+    #[error("Chat template error: {0}")]
+    ChatTemplate(String),
+
+    /// Text is larger than `tokenizer.max_input_bytes`
+    #[error("Input too large: {size} bytes, the limit is {max_size} (tokenizer.max_input_bytes)")]
+    InputTooLarge {
+        /// Size of the input in bytes
+        size: usize,
+        /// Largest input allowed in bytes
+        max_size: usize,
+    },
 }
 
 pub type Result<T> = std::result::Result<T, TokenizerError>;
 
-// Implement From for LuaError to convert our error type
+impl TokenizerError {
+    /// Shared error code for this error
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            TokenizerError::IoError(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                ErrorCode::NotFound
+            }
+            TokenizerError::UnknownTokenizer(_) => ErrorCode::NotFound,
+            TokenizerError::IoError(_) => ErrorCode::Io,
+            TokenizerError::TokenizerError(_) | TokenizerError::ModelLoadError(_) => {
+                ErrorCode::Tokenizer
+            }
+            TokenizerError::InvalidPath(_)
+            | TokenizerError::UrlError(_)
+            | TokenizerError::InvalidUrl(_)
+            | TokenizerError::PathNotAbsolute(_)
+            | TokenizerError::InvalidArgument(_)
+            | TokenizerError::DisallowedSpecialToken(_)
+            | TokenizerError::ChatTemplate(_)
+            | TokenizerError::InputTooLarge { .. } => ErrorCode::InvalidInput,
+            TokenizerError::NetworkError(_)
+            | TokenizerError::NetworkDisabled(_)
+            | TokenizerError::DownloadSizeExceeded { .. }
+            | TokenizerError::HttpStatus { .. } => ErrorCode::Network,
+            TokenizerError::SerializationError(_) => ErrorCode::Parse,
+            TokenizerError::LockError(_) => ErrorCode::Lock,
+            TokenizerError::OutputFormatError(_) => ErrorCode::Internal,
+            TokenizerError::InsecureProtocol(_)
+            | TokenizerError::DomainNotAllowed(_)
+            | TokenizerError::PathTraversalAttempt { .. }
+            | TokenizerError::InsecurePermissions(_) => ErrorCode::Security,
+        }
+    }
+}
+
+impl TokenizerError {
+    /// Stable numeric code identifying the variant
+    ///
+    /// Codes are never reused, so they can be matched on by callers and in
+    /// bug reports even if messages change.
+    pub fn number(&self) -> u16 {
+        match self {
+            TokenizerError::IoError(_) => 1001,
+            TokenizerError::TokenizerError(_) => 1002,
+            TokenizerError::InvalidPath(_) => 1003,
+            TokenizerError::NetworkError(_) => 1004,
+            TokenizerError::UrlError(_) => 1005,
+            TokenizerError::InvalidUrl(_) => 1006,
+            TokenizerError::SerializationError(_) => 1007,
+            TokenizerError::ModelLoadError(_) => 1008,
+            TokenizerError::LockError(_) => 1009,
+            TokenizerError::InsecureProtocol(_) => 1010,
+            TokenizerError::DownloadSizeExceeded { .. } => 1011,
+            TokenizerError::DomainNotAllowed(_) => 1012,
+            TokenizerError::PathTraversalAttempt { .. } => 1013,
+            TokenizerError::InsecurePermissions(_) => 1014,
+            TokenizerError::PathNotAbsolute(_) => 1015,
+            TokenizerError::HttpStatus { .. } => 1016,
+            TokenizerError::OutputFormatError(_) => 1017,
+            TokenizerError::InvalidArgument(_) => 1018,
+            TokenizerError::DisallowedSpecialToken(_) => 1019,
+            TokenizerError::UnknownTokenizer(_) => 1020,
+            TokenizerError::NetworkDisabled(_) => 1021,
+            TokenizerError::ChatTemplate(_) => 1022,
+            TokenizerError::InputTooLarge { .. } => 1023,
+        }
+    }
+
+    /// Stable string code identifying the variant, e.g. `"insecure_protocol"`
+    pub fn name(&self) -> &'static str {
+        match self {
+            TokenizerError::IoError(_) => "io",
+            TokenizerError::TokenizerError(_) => "tokenizer",
+            TokenizerError::InvalidPath(_) => "invalid_path",
+            TokenizerError::NetworkError(_) => "network",
+            TokenizerError::UrlError(_) => "url_parse",
+            TokenizerError::InvalidUrl(_) => "invalid_url",
+            TokenizerError::SerializationError(_) => "serialization",
+            TokenizerError::ModelLoadError(_) => "model_load",
+            TokenizerError::LockError(_) => "lock",
+            TokenizerError::InsecureProtocol(_) => "insecure_protocol",
+            TokenizerError::DownloadSizeExceeded { .. } => "download_size_exceeded",
+            TokenizerError::DomainNotAllowed(_) => "domain_not_allowed",
+            TokenizerError::PathTraversalAttempt { .. } => "path_traversal",
+            TokenizerError::InsecurePermissions(_) => "insecure_permissions",
+            TokenizerError::PathNotAbsolute(_) => "path_not_absolute",
+            TokenizerError::HttpStatus { .. } => "http_status",
+            TokenizerError::OutputFormatError(_) => "output_format",
+            TokenizerError::InvalidArgument(_) => "invalid_argument",
+            TokenizerError::DisallowedSpecialToken(_) => "disallowed_special_token",
+            TokenizerError::UnknownTokenizer(_) => "unknown_tokenizer",
+            TokenizerError::NetworkDisabled(_) => "network_disabled",
+            TokenizerError::ChatTemplate(_) => "chat_template",
+            TokenizerError::InputTooLarge { .. } => "input_too_large",
+        }
+    }
+
+    /// Whether the operation may succeed if attempted again
+    ///
+    /// Only transient network and I/O failures qualify; security and
+    /// validation errors never do.
+    pub fn retryable(&self) -> bool {
+        match self {
+            TokenizerError::NetworkError(_) => true,
+            TokenizerError::HttpStatus { status, .. } => *status == 429 || *status >= 500,
+            TokenizerError::IoError(e) => matches!(
+                e.kind(),
+                std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::WouldBlock
+                    | std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl From<TokenizerError> for neopilot_error::Error {
+    fn from(err: TokenizerError) -> Self {
+        neopilot_error::Error::new(err.code(), err.to_string()).with_source(err)
+    }
+}
+
+// Lua errors are produced through the shared error type so every crate
+// reports them the same way
+#[cfg(feature = "lua")]
 impl From<TokenizerError> for mlua::Error {
     fn from(err: TokenizerError) -> Self {
-        mlua::Error::RuntimeError(err.to_string())
+        neopilot_error::Error::from(err).into()
+    }
+}
+
+#[cfg(feature = "python")]
+impl From<TokenizerError> for pyo3::PyErr {
+    fn from(err: TokenizerError) -> Self {
+        neopilot_error::Error::from(err).into()
     }
 }
@@ -0,0 +1,49 @@
+//! Serialization of tokenization results for the RPC and CLI layers
+//!
+//! The wire formats are shared with the repo map, see
+//! [`neopilot_common::export`].
+
+use serde::Serialize;
+
+pub use neopilot_common::export::OutputFormat;
+
+use crate::error::{Result, TokenizerError};
+
+/// Result of [`crate::encode`] in serializable form
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TokenCount {
+    pub tokens: Vec<u32>,
+    pub num_tokens: usize,
+    pub num_chars: usize,
+}
+
+/// Serialize `value` in `format`
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T, format: OutputFormat) -> Result<Vec<u8>> {
+    neopilot_common::export::to_bytes(value, format)
+        .map_err(|e| TokenizerError::OutputFormatError(e.message().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_bytes() {
+        let count = TokenCount {
+            tokens: vec![1, 2, 3],
+            num_tokens: 3,
+            num_chars: 11,
+        };
+        let json: serde_json::Value =
+            serde_json::from_slice(&to_bytes(&count, OutputFormat::Json).unwrap()).unwrap();
+        assert_eq!(json["num_tokens"], 3);
+
+        let msgpack = to_bytes(&count, OutputFormat::MessagePack).unwrap();
+        let decoded: serde_json::Value = rmp_serde::from_slice(&msgpack).unwrap();
+        assert_eq!(decoded, json);
+
+        let cbor = to_bytes(&count, OutputFormat::Cbor).unwrap();
+        let decoded: serde_json::Value = ciborium::from_reader(cbor.as_slice()).unwrap();
+        assert_eq!(decoded, json);
+    }
+}
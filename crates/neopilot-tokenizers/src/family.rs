@@ -0,0 +1,208 @@
+//! Model family detection
+//!
+//! Classifies a model name, URL or path into a family and suggests where its
+//! tokenizer should be loaded from.
+
+use std::path::Path;
+
+use crate::huggingface::{is_valid_url, parse_repo_id};
+use crate::tiktoken::{lookup_encoding, Encoding, MODEL_ENCODINGS};
+
+/// Families of models with a known tokenizer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModelFamily {
+    OpenAI,
+    Llama,
+    Mistral,
+    Qwen,
+    Cohere,
+    Gemma,
+    Anthropic,
+    Unknown,
+}
+
+impl ModelFamily {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ModelFamily::OpenAI => "openai",
+            ModelFamily::Llama => "llama",
+            ModelFamily::Mistral => "mistral",
+            ModelFamily::Qwen => "qwen",
+            ModelFamily::Cohere => "cohere",
+            ModelFamily::Gemma => "gemma",
+            ModelFamily::Anthropic => "anthropic",
+            ModelFamily::Unknown => "unknown",
+        }
+    }
+
+    /// Publicly downloadable tokenizer.json representative of the family
+    ///
+    /// The official repos of several families are gated behind a license
+    /// click-through, so those point at ungated copies of the same tokenizer.
+    pub fn default_tokenizer_url(&self) -> Option<&'static str> {
+        match self {
+            ModelFamily::Llama => Some(
+                "https://huggingface.co/hf-internal-testing/llama-tokenizer/resolve/main/tokenizer.json",
+            ),
+            ModelFamily::Mistral => Some(
+                "https://huggingface.co/Xenova/mistral-tokenizer-v1/resolve/main/tokenizer.json",
+            ),
+            ModelFamily::Qwen => Some(
+                "https://huggingface.co/Qwen/Qwen2.5-7B-Instruct/resolve/main/tokenizer.json",
+            ),
+            ModelFamily::Cohere => Some(
+                "https://huggingface.co/Xenova/c4ai-command-r-v01-tokenizer/resolve/main/tokenizer.json",
+            ),
+            ModelFamily::Gemma => Some(
+                "https://huggingface.co/Xenova/gemma-tokenizer/resolve/main/tokenizer.json",
+            ),
+            ModelFamily::OpenAI | ModelFamily::Anthropic | ModelFamily::Unknown => None,
+        }
+    }
+}
+
+/// Where the tokenizer for a model should be loaded from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenizerSource {
+    /// Built-in tiktoken encoding
+    Tiktoken,
+    /// A tokenizer.json at the given URL or local path
+    HuggingFace(String),
+    /// Calibrated approximation of the unpublished Claude tokenizer
+    Anthropic,
+}
+
+const FAMILY_MARKERS: &[(&str, ModelFamily)] = &[
+    ("llama", ModelFamily::Llama),
+    ("codellama", ModelFamily::Llama),
+    ("mistral", ModelFamily::Mistral),
+    ("mixtral", ModelFamily::Mistral),
+    ("codestral", ModelFamily::Mistral),
+    ("qwen", ModelFamily::Qwen),
+    ("command-r", ModelFamily::Cohere),
+    ("cohere", ModelFamily::Cohere),
+    ("c4ai", ModelFamily::Cohere),
+    ("gemma", ModelFamily::Gemma),
+    ("claude", ModelFamily::Anthropic),
+];
+
+/// Classify a model name, URL or path into a [`ModelFamily`]
+///
+/// OpenAI models are the ones [`MODEL_ENCODINGS`] has an encoding for, so
+/// every model detected as OpenAI also loads with tiktoken. Tiktoken encoding
+/// names such as `cl100k_base` count as OpenAI models too.
+pub fn detect_family(model: &str) -> ModelFamily {
+    let lower = model.to_lowercase();
+    if lower.parse::<Encoding>().is_ok()
+        || lookup_encoding(&lower, MODEL_ENCODINGS.iter().copied()).is_some()
+    {
+        return ModelFamily::OpenAI;
+    }
+    FAMILY_MARKERS
+        .iter()
+        .find(|(marker, _)| lower.contains(marker))
+        .map(|(_, family)| *family)
+        .unwrap_or(ModelFamily::Unknown)
+}
+
+/// Suggest the tokenizer source for `model`
+///
+/// URLs and existing local files are used as given. OpenAI models use
+/// tiktoken, Claude models an approximation, Hugging Face Hub repository IDs
+/// (`org/name`) the repository's tokenizer, and bare names of other known
+/// families resolve to the family's reference tokenizer.
+pub fn suggest_source(model: &str) -> TokenizerSource {
+    if is_valid_url(model) || Path::new(model).exists() {
+        return TokenizerSource::HuggingFace(model.to_string());
+    }
+    let family = detect_family(model);
+    match family {
+        ModelFamily::OpenAI => return TokenizerSource::Tiktoken,
+        ModelFamily::Anthropic => return TokenizerSource::Anthropic,
+        _ => {},
+    }
+    if parse_repo_id(model).is_some() {
+        return TokenizerSource::HuggingFace(model.to_string());
+    }
+    match family.default_tokenizer_url() {
+        Some(url) => TokenizerSource::HuggingFace(url.to_string()),
+        None => TokenizerSource::HuggingFace(model.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiktoken::model_encoding;
+
+    #[test]
+    fn test_detect_family() {
+        assert_eq!(detect_family("gpt-4o"), ModelFamily::OpenAI);
+        assert_eq!(detect_family("o3-mini"), ModelFamily::OpenAI);
+        assert_eq!(detect_family("text-embedding-3-small"), ModelFamily::OpenAI);
+        assert_eq!(detect_family("meta-llama/Llama-3.1-8B"), ModelFamily::Llama);
+        assert_eq!(detect_family("Mixtral-8x7B"), ModelFamily::Mistral);
+        assert_eq!(detect_family("Qwen2.5-Coder-7B"), ModelFamily::Qwen);
+        assert_eq!(detect_family("command-r-plus"), ModelFamily::Cohere);
+        assert_eq!(
+            detect_family("https://huggingface.co/google/gemma-2b/resolve/main/tokenizer.json"),
+            ModelFamily::Gemma
+        );
+        assert_eq!(detect_family("bert-base-uncased"), ModelFamily::Unknown);
+        assert_eq!(detect_family("o200k_base"), ModelFamily::OpenAI);
+        assert_eq!(detect_family("claude-sonnet-4-5"), ModelFamily::Anthropic);
+        assert_eq!(detect_family("gpt-4oops"), ModelFamily::Unknown);
+    }
+
+    #[test]
+    fn test_every_builtin_model_is_openai() {
+        for (pattern, _) in MODEL_ENCODINGS {
+            let model = pattern.replace('*', "test");
+            assert_eq!(detect_family(&model), ModelFamily::OpenAI, "{model}");
+            assert!(model_encoding(&model).is_ok(), "{model}");
+        }
+        assert_eq!(detect_family("ft:gpt-4o-mini:org::abc"), ModelFamily::OpenAI);
+    }
+
+    #[test]
+    fn test_default_tokenizer_urls() {
+        // Gated repos fail to download without a token and an accepted license
+        const GATED: &[&str] = &["/mistralai/", "/CohereForAI/", "/google/", "/meta-llama/"];
+        for family in [
+            ModelFamily::Llama,
+            ModelFamily::Mistral,
+            ModelFamily::Qwen,
+            ModelFamily::Cohere,
+            ModelFamily::Gemma,
+        ] {
+            let url = family.default_tokenizer_url().unwrap();
+            assert!(is_valid_url(url), "{url}");
+            assert!(!GATED.iter().any(|org| url.contains(org)), "{url}");
+            assert_eq!(detect_family(url), family, "{url}");
+        }
+        assert_eq!(ModelFamily::OpenAI.default_tokenizer_url(), None);
+    }
+
+    #[test]
+    fn test_suggest_source() {
+        assert_eq!(suggest_source("gpt-4o"), TokenizerSource::Tiktoken);
+        assert_eq!(suggest_source("cl100k_base"), TokenizerSource::Tiktoken);
+        assert_eq!(suggest_source("claude-3-5-haiku-latest"), TokenizerSource::Anthropic);
+        assert_eq!(
+            suggest_source("qwen"),
+            TokenizerSource::HuggingFace(
+                ModelFamily::Qwen.default_tokenizer_url().unwrap().to_string()
+            )
+        );
+        assert_eq!(
+            suggest_source("meta-llama/Llama-3.1-8B"),
+            TokenizerSource::HuggingFace("meta-llama/Llama-3.1-8B".to_string())
+        );
+        let url = "https://example.com/llama/tokenizer.json";
+        assert_eq!(suggest_source(url), TokenizerSource::HuggingFace(url.to_string()));
+        assert_eq!(
+            suggest_source("bert-base-uncased"),
+            TokenizerSource::HuggingFace("bert-base-uncased".to_string())
+        );
+    }
+}
@@ -0,0 +1,167 @@
+//! Token counts for files on disk
+//!
+//! Repository-wide budgeting needs counts for hundreds of files. Reading them
+//! in Lua copies every file into a Lua string first; here files are memory
+//! mapped, decoded like the repo map decodes sources, see
+//! [`neopilot_common::text`], and counted in parallel.
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use memmap2::Mmap;
+use neopilot_common::text;
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::error::{Result, TokenizerError};
+use crate::long_lines::{encode_guarded, LongLineMode};
+
+/// Detected text encoding of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextEncoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    /// Not valid UTF-8 and without a byte order mark
+    Latin1,
+    /// Not text; the file is not counted
+    Binary,
+}
+
+impl TextEncoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TextEncoding::Utf8 => "utf8",
+            TextEncoding::Utf16Le => "utf16le",
+            TextEncoding::Utf16Be => "utf16be",
+            TextEncoding::Latin1 => "latin1",
+            TextEncoding::Binary => "binary",
+        }
+    }
+}
+
+/// Token count of one file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileCount {
+    pub encoding: TextEncoding,
+    pub num_tokens: usize,
+    pub num_chars: usize,
+    /// Whether invalid sequences were replaced with U+FFFD while decoding
+    pub lossy: bool,
+    /// Whether pathologically long lines were estimated, see [`crate::long_lines`]
+    pub estimated: bool,
+}
+
+/// Detect the encoding of `bytes` and decode them
+pub fn decode_text(bytes: &[u8]) -> (TextEncoding, Cow<'_, str>, bool) {
+    let Some(decoded) = text::decode(bytes) else {
+        return (TextEncoding::Binary, Cow::Borrowed(""), false);
+    };
+    let encoding = match decoded.encoding {
+        text::TextEncoding::Utf8 => TextEncoding::Utf8,
+        text::TextEncoding::Utf16Le => TextEncoding::Utf16Le,
+        text::TextEncoding::Utf16Be => TextEncoding::Utf16Be,
+        text::TextEncoding::Latin1 => TextEncoding::Latin1,
+    };
+    (encoding, decoded.text, decoded.lossy)
+}
+
+fn count_file<F>(path: &Path, max_bytes: usize, encode: &F) -> Result<FileCount>
+where
+    F: Fn(&str) -> Result<Vec<u32>>,
+{
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+    if max_bytes > 0 && size > max_bytes as u64 {
+        return Err(TokenizerError::InputTooLarge {
+            size: usize::try_from(size).unwrap_or(usize::MAX),
+            max_size: max_bytes,
+        });
+    }
+    // Mapping an empty file fails on some platforms
+    if size == 0 {
+        return Ok(FileCount {
+            encoding: TextEncoding::Utf8,
+            num_tokens: 0,
+            num_chars: 0,
+            lossy: false,
+            estimated: false,
+        });
+    }
+    // SAFETY: the map is only read while counting. A file truncated by another
+    // process in the meantime can still fault, as with any mmap based reader.
+    let map = unsafe { Mmap::map(&file)? };
+    let (encoding, text, lossy) = decode_text(&map);
+    let encoded = encode_guarded(&text, LongLineMode::default(), encode)?;
+    Ok(FileCount {
+        encoding,
+        num_tokens: encoded.num_tokens,
+        num_chars: encoded.num_chars,
+        lossy,
+        estimated: encoded.estimated,
+    })
+}
+
+/// Count the tokens of every file in `paths` in parallel
+///
+/// Results are in the same order as `paths`; a file that cannot be read, or
+/// is larger than `max_bytes` on disk, only fails its own entry. A
+/// `max_bytes` of 0 counts files of any size.
+pub(crate) fn count_files<F>(
+    paths: &[PathBuf],
+    max_bytes: usize,
+    encode: F,
+) -> Vec<Result<FileCount>>
+where
+    F: Fn(&str) -> Result<Vec<u32>> + Sync,
+{
+    paths.par_iter().map(|path| count_file(path, max_bytes, &encode)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replacement::tests::fake_encode;
+
+    #[test]
+    fn test_decode_text() {
+        let (encoding, text, lossy) = decode_text(b"\xEF\xBB\xBFcaf\xC3\xA9");
+        assert_eq!((encoding, text.as_ref(), lossy), (TextEncoding::Utf8, "café", false));
+
+        let (encoding, text, lossy) = decode_text(b"\xFF\xFEh\x00i\x00");
+        assert_eq!((encoding, text.as_ref(), lossy), (TextEncoding::Utf16Le, "hi", false));
+
+        let (encoding, text, _) = decode_text(b"\xFE\xFF\x00h\x00i");
+        assert_eq!((encoding, text.as_ref()), (TextEncoding::Utf16Be, "hi"));
+
+        let (encoding, text, lossy) = decode_text(b"caf\xE9");
+        assert_eq!((encoding, text.as_ref(), lossy), (TextEncoding::Latin1, "café", false));
+
+        assert_eq!(decode_text(b"\x7FELF\x00\x01").0, TextEncoding::Binary);
+    }
+
+    #[test]
+    fn test_count_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = dir.path().join("a.txt");
+        let empty = dir.path().join("empty.txt");
+        std::fs::write(&text, "hello").unwrap();
+        std::fs::write(&empty, "").unwrap();
+        let missing = dir.path().join("missing.txt");
+
+        let paths = [text, empty, missing];
+        let results = count_files(&paths, 0, fake_encode);
+        assert_eq!(results[0].as_ref().unwrap().num_tokens, 5);
+        assert_eq!(results[1].as_ref().unwrap().num_tokens, 0);
+        assert!(results[2].is_err());
+
+        let results = count_files(&paths, 4, fake_encode);
+        assert!(matches!(
+            results[0],
+            Err(TokenizerError::InputTooLarge { size: 5, max_size: 4 })
+        ));
+        assert_eq!(results[1].as_ref().unwrap().num_tokens, 0);
+    }
+}
@@ -0,0 +1,545 @@
+//! Tokenizers embedded in GGUF model files
+//!
+//! llama.cpp and ollama store models as GGUF files, whose metadata carries
+//! the tokenizer: the vocabulary, scores or merges and the token types. This
+//! module reads that metadata, skipping the tensors, and rebuilds an
+//! equivalent HuggingFace `tokenizer.json` so the model's own tokenizer is
+//! used without a separate download.
+//!
+//! Two tokenizer models are supported: `llama` (SentencePiece, with merges
+//! derived from the token scores) and `gpt2` (byte-level BPE). Files that
+//! embed the original `tokenizer.json` are loaded from it directly.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use serde_json::json;
+
+use crate::error::{Result, TokenizerError};
+use crate::template::ChatTemplate;
+
+const MAGIC: &[u8; 4] = b"GGUF";
+
+/// Longest string or array accepted, to fail cleanly on corrupted files
+const MAX_LENGTH: u64 = 1 << 28;
+
+/// Deepest nesting of arrays accepted, so corrupted files cannot exhaust the stack
+const MAX_DEPTH: usize = 8;
+
+/// `tokenizer.ggml.token_type` of a regular token
+const NORMAL: i64 = 1;
+/// `tokenizer.ggml.token_type` of the unknown token
+const UNKNOWN: i64 = 2;
+/// `tokenizer.ggml.token_type` of special tokens such as `<s>`
+const CONTROL: i64 = 3;
+/// `tokenizer.ggml.token_type` of tokens added on top of the vocabulary
+const USER_DEFINED: i64 = 4;
+
+/// Split pattern of Llama 3 and models copying its pre-tokenizer
+const LLAMA3_PATTERN: &str = concat!(
+    r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}",
+    r"| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+",
+);
+/// Split pattern of Qwen 2, like Llama 3's with single digits
+const QWEN2_PATTERN: &str = concat!(
+    r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}",
+    r"| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+",
+);
+
+/// A metadata value
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    UInt(u64),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    String(String),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Value::UInt(n) => i64::try_from(n).ok(),
+            Value::Int(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::UInt(n) => Some(n as f64),
+            Value::Int(n) => Some(n as f64),
+            Value::Float(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+fn invalid(message: impl std::fmt::Display) -> TokenizerError {
+    TokenizerError::ModelLoadError(format!("Invalid GGUF file: {message}"))
+}
+
+fn read_bytes<const N: usize>(reader: &mut impl Read) -> Result<[u8; N]> {
+    let mut buf = [0; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_length(reader: &mut impl Read) -> Result<u64> {
+    let length = u64::from_le_bytes(read_bytes(reader)?);
+    if length > MAX_LENGTH {
+        return Err(invalid(format!("length {length} is too large")));
+    }
+    Ok(length)
+}
+
+fn read_string(reader: &mut impl Read) -> Result<String> {
+    let mut buf = vec![0; read_length(reader)? as usize];
+    reader.read_exact(&mut buf)?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+/// Read a value of type `kind`, as numbered by the GGUF specification, inside
+/// `depth` arrays
+fn read_value(reader: &mut impl Read, kind: u32, depth: usize) -> Result<Value> {
+    Ok(match kind {
+        0 => Value::UInt(u8::from_le_bytes(read_bytes(reader)?).into()),
+        1 => Value::Int(i8::from_le_bytes(read_bytes(reader)?).into()),
+        2 => Value::UInt(u16::from_le_bytes(read_bytes(reader)?).into()),
+        3 => Value::Int(i16::from_le_bytes(read_bytes(reader)?).into()),
+        4 => Value::UInt(u32::from_le_bytes(read_bytes(reader)?).into()),
+        5 => Value::Int(i32::from_le_bytes(read_bytes(reader)?).into()),
+        6 => Value::Float(f32::from_le_bytes(read_bytes(reader)?).into()),
+        7 => Value::Bool(read_bytes::<1>(reader)?[0] != 0),
+        8 => Value::String(read_string(reader)?),
+        9 => {
+            if depth >= MAX_DEPTH {
+                return Err(invalid(format!("arrays nested deeper than {MAX_DEPTH}")));
+            }
+            let kind = u32::from_le_bytes(read_bytes(reader)?);
+            let length = read_length(reader)?;
+            // Not preallocated: the length is not trusted
+            let mut values = Vec::new();
+            for _ in 0..length {
+                values.push(read_value(reader, kind, depth + 1)?);
+            }
+            Value::Array(values)
+        },
+        10 => Value::UInt(u64::from_le_bytes(read_bytes(reader)?)),
+        11 => Value::Int(i64::from_le_bytes(read_bytes(reader)?)),
+        12 => Value::Float(f64::from_le_bytes(read_bytes(reader)?)),
+        _ => return Err(invalid(format!("unknown value type {kind}"))),
+    })
+}
+
+/// Whether the file at `path` starts with the GGUF magic
+///
+/// Checked rather than the extension, as ollama stores models as blobs
+/// named after their digest.
+pub(crate) fn is_gguf(path: &Path) -> bool {
+    let mut magic = [0; 4];
+    File::open(path).and_then(|mut file| file.read_exact(&mut magic)).is_ok() && &magic == MAGIC
+}
+
+/// The `tokenizer.*` metadata of a GGUF file, keyed by name
+///
+/// Reading stops before the tensors, so only the start of the file is read.
+pub(crate) fn read_metadata(reader: &mut impl Read) -> Result<HashMap<String, Value>> {
+    if &read_bytes::<4>(reader)? != MAGIC {
+        return Err(invalid("missing GGUF magic"));
+    }
+    let version = u32::from_le_bytes(read_bytes(reader)?);
+    if !(2..=3).contains(&version) {
+        return Err(invalid(format!("unsupported version {version}")));
+    }
+    let _tensor_count = u64::from_le_bytes(read_bytes(reader)?);
+    let metadata_count = u64::from_le_bytes(read_bytes(reader)?);
+
+    let mut metadata = HashMap::new();
+    for _ in 0..metadata_count {
+        let key = read_string(reader)?;
+        let kind = u32::from_le_bytes(read_bytes(reader)?);
+        let value = read_value(reader, kind, 0)?;
+        if key.starts_with("tokenizer.") {
+            metadata.insert(key, value);
+        }
+    }
+    Ok(metadata)
+}
+
+/// The `tokenizer.*` metadata of the GGUF file at `path`
+pub(crate) fn read_metadata_file(path: &Path) -> Result<HashMap<String, Value>> {
+    read_metadata(&mut BufReader::new(File::open(path)?))
+}
+
+/// BPE merges of a SentencePiece vocabulary, most likely first
+///
+/// Every token splitting into two other tokens yields a merge, ranked by the
+/// score of the token, as HuggingFace's converters do.
+fn sentencepiece_merges(
+    tokens: &[&str],
+    scores: &[f64],
+    ids: &HashMap<&str, u32>,
+) -> Vec<String> {
+    let mut merges: Vec<(f64, u32, u32, String)> = Vec::new();
+    for (id, token) in tokens.iter().enumerate() {
+        let score = scores.get(id).copied().unwrap_or(0.0);
+        let mut splits = Vec::new();
+        for (at, _) in token.char_indices().skip(1) {
+            let (left, right) = token.split_at(at);
+            if let (Some(&left_id), Some(&right_id)) = (ids.get(left), ids.get(right)) {
+                splits.push((score, left_id, right_id, format!("{left} {right}")));
+            }
+        }
+        splits.sort_by_key(|&(_, left_id, right_id, _)| (left_id, right_id));
+        merges.extend(splits);
+    }
+    // Stable, so equal scores keep vocabulary order
+    merges.sort_by(|a, b| b.0.total_cmp(&a.0));
+    merges.into_iter().map(|(_, _, _, merge)| merge).collect()
+}
+
+/// The chat template of GGUF tokenizer metadata, with its BOS and EOS tokens
+pub(crate) fn chat_template(metadata: &HashMap<String, Value>) -> Option<ChatTemplate> {
+    let source = metadata.get("tokenizer.chat_template")?.as_str()?;
+    let tokens = metadata.get("tokenizer.ggml.tokens").and_then(Value::as_array);
+    let token = |key: &str| {
+        let id = metadata.get(key)?.as_i64()?;
+        let token = tokens?.get(usize::try_from(id).ok()?)?;
+        token.as_str().map(str::to_string)
+    };
+    Some(ChatTemplate {
+        source: source.to_string(),
+        bos_token: token("tokenizer.ggml.bos_token_id"),
+        eos_token: token("tokenizer.ggml.eos_token_id"),
+    })
+}
+
+/// The HuggingFace `tokenizer.json` equivalent to GGUF tokenizer metadata
+pub(crate) fn tokenizer_json(metadata: &HashMap<String, Value>) -> Result<serde_json::Value> {
+    let get = |key: &str| metadata.get(key);
+    if let Some(json) = get("tokenizer.huggingface.json").and_then(Value::as_str) {
+        return Ok(serde_json::from_str(json)?);
+    }
+
+    let model = get("tokenizer.ggml.model")
+        .and_then(Value::as_str)
+        .ok_or_else(|| invalid("no tokenizer.ggml.model"))?;
+    let tokens: Vec<&str> = get("tokenizer.ggml.tokens")
+        .and_then(Value::as_array)
+        .ok_or_else(|| invalid("no tokenizer.ggml.tokens"))?
+        .iter()
+        .map(|token| token.as_str().ok_or_else(|| invalid("token is not a string")))
+        .collect::<Result<_>>()?;
+    let token_types: Vec<i64> = get("tokenizer.ggml.token_type")
+        .and_then(Value::as_array)
+        .map(|types| types.iter().map(|t| t.as_i64().unwrap_or(NORMAL)).collect())
+        .unwrap_or_default();
+    let token_type = |id: usize| token_types.get(id).copied().unwrap_or(NORMAL);
+
+    // Later duplicates of a token keep the first ID
+    let mut ids: HashMap<&str, u32> = HashMap::new();
+    let mut vocab = serde_json::Map::new();
+    for (id, &token) in tokens.iter().enumerate() {
+        if !ids.contains_key(token) {
+            ids.insert(token, id as u32);
+            vocab.insert(token.to_string(), json!(id));
+        }
+    }
+    let added_tokens: Vec<serde_json::Value> = tokens
+        .iter()
+        .enumerate()
+        .filter(|&(id, _)| matches!(token_type(id), CONTROL | USER_DEFINED))
+        .map(|(id, token)| {
+            json!({
+                "id": id,
+                "content": token,
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": token_type(id) == CONTROL,
+            })
+        })
+        .collect();
+
+    let (model, normalizer, pre_tokenizer, decoder) = match model {
+        "llama" => {
+            let scores: Vec<f64> = get("tokenizer.ggml.scores")
+                .and_then(Value::as_array)
+                .map(|scores| scores.iter().map(|s| s.as_f64().unwrap_or(0.0)).collect())
+                .unwrap_or_default();
+            let unknown = get("tokenizer.ggml.unknown_token_id")
+                .and_then(Value::as_i64)
+                .and_then(|id| tokens.get(usize::try_from(id).ok()?))
+                .or_else(|| {
+                    let id = (0..tokens.len()).find(|&id| token_type(id) == UNKNOWN)?;
+                    tokens.get(id)
+                })
+                .copied();
+            let add_space_prefix =
+                get("tokenizer.ggml.add_space_prefix").and_then(Value::as_bool).unwrap_or(true);
+
+            let mut normalizers = Vec::new();
+            let mut decoders = vec![
+                json!({"type": "Replace", "pattern": {"String": "\u{2581}"}, "content": " "}),
+                json!({"type": "ByteFallback"}),
+                json!({"type": "Fuse"}),
+            ];
+            if add_space_prefix {
+                normalizers.push(json!({"type": "Prepend", "prepend": "\u{2581}"}));
+                decoders.push(json!({"type": "Strip", "content": " ", "start": 1, "stop": 0}));
+            }
+            normalizers.push(
+                json!({"type": "Replace", "pattern": {"String": " "}, "content": "\u{2581}"}),
+            );
+            (
+                json!({
+                    "type": "BPE",
+                    "dropout": null,
+                    "unk_token": unknown,
+                    "continuing_subword_prefix": null,
+                    "end_of_word_suffix": null,
+                    "fuse_unk": true,
+                    "byte_fallback": true,
+                    "vocab": vocab,
+                    "merges": sentencepiece_merges(&tokens, &scores, &ids),
+                }),
+                json!({"type": "Sequence", "normalizers": normalizers}),
+                serde_json::Value::Null,
+                json!({"type": "Sequence", "decoders": decoders}),
+            )
+        },
+        "gpt2" => {
+            let merges: Vec<&str> = get("tokenizer.ggml.merges")
+                .and_then(Value::as_array)
+                .ok_or_else(|| invalid("no tokenizer.ggml.merges"))?
+                .iter()
+                .filter_map(Value::as_str)
+                .collect();
+            let pattern = match get("tokenizer.ggml.pre").and_then(Value::as_str) {
+                Some("llama-bpe" | "llama3" | "smaug-bpe") => Some(LLAMA3_PATTERN),
+                Some("qwen2" | "deepseek-r1-qwen") => Some(QWEN2_PATTERN),
+                _ => None,
+            };
+            let pre_tokenizer = match pattern {
+                Some(pattern) => json!({
+                    "type": "Sequence",
+                    "pretokenizers": [
+                        {
+                            "type": "Split",
+                            "pattern": {"Regex": pattern},
+                            "behavior": "Isolated",
+                            "invert": false,
+                        },
+                        {
+                            "type": "ByteLevel",
+                            "add_prefix_space": false,
+                            "trim_offsets": true,
+                            "use_regex": false,
+                        },
+                    ],
+                }),
+                // GPT-2's own split pattern
+                None => json!({
+                    "type": "ByteLevel",
+                    "add_prefix_space": false,
+                    "trim_offsets": true,
+                    "use_regex": true,
+                }),
+            };
+            (
+                json!({
+                    "type": "BPE",
+                    "dropout": null,
+                    "unk_token": null,
+                    "continuing_subword_prefix": null,
+                    "end_of_word_suffix": null,
+                    "fuse_unk": false,
+                    "byte_fallback": false,
+                    "vocab": vocab,
+                    "merges": merges,
+                }),
+                serde_json::Value::Null,
+                pre_tokenizer,
+                json!({
+                    "type": "ByteLevel",
+                    "add_prefix_space": true,
+                    "trim_offsets": true,
+                    "use_regex": true,
+                }),
+            )
+        },
+        other => {
+            return Err(TokenizerError::ModelLoadError(format!(
+                "GGUF tokenizer model {other:?} is not supported"
+            )))
+        },
+    };
+
+    Ok(json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": added_tokens,
+        "normalizer": normalizer,
+        "pre_tokenizer": pre_tokenizer,
+        "post_processor": null,
+        "decoder": decoder,
+        "model": model,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn push_string(buf: &mut Vec<u8>, s: &str) {
+        buf.extend((s.len() as u64).to_le_bytes());
+        buf.extend(s.as_bytes());
+    }
+
+    /// A GGUF file with string, string array, f32 array and i32 array metadata
+    fn gguf_file(
+        strings: &[(&str, &str)],
+        arrays: &[(&str, &[&str])],
+        scores: &[f32],
+    ) -> Vec<u8> {
+        let mut buf = MAGIC.to_vec();
+        buf.extend(3u32.to_le_bytes());
+        buf.extend(0u64.to_le_bytes());
+        let count = strings.len() + arrays.len() + usize::from(!scores.is_empty());
+        buf.extend((count as u64).to_le_bytes());
+        for (key, value) in strings {
+            push_string(&mut buf, key);
+            buf.extend(8u32.to_le_bytes());
+            push_string(&mut buf, value);
+        }
+        for (key, values) in arrays {
+            push_string(&mut buf, key);
+            buf.extend(9u32.to_le_bytes());
+            buf.extend(8u32.to_le_bytes());
+            buf.extend((values.len() as u64).to_le_bytes());
+            for value in values.iter() {
+                push_string(&mut buf, value);
+            }
+        }
+        if !scores.is_empty() {
+            push_string(&mut buf, "tokenizer.ggml.scores");
+            buf.extend(9u32.to_le_bytes());
+            buf.extend(6u32.to_le_bytes());
+            buf.extend((scores.len() as u64).to_le_bytes());
+            for score in scores {
+                buf.extend(score.to_le_bytes());
+            }
+        }
+        buf
+    }
+
+    #[test]
+    fn test_nested_arrays() {
+        // Arrays of single element arrays down to one bool
+        let nested = |depth: usize| {
+            let mut buf = Vec::new();
+            for level in 1..=depth {
+                let kind: u32 = if level < depth { 9 } else { 7 };
+                buf.extend(kind.to_le_bytes());
+                buf.extend(1u64.to_le_bytes());
+            }
+            buf.push(1);
+            buf
+        };
+        assert!(read_value(&mut &nested(MAX_DEPTH)[..], 9, 0).is_ok());
+        assert!(matches!(
+            read_value(&mut &nested(MAX_DEPTH + 1)[..], 9, 0),
+            Err(TokenizerError::ModelLoadError(_))
+        ));
+    }
+
+    fn load(file: &[u8]) -> tokenizers::Tokenizer {
+        let metadata = read_metadata(&mut &file[..]).unwrap();
+        let json = tokenizer_json(&metadata).unwrap();
+        tokenizers::Tokenizer::from_str(&json.to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_read_metadata() -> Result<()> {
+        let file = gguf_file(
+            &[("general.name", "test"), ("tokenizer.ggml.model", "gpt2")],
+            &[("tokenizer.ggml.tokens", &["a", "b"])],
+            &[],
+        );
+        let metadata = read_metadata(&mut &file[..])?;
+        assert!(!metadata.contains_key("general.name"));
+        assert_eq!(metadata["tokenizer.ggml.model"], Value::String("gpt2".to_string()));
+        assert_eq!(metadata["tokenizer.ggml.tokens"].as_array().map(<[_]>::len), Some(2));
+        assert_eq!(chat_template(&metadata), None);
+
+        let file = gguf_file(&[("tokenizer.chat_template", "{{ messages }}")], &[], &[]);
+        let template = chat_template(&read_metadata(&mut &file[..])?).unwrap();
+        assert_eq!(template.source, "{{ messages }}");
+        assert_eq!(template.bos_token, None);
+
+        assert!(matches!(
+            read_metadata(&mut &b"GGML\x03\x00\x00\x00"[..]),
+            Err(TokenizerError::ModelLoadError(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sentencepiece() {
+        let tokens = ["<unk>", "<s>", "\u{2581}", "a", "b", "\u{2581}a", "ab", "\u{2581}ab"];
+        let file = gguf_file(
+            &[("tokenizer.ggml.model", "llama")],
+            &[("tokenizer.ggml.tokens", &tokens)],
+            &[0.0, 0.0, -1.0, -1.0, -1.0, -3.0, -4.0, -2.0],
+        );
+        let tokenizer = load(&file);
+        let encoding = tokenizer.encode("ab", false).unwrap();
+        assert_eq!(encoding.get_ids(), &[7]);
+        assert_eq!(tokenizer.decode(&[7, 5], false).unwrap(), "ab a");
+    }
+
+    #[test]
+    fn test_byte_level_bpe() {
+        let tokens = ["a", "b", "ab", "\u{120}", "\u{120}ab"];
+        let file = gguf_file(
+            &[("tokenizer.ggml.model", "gpt2")],
+            &[
+                ("tokenizer.ggml.tokens", &tokens),
+                ("tokenizer.ggml.merges", &["a b", "\u{120} ab"]),
+            ],
+            &[],
+        );
+        let tokenizer = load(&file);
+        let encoding = tokenizer.encode("ab ab", false).unwrap();
+        assert_eq!(encoding.get_ids(), &[2, 4]);
+        assert_eq!(tokenizer.decode(&[2, 4], false).unwrap(), "ab ab");
+    }
+}
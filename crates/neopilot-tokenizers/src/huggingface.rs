@@ -1,41 +1,280 @@
 //! HuggingFace tokenizer implementation for models from the HuggingFace Hub
 
 use crate::error::{Result, TokenizerError};
+use crate::gguf;
+use crate::offsets::OffsetUnit;
+use crate::retry::{network_error, with_retries, RetryPolicy};
+use crate::special::SpecialTokens;
+use crate::template::ChatTemplate;
+use crate::security::{check_permissions, ensure_within, secure_dir, secure_file};
+use crate::vocab::{VocabToken, Vocabulary};
+use neopilot_common::events::{self, Event};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, LOCATION, USER_AGENT};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
 use tokenizers::Tokenizer;
 use url::Url;
 
 const MAX_DOWNLOAD_SIZE: u64 = 100 * 1024 * 1024; // 100MB
 
+const HUB_URL: &str = "https://huggingface.co";
+
+/// Redirects followed by a download before it fails
+const MAX_REDIRECTS: usize = 10;
+
+/// `User-Agent` of downloads unless configured otherwise
+pub const DEFAULT_USER_AGENT: &str = concat!("neopilot-tokenizers/", env!("CARGO_PKG_VERSION"));
+
+/// Headers sent with tokenizer downloads
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct DownloadHeaders {
+    /// `User-Agent`, [`DEFAULT_USER_AGENT`] when unset
+    pub user_agent: Option<String>,
+    /// Headers for the configured sources, e.g. the credentials of an
+    /// artifact mirror: they are only sent to the hosts of mirrors and of
+    /// tokenizer URLs given as the model, never to Hugging Face hosts, and
+    /// are dropped when a download is redirected to another origin
+    pub headers: Vec<(String, String)>,
+}
+
+// Written by hand to keep header values, which often hold credentials, out of logs
+impl std::fmt::Debug for DownloadHeaders {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<&str> = self.headers.iter().map(|(name, _)| name.as_str()).collect();
+        f.debug_struct("DownloadHeaders")
+            .field("user_agent", &self.user_agent)
+            .field("headers", &names)
+            .finish()
+    }
+}
+
+impl DownloadHeaders {
+    /// Check that every header is valid, so bad configuration fails when set
+    pub fn validate(&self) -> Result<()> {
+        self.header_map(true).map(drop)
+    }
+
+    /// Headers of a request, leaving out the configured ones unless
+    /// `configured` is set
+    fn header_map(&self, configured: bool) -> Result<HeaderMap> {
+        let invalid = |name: &str, e: &dyn std::fmt::Display| {
+            TokenizerError::InvalidArgument(format!("Invalid download header '{name}': {e}"))
+        };
+        let user_agent = self.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT);
+        let mut map = HeaderMap::new();
+        map.insert(
+            USER_AGENT,
+            HeaderValue::from_str(user_agent).map_err(|e| invalid("User-Agent", &e))?,
+        );
+        for (name, value) in self.headers.iter().filter(|_| configured) {
+            let header = HeaderName::from_str(name).map_err(|e| invalid(name, &e))?;
+            let mut value = HeaderValue::from_str(value).map_err(|e| invalid(name, &e))?;
+            value.set_sensitive(true);
+            map.insert(header, value);
+        }
+        Ok(map)
+    }
+}
+
+/// How tokenizers are downloaded
+#[derive(Clone, PartialEq, Eq)]
+pub struct DownloadOptions {
+    /// How often failed downloads are retried
+    pub policy: RetryPolicy,
+    /// Whether tokenizers may be downloaded; with network access disabled,
+    /// tokenizers downloaded earlier are still loaded from the cache and
+    /// anything else fails with [`TokenizerError::NetworkDisabled`]
+    pub network_enabled: bool,
+    /// Access token unlocking gated Hub repositories such as Llama, only
+    /// sent to Hugging Face hosts
+    pub hf_token: Option<String>,
+    pub headers: DownloadHeaders,
+    /// Endpoints tried in order when a download fails, see
+    /// [`HuggingFaceTokenizer::with_options`]
+    pub mirrors: Vec<String>,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            policy: RetryPolicy::default(),
+            network_enabled: true,
+            hf_token: None,
+            headers: DownloadHeaders::default(),
+            mirrors: Vec::new(),
+        }
+    }
+}
+
+// Written by hand to keep the access token out of logs
+impl std::fmt::Debug for DownloadOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DownloadOptions")
+            .field("policy", &self.policy)
+            .field("network_enabled", &self.network_enabled)
+            .field("hf_token", &self.hf_token.as_ref().map(|_| "<redacted>"))
+            .field("headers", &self.headers)
+            .field("mirrors", &self.mirrors)
+            .finish()
+    }
+}
+
 /// Wrapper around the HuggingFace tokenizer
 pub struct HuggingFaceTokenizer {
     tokenizer: Tokenizer,
+    /// Copy of `tokenizer` that encodes special tokens as text, built on first use
+    ordinary: OnceLock<Tokenizer>,
+    chat_template: Option<ChatTemplate>,
 }
 
 impl HuggingFaceTokenizer {
     /// Create a new HuggingFace tokenizer
     ///
     /// # Arguments
-    /// * `model` - The model name (e.g., "bert-base-uncased") or path to a local tokenizer file
+    /// * `model` - The model name (e.g., "bert-base-uncased"), a Hugging Face Hub
+    ///   repository (e.g., "meta-llama/Llama-3.1-8B", optionally followed by
+    ///   "@revision") or path to a local tokenizer file
     pub fn new(model: &str) -> Result<Self> {
-        let tokenizer_path = if is_valid_url(model) {
-            Self::download_tokenizer(model)?
+        Self::with_options(model, &DownloadOptions::default())
+    }
+
+    /// Create a new HuggingFace tokenizer, downloading it as `options` say
+    ///
+    /// Local GGUF model files load the tokenizer embedded in them, see
+    /// [`HuggingFaceTokenizer::from_gguf`].
+    ///
+    /// For Hub repositories a mirror is a Hub endpoint such as
+    /// `https://hf-mirror.com`, serving the same `/{repo}/resolve/{revision}/`
+    /// paths; for tokenizer URLs it is another URL of the same file. Mirrors
+    /// are tried in order, and if they all fail the error of the primary
+    /// source is returned.
+    pub fn with_options(model: &str, options: &DownloadOptions) -> Result<Self> {
+        let mirrors = &options.mirrors;
+        let model_url = is_valid_url(model).then_some(model);
+        let sources = Sources {
+            options,
+            header_hosts: mirrors
+                .iter()
+                .map(String::as_str)
+                .chain(model_url)
+                .filter_map(|url| Url::parse(url).ok()?.host_str().map(str::to_string))
+                .collect(),
+        };
+        let (tokenizer_path, chat_template) = if let Some(model_url) = model_url {
+            let urls: Vec<String> = std::iter::once(model_url.to_string())
+                .chain(mirrors.iter().cloned())
+                .collect();
+            (Self::download_any(&urls, &sources)?, None)
+        } else if is_insecure_url(model) {
+            return Err(TokenizerError::InsecureProtocol(model.to_string()));
+        } else if let Some((repo, revision)) =
+            parse_repo_id(model).filter(|_| !Path::new(model).exists())
+        {
+            let hub = HubFiles {
+                repo,
+                revision,
+                mirrors,
+            };
+            let path = Self::download_from_hub(&hub, &sources)?;
+            // The tokenizer is usable without its template, so failing to
+            // download the configuration is not an error
+            let urls = hub.urls("tokenizer_config.json");
+            let config = Self::download_any(&urls, &sources)
+                .and_then(|path| Ok(std::fs::read_to_string(path)?))
+                .and_then(|json| ChatTemplate::from_tokenizer_config(&json));
+            let chat_template = config.unwrap_or_else(|e| {
+                log::debug!("No chat template for {repo}: {e}");
+                None
+            });
+            (path, chat_template)
         } else {
             // For local models, ensure they exist and are accessible
             let path = Path::new(model);
             if !path.exists() {
                 return Err(TokenizerError::InvalidPath(path.to_path_buf()));
             }
-            path.to_path_buf()
+            if gguf::is_gguf(path) {
+                return Self::from_gguf(path);
+            }
+            let chat_template = path.parent().and_then(ChatTemplate::from_directory);
+            (path.to_path_buf(), chat_template)
         };
 
         let tokenizer = Tokenizer::from_file(tokenizer_path)
             .map_err(|e| TokenizerError::TokenizerError(e.to_string()))?;
 
-        Ok(Self { tokenizer })
+        Ok(Self::from_tokenizer(tokenizer).with_chat_template(chat_template))
+    }
+
+    /// Load the tokenizer embedded in a GGUF model file, as used by llama.cpp and ollama
+    ///
+    /// Only the metadata at the start of the file is read, so this is quick
+    /// even for large models.
+    pub fn from_gguf(path: &Path) -> Result<Self> {
+        let metadata = gguf::read_metadata_file(path)?;
+        let json = gguf::tokenizer_json(&metadata)?;
+        Ok(Self::from_json(&json.to_string())?.with_chat_template(gguf::chat_template(&metadata)))
+    }
+
+    /// Load a tokenizer from the contents of a `tokenizer.json` file
+    ///
+    /// Nothing is read from disk or downloaded, so callers can embed or fetch
+    /// the JSON themselves.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let tokenizer = Tokenizer::from_str(json)
+            .map_err(|e| TokenizerError::TokenizerError(e.to_string()))?;
+        Ok(Self::from_tokenizer(tokenizer))
+    }
+
+    fn from_tokenizer(tokenizer: Tokenizer) -> Self {
+        Self {
+            tokenizer,
+            ordinary: OnceLock::new(),
+            chat_template: None,
+        }
+    }
+
+    /// A copy for another worker thread
+    ///
+    /// BPE models of the tokenizers library cache merges behind a lock, so
+    /// threads encoding with one tokenizer contend on it; each copy gets its
+    /// own cache.
+    pub(crate) fn worker_copy(&self) -> Self {
+        Self {
+            tokenizer: self.tokenizer.clone(),
+            ordinary: OnceLock::new(),
+            chat_template: self.chat_template.clone(),
+        }
+    }
+
+    /// Replace the chat template of the tokenizer
+    pub fn with_chat_template(mut self, chat_template: Option<ChatTemplate>) -> Self {
+        self.chat_template = chat_template;
+        self
+    }
+
+    /// The chat template shipped with the tokenizer, if any
+    ///
+    /// It comes from `tokenizer_config.json` next to a local or Hub
+    /// tokenizer, or from the metadata of a GGUF file.
+    pub fn chat_template(&self) -> Option<&ChatTemplate> {
+        self.chat_template.as_ref()
+    }
+
+    /// The tokenizer to encode with, according to `special`
+    fn tokenizer_for(&self, special: SpecialTokens) -> &Tokenizer {
+        match special {
+            SpecialTokens::Special => &self.tokenizer,
+            SpecialTokens::Ordinary => self.ordinary.get_or_init(|| {
+                let mut tokenizer = self.tokenizer.clone();
+                tokenizer.set_encode_special_tokens(true);
+                tokenizer
+            }),
+        }
     }
 
-    /// Encode text into tokens
+    /// Encode text into tokens, treating special token strings as special
     ///
     /// # Arguments
     /// * `text` - The text to encode
@@ -46,7 +285,19 @@ impl HuggingFaceTokenizer {
     /// - The number of tokens
     /// - The number of characters in the input text
     pub fn encode(&self, text: &str) -> Result<(Vec<u32>, usize, usize)> {
-        let encoding = self.tokenizer
+        self.encode_with_special(text, SpecialTokens::Special)
+    }
+
+    /// Encode text into tokens, handling special token strings as `special` says
+    ///
+    /// No BOS or EOS tokens are added in either case.
+    pub fn encode_with_special(
+        &self,
+        text: &str,
+        special: SpecialTokens,
+    ) -> Result<(Vec<u32>, usize, usize)> {
+        let encoding = self
+            .tokenizer_for(special)
             .encode(text, false)
             .map_err(|e| TokenizerError::TokenizerError(e.to_string()))?;
 
@@ -57,50 +308,176 @@ impl HuggingFaceTokenizer {
         Ok((tokens, num_tokens, num_chars))
     }
 
+    /// Encode text into tokens with the span of each token in `unit`
+    pub fn encode_with_offsets(
+        &self,
+        text: &str,
+        unit: OffsetUnit,
+        special: SpecialTokens,
+    ) -> Result<(Vec<u32>, Vec<(usize, usize)>)> {
+        let tokenizer = self.tokenizer_for(special);
+        // `encode` reports byte offsets, `encode_char_offsets` char offsets
+        let encoding = match unit {
+            OffsetUnit::Char => tokenizer.encode_char_offsets(text, false),
+            OffsetUnit::Byte => tokenizer.encode(text, false),
+        }
+        .map_err(|e| TokenizerError::TokenizerError(e.to_string()))?;
+
+        Ok((encoding.get_ids().to_vec(), encoding.get_offsets().to_vec()))
+    }
+
+    /// Added tokens flagged as special and their IDs
+    pub fn special_tokens(&self) -> Vec<(String, u32)> {
+        self.tokenizer
+            .get_added_tokens_decoder()
+            .into_iter()
+            .filter(|(_, token)| token.special)
+            .map(|(id, token)| (token.content, id))
+            .collect()
+    }
+
+    /// Decode tokens into text, keeping special tokens
+    pub fn decode(&self, tokens: &[u32]) -> Result<String> {
+        self.decode_with_special(tokens, false)
+    }
+
+    /// Decode tokens into text, leaving out special tokens such as
+    /// `<|im_end|>` and padding if `skip_special_tokens` is set
+    pub fn decode_with_special(&self, tokens: &[u32], skip_special_tokens: bool) -> Result<String> {
+        self.tokenizer
+            .decode(tokens, skip_special_tokens)
+            .map_err(|e| TokenizerError::TokenizerError(e.to_string()))
+    }
+
+    /// ID of `token` in the vocabulary, including added tokens
+    pub fn token_to_id(&self, token: &str) -> Option<u32> {
+        self.tokenizer.token_to_id(token)
+    }
+
+    /// Vocabulary entry of token `id` as [`HuggingFaceTokenizer::vocabulary`]
+    /// writes it
+    pub fn id_to_token(&self, id: u32) -> Option<String> {
+        self.tokenizer.id_to_token(id)
+    }
+
+    /// Vocabulary including added tokens, and the merges of BPE models
+    pub fn vocabulary(&self) -> Result<Vocabulary> {
+        let mut tokens: Vec<VocabToken> = self
+            .tokenizer
+            .get_vocab(true)
+            .into_iter()
+            .map(|(token, id)| VocabToken { id, token })
+            .collect();
+        tokens.sort_by_key(|token| token.id);
+
+        // Merges are not exposed by the model API, so read them from its JSON form
+        let json = self
+            .tokenizer
+            .to_string(false)
+            .map_err(|e| TokenizerError::TokenizerError(e.to_string()))?;
+        let json: serde_json::Value = serde_json::from_str(&json)?;
+        let merges = json["model"]["merges"]
+            .as_array()
+            .map(|merges| merges.iter().filter_map(parse_merge).collect())
+            .unwrap_or_default();
+
+        Ok(Vocabulary { tokens, merges })
+    }
+
+    /// Download `tokenizer.json` of a Hub repository
+    fn download_from_hub(hub: &HubFiles, sources: &Sources) -> Result<PathBuf> {
+        let HubFiles { repo, revision, .. } = hub;
+        let urls = hub.urls("tokenizer.json");
+        let downloaded = Self::download_any(&urls, sources);
+        downloaded.map_err(|e| match e {
+            TokenizerError::HttpStatus { status: 404, .. } => TokenizerError::ModelLoadError(
+                format!("No tokenizer.json in Hugging Face repository {repo} at {revision}"),
+            ),
+            // The Hub answers 401 for missing repositories too
+            TokenizerError::HttpStatus { status: 401 | 403, .. } => {
+                TokenizerError::ModelLoadError(format!(
+                    "Hugging Face repository {repo} does not exist or needs an access token"
+                ))
+            },
+            e => e,
+        })
+    }
+
+    /// Download the first of `urls` that can be downloaded
+    ///
+    /// Returns the error of the first URL if none can, since the others are
+    /// only fallbacks.
+    fn download_any(urls: &[String], sources: &Sources) -> Result<PathBuf> {
+        let mut first_error = None;
+        for (i, url) in urls.iter().enumerate() {
+            match Self::download_tokenizer(url, sources) {
+                Ok(path) => return Ok(path),
+                Err(e) => {
+                    if let Some(next) = urls.get(i + 1) {
+                        log::warn!("Download of {url} failed, trying mirror {next}: {e}");
+                    }
+                    first_error.get_or_insert(e);
+                },
+            }
+        }
+        Err(first_error
+            .unwrap_or_else(|| TokenizerError::InvalidUrl("No URL to download".to_string())))
+    }
+
     /// Download a tokenizer from a URL and cache it locally
-    fn download_tokenizer(url: &str) -> Result<PathBuf> {
+    ///
+    /// See [`Sources::send`] for the credentials sent along.
+    fn download_tokenizer(url: &str, sources: &Sources) -> Result<PathBuf> {
+        let options = sources.options;
         let parsed_url = validate_url(url)?;
+        // Named after the whole path, so the `tokenizer.json` files of
+        // different repositories do not overwrite each other
         let filename = parsed_url.path_segments()
-            .and_then(|segments| segments.last()
-            .filter(|&s| !s.is_empty() && s != "/")
-            .map(|s| s.to_string()))
+            .filter(|segments| segments.clone().last().is_some_and(|s| !s.is_empty()))
+            .map(|segments| segments.filter(|s| !s.is_empty()).collect::<Vec<_>>().join("--"))
             .ok_or_else(|| TokenizerError::InvalidUrl("Invalid URL path or filename".to_string()))?;
         
-        let cache_dir = dirs::cache_dir()
-            .ok_or_else(|| TokenizerError::IoError(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                "Could not determine cache directory"
-            )))?
-            .join("neopilot");
-            
+        let cache_dir = cache_dir()?;
         std::fs::create_dir_all(&cache_dir)
             .map_err(TokenizerError::IoError)?;
-            
-        let cache_path = cache_dir.join(&filename);
-        
-        // Check if file exists and is valid
-        if let Ok(metadata) = std::fs::metadata(&cache_path) {
-            if metadata.len() > 0 && metadata.len() < MAX_DOWNLOAD_SIZE * 2 {
-                return Ok(cache_path);
-            }
+        secure_dir(&cache_dir)?;
+
+        // The file name comes from the URL, so make sure it cannot point
+        // outside of the cache directory
+        let cache_path = ensure_within(&cache_dir.join(&filename), &cache_dir)?;
+
+        if is_cached(&cache_path) {
+            check_permissions(&cache_path)?;
+            return Ok(cache_path);
         }
         
-        // Download the file
-        let client = reqwest::blocking::Client::new();
-        let response = client.get(url)
-            .send()
-            .map_err(|e| TokenizerError::NetworkError(e.to_string()))?;
-            
-        if !response.status().is_success() {
-            return Err(TokenizerError::NetworkError(
-                format!("HTTP error: {}", response.status())
-            ));
+        if !options.network_enabled {
+            return Err(TokenizerError::NetworkDisabled(url.to_string()));
         }
-        
-        // Download with size limit
-        let content = response.bytes()
-            .map_err(|e| TokenizerError::NetworkError(e.to_string()))?;
-            
+
+        // Download the file, retrying transient failures. Redirects are
+        // followed by hand to decide which credentials go to each hop.
+        let client = reqwest::blocking::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(network_error)?;
+        let content = with_retries(&options.policy, || {
+            let response = sources.send(&client, &parsed_url)?;
+            if !response.status().is_success() {
+                return Err(TokenizerError::HttpStatus {
+                    url: url.to_string(),
+                    status: response.status().as_u16(),
+                });
+            }
+            response.bytes().map_err(network_error)
+        });
+        events::emit(Event::DownloadFinished {
+            url: url.to_string(),
+            error: content.as_ref().err().map(ToString::to_string),
+        });
+        let content = content?;
+
+        // Enforce size limit
         if content.len() as u64 > MAX_DOWNLOAD_SIZE {
             return Err(TokenizerError::DownloadSizeExceeded {
                 url: url.to_string(),
@@ -112,6 +489,7 @@ impl HuggingFaceTokenizer {
         let temp_path = cache_path.with_extension(".tmp");
         std::fs::write(&temp_path, &content)
             .map_err(TokenizerError::IoError)?;
+        secure_file(&temp_path)?;
             
         // Atomic rename
         std::fs::rename(&temp_path, &cache_path)
@@ -122,7 +500,7 @@ impl HuggingFaceTokenizer {
 }
 
 /// Validate that a URL is valid and secure (HTTPS)
-fn is_valid_url(url: &str) -> bool {
+pub(crate) fn is_valid_url(url: &str) -> bool {
     let parsed = match Url::parse(url) {
         Ok(p) => p,
         Err(_) => return false,
@@ -141,19 +519,223 @@ fn is_valid_url(url: &str) -> bool {
     true
 }
 
+/// Directory of downloaded tokenizers
+fn cache_dir() -> Result<PathBuf> {
+    let cache_dir = dirs::cache_dir().ok_or_else(|| {
+        TokenizerError::IoError(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "Could not determine cache directory",
+        ))
+    })?;
+    Ok(cache_dir.join("neopilot"))
+}
+
+/// Whether `path` is a usable download, i.e. exists and has a plausible size
+fn is_cached(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .is_ok_and(|metadata| metadata.len() > 0 && metadata.len() < MAX_DOWNLOAD_SIZE * 2)
+}
+
+/// Hub repository of a downloaded `tokenizer.json`, as a model name
+///
+/// Downloads are named after their URL path, e.g.
+/// `org--name--resolve--main--tokenizer.json`; a mirror may add segments in
+/// front. Other downloads give `None`.
+fn cached_model_name(filename: &str) -> Option<String> {
+    let path = filename.strip_suffix("--tokenizer.json")?;
+    let (repo, revision) = path.rsplit_once("--resolve--")?;
+    let mut segments = repo.rsplit("--");
+    let (name, org) = (segments.next()?, segments.next()?);
+    let model = match revision {
+        "main" => format!("{org}/{name}"),
+        // Revisions with slashes cannot be named in a model name
+        revision if revision.contains("--") => return None,
+        revision => format!("{org}/{name}@{revision}"),
+    };
+    parse_repo_id(&model).is_some().then_some(model)
+}
+
+/// Hub repositories whose tokenizer was downloaded before, sorted
+///
+/// These load without the network. Files are not checked beyond their name.
+pub fn cached_models() -> Result<Vec<String>> {
+    let entries = match std::fs::read_dir(cache_dir()?) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(TokenizerError::IoError(e)),
+    };
+    let mut models = std::collections::BTreeSet::new();
+    for entry in entries {
+        let entry = entry.map_err(TokenizerError::IoError)?;
+        if let Some(model) = entry.file_name().to_str().and_then(cached_model_name) {
+            models.insert(model);
+        }
+    }
+    Ok(models.into_iter().collect())
+}
+
+/// Split a Hugging Face Hub repository ID, `org/name` or `org/name@revision`
+///
+/// The revision defaults to `main`. Returns `None` for anything else, such
+/// as model names without an organization or relative paths of JSON files.
+pub(crate) fn parse_repo_id(model: &str) -> Option<(&str, &str)> {
+    let (repo, revision) = model.split_once('@').unwrap_or((model, "main"));
+    let (org, name) = repo.split_once('/')?;
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    let is_valid = |part: &str| {
+        !part.is_empty()
+            && !part.starts_with(['.', '-'])
+            && !part.contains("..")
+            && part.chars().all(is_name_char)
+    };
+    let is_repo = is_valid(org) && is_valid(name) && !name.ends_with(".json");
+    (is_repo && is_valid(revision)).then_some((repo, revision))
+}
+
+/// Whether `url` is on huggingface.co or hf.co, the hosts an access token is sent to
+fn is_hub_url(url: &Url) -> bool {
+    url.host_str().is_some_and(|host| {
+        ["huggingface.co", "hf.co"]
+            .iter()
+            .any(|hub| host == *hub || host.ends_with(&format!(".{hub}")))
+    })
+}
+
+/// URL of `tokenizer.json` in the Hub repository `repo` at `revision`
+pub(crate) fn hub_tokenizer_url(repo: &str, revision: &str) -> String {
+    hub_file_url(HUB_URL, repo, revision, "tokenizer.json")
+}
+
+/// URL of `file` in the Hub repository `repo` at `revision`, served by the
+/// Hub or a mirror at `endpoint`
+fn hub_file_url(endpoint: &str, repo: &str, revision: &str, file: &str) -> String {
+    let endpoint = endpoint.trim_end_matches('/');
+    format!("{endpoint}/{repo}/resolve/{revision}/{file}")
+}
+
+/// A Hub repository at a revision, with the mirrors serving it
+struct HubFiles<'a> {
+    repo: &'a str,
+    revision: &'a str,
+    mirrors: &'a [String],
+}
+
+impl HubFiles<'_> {
+    /// URLs of `file` on the Hub, then on each mirror
+    fn urls(&self, file: &str) -> Vec<String> {
+        std::iter::once(HUB_URL)
+            .chain(self.mirrors.iter().map(String::as_str))
+            .map(|endpoint| hub_file_url(endpoint, self.repo, self.revision, file))
+            .collect()
+    }
+}
+
+/// Whether `url` is a well-formed plain HTTP URL, which is rejected rather than
+/// treated as a local path
+fn is_insecure_url(url: &str) -> bool {
+    Url::parse(url).map_or(false, |parsed| parsed.scheme() == "http")
+}
+
+/// The options of a download with the hosts its configured headers are for
+struct Sources<'a> {
+    options: &'a DownloadOptions,
+    /// Hosts of the mirrors and of a tokenizer URL given as the model
+    header_hosts: Vec<String>,
+}
+
+impl Sources<'_> {
+    /// GET `url`, following up to [`MAX_REDIRECTS`] redirects
+    ///
+    /// The access token only goes to Hugging Face hosts and the configured
+    /// headers only to [`Sources::header_hosts`]; both are dropped once a
+    /// redirect leaves the origin of `url`, e.g. for a CDN.
+    fn send(
+        &self,
+        client: &reqwest::blocking::Client,
+        url: &Url,
+    ) -> Result<reqwest::blocking::Response> {
+        let mut current = url.clone();
+        for _ in 0..=MAX_REDIRECTS {
+            let same_origin = current.origin() == url.origin();
+            let to_header_host = current
+                .host_str()
+                .is_some_and(|host| self.header_hosts.iter().any(|allowed| allowed == host));
+            let configured = same_origin && to_header_host && !is_hub_url(&current);
+            let mut request = client.get(current.clone());
+            if let Some(token) = self.options.hf_token.as_deref() {
+                if same_origin && is_hub_url(&current) {
+                    request = request.bearer_auth(token);
+                }
+            }
+            let response = request
+                .headers(self.options.headers.header_map(configured)?)
+                .send()
+                .map_err(network_error)?;
+            if !response.status().is_redirection() {
+                return Ok(response);
+            }
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .ok_or_else(|| TokenizerError::HttpStatus {
+                    url: current.to_string(),
+                    status: response.status().as_u16(),
+                })?;
+            let next = current.join(location).map_err(TokenizerError::UrlError)?;
+            current = validate_url(next.as_str())?;
+        }
+        Err(TokenizerError::NetworkError(format!("Too many redirects downloading {url}")))
+    }
+}
+
 /// Parse and validate a URL
 fn validate_url(url: &str) -> Result<Url> {
     let parsed = Url::parse(url).map_err(TokenizerError::UrlError)?;
+    if parsed.scheme() == "http" {
+        return Err(TokenizerError::InsecureProtocol(url.to_string()));
+    }
     if !is_valid_url(url) {
         return Err(TokenizerError::InvalidUrl(url.to_string()));
     }
     Ok(parsed)
 }
 
+/// A merge serialized either as `"left right"` or as `["left", "right"]`
+fn parse_merge(merge: &serde_json::Value) -> Option<(String, String)> {
+    match merge {
+        serde_json::Value::String(merge) => {
+            let (left, right) = merge.split_once(' ')?;
+            Some((left.to_string(), right.to_string()))
+        }
+        serde_json::Value::Array(pair) => match pair.as_slice() {
+            [left, right] => Some((left.as_str()?.to_string(), right.as_str()?.to_string())),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_cached_model_name() {
+        let name = |filename: &str| cached_model_name(filename);
+        assert_eq!(
+            name("meta-llama--Llama-3.1-8B--resolve--main--tokenizer.json").as_deref(),
+            Some("meta-llama/Llama-3.1-8B")
+        );
+        assert_eq!(
+            name("hf--org--model--resolve--v1.0--tokenizer.json").as_deref(),
+            Some("org/model@v1.0")
+        );
+        assert_eq!(name("org--model--resolve--refs--pr--1--tokenizer.json"), None);
+        assert_eq!(name("org--model--resolve--main--tokenizer.json.tmp"), None);
+        assert_eq!(name("files--tokenizer.json"), None);
+    }
+
     #[test]
     fn test_invalid_url() {
         let result = HuggingFaceTokenizer::new("http://invalid-url");
@@ -171,4 +753,166 @@ mod tests {
             Err(TokenizerError::InvalidPath(_))
         ));
     }
+
+    #[test]
+    fn test_from_json() -> Result<()> {
+        let json = serde_json::json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [],
+            "normalizer": null,
+            "pre_tokenizer": {"type": "Whitespace"},
+            "post_processor": null,
+            "decoder": null,
+            "model": {
+                "type": "WordLevel",
+                "vocab": {"[UNK]": 0, "hello": 1, "world": 2},
+                "unk_token": "[UNK]",
+            },
+        });
+        let tokenizer = HuggingFaceTokenizer::from_json(&json.to_string())?;
+        let (tokens, num_tokens, _) = tokenizer.encode("hello big world")?;
+        assert_eq!(tokens, vec![1, 0, 2]);
+        assert_eq!(num_tokens, 3);
+        assert_eq!(tokenizer.token_to_id("world"), Some(2));
+        assert_eq!(tokenizer.id_to_token(1).as_deref(), Some("hello"));
+        assert_eq!(tokenizer.id_to_token(3), None);
+
+        assert!(matches!(
+            HuggingFaceTokenizer::from_json("{\"model\": 1}"),
+            Err(TokenizerError::TokenizerError(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_special_tokens() -> Result<()> {
+        let json = serde_json::json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [{
+                "id": 3,
+                "content": "<|im_end|>",
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": true,
+            }],
+            "normalizer": null,
+            "pre_tokenizer": {"type": "Whitespace"},
+            "post_processor": null,
+            "decoder": null,
+            "model": {
+                "type": "WordLevel",
+                "vocab": {"[UNK]": 0, "hello": 1, "world": 2, "<|im_end|>": 3},
+                "unk_token": "[UNK]",
+            },
+        });
+        let tokenizer = HuggingFaceTokenizer::from_json(&json.to_string())?;
+        assert_eq!(tokenizer.decode_with_special(&[1, 2, 3], false)?, "hello world <|im_end|>");
+        assert_eq!(tokenizer.decode_with_special(&[1, 2, 3], true)?, "hello world");
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_repo_id() {
+        assert_eq!(
+            parse_repo_id("meta-llama/Llama-3.1-8B"),
+            Some(("meta-llama/Llama-3.1-8B", "main"))
+        );
+        assert_eq!(parse_repo_id("Qwen/Qwen2.5-7B@v1.0"), Some(("Qwen/Qwen2.5-7B", "v1.0")));
+        assert_eq!(parse_repo_id("bert-base-uncased"), None);
+        assert_eq!(parse_repo_id("a/b/c"), None);
+        assert_eq!(parse_repo_id("models/tokenizer.json"), None);
+        assert_eq!(parse_repo_id("../tokenizer.json"), None);
+        assert_eq!(parse_repo_id("org/name@"), None);
+        assert_eq!(
+            hub_tokenizer_url("org/name", "main"),
+            "https://huggingface.co/org/name/resolve/main/tokenizer.json"
+        );
+    }
+
+    #[test]
+    fn test_mirror_urls() {
+        let mirrors = ["https://hf-mirror.com/".to_string()];
+        let hub = HubFiles {
+            repo: "org/name",
+            revision: "v1",
+            mirrors: &mirrors,
+        };
+        assert_eq!(
+            hub.urls("tokenizer.json"),
+            [
+                "https://huggingface.co/org/name/resolve/v1/tokenizer.json",
+                "https://hf-mirror.com/org/name/resolve/v1/tokenizer.json",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mirrors_offline() {
+        let options = DownloadOptions {
+            network_enabled: false,
+            mirrors: vec!["https://hf-mirror.com".to_string()],
+            ..Default::default()
+        };
+        let result = HuggingFaceTokenizer::with_options("neopilot/never-cached", &options);
+        // The error of the Hub is returned, not the mirror's
+        assert!(matches!(
+            result,
+            Err(TokenizerError::NetworkDisabled(url)) if url.starts_with("https://huggingface.co/")
+        ));
+    }
+
+    #[test]
+    fn test_download_headers() {
+        let headers = DownloadHeaders {
+            user_agent: None,
+            headers: vec![("X-Mirror-Token".to_string(), "secret".to_string())],
+        };
+        assert!(headers.validate().is_ok());
+        assert!(headers.header_map(true).unwrap().contains_key("x-mirror-token"));
+        let scoped_out = headers.header_map(false).unwrap();
+        assert!(!scoped_out.contains_key("x-mirror-token"));
+        assert!(scoped_out.contains_key(USER_AGENT));
+
+        for (name, value) in [("X-Bad\nName", "value"), ("X-Token", "caf\u{e9}"), ("X", "a\nb")] {
+            let headers = DownloadHeaders {
+                user_agent: None,
+                headers: vec![(name.to_string(), value.to_string())],
+            };
+            assert!(matches!(headers.validate(), Err(TokenizerError::InvalidArgument(_))));
+        }
+    }
+
+    #[test]
+    fn test_is_hub_url() {
+        let is_hub = |url: &str| is_hub_url(&Url::parse(url).unwrap());
+        assert!(is_hub("https://huggingface.co/org/name/resolve/main/tokenizer.json"));
+        assert!(is_hub("https://cdn-lfs.huggingface.co/repos/file"));
+        assert!(is_hub("https://hf.co/org/name"));
+        assert!(!is_hub("https://example.com/tokenizer.json"));
+        assert!(!is_hub("https://nothuggingface.co/tokenizer.json"));
+    }
+
+    #[test]
+    fn test_hub_repo_offline() {
+        let options = DownloadOptions {
+            network_enabled: false,
+            ..Default::default()
+        };
+        let result = HuggingFaceTokenizer::with_options("neopilot/never-cached", &options);
+        assert!(matches!(result, Err(TokenizerError::NetworkDisabled(_))));
+    }
+
+    #[test]
+    fn test_parse_merge() {
+        let expected = Some(("a".to_string(), "b".to_string()));
+        assert_eq!(parse_merge(&serde_json::json!("a b")), expected);
+        assert_eq!(parse_merge(&serde_json::json!(["a", "b"])), expected);
+        assert_eq!(parse_merge(&serde_json::json!("ab")), None);
+    }
 }
@@ -0,0 +1,145 @@
+//! Running token counts of text that grows at the end
+//!
+//! A live token counter on a large buffer cannot afford to encode the whole
+//! buffer on every keystroke. Appending text only changes the tokens after
+//! the last point where the tokenizer always splits, so
+//! [`IncrementalEncoder`] counts the text before that point once and only
+//! encodes the rest again.
+//!
+//! The split points used are line starts: the tiktoken patterns, and the
+//! byte-level BPE pre-tokenizers modelled on them, never merge a line break
+//! with the non-blank line after it. Counts then match encoding the whole
+//! text. SentencePiece tokenizers that mark the start of the text may count
+//! a token more or less per line.
+
+use std::sync::Arc;
+
+use crate::error::Result;
+use crate::TokenizerType;
+
+/// Counts the tokens of text appended piece by piece
+///
+/// The tokenizer is fixed when the encoder is created, like
+/// [`crate::StreamDecoder`].
+#[derive(Clone)]
+pub struct IncrementalEncoder {
+    tokenizer: Arc<TokenizerType>,
+    /// Text after the last split point, encoded again on every append
+    tail: String,
+    /// Tokens of the text before `tail`
+    committed_tokens: usize,
+    /// Tokens of `tail`
+    tail_tokens: usize,
+    num_chars: usize,
+}
+
+/// Start of the last line of `text` that has a character other than
+/// whitespace, after at least one line break
+///
+/// Tokens never span that point, whatever is appended to `text`. Blank lines
+/// do not qualify: their whitespace merges with the line breaks around them.
+pub(crate) fn last_split_point(text: &str) -> Option<usize> {
+    let mut line_end = text.len();
+    for (newline, _) in text.rmatch_indices('\n') {
+        let line = &text[newline + 1..line_end];
+        if line.chars().any(|c| !c.is_whitespace()) {
+            return Some(newline + 1);
+        }
+        line_end = newline;
+    }
+    None
+}
+
+impl IncrementalEncoder {
+    pub fn new(tokenizer: Arc<TokenizerType>) -> Self {
+        Self {
+            tokenizer,
+            tail: String::new(),
+            committed_tokens: 0,
+            tail_tokens: 0,
+            num_chars: 0,
+        }
+    }
+
+    /// Tokens of `text` as the tokenizer produces them, before any estimate
+    fn count(&self, text: &str) -> Result<usize> {
+        let (tokens, _, _) = self.tokenizer.encode(text)?;
+        Ok(tokens.len())
+    }
+
+    /// Append `text` and return the token count of everything appended
+    pub fn append(&mut self, text: &str) -> Result<usize> {
+        self.tail.push_str(text);
+        self.num_chars += text.chars().count();
+        if let Some(split) = last_split_point(&self.tail) {
+            self.committed_tokens += self.count(&self.tail[..split])?;
+            self.tail.drain(..split);
+        }
+        self.tail_tokens = self.count(&self.tail)?;
+        Ok(self.num_tokens())
+    }
+
+    /// Token count of everything appended
+    ///
+    /// Anthropic approximations estimate the count from the whole text, as
+    /// [`TokenizerType::encode`] does.
+    pub fn num_tokens(&self) -> usize {
+        let tokens = self.committed_tokens + self.tail_tokens;
+        match self.tokenizer.as_ref() {
+            TokenizerType::Anthropic(tokenizer) => tokenizer.estimate(tokens),
+            _ => tokens,
+        }
+    }
+
+    /// Characters appended so far
+    pub fn num_chars(&self) -> usize {
+        self.num_chars
+    }
+
+    /// Forget the text appended so far
+    pub fn reset(&mut self) {
+        self.tail.clear();
+        self.committed_tokens = 0;
+        self.tail_tokens = 0;
+        self.num_chars = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiktoken::Tiktoken;
+
+    const SOURCE: &str = "fn main() {\n    let x = 1;\n  \n\n\tprintln!(\"{x}\");\r\n}\n\n";
+
+    #[test]
+    fn test_last_split_point() {
+        assert_eq!(last_split_point("a\nb\n"), Some(2));
+        assert_eq!(last_split_point("a\n  b\n   \n\n"), Some(2));
+        assert_eq!(last_split_point("a\n"), None);
+        assert_eq!(last_split_point("abc"), None);
+    }
+
+    #[test]
+    fn test_matches_full_encode() -> Result<()> {
+        let tokenizer = Arc::new(TokenizerType::Tiktoken(Tiktoken::new("gpt-4o")?));
+        for chunk_len in [1, 3, 7] {
+            let mut encoder = IncrementalEncoder::new(Arc::clone(&tokenizer));
+            let chars: Vec<char> = SOURCE.chars().collect();
+            let mut appended = String::new();
+            for chunk in chars.chunks(chunk_len) {
+                let chunk: String = chunk.iter().collect();
+                appended.push_str(&chunk);
+                let expected = tokenizer.encode(&appended)?.1;
+                assert_eq!(encoder.append(&chunk)?, expected, "after {appended:?}");
+            }
+            assert_eq!(encoder.num_chars(), chars.len());
+        }
+
+        let mut encoder = IncrementalEncoder::new(tokenizer);
+        encoder.append("hello\nworld")?;
+        encoder.reset();
+        assert_eq!((encoder.num_tokens(), encoder.num_chars()), (0, 0));
+        Ok(())
+    }
+}
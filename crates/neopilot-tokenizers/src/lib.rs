@@ -3,15 +3,83 @@
 //! A Rust library for tokenization with support for multiple backends including
 //! Tiktoken and HuggingFace tokenizers.
 
+pub mod anthropic;
+pub mod budget;
+pub mod buffer;
+pub mod chat;
+pub mod chunk;
+pub mod consistency;
 pub mod error;
+pub mod export;
+pub mod family;
+pub mod files;
+mod gguf;
+pub mod logit_bias;
 pub mod tiktoken;
 pub mod huggingface;
+pub mod incremental;
+pub mod long_lines;
+pub mod offsets;
+pub mod replacement;
+pub mod retry;
+pub mod security;
+pub mod special;
+pub mod stats;
+pub mod stop;
+pub mod stream;
+pub mod template;
+pub mod truncate;
+pub mod vocab;
+mod locks;
+mod workers;
 
-use std::sync::{Arc, Mutex};
+#[cfg(feature = "python")]
+mod python;
 
+#[cfg(feature = "lua")]
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "lua")]
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+#[cfg(feature = "lua")]
+use mlua::prelude::*;
+use neopilot_common::events::{self, Event};
+use neopilot_common::scheduler::{Priority, Scheduler, SchedulerSlot, TaskHandle};
+use rayon::prelude::*;
+
+pub use budget::TokenBudget;
+pub use buffer::TokenizedBuffer;
+pub use chat::{ChatFraming, ChatMessage, ImageDetail, MessagePart, PartRules};
+pub use chunk::Chunk;
+pub use consistency::Mismatch;
 pub use error::{Result, TokenizerError};
-use tiktoken::Tiktoken;
+pub use export::{OutputFormat, TokenCount};
+pub use family::{detect_family, suggest_source, ModelFamily, TokenizerSource};
+pub use files::{FileCount, TextEncoding};
+pub use huggingface::{DownloadHeaders, DownloadOptions};
+pub use incremental::IncrementalEncoder;
+pub use logit_bias::WordTokens;
+pub use long_lines::{GuardedEncoding, LongLineMode};
+pub use offsets::{EncodingWithOffsets, OffsetUnit, TokenRange};
+pub use replacement::{LossyEncoding, ReplacementMode};
+pub use retry::RetryPolicy;
+pub use special::{SpecialSet, SpecialTokenPolicy, SpecialTokens};
+pub use stats::{Counters, Stats, Timing};
+pub use stop::{StopSequenceReport, StopWarning};
+pub use stream::StreamDecoder;
+pub use template::ChatTemplate;
+pub use truncate::{TruncateStrategy, Truncation};
+pub use vocab::{VocabFormat, Vocabulary};
+use tiktoken::{Encoding, RankCache, Tiktoken};
 use huggingface::HuggingFaceTokenizer;
+use workers::{LoadSlots, PoolCache, WorkerCache, WorkerEncoders};
+use locks::RecoverLock;
+use anthropic::Anthropic;
 
 /// Represents the type of tokenizer being used
 pub enum TokenizerType {
@@ -19,47 +87,847 @@ pub enum TokenizerType {
     Tiktoken(Tiktoken),
     /// HuggingFace tokenizer (for models from the HuggingFace Hub)
     HuggingFace(Box<HuggingFaceTokenizer>),
+    /// Calibrated approximation for Claude models, whose tokenizer is not
+    /// published; counts are estimates, see [`anthropic`]
+    Anthropic(Anthropic),
+}
+
+impl TokenizerType {
+    /// Encode text into tokens, see [`encode`]
+    pub fn encode(&self, text: &str) -> Result<(Vec<u32>, usize, usize)> {
+        self.encode_with_special(text, SpecialTokens::default())
+    }
+
+    /// Encode text into tokens, handling special token strings as `special` says
+    pub fn encode_with_special(
+        &self,
+        text: &str,
+        special: SpecialTokens,
+    ) -> Result<(Vec<u32>, usize, usize)> {
+        match self {
+            TokenizerType::Tiktoken(tokenizer) => Ok(tokenizer.encode_with_special(text, special)),
+            TokenizerType::HuggingFace(tokenizer) => tokenizer.encode_with_special(text, special),
+            TokenizerType::Anthropic(tokenizer) => Ok(tokenizer.encode(text)),
+        }
+    }
+
+    /// Encode text into tokens, accepting or rejecting each special token
+    /// string according to `policy`
+    pub fn encode_with_policy(
+        &self,
+        text: &str,
+        policy: &SpecialTokenPolicy,
+    ) -> Result<(Vec<u32>, usize, usize)> {
+        let specials = match self {
+            TokenizerType::Tiktoken(tokenizer) => tokenizer.special_tokens(),
+            TokenizerType::HuggingFace(tokenizer) => tokenizer.special_tokens(),
+            TokenizerType::Anthropic(_) => Vec::new(),
+        };
+        let tokens = special::encode_with_policy(text, policy, &specials, |text| {
+            self.encode_with_special(text, SpecialTokens::Ordinary).map(|(tokens, _, _)| tokens)
+        })?;
+        let num_tokens = match self {
+            TokenizerType::Anthropic(tokenizer) => tokenizer.estimate(tokens.len()),
+            _ => tokens.len(),
+        };
+        Ok((tokens, num_tokens, text.chars().count()))
+    }
+
+    /// Decode tokens into text, see [`decode`]
+    pub fn decode(&self, tokens: &[u32]) -> Result<String> {
+        self.decode_with_special(tokens, false)
+    }
+
+    /// Decode tokens into text, leaving out special tokens if
+    /// `skip_special_tokens` is set, see [`decode_with_special`]
+    pub fn decode_with_special(&self, tokens: &[u32], skip_special_tokens: bool) -> Result<String> {
+        match self {
+            TokenizerType::Tiktoken(tokenizer) => {
+                tokenizer.decode_with_special(tokens, skip_special_tokens)
+            },
+            TokenizerType::HuggingFace(tokenizer) => {
+                tokenizer.decode_with_special(tokens, skip_special_tokens)
+            },
+            TokenizerType::Anthropic(tokenizer) => {
+                tokenizer.base().decode_with_special(tokens, skip_special_tokens)
+            },
+        }
+    }
+
+    /// ID of a single token given as its vocabulary entry, see [`token_to_id`]
+    pub fn token_to_id(&self, token: &str) -> Option<u32> {
+        match self {
+            TokenizerType::Tiktoken(tokenizer) => tokenizer.token_to_id(token),
+            TokenizerType::HuggingFace(tokenizer) => tokenizer.token_to_id(token),
+            TokenizerType::Anthropic(tokenizer) => tokenizer.base().token_to_id(token),
+        }
+    }
+
+    /// Vocabulary entry of a single token, see [`id_to_token`]
+    pub fn id_to_token(&self, id: u32) -> Option<String> {
+        match self {
+            TokenizerType::Tiktoken(tokenizer) => tokenizer.id_to_token(id),
+            TokenizerType::HuggingFace(tokenizer) => tokenizer.id_to_token(id),
+            TokenizerType::Anthropic(tokenizer) => tokenizer.base().id_to_token(id),
+        }
+    }
+
+    /// Name of the backend: `"tiktoken"`, `"huggingface"` or `"anthropic"`
+    pub fn backend(&self) -> &'static str {
+        match self {
+            TokenizerType::Tiktoken(_) => "tiktoken",
+            TokenizerType::HuggingFace(_) => "huggingface",
+            TokenizerType::Anthropic(_) => "anthropic",
+        }
+    }
+
+    /// Chat template shipped with the tokenizer; only HuggingFace tokenizers
+    /// have one
+    pub fn chat_template(&self) -> Option<&ChatTemplate> {
+        match self {
+            TokenizerType::HuggingFace(tokenizer) => tokenizer.chat_template(),
+            _ => None,
+        }
+    }
+
+    /// Encode text and report the span of every token in `unit`
+    ///
+    /// Tiktoken has no notion of offsets, so its spans are rebuilt from the
+    /// byte length of every token. Anthropic approximations report the spans
+    /// of their cl100k_base tokens.
+    pub fn encode_with_offsets(
+        &self,
+        text: &str,
+        unit: OffsetUnit,
+        special: SpecialTokens,
+    ) -> Result<(Vec<u32>, Vec<(usize, usize)>)> {
+        let tiktoken_offsets = |tokenizer: &Tiktoken, special| {
+            let (tokens, _, _) = tokenizer.encode_with_special(text, special);
+            let spans = tokenizer.byte_spans(&tokens);
+            let offsets = match unit {
+                OffsetUnit::Char => offsets::byte_to_char_spans(text, &spans),
+                OffsetUnit::Byte => spans,
+            };
+            Ok((tokens, offsets))
+        };
+        match self {
+            TokenizerType::Tiktoken(tokenizer) => tiktoken_offsets(tokenizer, special),
+            TokenizerType::HuggingFace(tokenizer) => {
+                tokenizer.encode_with_offsets(text, unit, special)
+            },
+            TokenizerType::Anthropic(tokenizer) => {
+                tiktoken_offsets(tokenizer.base(), SpecialTokens::Ordinary)
+            },
+        }
+    }
 }
 
 /// Global state for the tokenizer
+///
+/// Encoding only reads the state, so it sits behind read-write locks: any
+/// number of threads encode at once and only loading a model takes the
+/// write lock.
 #[derive(Clone)]
 pub struct State {
-    /// The tokenizer instance wrapped in an Arc<Mutex<>> for thread safety
-    pub tokenizer: Arc<Mutex<Option<TokenizerType>>>,
+    /// The current tokenizer, replaced by [`from_pretrained`]
+    pub tokenizer: Arc<RwLock<Option<Arc<TokenizerType>>>>,
+    /// Model of the current tokenizer, which picks the rules of
+    /// [`count_message_parts`]
+    pub model: Arc<RwLock<Option<String>>>,
+    /// Times the current tokenizer was replaced or unloaded, so a
+    /// [`from_pretrained_async`] overtaken by another switch does not undo it
+    pub switches: Arc<AtomicU64>,
+    /// Tokenizers loaded so far, keyed by model, so switching models is instant
+    pub loaded: Arc<RwLock<HashMap<String, Arc<TokenizerType>>>>,
+    /// Tokenizers registered under a name of the caller's choice, used next
+    /// to the current one, see [`register`]
+    pub registry: Arc<RwLock<HashMap<String, Arc<TokenizerType>>>>,
+    /// Model name patterns mapped to tiktoken encodings, checked before the
+    /// built-in table, see [`set_encoding`]
+    pub encodings: Arc<RwLock<HashMap<String, Encoding>>>,
+    /// Whether tokenizers may be downloaded, see [`set_network_enabled`]
+    pub network_enabled: Arc<AtomicBool>,
+    /// Hugging Face access token for gated repositories, see [`set_hf_token`]
+    pub hf_token: Arc<RwLock<Option<String>>>,
+    /// User-Agent and headers of downloads, see [`set_download_headers`]
+    pub download_headers: Arc<RwLock<DownloadHeaders>>,
+    /// Mirrors tried when downloading the tokenizer of a model fails, see
+    /// [`set_mirrors`]
+    pub mirrors: Arc<RwLock<HashMap<String, Vec<String>>>>,
+    /// Retries of a failed download, see [`set_max_retries`]
+    pub max_retries: Arc<AtomicU32>,
+    /// Cumulative timing of the encodes with the current tokenizer
+    pub stats: Arc<Stats>,
+    /// Largest text encoded at once, see [`set_max_input_bytes`]
+    pub max_input_bytes: Arc<AtomicUsize>,
+    /// Whether batch and buffer counts are compared with encoding the text
+    /// whole, see [`set_cross_check`]
+    pub cross_check: Arc<AtomicBool>,
+    /// Per-worker copies of the tokenizer, kept across batches
+    pub(crate) workers: Arc<WorkerCache>,
+    /// Thread pool of [`encode_batch_parallel`], kept across batches
+    pub(crate) pools: Arc<PoolCache>,
+    /// Background loads of [`preload`] in progress
+    pub(crate) preloads: Arc<LoadSlots>,
+    /// Parsed tiktoken ranks shared by the tokenizers, freed by [`unload`]
+    pub(crate) ranks: Arc<RankCache>,
+    /// Runs background loads, see [`State::set_scheduler`]
+    pub(crate) scheduler: Arc<SchedulerSlot>,
 }
 
 impl State {
-    /// Create a new State with no tokenizer loaded
+    /// Create a new State with no tokenizer loaded, configured from the environment
     pub fn new() -> Self {
+        Self::with_settings(Settings::from_env())
+    }
+
+    /// Create a new State with no tokenizer loaded and the given settings
+    pub fn with_settings(settings: Settings) -> Self {
+        Self {
+            tokenizer: Arc::new(RwLock::new(None)),
+            model: Arc::new(RwLock::new(None)),
+            switches: Arc::new(AtomicU64::new(0)),
+            loaded: Arc::new(RwLock::new(HashMap::new())),
+            registry: Arc::new(RwLock::new(HashMap::new())),
+            encodings: Arc::new(RwLock::new(HashMap::new())),
+            network_enabled: Arc::new(AtomicBool::new(settings.network_enabled)),
+            hf_token: Arc::new(RwLock::new(settings.hf_token)),
+            download_headers: Arc::new(RwLock::new(DownloadHeaders {
+                user_agent: settings.user_agent,
+                headers: settings.headers,
+            })),
+            mirrors: Arc::new(RwLock::new(settings.mirrors.into_iter().collect())),
+            max_retries: Arc::new(AtomicU32::new(settings.max_retries)),
+            stats: Arc::new(Stats::default()),
+            max_input_bytes: Arc::new(AtomicUsize::new(settings.max_input_bytes)),
+            cross_check: Arc::new(AtomicBool::new(cfg!(debug_assertions))),
+            workers: Arc::default(),
+            pools: Arc::default(),
+            preloads: Arc::default(),
+            ranks: Arc::default(),
+            scheduler: Arc::default(),
+        }
+    }
+
+    /// Run later background loads and warm-ups on `scheduler`
+    ///
+    /// Without one, a scheduler with a worker per CPU is started on first use.
+    pub fn set_scheduler(&self, scheduler: Arc<Scheduler>) {
+        self.scheduler.install(scheduler);
+    }
+}
+
+/// Run `task` on the background queue of the scheduler of `state`
+fn spawn_background<T, F>(state: &State, task: F) -> Result<TaskHandle<T>>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    state
+        .scheduler
+        .get_or_start_default()
+        .and_then(|scheduler| scheduler.submit(Priority::Background, task))
+        .map_err(|e| TokenizerError::TokenizerError(e.to_string()))
+}
+
+/// The parts of the neopilot configuration the tokenizers use
+#[derive(Clone, PartialEq, Eq)]
+pub struct Settings {
+    /// `network.enabled`
+    pub network_enabled: bool,
+    /// `network.hf_token`
+    pub hf_token: Option<String>,
+    /// `network.user_agent`
+    pub user_agent: Option<String>,
+    /// `network.headers`
+    pub headers: Vec<(String, String)>,
+    /// `network.mirrors`, the mirror URLs of each model
+    pub mirrors: Vec<(String, Vec<String>)>,
+    /// `network.max_retries`
+    pub max_retries: u32,
+    /// `tokenizer.max_input_bytes`, 0 for no limit
+    pub max_input_bytes: usize,
+}
+
+// Written by hand to keep the token out of logs
+impl std::fmt::Debug for Settings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Settings")
+            .field("network_enabled", &self.network_enabled)
+            .field("hf_token", &self.hf_token.as_ref().map(|_| "<redacted>"))
+            .field("user_agent", &self.user_agent)
+            .field("headers", &self.headers.iter().map(|(name, _)| name).collect::<Vec<_>>())
+            .field("mirrors", &self.mirrors)
+            .field("max_retries", &self.max_retries)
+            .field("max_input_bytes", &self.max_input_bytes)
+            .finish()
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            network_enabled: true,
+            hf_token: None,
+            user_agent: None,
+            headers: Vec::new(),
+            mirrors: Vec::new(),
+            max_retries: RetryPolicy::default().max_retries,
+            max_input_bytes: DEFAULT_MAX_INPUT_BYTES,
+        }
+    }
+}
+
+/// Default of `tokenizer.max_input_bytes`
+pub const DEFAULT_MAX_INPUT_BYTES: usize = 10 * 1024 * 1024;
+
+/// Environment variables holding a Hugging Face access token, in order of precedence
+const HF_TOKEN_VARS: &[&str] =
+    &["NEOPILOT_NETWORK__HF_TOKEN", "HF_TOKEN", "HUGGING_FACE_HUB_TOKEN"];
+
+impl Settings {
+    /// Settings from the environment, e.g. `NEOPILOT_NETWORK__ENABLED=false`
+    ///
+    /// Network access is allowed unless the variable is `false` or `0`. The
+    /// access token is read from `NEOPILOT_NETWORK__HF_TOKEN`, or from
+    /// `HF_TOKEN` and `HUGGING_FACE_HUB_TOKEN` like the Hugging Face tools do,
+    /// the User-Agent from `NEOPILOT_NETWORK__USER_AGENT` and the download
+    /// retries from `NEOPILOT_NETWORK__MAX_RETRIES`. Headers and mirrors are
+    /// only configured through [`apply_settings`].
+    pub fn from_env() -> Self {
+        let network_enabled = std::env::var("NEOPILOT_NETWORK__ENABLED")
+            .map_or(true, |value| !matches!(value.trim(), "false" | "0"));
+        let hf_token = HF_TOKEN_VARS
+            .iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .map(|token| token.trim().to_string())
+            .find(|token| !token.is_empty());
+        let user_agent = std::env::var("NEOPILOT_NETWORK__USER_AGENT")
+            .ok()
+            .filter(|agent| !agent.trim().is_empty());
+        let max_retries = std::env::var("NEOPILOT_NETWORK__MAX_RETRIES")
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(RetryPolicy::default().max_retries);
+        Self {
+            network_enabled,
+            hf_token,
+            user_agent,
+            headers: Vec::new(),
+            mirrors: Vec::new(),
+            max_retries,
+            max_input_bytes: DEFAULT_MAX_INPUT_BYTES,
+        }
+    }
+
+    /// Settings for tests, ignoring the environment
+    ///
+    /// Network access is disabled, so only built-in encodings and cached
+    /// tokenizers load.
+    pub fn for_tests() -> Self {
         Self {
-            tokenizer: Arc::new(Mutex::new(None)),
+            network_enabled: false,
+            ..Self::default()
         }
     }
 }
 
+/// Encoding [`set_encoding`] gave to `model`
+fn custom_encoding(state: &State, model: &str) -> Option<Encoding> {
+    let encodings = state.encodings.read_recovered();
+    tiktoken::lookup_encoding(model, encodings.iter().map(|(p, e)| (p.as_str(), *e)))
+}
+
+/// Build the tokenizer for `model` from scratch
+fn load_tokenizer(state: &State, model: &str) -> Result<TokenizerType> {
+    let custom = custom_encoding(state, model);
+    if let Some(encoding) = custom {
+        return Ok(TokenizerType::Tiktoken(Tiktoken::with_ranks(encoding, &state.ranks)?));
+    }
+    Ok(match suggest_source(model) {
+        TokenizerSource::Tiktoken => {
+            let encoding = tiktoken::model_encoding(model)?;
+            TokenizerType::Tiktoken(Tiktoken::with_ranks(encoding, &state.ranks)?)
+        },
+        TokenizerSource::HuggingFace(source) => {
+            let mirrors = {
+                let mirrors = state.mirrors.read_recovered();
+                mirrors.get(model).or_else(|| mirrors.get(&source)).cloned().unwrap_or_default()
+            };
+            let options = DownloadOptions {
+                policy: RetryPolicy {
+                    max_retries: state.max_retries.load(Ordering::Relaxed),
+                    ..RetryPolicy::default()
+                },
+                network_enabled: state.network_enabled.load(Ordering::Relaxed),
+                hf_token: state.hf_token.read_recovered().clone(),
+                headers: state.download_headers.read_recovered().clone(),
+                mirrors,
+            };
+            let hf_tokenizer = HuggingFaceTokenizer::with_options(&source, &options)?;
+            TokenizerType::HuggingFace(Box::new(hf_tokenizer))
+        },
+        TokenizerSource::Anthropic => {
+            let base = Tiktoken::with_ranks(Encoding::Cl100kBase, &state.ranks)?;
+            TokenizerType::Anthropic(Anthropic::with_base(base))
+        },
+    })
+}
+
+/// The tokenizer for `model`, loading it into the cache if needed
+///
+/// The cache is not locked while loading, so a slow download does not block
+/// other models.
+fn cached_tokenizer(state: &State, model: &str) -> Result<Arc<TokenizerType>> {
+    let cached = state.loaded.read_or_reset().get(model).cloned();
+    if let Some(tokenizer) = cached {
+        return Ok(tokenizer);
+    }
+    let tokenizer = Arc::new(load_tokenizer(state, model)?);
+    let mut loaded = state.loaded.write_or_reset();
+    Ok(Arc::clone(loaded.entry(model.to_string()).or_insert(tokenizer)))
+}
+
+/// Use the tiktoken `encoding` for models matching `pattern`
+///
+/// `pattern` is a model name, or a prefix followed by `*` such as
+/// `"my-proxy-*"`. These patterns are checked before the built-in
+/// [`tiktoken::MODEL_ENCODINGS`] and before any other source, so models
+/// served under custom names count tokens like the OpenAI model behind them.
+/// Tokenizers already loaded for matching models are dropped from the cache,
+/// which is reported as an [`Event::CacheEvicted`].
+pub fn set_encoding(state: &State, pattern: &str, encoding: Encoding) -> Result<()> {
+    let pattern = pattern.to_lowercase();
+    let evicted = {
+        let mut loaded = state.loaded.write_or_reset();
+        let before = loaded.len();
+        loaded.retain(|model, _| !tiktoken::pattern_matches(&pattern, &model.to_lowercase()));
+        before - loaded.len()
+    };
+    if evicted > 0 {
+        events::emit(Event::CacheEvicted { cache: "tokenizers", entries: evicted });
+    }
+    state.encodings.write_recovered().insert(pattern, encoding);
+    Ok(())
+}
+
+/// Allow or forbid downloading tokenizers, mirroring `network.enabled`
+///
+/// While disabled, loading a tokenizer that is not on disk or in the
+/// download cache fails with [`TokenizerError::NetworkDisabled`]. Built-in
+/// tiktoken encodings never need the network.
+pub fn set_network_enabled(state: &State, enabled: bool) {
+    state.network_enabled.store(enabled, Ordering::Relaxed);
+}
+
+/// Retry a failed tokenizer download up to `max_retries` times, mirroring
+/// `network.max_retries`
+pub fn set_max_retries(state: &State, max_retries: u32) {
+    state.max_retries.store(max_retries, Ordering::Relaxed);
+}
+
+/// Refuse to encode texts larger than `max_bytes` bytes, mirroring
+/// `tokenizer.max_input_bytes`; 0 removes the limit
+///
+/// Encoding a huge file, such as a log opened by accident, takes seconds.
+/// Larger texts fail with [`TokenizerError::InputTooLarge`] instead, which
+/// callers can answer with an estimate.
+pub fn set_max_input_bytes(state: &State, max_bytes: usize) {
+    state.max_input_bytes.store(max_bytes, Ordering::Relaxed);
+}
+
+/// Compare the counts of batches and tokenized buffers with encoding each
+/// text whole, see [`consistency`]
+///
+/// Enabled by default in development builds, where a disagreement fails a
+/// debug assertion; release builds only log it.
+pub fn set_cross_check(state: &State, enabled: bool) {
+    state.cross_check.store(enabled, Ordering::Relaxed);
+}
+
+/// Fail with [`TokenizerError::InputTooLarge`] if `size` bytes exceed the limit
+fn check_input_size(state: &State, size: usize) -> Result<()> {
+    let max_size = state.max_input_bytes.load(Ordering::Relaxed);
+    if max_size > 0 && size > max_size {
+        return Err(TokenizerError::InputTooLarge { size, max_size });
+    }
+    Ok(())
+}
+
+/// Authenticate downloads from the Hugging Face Hub with `token`, or stop with `None`
+///
+/// Gated repositories such as Llama's need an access token from an account
+/// that accepted their license. The token is only sent to Hugging Face hosts.
+pub fn set_hf_token(state: &State, token: Option<String>) -> Result<()> {
+    let token = token.map(|token| token.trim().to_string()).filter(|token| !token.is_empty());
+    *state.hf_token.write_recovered() = token;
+    Ok(())
+}
+
+/// Send `headers` with tokenizer downloads, e.g. for an artifact mirror that
+/// requires authentication
+///
+/// Fails with [`TokenizerError::InvalidArgument`] if a header name or value
+/// cannot be sent, leaving the previous headers in place.
+pub fn set_download_headers(state: &State, headers: DownloadHeaders) -> Result<()> {
+    headers.validate()?;
+    *state.download_headers.write_recovered() = headers;
+    Ok(())
+}
+
+/// Fail with [`TokenizerError::InvalidArgument`] unless every mirror of
+/// `model` is an HTTPS URL
+fn validate_mirrors(model: &str, mirrors: &[String]) -> Result<()> {
+    match mirrors.iter().find(|mirror| !huggingface::is_valid_url(mirror)) {
+        Some(mirror) => Err(TokenizerError::InvalidArgument(format!(
+            "Mirror '{mirror}' of {model} is not an HTTPS URL"
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Download the tokenizer of `model` from `mirrors` when its source fails
+///
+/// `model` is a name given to [`from_pretrained`] or the Hugging Face
+/// repository it resolves to. Mirrors of Hub repositories are Hub endpoints
+/// such as `https://hf-mirror.com`; mirrors of tokenizer URLs are other URLs
+/// of the same file. They are tried in order, after the primary source. An
+/// empty list removes the mirrors of `model`.
+pub fn set_mirrors(state: &State, model: &str, mirrors: Vec<String>) -> Result<()> {
+    validate_mirrors(model, &mirrors)?;
+    let mut all = state.mirrors.write_recovered();
+    if mirrors.is_empty() {
+        all.remove(model);
+    } else {
+        all.insert(model.to_string(), mirrors);
+    }
+    Ok(())
+}
+
+/// Replace the settings of `state`, keeping the tokenizers already loaded
+pub fn apply_settings(state: &State, settings: Settings) -> Result<()> {
+    for (model, mirrors) in &settings.mirrors {
+        validate_mirrors(model, mirrors)?;
+    }
+    *state.mirrors.write_recovered() = settings.mirrors.into_iter().collect();
+    set_download_headers(
+        state,
+        DownloadHeaders {
+            user_agent: settings.user_agent,
+            headers: settings.headers,
+        },
+    )?;
+    set_network_enabled(state, settings.network_enabled);
+    set_max_retries(state, settings.max_retries);
+    set_max_input_bytes(state, settings.max_input_bytes);
+    set_hf_token(state, settings.hf_token)
+}
+
 /// Load a pretrained tokenizer by model name or path
 ///
 /// # Arguments
 /// * `state` - The global state to store the tokenizer in
-/// * `model` - The model name (e.g., "gpt-4") or path to a local tokenizer file
+/// * `model` - The model name (e.g., "gpt-4"), a tiktoken encoding name
+///   (e.g., "o200k_base"), a Hugging Face Hub repository (e.g.,
+///   "meta-llama/Llama-3.1-8B") or path to a local tokenizer file, which may
+///   also be a GGUF model file with an embedded tokenizer
 ///
 /// # Returns
 /// `Result<()>` indicating success or failure
 pub fn from_pretrained(state: &State, model: &str) -> Result<()> {
-    let mut tokenizer_mutex = state.tokenizer.lock()
-        .map_err(|e| TokenizerError::LockError(e.to_string()))?;
-    
-    *tokenizer_mutex = Some(match model {
-        "gpt-4" | "gpt-3.5-turbo" => {
-            let tiktoken = Tiktoken::new(model)?;
-            TokenizerType::Tiktoken(tiktoken)
-        },
-        _ => {
-            let hf_tokenizer = HuggingFaceTokenizer::new(model)?;
-            TokenizerType::HuggingFace(Box::new(hf_tokenizer))
-        },
-    });
-    
+    let tokenizer = cached_tokenizer(state, model)?;
+    make_current(state, model, tokenizer, None)?;
+    Ok(())
+}
+
+/// Make `tokenizer` the current one, unless `unless_switched_since` is given
+/// and the current tokenizer was switched since; returns whether it was made
+/// current
+fn make_current(
+    state: &State,
+    model: &str,
+    tokenizer: Arc<TokenizerType>,
+    unless_switched_since: Option<u64>,
+) -> Result<bool> {
+    let mut current = state.tokenizer.write_recovered();
+    let mut current_model = state.model.write_recovered();
+    // Read and bumped under the locks, so switches are ordered
+    let switches = state.switches.load(Ordering::Acquire);
+    if unless_switched_since.is_some_and(|started| started != switches) {
+        return Ok(false);
+    }
+    *current = Some(tokenizer);
+    *current_model = Some(model.to_string());
+    state.switches.store(switches + 1, Ordering::Release);
+    Ok(true)
+}
+
+/// Like [`from_pretrained`], loading as a background task of the scheduler
+///
+/// Downloading and parsing a large tokenizer takes seconds; the editor
+/// stays responsive while this runs. The handle returns the tokenizer and
+/// whether it became current: a [`from_pretrained`] or [`unload`] called
+/// after this one started wins, and the load is then only cached. Fails
+/// only if the scheduler does not take the task.
+pub fn from_pretrained_async(
+    state: &State,
+    model: String,
+) -> Result<TaskHandle<Result<(Arc<TokenizerType>, bool)>>> {
+    let task_state = state.clone();
+    let started = state.switches.load(Ordering::Acquire);
+    spawn_background(state, move || {
+        let state = task_state;
+        let tokenizer = cached_tokenizer(&state, &model)?;
+        let current = make_current(&state, &model, Arc::clone(&tokenizer), Some(started))?;
+        Ok((tokenizer, current))
+    })
+}
+
+/// Load the tokenizer for `model` as an owned handle
+///
+/// Unlike [`from_pretrained`] the current tokenizer is left unchanged, so
+/// callers that each need their own model do not race on the shared slot.
+/// The handle encodes and decodes on its own; loads are cached in `state`.
+pub fn load(state: &State, model: &str) -> Result<Arc<TokenizerType>> {
+    cached_tokenizer(state, model)
+}
+
+/// The current tokenizer, as reported by [`current_model`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CurrentModel {
+    /// Name given to [`from_pretrained`]
+    pub model: String,
+    /// Backend, see [`TokenizerType::backend`]
+    pub backend: &'static str,
+    /// tiktoken encoding of tiktoken tokenizers
+    pub encoding: Option<&'static str>,
+}
+
+/// The model of the current tokenizer, `None` before [`from_pretrained`]
+/// and after [`unload`]
+pub fn current_model(state: &State) -> Result<Option<CurrentModel>> {
+    let tokenizer = state.tokenizer.read_recovered();
+    let model = state.model.read_recovered();
+    let (Some(tokenizer), Some(model)) = (tokenizer.as_deref(), model.as_ref()) else {
+        return Ok(None);
+    };
+    let encoding = match tokenizer {
+        TokenizerType::Tiktoken(tiktoken) => Some(tiktoken.encoding().as_str()),
+        _ => None,
+    };
+    Ok(Some(CurrentModel {
+        model: model.clone(),
+        backend: tokenizer.backend(),
+        encoding,
+    }))
+}
+
+/// Whether a tokenizer is current, or with `model`, whether it is the one
+/// [`from_pretrained`] loaded for `model`
+///
+/// Plugins check this to skip loading a model that is already current.
+pub fn is_loaded(state: &State, model: Option<&str>) -> Result<bool> {
+    let current = current_model(state)?;
+    Ok(match model {
+        Some(model) => current.is_some_and(|current| current.model == model),
+        None => current.is_some(),
+    })
+}
+
+/// Model names [`from_pretrained`] loads, for completion in the plugin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SupportedModels {
+    /// OpenAI model names with a built-in encoding, and the exact names given
+    /// to [`set_encoding`] that resolve to an encoding, sorted; name patterns
+    /// are left out
+    pub models: Vec<String>,
+    /// tiktoken encoding names
+    pub encodings: Vec<String>,
+    /// Hugging Face repositories whose tokenizer was downloaded before
+    pub cached: Vec<String>,
+}
+
+/// The model names [`from_pretrained`] loads without guessing
+///
+/// Any other Hub repository or tokenizer file loads as well; these are the
+/// names worth offering as completions.
+pub fn list_supported_models(state: &State) -> Result<SupportedModels> {
+    let custom = state.encodings.read_recovered().keys().cloned().collect::<Vec<_>>();
+    let models: std::collections::BTreeSet<String> = tiktoken::MODEL_ENCODINGS
+        .iter()
+        .map(|(pattern, _)| pattern.to_string())
+        .chain(custom)
+        .filter(|name| !name.ends_with('*'))
+        .filter(|name| {
+            custom_encoding(state, name).is_some()
+                || tiktoken::lookup_encoding(name, tiktoken::MODEL_ENCODINGS.iter().copied())
+                    .is_some()
+        })
+        .collect();
+    Ok(SupportedModels {
+        models: models.into_iter().collect(),
+        encodings: Encoding::ALL.iter().map(|encoding| encoding.as_str().to_string()).collect(),
+        cached: huggingface::cached_models()?,
+    })
+}
+
+/// Drop the current tokenizer and every cached load; returns whether a
+/// tokenizer was current
+///
+/// Hugging Face tokenizers with large vocabularies hold tens of MB, which a
+/// long session gets back here, as do the parsed tiktoken ranks. Tokenizers
+/// stay alive while a [`register`]ed name or an outstanding handle, e.g. from
+/// [`load`], still refers to them.
+/// Until the next [`from_pretrained`], the functions using the current
+/// tokenizer fail as they do before the first one.
+pub fn unload(state: &State) -> Result<bool> {
+    let unloaded = {
+        let mut current = state.tokenizer.write_recovered();
+        state.model.write_recovered().take();
+        state.switches.fetch_add(1, Ordering::AcqRel);
+        current.take().is_some()
+    };
+    let evicted = {
+        let mut loaded = state.loaded.write_or_reset();
+        let evicted = loaded.len();
+        *loaded = HashMap::new();
+        evicted
+    };
+    state.workers.clear();
+    state.ranks.clear();
+    if evicted > 0 {
+        events::emit(Event::CacheEvicted { cache: "tokenizers", entries: evicted });
+    }
+    Ok(unloaded)
+}
+
+/// Load a HuggingFace tokenizer from the contents of a `tokenizer.json` file
+///
+/// Like [`load`], the result is a handle and the current tokenizer is left
+/// unchanged. Nothing touches the filesystem or the network.
+pub fn from_json(json: &str) -> Result<Arc<TokenizerType>> {
+    let tokenizer = HuggingFaceTokenizer::from_json(json)?;
+    Ok(Arc::new(TokenizerType::HuggingFace(Box::new(tokenizer))))
+}
+
+/// Register the tokenizer for `model` under `name`
+///
+/// Named tokenizers live next to the current one, so a session talking to
+/// several models encodes for each of them with [`encode_with`] instead of
+/// switching back and forth with [`from_pretrained`]. Registering a name
+/// again replaces its tokenizer.
+pub fn register(state: &State, name: &str, model: &str) -> Result<()> {
+    let tokenizer = cached_tokenizer(state, model)?;
+    state.registry.write_recovered().insert(name.to_string(), tokenizer);
+    Ok(())
+}
+
+/// Remove the tokenizer registered under `name`; returns whether there was one
+pub fn unregister(state: &State, name: &str) -> Result<bool> {
+    let mut registry = state.registry.write_recovered();
+    Ok(registry.remove(name).is_some())
+}
+
+/// Names of the registered tokenizers, sorted
+pub fn registered(state: &State) -> Result<Vec<String>> {
+    let registry = state.registry.read_recovered();
+    let mut names: Vec<String> = registry.keys().cloned().collect();
+    names.sort();
+    Ok(names)
+}
+
+/// The tokenizer registered under `name`
+fn registered_tokenizer(state: &State, name: &str) -> Result<Arc<TokenizerType>> {
+    state.registry.read_recovered()
+        .get(name)
+        .cloned()
+        .ok_or_else(|| TokenizerError::UnknownTokenizer(name.to_string()))
+}
+
+/// Encode text like [`encode`], with the tokenizer registered under `name`
+pub fn encode_with(state: &State, name: &str, text: &str) -> Result<(Vec<u32>, usize, usize)> {
+    registered_tokenizer(state, name)?.encode(text)
+}
+
+/// Decode tokens like [`decode`], with the tokenizer registered under `name`
+pub fn decode_with(state: &State, name: &str, tokens: &[u32]) -> Result<String> {
+    registered_tokenizer(state, name)?.decode(tokens)
+}
+
+/// Most models one [`preload`] call loads
+pub const MAX_PRELOAD_MODELS: usize = 8;
+
+/// Most tokenizers [`preload`] loads at once, across calls
+pub const MAX_CONCURRENT_PRELOADS: usize = 2;
+
+/// Load tokenizers for `models` as background tasks of the scheduler
+///
+/// Loaded tokenizers are cached in `state`, so a later [`from_pretrained`]
+/// for one of the models does not wait for BPE construction or downloads.
+/// The current tokenizer is left unchanged. Models already loaded or listed
+/// twice are skipped, and only the first [`MAX_PRELOAD_MODELS`] of the rest
+/// are loaded, at most [`MAX_CONCURRENT_PRELOADS`] at a time, since every
+/// tokenizer stays in memory. Returns one handle per model loaded.
+pub fn preload(state: &State, models: Vec<String>) -> Result<Vec<TaskHandle<Result<()>>>> {
+    let mut models: Vec<String> = {
+        let loaded = state.loaded.read_recovered();
+        let mut seen = std::collections::HashSet::new();
+        models
+            .into_iter()
+            .filter(|model| !loaded.contains_key(model) && seen.insert(model.clone()))
+            .collect()
+    };
+    if models.len() > MAX_PRELOAD_MODELS {
+        log::warn!(
+            "Preloading only {MAX_PRELOAD_MODELS} of {} tokenizers: {}",
+            models.len(),
+            models[MAX_PRELOAD_MODELS..].join(", ")
+        );
+        models.truncate(MAX_PRELOAD_MODELS);
+    }
+    models
+        .into_iter()
+        .map(|model| {
+            let task_state = state.clone();
+            spawn_background(state, move || {
+                let state = task_state;
+                let _slot = state.preloads.acquire(MAX_CONCURRENT_PRELOADS);
+                cached_tokenizer(&state, &model).map(|_| ()).map_err(|e| {
+                    log::warn!("Failed to preload tokenizer for {model}: {e}");
+                    e
+                })
+            })
+        })
+        .collect()
+}
+
+/// Warm up `encodings` and the current tokenizer as a background task
+///
+/// Parses the ranks of each encoding and runs a first encode with it and with
+/// the current tokenizer, see [`Encoding::warm_up`], so the first interactive
+/// count is not the slow one. Returns the handle of the task.
+pub fn warmup(state: &State, encodings: Vec<Encoding>) -> Result<TaskHandle<Result<()>>> {
+    let task_state = state.clone();
+    spawn_background(state, move || {
+        let state = task_state;
+        let started = Instant::now();
+        let warmed = warm_up(&state, &encodings);
+        match &warmed {
+            Ok(()) => log::debug!("Warmed up tokenizers in {:?}", started.elapsed()),
+            Err(e) => log::warn!("Failed to warm up tokenizers: {e}"),
+        }
+        warmed
+    })
+}
+
+fn warm_up(state: &State, encodings: &[Encoding]) -> Result<()> {
+    for encoding in encodings {
+        encoding.warm_up(&state.ranks)?;
+    }
+    let current = state.tokenizer.read_recovered().clone();
+    if let Some(tokenizer) = current {
+        tokenizer.encode(tiktoken::WARMUP_TEXT)?;
+    }
     Ok(())
 }
 
@@ -75,39 +943,2384 @@ pub fn from_pretrained(state: &State, model: &str) -> Result<()> {
 /// - The number of tokens
 /// - The number of characters in the input text
 pub fn encode(state: &State, text: &str) -> Result<(Vec<u32>, usize, usize)> {
-    let tokenizer = state.tokenizer.lock()
-        .map_err(|e| TokenizerError::LockError(e.to_string()))?;
-        
-    match tokenizer.as_ref() {
-        Some(TokenizerType::Tiktoken(tokenizer)) => {
-            let (tokens, num_tokens, num_chars) = tokenizer.encode(text);
-            Ok((tokens, num_tokens, num_chars))
-        },
-        Some(TokenizerType::HuggingFace(tokenizer)) => {
-            tokenizer.encode(text)
-        },
+    encode_with_special(state, text, SpecialTokens::default())
+}
+
+/// Encode text like [`encode`], handling special token strings as `special` says
+///
+/// Use [`SpecialTokens::Ordinary`] for untrusted text, so strings such as
+/// `<|endoftext|>` cannot turn into real special tokens.
+pub fn encode_with_special(
+    state: &State,
+    text: &str,
+    special: SpecialTokens,
+) -> Result<(Vec<u32>, usize, usize)> {
+    encode_timed(state, text, special).map(|(encoded, _)| encoded)
+}
+
+/// Encode text like [`encode_with_special`] and report how long it took
+/// with which backend
+///
+/// Every encode with the current tokenizer adds its timing to
+/// `state.stats`; this one also returns it.
+pub fn encode_timed(
+    state: &State,
+    text: &str,
+    special: SpecialTokens,
+) -> Result<((Vec<u32>, usize, usize), Timing)> {
+    timed(state, text.len(), |tokenizer| {
+        let encoded = tokenizer.encode_with_special(text, special)?;
+        Ok((encoded.1, encoded))
+    })
+}
+
+/// Run `encode` with the current tokenizer and add its timing to
+/// `state.stats`; `encode` returns the number of tokens with its result
+fn timed<T>(
+    state: &State,
+    bytes: usize,
+    encode: impl FnOnce(&TokenizerType) -> Result<(usize, T)>,
+) -> Result<(T, Timing)> {
+    check_input_size(state, bytes)?;
+    let tokenizer = state.tokenizer.read_recovered();
+    let tokenizer = tokenizer.as_deref().ok_or_else(|| {
+        TokenizerError::TokenizerError("Tokenizer not initialized".to_string())
+    })?;
+
+    let started = Instant::now();
+    let (num_tokens, encoded) = encode(tokenizer)?;
+    let timing = record_encode(state, tokenizer, started, bytes, num_tokens);
+    Ok((encoded, timing))
+}
+
+/// Add an encode of `bytes` bytes into `num_tokens` tokens, begun at
+/// `started`, to `state.stats`
+fn record_encode(
+    state: &State,
+    tokenizer: &TokenizerType,
+    started: Instant,
+    bytes: usize,
+    num_tokens: usize,
+) -> Timing {
+    let timing = Timing {
+        backend: tokenizer.backend(),
+        elapsed: started.elapsed(),
+        num_tokens,
+        bytes,
+    };
+    state.stats.record(&timing);
+    timing
+}
+
+/// Encode text like [`encode`], with tiktoken-style allowed and disallowed
+/// special tokens
+///
+/// Fails with [`TokenizerError::DisallowedSpecialToken`] when the text
+/// contains a disallowed special token string. The default policy disallows
+/// all of them, which suits text from buffers or files.
+pub fn encode_with_policy(
+    state: &State,
+    text: &str,
+    policy: &SpecialTokenPolicy,
+) -> Result<(Vec<u32>, usize, usize)> {
+    encode_with_policy_timed(state, text, policy).map(|(encoded, _)| encoded)
+}
+
+fn encode_with_policy_timed(
+    state: &State,
+    text: &str,
+    policy: &SpecialTokenPolicy,
+) -> Result<((Vec<u32>, usize, usize), Timing)> {
+    timed(state, text.len(), |tokenizer| {
+        let encoded = tokenizer.encode_with_policy(text, policy)?;
+        Ok((encoded.1, encoded))
+    })
+}
+
+/// Encode text and report the span of every token in `unit`
+pub fn encode_with_offsets(
+    state: &State,
+    text: &str,
+    unit: OffsetUnit,
+    special: SpecialTokens,
+) -> Result<EncodingWithOffsets> {
+    encode_with_offsets_timed(state, text, unit, special).map(|(encoded, _)| encoded)
+}
+
+/// Encode the text of a buffer and report every token as a range of
+/// (row, byte column) positions, e.g. to highlight where tokens split
+///
+/// `text` is the buffer lines joined with `\n`, see [`TokenRange`].
+pub fn token_ranges(state: &State, text: &str, special: SpecialTokens) -> Result<Vec<TokenRange>> {
+    let encoded = encode_with_offsets(state, text, OffsetUnit::Byte, special)?;
+    Ok(offsets::token_ranges(text, &encoded.tokens, &encoded.offsets))
+}
+
+fn encode_with_offsets_timed(
+    state: &State,
+    text: &str,
+    unit: OffsetUnit,
+    special: SpecialTokens,
+) -> Result<(EncodingWithOffsets, Timing)> {
+    timed(state, text.len(), |tokenizer| {
+        let (tokens, offsets) = tokenizer.encode_with_offsets(text, unit, special)?;
+        let encoded = EncodingWithOffsets {
+            num_tokens: tokens.len(),
+            num_chars: text.chars().count(),
+            tokens,
+            offsets,
+            byte_offsets: unit == OffsetUnit::Byte,
+        };
+        Ok((encoded.num_tokens, encoded))
+    })
+}
+
+/// Encode several texts, locking the tokenizer only once
+///
+/// Returns the same tuple as [`encode`] for every text, in order. Fails on
+/// the first text that cannot be encoded, or is larger than
+/// [`set_max_input_bytes`] allows.
+pub fn encode_batch(state: &State, texts: &[String]) -> Result<Vec<(Vec<u32>, usize, usize)>> {
+    for text in texts {
+        check_input_size(state, text.len())?;
+    }
+    let tokenizer = state.tokenizer.read_recovered();
+
+    let tokenizer = tokenizer.as_deref().ok_or_else(|| {
+        TokenizerError::TokenizerError("Tokenizer not initialized".to_string())
+    })?;
+
+    let started = Instant::now();
+    let encoded: Vec<_> = texts.iter().map(|text| tokenizer.encode(text)).collect::<Result<_>>()?;
+    record_batch(state, tokenizer, started, texts, &encoded);
+    Ok(encoded)
+}
+
+/// Add a batch encode to `state.stats`, as a single call
+fn record_batch(
+    state: &State,
+    tokenizer: &TokenizerType,
+    started: Instant,
+    texts: &[String],
+    encoded: &[(Vec<u32>, usize, usize)],
+) {
+    let bytes = texts.iter().map(String::len).sum();
+    let num_tokens = encoded.iter().map(|(_, num_tokens, _)| num_tokens).sum();
+    record_encode(state, tokenizer, started, bytes, num_tokens);
+}
+
+/// Count `text` with the current tokenizer through every counting path and
+/// report the ones disagreeing with [`encode`], see [`consistency::check`]
+pub fn check_consistency(state: &State, text: &str) -> Result<Vec<Mismatch>> {
+    check_input_size(state, text.len())?;
+    let tokenizer = state.tokenizer.read_recovered()
+        .clone()
+        .ok_or_else(|| TokenizerError::TokenizerError("Tokenizer not initialized".to_string()))?;
+    let model = state.model.read_recovered().clone();
+    // The batch path runs on the pool of the batches, whatever its size
+    let pool = state.pools.any(1)?;
+    consistency::check(&tokenizer, text, model.as_deref(), &pool)
+}
+
+/// Compare the counts of a parallel batch with encoding each text whole, if
+/// `state.cross_check` is set
+///
+/// Disagreements are logged; returns how many texts disagreed.
+fn cross_check_batch(
+    state: &State,
+    tokenizer: &TokenizerType,
+    texts: &[String],
+    encoded: &[(Vec<u32>, usize, usize)],
+) -> usize {
+    if !state.cross_check.load(Ordering::Relaxed) {
+        return 0;
+    }
+    texts
+        .iter()
+        .zip(encoded)
+        .filter(|(text, (_, num_tokens, _))| {
+            !consistency::cross_check(tokenizer, "batch", text, *num_tokens)
+        })
+        .count()
+}
+
+/// Decode token IDs into text using the loaded tokenizer
+///
+/// Incomplete UTF-8 sequences are replaced with U+FFFD; use
+/// [`decode_stream`] to decode tokens as they stream in.
+pub fn decode(state: &State, tokens: &[u32]) -> Result<String> {
+    decode_with_special(state, tokens, false)
+}
+
+/// Decode token IDs like [`decode`], leaving out special tokens such as
+/// `<|endoftext|>` or `<|im_end|>` if `skip_special_tokens` is set
+///
+/// Completions shown to the user should not end in chat markers; counts and
+/// round trips need them kept.
+pub fn decode_with_special(
+    state: &State,
+    tokens: &[u32],
+    skip_special_tokens: bool,
+) -> Result<String> {
+    let tokenizer = state.tokenizer.read_recovered();
+
+    match tokenizer.as_deref() {
+        Some(tokenizer) => tokenizer.decode_with_special(tokens, skip_special_tokens),
         None => Err(TokenizerError::TokenizerError("Tokenizer not initialized".to_string())),
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Start decoding a streamed completion with the current tokenizer
+///
+/// Feed the token IDs to the returned [`StreamDecoder`] as they arrive; it
+/// only emits text once a character is complete.
+pub fn decode_stream(state: &State) -> Result<StreamDecoder> {
+    decode_stream_with_special(state, false)
+}
 
-    #[test]
-    fn test_tokenizer_initialization() {
-        let state = State::new();
-        assert!(from_pretrained(&state, "gpt-4").is_ok());
+/// Start decoding a streamed completion like [`decode_stream`], leaving out
+/// special tokens if `skip_special_tokens` is set, see [`decode_with_special`]
+pub fn decode_stream_with_special(
+    state: &State,
+    skip_special_tokens: bool,
+) -> Result<StreamDecoder> {
+    let tokenizer = state.tokenizer.read_recovered()
+        .clone()
+        .ok_or_else(|| TokenizerError::TokenizerError("Tokenizer not initialized".to_string()))?;
+    Ok(StreamDecoder::with_special(tokenizer, skip_special_tokens))
+}
+
+/// A budget of `max_tokens` tokens counted with the current tokenizer
+///
+/// Like [`decode_stream`], the budget keeps its tokenizer when the model changes.
+pub fn token_budget(state: &State, max_tokens: usize) -> Result<TokenBudget> {
+    let tokenizer = state.tokenizer.read_recovered()
+        .clone()
+        .ok_or_else(|| TokenizerError::TokenizerError("Tokenizer not initialized".to_string()))?;
+    Ok(TokenBudget::new(tokenizer, max_tokens))
+}
+
+/// An encoder counting text appended piece by piece with the current tokenizer
+///
+/// Like [`decode_stream`], the encoder keeps its tokenizer when the model
+/// changes.
+pub fn incremental_encoder(state: &State) -> Result<IncrementalEncoder> {
+    let tokenizer = state.tokenizer.read_recovered()
+        .clone()
+        .ok_or_else(|| TokenizerError::TokenizerError("Tokenizer not initialized".to_string()))?;
+    Ok(IncrementalEncoder::new(tokenizer))
+}
+
+/// `text` encoded with the current tokenizer, to be kept up to date with
+/// [`TokenizedBuffer::edit`] as the buffer changes
+///
+/// Like [`decode_stream`], the buffer keeps its tokenizer when the model
+/// changes.
+pub fn tokenized_buffer(state: &State, text: &str) -> Result<TokenizedBuffer> {
+    let tokenizer = state.tokenizer.read_recovered()
+        .clone()
+        .ok_or_else(|| TokenizerError::TokenizerError("Tokenizer not initialized".to_string()))?;
+    let buffer = TokenizedBuffer::new(tokenizer, text)?;
+    Ok(buffer.with_cross_check(state.cross_check.load(Ordering::Relaxed)))
+}
+
+/// Encode several texts in parallel on a pool of `worker_threads` threads
+///
+/// Meant for large batches such as whole-repository counts; the pool size
+/// usually comes from `PerformanceConfig.worker_threads`, and the pool is
+/// kept for the next batch of the same size. Results are in the
+/// same order as `texts`. Workers encode with their own handles rather than
+/// through `state`, so throughput grows with the pool.
+pub fn encode_batch_parallel(
+    state: &State,
+    texts: &[String],
+    worker_threads: usize,
+) -> Result<Vec<(Vec<u32>, usize, usize)>> {
+    for text in texts {
+        check_input_size(state, text.len())?;
     }
+    // Clone the tokenizer out of the lock so loading a model is not blocked
+    let tokenizer = state.tokenizer.read_recovered()
+        .clone()
+        .ok_or_else(|| TokenizerError::TokenizerError("Tokenizer not initialized".to_string()))?;
+    let worker_threads = worker_threads.max(1);
+    let pool = state.pools.get(worker_threads)?;
+    let encoders = WorkerEncoders::cached(&state.workers, Arc::clone(&tokenizer), worker_threads);
 
-    #[test]
-    fn test_encoding() {
-        let state = State::new();
-        from_pretrained(&state, "gpt-4").unwrap();
-        let (tokens, num_tokens, num_chars) = encode(&state, "Hello, world!").unwrap();
-        assert!(!tokens.is_empty());
-        assert!(num_tokens > 0);
-        assert!(num_chars > 0);
+    let started = Instant::now();
+    let encoded: Vec<_> = pool.install(|| {
+        texts.par_iter().map(|text| encoders.get().encode(text)).collect::<Result<_>>()
+    })?;
+    record_batch(state, &tokenizer, started, texts, &encoded);
+    cross_check_batch(state, &tokenizer, texts, &encoded);
+    Ok(encoded)
+}
+
+/// Encode text that may contain U+FFFD replacement characters
+///
+/// Replacement characters are handled according to `mode` and their character
+/// positions are reported, so budgets computed for buffers that were not valid
+/// UTF-8 stay consistent.
+pub fn encode_lossy(state: &State, text: &str, mode: ReplacementMode) -> Result<LossyEncoding> {
+    replacement::encode_lossy(text, mode, |text| {
+        encode(state, text).map(|(tokens, _, _)| tokens)
+    })
+}
+
+/// Encode text, estimating lines that are too long to encode quickly
+///
+/// With [`LongLineMode::Estimate`], lines longer than the threshold are not
+/// passed to the tokenizer and the result is flagged as estimated.
+pub fn encode_guarded(state: &State, text: &str, mode: LongLineMode) -> Result<GuardedEncoding> {
+    check_input_size(state, text.len())?;
+    let tokenizer = state.tokenizer.read_recovered();
+    let tokenizer = tokenizer.as_deref().ok_or_else(|| {
+        TokenizerError::TokenizerError("Tokenizer not initialized".to_string())
+    })?;
+
+    long_lines::encode_guarded(text, mode, |text| {
+        tokenizer.encode(text).map(|(tokens, _, _)| tokens)
+    })
+}
+
+/// Count the tokens of files on disk in parallel, see [`files`]
+///
+/// Returns one result per path, in order.
+pub fn count_files(state: &State, paths: &[PathBuf]) -> Result<Vec<Result<FileCount>>> {
+    let tokenizer = state.tokenizer.read_recovered()
+        .clone()
+        .ok_or_else(|| TokenizerError::TokenizerError("Tokenizer not initialized".to_string()))?;
+    let encoders = WorkerEncoders::cached(&state.workers, tokenizer, rayon::current_num_threads());
+    let max_bytes = state.max_input_bytes.load(Ordering::Relaxed);
+
+    Ok(files::count_files(paths, max_bytes, |text| {
+        encoders.get().encode(text).map(|(tokens, _, _)| tokens)
+    }))
+}
+
+/// ID of the token written as `token` in the vocabulary of the loaded
+/// tokenizer, or `None` if no single token is
+///
+/// Tokens are written as in [`vocabulary`]: raw vocabulary strings for
+/// HuggingFace tokenizers, text with `<0xNN>` for partial UTF-8 bytes for
+/// tiktoken. Anthropic approximations look up their cl100k_base tokens.
+pub fn token_to_id(state: &State, token: &str) -> Result<Option<u32>> {
+    let tokenizer = state.tokenizer.read_recovered();
+    let tokenizer = tokenizer.as_deref().ok_or_else(|| {
+        TokenizerError::TokenizerError("Tokenizer not initialized".to_string())
+    })?;
+    Ok(tokenizer.token_to_id(token))
+}
+
+/// Vocabulary entry of token `id` in the loaded tokenizer, or `None` for IDs
+/// it does not use, see [`token_to_id`]
+pub fn id_to_token(state: &State, id: u32) -> Result<Option<String>> {
+    let tokenizer = state.tokenizer.read_recovered();
+    let tokenizer = tokenizer.as_deref().ok_or_else(|| {
+        TokenizerError::TokenizerError("Tokenizer not initialized".to_string())
+    })?;
+    Ok(tokenizer.id_to_token(id))
+}
+
+/// Vocabulary and merges of the loaded tokenizer
+pub fn vocabulary(state: &State) -> Result<Vocabulary> {
+    let tokenizer = state.tokenizer.read_recovered();
+
+    match tokenizer.as_deref() {
+        Some(TokenizerType::Tiktoken(tokenizer)) => Ok(tokenizer.vocabulary()),
+        Some(TokenizerType::HuggingFace(tokenizer)) => tokenizer.vocabulary(),
+        Some(TokenizerType::Anthropic(_)) => Err(TokenizerError::TokenizerError(
+            "Anthropic tokenizers are approximated and have no vocabulary".to_string(),
+        )),
+        None => Err(TokenizerError::TokenizerError("Tokenizer not initialized".to_string())),
+    }
+}
+
+/// Dump the vocabulary and merges of the loaded tokenizer to `path`
+pub fn export_vocab(state: &State, path: &Path, format: VocabFormat) -> Result<()> {
+    vocab::write_vocab(&vocabulary(state)?, path, format)
+}
+
+/// Cut `text` to at most `max_tokens` tokens, keeping the part chosen by `strategy`
+///
+/// The cut follows the token boundaries of the loaded tokenizer, see
+/// [`truncate`]. [`TruncateStrategy::Middle`] elides the middle with
+/// [`truncate::DEFAULT_MARKER`].
+pub fn truncate(
+    state: &State,
+    text: &str,
+    max_tokens: usize,
+    strategy: TruncateStrategy,
+) -> Result<Truncation> {
+    truncate_with_marker(state, text, max_tokens, strategy, truncate::DEFAULT_MARKER)
+}
+
+/// Cut `text` like [`truncate`], eliding the middle with `marker`
+///
+/// The marker counts towards `max_tokens`.
+pub fn truncate_with_marker(
+    state: &State,
+    text: &str,
+    max_tokens: usize,
+    strategy: TruncateStrategy,
+    marker: &str,
+) -> Result<Truncation> {
+    check_input_size(state, text.len())?;
+    let tokenizer = state.tokenizer.read_recovered();
+    let tokenizer = tokenizer.as_deref().ok_or_else(|| {
+        TokenizerError::TokenizerError("Tokenizer not initialized".to_string())
+    })?;
+
+    truncate::truncate(text, max_tokens, strategy, marker, |text| {
+        tokenizer.encode_with_offsets(text, OffsetUnit::Byte, SpecialTokens::default())
+    })
+}
+
+/// Token IDs of each word with and without a leading space, for `logit_bias`
+///
+/// Special token strings are encoded as ordinary text, see [`logit_bias`].
+pub fn tokens_for_words(state: &State, words: &[String]) -> Result<Vec<WordTokens>> {
+    let tokenizer = state.tokenizer.read_recovered();
+    let tokenizer = tokenizer.as_deref().ok_or_else(|| {
+        TokenizerError::TokenizerError("Tokenizer not initialized".to_string())
+    })?;
+
+    logit_bias::tokens_for_words(words, |text| {
+        tokenizer.encode_with_special(text, SpecialTokens::Ordinary).map(|(tokens, _, _)| tokens)
+    })
+}
+
+/// Report how each stop sequence tokenizes and where it merges with the
+/// surrounding text, see [`stop`]
+pub fn analyze_stop_sequences(
+    state: &State,
+    sequences: &[String],
+) -> Result<Vec<StopSequenceReport>> {
+    let tokenizer = state.tokenizer.read_recovered();
+    let tokenizer = tokenizer.as_deref().ok_or_else(|| {
+        TokenizerError::TokenizerError("Tokenizer not initialized".to_string())
+    })?;
+
+    stop::analyze_stop_sequences(sequences, |text| {
+        tokenizer.encode_with_offsets(text, OffsetUnit::Byte, SpecialTokens::default())
+    })
+}
+
+/// Split `text` into chunks of `max_tokens` tokens sharing `overlap_tokens`, see [`chunk`]
+pub fn chunk(
+    state: &State,
+    text: &str,
+    max_tokens: usize,
+    overlap_tokens: usize,
+) -> Result<Vec<Chunk>> {
+    check_input_size(state, text.len())?;
+    let tokenizer = state.tokenizer.read_recovered();
+    let tokenizer = tokenizer.as_deref().ok_or_else(|| {
+        TokenizerError::TokenizerError("Tokenizer not initialized".to_string())
+    })?;
+
+    chunk::chunk(text, max_tokens, overlap_tokens, |text| {
+        tokenizer.encode_with_offsets(text, OffsetUnit::Byte, SpecialTokens::default())
+    })
+}
+
+/// Tokens of each part of a chat message, see [`chat`]
+///
+/// Text is encoded with the current tokenizer; images and tool calls are
+/// counted by the rules of the model given to [`from_pretrained`].
+pub fn count_message_parts(state: &State, parts: &[MessagePart]) -> Result<Vec<usize>> {
+    let tokenizer = state.tokenizer.read_recovered();
+    let tokenizer = tokenizer.as_deref().ok_or_else(|| {
+        TokenizerError::TokenizerError("Tokenizer not initialized".to_string())
+    })?;
+    let model = state.model.read_recovered();
+    let rules = PartRules::for_model(model.as_deref());
+
+    chat::count_parts(parts, rules, |text| {
+        tokenizer.encode(text).map(|(_, num_tokens, _)| num_tokens)
+    })
+}
+
+/// Tokens of a conversation as the chat API of the current model reports
+/// them in its usage, see [`chat`]
+///
+/// Adds the framing of every message and of the reply to the tokens of the
+/// roles, names and contents.
+pub fn count_chat_tokens(state: &State, messages: &[ChatMessage]) -> Result<usize> {
+    let tokenizer = state.tokenizer.read_recovered();
+    let tokenizer = tokenizer.as_deref().ok_or_else(|| {
+        TokenizerError::TokenizerError("Tokenizer not initialized".to_string())
+    })?;
+    let model = state.model.read_recovered();
+    let framing = ChatFraming::for_model(model.as_deref());
+    let rules = PartRules::for_model(model.as_deref());
+
+    chat::count_messages(messages, framing, rules, |text| {
+        tokenizer.encode(text).map(|(_, num_tokens, _)| num_tokens)
+    })
+}
+
+/// Render `messages` with a chat template and count the tokens of the prompt
+///
+/// `template` defaults to the one shipped with the loaded tokenizer; models
+/// without one, such as OpenAI's, need it passed explicitly. Special tokens
+/// written by the template are counted as the single tokens the model sees.
+/// Returns the prompt and its number of tokens.
+pub fn apply_chat_template(
+    state: &State,
+    messages: &[ChatMessage],
+    template: Option<&ChatTemplate>,
+    add_generation_prompt: bool,
+) -> Result<(String, usize)> {
+    let tokenizer = state.tokenizer.read_recovered();
+    let tokenizer = tokenizer.as_deref().ok_or_else(|| {
+        TokenizerError::TokenizerError("Tokenizer not initialized".to_string())
+    })?;
+    let template = template.or_else(|| tokenizer.chat_template()).ok_or_else(|| {
+        TokenizerError::ChatTemplate("The loaded tokenizer has no chat template".to_string())
+    })?;
+
+    let prompt = template.render(messages, add_generation_prompt)?;
+    let (_, num_tokens, _) = tokenizer.encode_with_special(&prompt, SpecialTokens::Special)?;
+    Ok((prompt, num_tokens))
+}
+
+/// Tokens of `value` serialized as compact JSON, the way tool schemas and
+/// arguments are sent
+///
+/// Returns the count with the serialized text, so callers can send the text
+/// they counted instead of serializing twice. Special token strings in the
+/// JSON are counted as ordinary text.
+pub fn count_json(state: &State, value: &serde_json::Value) -> Result<(usize, String)> {
+    let json = serde_json::to_string(value)?;
+    let (_, num_tokens, _) = encode_with_special(state, &json, SpecialTokens::Ordinary)?;
+    Ok((num_tokens, json))
+}
+
+/// Encode text and serialize the resulting [`TokenCount`] in `format`
+pub fn encode_as(state: &State, text: &str, format: OutputFormat) -> Result<Vec<u8>> {
+    let (tokens, num_tokens, num_chars) = encode(state, text)?;
+    export::to_bytes(
+        &TokenCount {
+            tokens,
+            num_tokens,
+            num_chars,
+        },
+        format,
+    )
+}
+
+#[cfg(feature = "lua")]
+fn timing_to_lua(lua: &Lua, timing: &Timing) -> LuaResult<LuaTable> {
+    let table = lua.create_table()?;
+    table.set("backend", timing.backend)?;
+    table.set("elapsed_ms", timing.elapsed.as_secs_f64() * 1000.0)?;
+    table.set("tokens_per_sec", timing.tokens_per_sec())?;
+    Ok(table)
+}
+
+/// A tokenizer error as a table, so Lua can match on its number instead of
+/// its message
+#[cfg(feature = "lua")]
+fn tokenizer_error_to_lua(lua: &Lua, err: &TokenizerError) -> LuaResult<LuaTable> {
+    let table = lua.create_table()?;
+    table.set("number", err.number())?;
+    table.set("name", err.name())?;
+    table.set("message", err.to_string())?;
+    Ok(table)
+}
+
+#[cfg(feature = "lua")]
+fn counters_to_lua(lua: &Lua, counters: &Counters) -> LuaResult<LuaTable> {
+    let table = lua.create_table()?;
+    table.set("calls", counters.calls)?;
+    table.set("tokens", counters.tokens)?;
+    table.set("bytes", counters.bytes)?;
+    table.set("elapsed_ms", counters.elapsed.as_secs_f64() * 1000.0)?;
+    table.set("tokens_per_sec", counters.tokens_per_sec())?;
+    Ok(table)
+}
+
+#[cfg(feature = "lua")]
+fn invalid_input(message: String) -> neopilot_error::Error {
+    neopilot_error::Error::new(neopilot_error::ErrorCode::InvalidInput, message)
+}
+
+/// Special token set from Lua: `"all"` or a list of token strings
+#[cfg(feature = "lua")]
+fn special_set_from_lua(value: LuaValue) -> LuaResult<Option<SpecialSet>> {
+    match value {
+        LuaValue::Nil => Ok(None),
+        LuaValue::String(set) if &*set.to_str()? == "all" => Ok(Some(SpecialSet::All)),
+        LuaValue::Table(tokens) => {
+            let tokens = tokens.sequence_values::<String>().collect::<LuaResult<_>>()?;
+            Ok(Some(SpecialSet::Only(tokens)))
+        }
+        other => Err(invalid_input(format!(
+            "Invalid special token set of type {}, expected \"all\" or a list",
+            other.type_name()
+        ))
+        .into()),
+    }
+}
+
+/// Policy from a `{ allowed_special?, disallowed_special? }` Lua table
+#[cfg(feature = "lua")]
+fn special_policy_from_lua(table: &LuaTable) -> LuaResult<SpecialTokenPolicy> {
+    let mut policy = SpecialTokenPolicy::default();
+    if let Some(allowed) = special_set_from_lua(table.get("allowed_special")?)? {
+        policy.allowed = allowed;
+    }
+    if let Some(disallowed) = special_set_from_lua(table.get("disallowed_special")?)? {
+        policy.disallowed = disallowed;
+    }
+    Ok(policy)
+}
+
+/// Message part from a `{ type = "text" | "image" | "tool_call", ... }` Lua table
+#[cfg(feature = "lua")]
+fn message_part_from_lua(part: LuaTable) -> LuaResult<MessagePart> {
+    let kind: String = part.get("type")?;
+    match kind.as_str() {
+        "text" => Ok(MessagePart::Text(part.get("text")?)),
+        "image" => Ok(MessagePart::Image {
+            width: part.get("width")?,
+            height: part.get("height")?,
+            detail: match part.get::<Option<String>>("detail")? {
+                Some(detail) => detail.parse().map_err(invalid_input)?,
+                None => ImageDetail::default(),
+            },
+        }),
+        "tool_call" => Ok(MessagePart::ToolCall {
+            name: part.get("name")?,
+            arguments: part.get("arguments")?,
+        }),
+        other => Err(invalid_input(format!(
+            "Unknown message part type '{other}', expected text, image or tool_call"
+        ))
+        .into()),
+    }
+}
+
+/// Deepest nesting of tables [`json_from_lua`] accepts, as `vim.json.encode`
+#[cfg(feature = "lua")]
+const MAX_JSON_DEPTH: usize = 1000;
+
+/// JSON value of a Lua value, close to what `vim.json.encode` produces
+///
+/// Sequences become arrays and other tables objects. Unlike `vim.json`, the
+/// keys are sorted so counts do not depend on table iteration order, and `/`
+/// is not escaped, so the text may differ from it. Empty tables are arrays
+/// unless they have a metatable, as `vim.empty_dict()` does; `nil` and
+/// `vim.NIL` are null. Tables nested deeper than [`MAX_JSON_DEPTH`], such as
+/// ones referring to themselves, are rejected.
+#[cfg(feature = "lua")]
+fn json_from_lua(value: LuaValue, depth: usize) -> LuaResult<serde_json::Value> {
+    use serde_json::Value;
+
+    match value {
+        LuaValue::Nil => Ok(Value::Null),
+        LuaValue::LightUserData(data) if data.0.is_null() => Ok(Value::Null),
+        LuaValue::Boolean(value) => Ok(Value::Bool(value)),
+        LuaValue::Integer(value) => Ok(Value::from(value)),
+        // Integral numbers are written without a fraction, as vim.json does
+        LuaValue::Number(value) if value.fract() == 0.0 && value.abs() < 1e15 => {
+            Ok(Value::from(value as i64))
+        }
+        LuaValue::Number(value) => serde_json::Number::from_f64(value)
+            .map(Value::Number)
+            .ok_or_else(|| invalid_input(format!("Cannot serialize {value} as JSON")).into()),
+        LuaValue::String(value) => Ok(Value::String(value.to_string_lossy())),
+        LuaValue::Table(_) if depth >= MAX_JSON_DEPTH => Err(invalid_input(format!(
+            "Cannot serialize tables nested deeper than {MAX_JSON_DEPTH} as JSON"
+        ))
+        .into()),
+        LuaValue::Table(table) => {
+            let len = table.raw_len();
+            let pairs = table.clone().pairs::<LuaValue, LuaValue>().collect::<LuaResult<Vec<_>>>()?;
+            if pairs.is_empty() && table.metatable().is_some() {
+                return Ok(Value::Object(serde_json::Map::new()));
+            }
+            if pairs.len() == len {
+                return table
+                    .sequence_values::<LuaValue>()
+                    .map(|value| json_from_lua(value?, depth + 1))
+                    .collect::<LuaResult<_>>()
+                    .map(Value::Array);
+            }
+            let mut entries = pairs
+                .into_iter()
+                .map(|(key, value)| {
+                    let key = match key {
+                        LuaValue::String(key) => key.to_string_lossy(),
+                        LuaValue::Integer(key) => key.to_string(),
+                        LuaValue::Number(key) => key.to_string(),
+                        other => {
+                            return Err(invalid_input(format!(
+                                "Cannot serialize a table key of type {} as JSON",
+                                other.type_name()
+                            ))
+                            .into())
+                        }
+                    };
+                    Ok((key, json_from_lua(value, depth + 1)?))
+                })
+                .collect::<LuaResult<Vec<_>>>()?;
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Ok(Value::Object(entries.into_iter().collect()))
+        }
+        other => Err(invalid_input(format!(
+            "Cannot serialize a value of type {} as JSON",
+            other.type_name()
+        ))
+        .into()),
+    }
+}
+
+/// Chat message from a `{ role, content, name? }` Lua table, whose content is
+/// a string or a list of message parts
+#[cfg(feature = "lua")]
+fn chat_message_from_lua(message: LuaTable) -> LuaResult<ChatMessage> {
+    let content = match message.get::<LuaValue>("content")? {
+        LuaValue::String(text) => vec![MessagePart::Text(text.to_str()?.to_string())],
+        LuaValue::Table(parts) => parts
+            .sequence_values::<LuaTable>()
+            .map(|part| message_part_from_lua(part?))
+            .collect::<LuaResult<_>>()?,
+        LuaValue::Nil => Vec::new(),
+        other => {
+            return Err(invalid_input(format!(
+                "Invalid message content of type {}, expected a string or a list of parts",
+                other.type_name()
+            ))
+            .into())
+        }
+    };
+    Ok(ChatMessage {
+        role: message.get("role")?,
+        name: message.get("name")?,
+        content,
+    })
+}
+
+/// Arguments of the Lua `truncate`: text, budget, strategy and marker
+#[cfg(feature = "lua")]
+type TruncateArgs = (LuaString, usize, Option<String>, Option<String>);
+
+/// Tokenizer handle exposed to Lua, independent of the module's current tokenizer
+#[cfg(feature = "lua")]
+struct LuaTokenizer {
+    tokenizer: Arc<TokenizerType>,
+}
+
+#[cfg(feature = "lua")]
+impl LuaUserData for LuaTokenizer {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("encode", |_, this, (text, special_tokens): (String, LuaValue)| {
+            let tokenizer = &this.tokenizer;
+            let encoded = match special_tokens {
+                LuaValue::Nil => tokenizer.encode(&text)?,
+                LuaValue::Boolean(special) => tokenizer.encode_with_special(&text, special.into())?,
+                LuaValue::Table(policy) => {
+                    tokenizer.encode_with_policy(&text, &special_policy_from_lua(&policy)?)?
+                }
+                other => {
+                    return Err(invalid_input(format!(
+                        "Invalid special_tokens option of type {}",
+                        other.type_name()
+                    ))
+                    .into())
+                }
+            };
+            Ok(encoded)
+        });
+        methods.add_method("decode", |_, this, (tokens, skip_special): (Vec<u32>, Option<bool>)| {
+            Ok(this.tokenizer.decode_with_special(&tokens, skip_special.unwrap_or(false))?)
+        });
+        methods.add_method("count", |_, this, text: String| {
+            let (_, num_tokens, _) = this.tokenizer.encode(&text)?;
+            Ok(num_tokens)
+        });
+        methods.add_method("token_to_id", |_, this, token: String| {
+            Ok(this.tokenizer.token_to_id(&token))
+        });
+        methods.add_method("id_to_token", |_, this, id: u32| Ok(this.tokenizer.id_to_token(id)));
+        methods.add_method("decode_stream", |_, this, skip_special: Option<bool>| {
+            let tokenizer = Arc::clone(&this.tokenizer);
+            let stream = StreamDecoder::with_special(tokenizer, skip_special.unwrap_or(false));
+            Ok(LuaStreamDecoder(stream))
+        });
+        methods.add_method("budget", |_, this, max_tokens: usize| {
+            Ok(LuaTokenBudget(TokenBudget::new(Arc::clone(&this.tokenizer), max_tokens)))
+        });
+        methods.add_method("incremental_encoder", |_, this, ()| {
+            Ok(LuaIncrementalEncoder(IncrementalEncoder::new(Arc::clone(&this.tokenizer))))
+        });
+        methods.add_method("buffer", |_, this, text: String| {
+            Ok(LuaTokenizedBuffer(TokenizedBuffer::new(Arc::clone(&this.tokenizer), &text)?))
+        });
+    }
+}
+
+/// Tokenized buffer exposed to Lua
+#[cfg(feature = "lua")]
+struct LuaTokenizedBuffer(TokenizedBuffer);
+
+#[cfg(feature = "lua")]
+impl LuaUserData for LuaTokenizedBuffer {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method_mut(
+            "edit",
+            |_, this, (start, old_end, new_text): (usize, usize, String)| {
+                Ok(this.0.edit(start, old_end, &new_text)?)
+            },
+        );
+        methods.add_method_mut("set_text", |_, this, text: String| Ok(this.0.set_text(&text)?));
+        methods.add_method("tokens", |_, this, ()| Ok(this.0.tokens().to_vec()));
+        methods.add_method("num_tokens", |_, this, ()| Ok(this.0.num_tokens()));
+    }
+}
+
+/// Incremental encoder exposed to Lua
+#[cfg(feature = "lua")]
+struct LuaIncrementalEncoder(IncrementalEncoder);
+
+#[cfg(feature = "lua")]
+impl LuaUserData for LuaIncrementalEncoder {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method_mut("append", |_, this, text: String| Ok(this.0.append(&text)?));
+        methods.add_method("num_tokens", |_, this, ()| Ok(this.0.num_tokens()));
+        methods.add_method("num_chars", |_, this, ()| Ok(this.0.num_chars()));
+        methods.add_method_mut("reset", |_, this, ()| {
+            this.0.reset();
+            Ok(())
+        });
+    }
+}
+
+/// Token budget exposed to Lua
+#[cfg(feature = "lua")]
+struct LuaTokenBudget(TokenBudget);
+
+#[cfg(feature = "lua")]
+impl LuaUserData for LuaTokenBudget {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method_mut("try_reserve", |_, this, text: String| {
+            Ok(match this.0.try_reserve(&text)? {
+                Some(num_tokens) => (true, Some(num_tokens)),
+                None => (false, None),
+            })
+        });
+        methods.add_method_mut("try_reserve_tokens", |_, this, tokens: usize| {
+            Ok(this.0.try_reserve_tokens(tokens))
+        });
+        methods.add_method_mut("release", |_, this, tokens: usize| {
+            this.0.release(tokens);
+            Ok(())
+        });
+        methods.add_method("remaining", |_, this, ()| Ok(this.0.remaining()));
+        methods.add_method("used", |_, this, ()| Ok(this.0.used()));
+        methods.add_method("max_tokens", |_, this, ()| Ok(this.0.max_tokens()));
+    }
+}
+
+/// Stream decoder exposed to Lua
+#[cfg(feature = "lua")]
+struct LuaStreamDecoder(StreamDecoder);
+
+#[cfg(feature = "lua")]
+impl LuaUserData for LuaStreamDecoder {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method_mut("push", |_, this, token: u32| Ok(this.0.push(token)?));
+        methods.add_method_mut("extend", |_, this, tokens: Vec<u32>| Ok(this.0.extend(&tokens)?));
+        methods.add_method_mut("finish", |_, this, ()| Ok(this.0.finish()?));
+        methods.add_method("has_pending", |_, this, ()| Ok(this.0.has_pending()));
+    }
+}
+
+/// A [`from_pretrained_async`] started from Lua
+#[cfg(feature = "lua")]
+struct PendingLoad {
+    model: String,
+    handle: TaskHandle<Result<(Arc<TokenizerType>, bool)>>,
+    callback: LuaFunction,
+}
+
+#[cfg(feature = "lua")]
+#[mlua::lua_module]
+fn neopilot_tokenizers(lua: &Lua) -> LuaResult<LuaTable> {
+    lua_exports(lua, Arc::new(State::new()))
+}
+
+/// The functions of the `neopilot_tokenizers` module, working on `state`
+///
+/// An engine of `neopilot-core` passes its own state, so the module shares
+/// the loaded tokenizers and settings with it.
+#[cfg(feature = "lua")]
+pub fn lua_exports(lua: &Lua, state: Arc<State>) -> LuaResult<LuaTable> {
+    let exports = lua.create_table()?;
+    let load_state = Arc::clone(&state);
+    exports.set(
+        "from_pretrained",
+        lua.create_function(move |_, model: String| {
+            from_pretrained(&load_state, &model)?;
+            Ok(LuaTokenizer {
+                tokenizer: load(&load_state, &model)?,
+            })
+        })?,
+    )?;
+    let handle_state = Arc::clone(&state);
+    exports.set(
+        "load",
+        lua.create_function(move |_, model: String| {
+            Ok(LuaTokenizer {
+                tokenizer: load(&handle_state, &model)?,
+            })
+        })?,
+    )?;
+    exports.set(
+        "from_json",
+        lua.create_function(move |_, json: String| {
+            Ok(LuaTokenizer {
+                tokenizer: from_json(&json)?,
+            })
+        })?,
+    )?;
+    let encoding_state = Arc::clone(&state);
+    exports.set(
+        "set_encoding",
+        lua.create_function(move |_, (pattern, encoding): (String, String)| {
+            let encoding: Encoding = encoding.parse().map_err(invalid_input)?;
+            set_encoding(&encoding_state, &pattern, encoding)?;
+            Ok(())
+        })?,
+    )?;
+    let network_state = Arc::clone(&state);
+    exports.set(
+        "set_network_enabled",
+        lua.create_function(move |_, enabled: bool| {
+            set_network_enabled(&network_state, enabled);
+            Ok(())
+        })?,
+    )?;
+    let token_state = Arc::clone(&state);
+    exports.set(
+        "set_hf_token",
+        lua.create_function(move |_, token: Option<String>| {
+            set_hf_token(&token_state, token)?;
+            Ok(())
+        })?,
+    )?;
+    let config_state = Arc::clone(&state);
+    exports.set(
+        "set_config",
+        lua.create_function(move |lua, config: Option<LuaTable>| {
+            let settings = match config {
+                Some(config) => {
+                    let network: LuaTable = match config.get("network")? {
+                        Some(network) => network,
+                        None => lua.create_table()?,
+                    };
+                    let headers: Option<HashMap<String, String>> = network.get("headers")?;
+                    let mut headers: Vec<_> = headers.unwrap_or_default().into_iter().collect();
+                    headers.sort();
+                    let mirrors: Option<HashMap<String, Vec<String>>> = network.get("mirrors")?;
+                    let mut mirrors: Vec<_> = mirrors.unwrap_or_default().into_iter().collect();
+                    mirrors.sort();
+                    let max_input_bytes = match config.get::<Option<LuaTable>>("tokenizer")? {
+                        Some(tokenizer) => tokenizer.get("max_input_bytes")?,
+                        None => None,
+                    };
+                    Settings {
+                        network_enabled: network.get::<Option<bool>>("enabled")?.unwrap_or(true),
+                        hf_token: network.get("hf_token")?,
+                        user_agent: network.get("user_agent")?,
+                        headers,
+                        mirrors,
+                        max_retries: network
+                            .get::<Option<u32>>("max_retries")?
+                            .unwrap_or(RetryPolicy::default().max_retries),
+                        max_input_bytes: max_input_bytes.unwrap_or(DEFAULT_MAX_INPUT_BYTES),
+                    }
+                },
+                None => Settings::from_env(),
+            };
+            apply_settings(&config_state, settings)?;
+            Ok(())
+        })?,
+    )?;
+    let max_input_state = Arc::clone(&state);
+    exports.set(
+        "set_max_input_bytes",
+        lua.create_function(move |_, max_bytes: usize| {
+            set_max_input_bytes(&max_input_state, max_bytes);
+            Ok(())
+        })?,
+    )?;
+    let cross_check_state = Arc::clone(&state);
+    exports.set(
+        "set_cross_check",
+        lua.create_function(move |_, enabled: bool| {
+            set_cross_check(&cross_check_state, enabled);
+            Ok(())
+        })?,
+    )?;
+    let consistency_state = Arc::clone(&state);
+    exports.set(
+        "check_consistency",
+        lua.create_function(move |lua, text: String| {
+            let mismatches = lua.create_table()?;
+            for mismatch in check_consistency(&consistency_state, &text)? {
+                let table = lua.create_table()?;
+                table.set("path", mismatch.path)?;
+                table.set("expected", mismatch.expected)?;
+                table.set("actual", mismatch.actual)?;
+                mismatches.push(table)?;
+            }
+            Ok(mismatches)
+        })?,
+    )?;
+    let register_state = Arc::clone(&state);
+    exports.set(
+        "register",
+        lua.create_function(move |_, (name, model): (String, String)| {
+            register(&register_state, &name, &model)?;
+            Ok(())
+        })?,
+    )?;
+    let current_state = Arc::clone(&state);
+    exports.set(
+        "current_model",
+        lua.create_function(move |lua, ()| {
+            let Some(current) = current_model(&current_state)? else {
+                return Ok(None);
+            };
+            let table = lua.create_table()?;
+            table.set("model", current.model)?;
+            table.set("backend", current.backend)?;
+            table.set("encoding", current.encoding)?;
+            Ok(Some(table))
+        })?,
+    )?;
+    let loaded_state = Arc::clone(&state);
+    exports.set(
+        "is_loaded",
+        lua.create_function(move |_, model: Option<String>| {
+            Ok(is_loaded(&loaded_state, model.as_deref())?)
+        })?,
+    )?;
+    let models_state = Arc::clone(&state);
+    exports.set(
+        "list_supported_models",
+        lua.create_function(move |lua, ()| {
+            let supported = list_supported_models(&models_state)?;
+            let table = lua.create_table()?;
+            table.set("models", supported.models)?;
+            table.set("encodings", supported.encodings)?;
+            table.set("cached", supported.cached)?;
+            Ok(table)
+        })?,
+    )?;
+    let unload_state = Arc::clone(&state);
+    exports.set(
+        "unload",
+        lua.create_function(move |_, ()| Ok(unload(&unload_state)?))?,
+    )?;
+    let unregister_state = Arc::clone(&state);
+    exports.set(
+        "unregister",
+        lua.create_function(move |_, name: String| Ok(unregister(&unregister_state, &name)?))?,
+    )?;
+    let registered_state = Arc::clone(&state);
+    exports.set(
+        "registered",
+        lua.create_function(move |_, ()| Ok(registered(&registered_state)?))?,
+    )?;
+    let encode_with_state = Arc::clone(&state);
+    exports.set(
+        "encode_with",
+        lua.create_function(move |_, (name, text): (String, String)| {
+            Ok(encode_with(&encode_with_state, &name, &text)?)
+        })?,
+    )?;
+    let decode_with_state = Arc::clone(&state);
+    exports.set(
+        "decode_with",
+        lua.create_function(
+            move |_, (name, tokens, skip_special): (String, Vec<u32>, Option<bool>)| {
+                let tokenizer = registered_tokenizer(&decode_with_state, &name)?;
+                Ok(tokenizer.decode_with_special(&tokens, skip_special.unwrap_or(false))?)
+            },
+        )?,
+    )?;
+    // Loads started from Lua, with the callbacks to call once they finish
+    let pending: Rc<RefCell<Vec<PendingLoad>>> = Rc::default();
+    let async_state = Arc::clone(&state);
+    let start_pending = Rc::clone(&pending);
+    exports.set(
+        "from_pretrained_async",
+        lua.create_function(move |_, (model, callback): (String, LuaFunction)| {
+            let handle = from_pretrained_async(&async_state, model.clone())?;
+            start_pending.borrow_mut().push(PendingLoad {
+                model,
+                handle,
+                callback,
+            });
+            Ok(())
+        })?,
+    )?;
+    exports.set(
+        "poll_loads",
+        lua.create_function(move |_, ()| {
+            let finished: Vec<PendingLoad> = {
+                let mut pending = pending.borrow_mut();
+                let (finished, running): (Vec<_>, Vec<_>) =
+                    pending.drain(..).partition(|pending_load| pending_load.handle.is_finished());
+                *pending = running;
+                finished
+            };
+            // Called without borrowing `pending`, so callbacks may start loads.
+            // A failing callback does not keep the others from running.
+            let mut errors = Vec::new();
+            for finished_load in finished {
+                let PendingLoad {
+                    model,
+                    handle,
+                    callback,
+                } = finished_load;
+                let result = handle.join().unwrap_or_else(|e| {
+                    Err(TokenizerError::TokenizerError(format!("Loading {model} failed: {e}")))
+                });
+                let called = match result {
+                    Ok((tokenizer, current)) => {
+                        callback.call::<()>((LuaNil, LuaTokenizer { tokenizer }, current))
+                    },
+                    Err(e) => callback.call::<()>(e.to_string()),
+                };
+                if let Err(e) = called {
+                    errors.push(format!("{model}: {e}"));
+                }
+            }
+            if !errors.is_empty() {
+                return Err(LuaError::RuntimeError(format!(
+                    "Load callbacks failed: {}",
+                    errors.join("; ")
+                )));
+            }
+            Ok(pending.borrow().len())
+        })?,
+    )?;
+    let preload_state = Arc::clone(&state);
+    exports.set(
+        "preload",
+        lua.create_function(move |_, models: Vec<String>| {
+            // Failures are logged by the loader threads, which are left detached
+            drop(preload(&preload_state, models)?);
+            Ok(())
+        })?,
+    )?;
+    let warmup_state = Arc::clone(&state);
+    exports.set(
+        "warmup",
+        lua.create_function(move |_, encodings: Option<Vec<String>>| {
+            let encodings = encodings
+                .unwrap_or_default()
+                .iter()
+                .map(|encoding| encoding.parse())
+                .collect::<std::result::Result<Vec<Encoding>, _>>()
+                .map_err(invalid_input)?;
+            // Failures are logged by the task, which is left detached
+            drop(warmup(&warmup_state, encodings)?);
+            Ok(())
+        })?,
+    )?;
+    let encode_state = Arc::clone(&state);
+    exports.set(
+        "encode",
+        lua.create_function(move |lua, args: (String, LuaValue, LuaValue, Option<bool>)| {
+            let (text, with_offsets, special_tokens, with_timing) = args;
+            let timing = |timing: Timing| match with_timing {
+                Some(true) => timing_to_lua(lua, &timing).map(LuaValue::Table),
+                _ => Ok(LuaValue::Nil),
+            };
+            let special = match special_tokens {
+                LuaValue::Nil => SpecialTokens::Special,
+                LuaValue::Boolean(special) => SpecialTokens::from(special),
+                LuaValue::Table(policy) => {
+                    if !matches!(with_offsets, LuaValue::Nil | LuaValue::Boolean(false)) {
+                        return Err(invalid_input(
+                            "Offsets are not supported with a special token policy".to_string(),
+                        )
+                        .into());
+                    }
+                    let policy = special_policy_from_lua(&policy)?;
+                    let ((tokens, num_tokens, num_chars), took) =
+                        encode_with_policy_timed(&encode_state, &text, &policy)?;
+                    return (tokens, num_tokens, num_chars, LuaValue::Nil, timing(took)?)
+                        .into_lua_multi(lua);
+                }
+                other => {
+                    return Err(invalid_input(format!(
+                        "Invalid special_tokens option of type {}",
+                        other.type_name()
+                    ))
+                    .into())
+                }
+            };
+            let unit = match with_offsets {
+                LuaValue::Nil | LuaValue::Boolean(false) => {
+                    let ((tokens, num_tokens, num_chars), took) =
+                        encode_timed(&encode_state, &text, special)?;
+                    return (tokens, num_tokens, num_chars, LuaValue::Nil, timing(took)?)
+                        .into_lua_multi(lua);
+                }
+                LuaValue::Boolean(true) => OffsetUnit::Char,
+                LuaValue::String(unit) => unit.to_str()?.parse().map_err(invalid_input)?,
+                other => {
+                    return Err(invalid_input(format!(
+                        "Invalid offsets option of type {}",
+                        other.type_name()
+                    ))
+                    .into())
+                }
+            };
+            let (result, took) = encode_with_offsets_timed(&encode_state, &text, unit, special)?;
+            let offsets = lua.create_table()?;
+            for (start, end) in result.offsets {
+                offsets.push(lua.create_sequence_from([start, end])?)?;
+            }
+            (result.tokens, result.num_tokens, result.num_chars, offsets, timing(took)?)
+                .into_lua_multi(lua)
+        })?,
+    )?;
+    let try_encode_state = Arc::clone(&state);
+    exports.set(
+        "try_encode",
+        lua.create_function(move |lua, text: String| match encode(&try_encode_state, &text) {
+            Ok((tokens, num_tokens, num_chars)) => {
+                (tokens, num_tokens, num_chars).into_lua_multi(lua)
+            }
+            Err(err) => (LuaValue::Nil, tokenizer_error_to_lua(lua, &err)?).into_lua_multi(lua),
+        })?,
+    )?;
+    let ranges_state = Arc::clone(&state);
+    exports.set(
+        "token_ranges",
+        lua.create_function(move |lua, (text, special_tokens): (String, Option<bool>)| {
+            let special = special_tokens.map(SpecialTokens::from).unwrap_or_default();
+            let table = lua.create_table()?;
+            for range in token_ranges(&ranges_state, &text, special)? {
+                let entry = lua.create_table()?;
+                entry.set("token", range.token)?;
+                entry.set("start_row", range.start.0)?;
+                entry.set("start_col", range.start.1)?;
+                entry.set("end_row", range.end.0)?;
+                entry.set("end_col", range.end.1)?;
+                table.push(entry)?;
+            }
+            Ok(table)
+        })?,
+    )?;
+    let stats_state = Arc::clone(&state);
+    exports.set(
+        "stats",
+        lua.create_function(move |lua, ()| {
+            let table = counters_to_lua(lua, &stats_state.stats.total())?;
+            let by_backend = lua.create_table()?;
+            for (backend, counters) in stats_state.stats.by_backend() {
+                by_backend.set(backend, counters_to_lua(lua, &counters)?)?;
+            }
+            table.set("by_backend", by_backend)?;
+            Ok(table)
+        })?,
+    )?;
+    let reset_stats_state = Arc::clone(&state);
+    exports.set(
+        "reset_stats",
+        lua.create_function(move |_, ()| {
+            reset_stats_state.stats.reset();
+            Ok(())
+        })?,
+    )?;
+    let batch_state = Arc::clone(&state);
+    exports.set(
+        "encode_batch",
+        lua.create_function(move |lua, (texts, worker_threads): (Vec<String>, Option<usize>)| {
+            let encoded = match worker_threads {
+                Some(worker_threads) if worker_threads > 1 => {
+                    encode_batch_parallel(&batch_state, &texts, worker_threads)?
+                }
+                _ => encode_batch(&batch_state, &texts)?,
+            };
+            let results = lua.create_table()?;
+            for (tokens, num_tokens, num_chars) in encoded {
+                let result = lua.create_table()?;
+                result.set("tokens", tokens)?;
+                result.set("num_tokens", num_tokens)?;
+                result.set("num_chars", num_chars)?;
+                results.push(result)?;
+            }
+            Ok(results)
+        })?,
+    )?;
+    let decode_state = Arc::clone(&state);
+    exports.set(
+        "decode",
+        lua.create_function(move |_, (tokens, skip_special): (Vec<u32>, Option<bool>)| {
+            Ok(decode_with_special(&decode_state, &tokens, skip_special.unwrap_or(false))?)
+        })?,
+    )?;
+    let token_to_id_state = Arc::clone(&state);
+    exports.set(
+        "token_to_id",
+        lua.create_function(move |_, token: String| {
+            Ok(token_to_id(&token_to_id_state, &token)?)
+        })?,
+    )?;
+    let id_to_token_state = Arc::clone(&state);
+    exports.set(
+        "id_to_token",
+        lua.create_function(move |_, id: u32| Ok(id_to_token(&id_to_token_state, id)?))?,
+    )?;
+    let stream_state = Arc::clone(&state);
+    let new_stream = lua.create_function(move |_, skip_special: Option<bool>| {
+        let stream = decode_stream_with_special(&stream_state, skip_special.unwrap_or(false))?;
+        Ok(LuaStreamDecoder(stream))
+    })?;
+    exports.set("decode_stream", new_stream.clone())?;
+    // Older name of `decode_stream`
+    exports.set("stream_decoder", new_stream)?;
+    let buffer_state = Arc::clone(&state);
+    exports.set(
+        "buffer",
+        lua.create_function(move |_, text: String| {
+            Ok(LuaTokenizedBuffer(tokenized_buffer(&buffer_state, &text)?))
+        })?,
+    )?;
+    let incremental_state = Arc::clone(&state);
+    exports.set(
+        "incremental_encoder",
+        lua.create_function(move |_, ()| {
+            Ok(LuaIncrementalEncoder(incremental_encoder(&incremental_state)?))
+        })?,
+    )?;
+    let budget_state = Arc::clone(&state);
+    exports.set(
+        "budget",
+        lua.create_function(move |_, max_tokens: usize| {
+            Ok(LuaTokenBudget(token_budget(&budget_state, max_tokens)?))
+        })?,
+    )?;
+    let lossy_state = Arc::clone(&state);
+    exports.set(
+        "encode_lossy",
+        lua.create_function(move |lua, (text, mode): (LuaString, Option<String>)| {
+            let mode = match mode {
+                Some(mode) => mode.parse().map_err(invalid_input)?,
+                None => ReplacementMode::default(),
+            };
+            let result = encode_lossy(&lossy_state, &text.to_string_lossy(), mode)?;
+            let table = lua.create_table()?;
+            table.set("tokens", result.tokens)?;
+            table.set("num_tokens", result.num_tokens)?;
+            table.set("num_chars", result.num_chars)?;
+            table.set("replacement_positions", result.replacement_positions)?;
+            Ok(table)
+        })?,
+    )?;
+    let guarded_state = Arc::clone(&state);
+    exports.set(
+        "encode_guarded",
+        lua.create_function(move |lua, (text, max_line_len): (LuaString, Option<usize>)| {
+            let mode = match max_line_len {
+                Some(0) => LongLineMode::Strict,
+                Some(max_line_len) => LongLineMode::Estimate { max_line_len },
+                None => LongLineMode::default(),
+            };
+            let result = encode_guarded(&guarded_state, &text.to_string_lossy(), mode)?;
+            let table = lua.create_table()?;
+            table.set("tokens", result.tokens)?;
+            table.set("num_tokens", result.num_tokens)?;
+            table.set("num_chars", result.num_chars)?;
+            table.set("estimated", result.estimated)?;
+            table.set("estimated_lines", result.estimated_lines)?;
+            Ok(table)
+        })?,
+    )?;
+    let truncate_state = Arc::clone(&state);
+    exports.set(
+        "truncate",
+        lua.create_function(
+            move |lua, (text, max_tokens, strategy, marker): TruncateArgs| {
+                let strategy = match strategy {
+                    Some(strategy) => strategy.parse().map_err(invalid_input)?,
+                    None => TruncateStrategy::default(),
+                };
+                let result = truncate_with_marker(
+                    &truncate_state,
+                    &text.to_string_lossy(),
+                    max_tokens,
+                    strategy,
+                    marker.as_deref().unwrap_or(truncate::DEFAULT_MARKER),
+                )?;
+                let table = lua.create_table()?;
+                table.set("text", result.text)?;
+                table.set("num_tokens", result.num_tokens)?;
+                table.set("truncated", result.truncated)?;
+                Ok(table)
+            },
+        )?,
+    )?;
+    let chunk_state = Arc::clone(&state);
+    exports.set(
+        "chunk",
+        lua.create_function(
+            move |lua, (text, max_tokens, overlap_tokens): (LuaString, usize, Option<usize>)| {
+                let text = text.to_string_lossy();
+                let chunks = chunk(&chunk_state, &text, max_tokens, overlap_tokens.unwrap_or(0))?;
+                let results = lua.create_table()?;
+                for chunk in chunks {
+                    let entry = lua.create_table()?;
+                    entry.set("num_tokens", chunk.num_tokens())?;
+                    entry.set("text", chunk.text)?;
+                    entry.set("start", chunk.start)?;
+                    entry.set("end", chunk.end)?;
+                    entry.set("token_start", chunk.token_start)?;
+                    entry.set("token_end", chunk.token_end)?;
+                    results.push(entry)?;
+                }
+                Ok(results)
+            },
+        )?,
+    )?;
+    let words_state = Arc::clone(&state);
+    exports.set(
+        "tokens_for_words",
+        lua.create_function(move |lua, words: Vec<String>| {
+            let results = lua.create_table()?;
+            for word in tokens_for_words(&words_state, &words)? {
+                let entry = lua.create_table()?;
+                entry.set("ids", word.ids())?;
+                entry.set("single_token", word.is_single_token())?;
+                entry.set("word", word.word)?;
+                entry.set("tokens", word.tokens)?;
+                entry.set("spaced_tokens", word.spaced_tokens)?;
+                results.push(entry)?;
+            }
+            Ok(results)
+        })?,
+    )?;
+    let stop_state = Arc::clone(&state);
+    exports.set(
+        "analyze_stop_sequences",
+        lua.create_function(move |lua, sequences: Vec<String>| {
+            let results = lua.create_table()?;
+            for report in analyze_stop_sequences(&stop_state, &sequences)? {
+                let entry = lua.create_table()?;
+                entry.set("reliable", report.is_reliable())?;
+                let warnings: Vec<String> =
+                    report.warnings.iter().map(ToString::to_string).collect();
+                entry.set("warnings", warnings)?;
+                entry.set("sequence", report.sequence)?;
+                entry.set("tokens", report.tokens)?;
+                results.push(entry)?;
+            }
+            Ok(results)
+        })?,
+    )?;
+    let parts_state = Arc::clone(&state);
+    exports.set(
+        "count_message_parts",
+        lua.create_function(move |_, parts: Vec<LuaTable>| {
+            let parts = parts
+                .into_iter()
+                .map(message_part_from_lua)
+                .collect::<LuaResult<Vec<_>>>()?;
+            let counts = count_message_parts(&parts_state, &parts)?;
+            Ok((counts.iter().sum::<usize>(), counts))
+        })?,
+    )?;
+    let chat_state = Arc::clone(&state);
+    exports.set(
+        "count_chat_tokens",
+        lua.create_function(move |_, messages: Vec<LuaTable>| {
+            let messages = messages
+                .into_iter()
+                .map(chat_message_from_lua)
+                .collect::<LuaResult<Vec<_>>>()?;
+            Ok(count_chat_tokens(&chat_state, &messages)?)
+        })?,
+    )?;
+    let template_state = Arc::clone(&state);
+    exports.set(
+        "apply_chat_template",
+        lua.create_function(move |lua, (messages, opts): (Vec<LuaTable>, Option<LuaTable>)| {
+            let messages = messages
+                .into_iter()
+                .map(chat_message_from_lua)
+                .collect::<LuaResult<Vec<_>>>()?;
+            let opts = match opts {
+                Some(opts) => opts,
+                None => lua.create_table()?,
+            };
+            let add_generation_prompt =
+                opts.get::<Option<bool>>("add_generation_prompt")?.unwrap_or(false);
+            let template = match opts.get::<Option<String>>("template")? {
+                Some(source) => Some(ChatTemplate {
+                    source,
+                    bos_token: opts.get("bos_token")?,
+                    eos_token: opts.get("eos_token")?,
+                }),
+                None => None,
+            };
+            Ok(apply_chat_template(
+                &template_state,
+                &messages,
+                template.as_ref(),
+                add_generation_prompt,
+            )?)
+        })?,
+    )?;
+    let json_state = Arc::clone(&state);
+    exports.set(
+        "count_json",
+        lua.create_function(move |_, value: LuaValue| {
+            Ok(count_json(&json_state, &json_from_lua(value, 0)?)?)
+        })?,
+    )?;
+    let encode_as_state = Arc::clone(&state);
+    exports.set(
+        "encode_as",
+        lua.create_function(move |lua, (text, format): (String, Option<String>)| {
+            let format = match format {
+                Some(format) => format.parse::<OutputFormat>()?,
+                None => OutputFormat::default(),
+            };
+            lua.create_string(encode_as(&encode_as_state, &text, format)?)
+        })?,
+    )?;
+    let files_state = Arc::clone(&state);
+    exports.set(
+        "count_files",
+        lua.create_function(move |lua, paths: Vec<String>| {
+            let paths: Vec<PathBuf> = paths.into_iter().map(PathBuf::from).collect();
+            let results = lua.create_table()?;
+            for (path, result) in paths.iter().zip(count_files(&files_state, &paths)?) {
+                let entry = lua.create_table()?;
+                match result {
+                    Ok(count) => {
+                        entry.set("encoding", count.encoding.as_str())?;
+                        entry.set("num_tokens", count.num_tokens)?;
+                        entry.set("num_chars", count.num_chars)?;
+                        entry.set("lossy", count.lossy)?;
+                        entry.set("estimated", count.estimated)?;
+                    }
+                    Err(e) => entry.set("error", e.to_string())?,
+                }
+                results.set(path.to_string_lossy(), entry)?;
+            }
+            Ok(results)
+        })?,
+    )?;
+    let vocab_state = Arc::clone(&state);
+    exports.set(
+        "export_vocab",
+        lua.create_function(move |_, (path, format): (String, Option<String>)| {
+            let format = match format {
+                Some(format) => format.parse::<OutputFormat>()?,
+                None => VocabFormat::default(),
+            };
+            Ok(export_vocab(&vocab_state, Path::new(&path), format)?)
+        })?,
+    )?;
+    exports.set(
+        "detect_family",
+        lua.create_function(move |lua, model: String| {
+            let table = lua.create_table()?;
+            table.set("family", detect_family(&model).as_str())?;
+            match suggest_source(&model) {
+                TokenizerSource::Tiktoken => table.set("source", "tiktoken")?,
+                TokenizerSource::Anthropic => table.set("source", "anthropic")?,
+                TokenizerSource::HuggingFace(source) => {
+                    table.set("source", "huggingface")?;
+                    table.set("location", source)?;
+                }
+            }
+            Ok(table)
+        })?,
+    )?;
+    neopilot_common::events::lua::install(lua, &exports)?;
+    exports.set("traced", neopilot_common::trace::lua::traced_function(lua, &exports)?)?;
+    Ok(exports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenizer_initialization() {
+        let state = State::new();
+        assert!(from_pretrained(&state, "gpt-4").is_ok());
+    }
+
+    #[test]
+    fn test_preload_caches_tokenizers() {
+        let state = State::new();
+        let results: Vec<Result<()>> = preload(&state, vec!["gpt-4".to_string()])
+            .unwrap()
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect();
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert!(state.loaded.read().unwrap().contains_key("gpt-4"));
+        // Preloading does not change the current tokenizer
+        assert!(state.tokenizer.read().unwrap().is_none());
+
+        from_pretrained(&state, "gpt-4").unwrap();
+        assert!(encode(&state, "hello").is_ok());
+    }
+
+    #[test]
+    fn test_preload_limits() {
+        let state = State::with_settings(Settings::for_tests());
+        from_pretrained(&state, "gpt-4").unwrap();
+        let mut models = vec!["gpt-4".to_string(), "gpt-4o".to_string(), "gpt-4o".to_string()];
+        let handles = preload(&state, models.clone()).unwrap();
+        assert_eq!(handles.len(), 1);
+        assert!(handles.into_iter().all(|handle| handle.join().unwrap().is_ok()));
+
+        // Unknown models fail to load without the network, but still count
+        models.extend((0..MAX_PRELOAD_MODELS * 2).map(|i| format!("missing/model-{i}")));
+        let handles = preload(&state, models).unwrap();
+        assert_eq!(handles.len(), MAX_PRELOAD_MODELS);
+        for handle in handles {
+            let _ = handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_warmup() {
+        let state = State::with_settings(Settings::for_tests());
+        warmup(&state, vec![Encoding::Cl100kBase]).unwrap().join().unwrap().unwrap();
+        from_pretrained(&state, "gpt-4o").unwrap();
+        warmup(&state, Vec::new()).unwrap().join().unwrap().unwrap();
+        assert!(encode(&state, "hello").is_ok());
+    }
+
+    #[test]
+    fn test_unload_frees_ranks() -> Result<()> {
+        let state = State::with_settings(Settings::for_tests());
+        from_pretrained(&state, "gpt-4")?;
+        let ranks = state.ranks.get(Encoding::Cl100kBase)?;
+        // The tokenizer of gpt-4 was built from the same ranks
+        from_pretrained(&state, "cl100k_base")?;
+        assert!(Arc::ptr_eq(&ranks, &state.ranks.get(Encoding::Cl100kBase)?));
+
+        unload(&state)?;
+        assert_eq!(Arc::strong_count(&ranks), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats() {
+        let state = State::with_settings(Settings::for_tests());
+        from_pretrained(&state, "gpt-4o").unwrap();
+        let (_, timing) = encode_timed(&state, "hello world", SpecialTokens::default()).unwrap();
+        assert_eq!((timing.backend, timing.num_tokens, timing.bytes), ("tiktoken", 2, 11));
+        encode_batch(&state, &["a".to_string(), "b c".to_string()]).unwrap();
+
+        let total = state.stats.total();
+        assert_eq!((total.calls, total.tokens, total.bytes), (2, 5, 15));
+        assert_eq!(state.stats.by_backend()["tiktoken"], total);
+        state.stats.reset();
+        assert_eq!(state.stats.total().calls, 0);
+    }
+
+    #[test]
+    fn test_custom_encodings() {
+        let state = State::new();
+        // Not an OpenAI name, so it would otherwise be looked up on the Hub
+        set_encoding(&state, "My-Proxy-*", Encoding::Cl100kBase).unwrap();
+        from_pretrained(&state, "my-proxy-gpt").unwrap();
+        let expected = Tiktoken::new("gpt-4").unwrap().encode("hello world");
+        assert_eq!(encode(&state, "hello world").unwrap(), expected);
+
+        // Remapping drops the cached tokenizer
+        set_encoding(&state, "my-proxy-gpt", Encoding::O200kBase).unwrap();
+        let tokenizer = load(&state, "my-proxy-gpt").unwrap();
+        assert!(matches!(
+            tokenizer.as_ref(),
+            TokenizerType::Tiktoken(tiktoken) if tiktoken.encoding() == Encoding::O200kBase
+        ));
+    }
+
+    #[test]
+    fn test_tokens_for_words() {
+        let state = State::new();
+        from_pretrained(&state, "gpt-4").unwrap();
+        let words = vec!["Paris".to_string(), "<|endoftext|>".to_string()];
+        let result = tokens_for_words(&state, &words).unwrap();
+
+        let paris = &result[0];
+        assert_ne!(paris.tokens, paris.spaced_tokens);
+        assert_eq!(decode(&state, &paris.spaced_tokens).unwrap(), " Paris");
+        // Special token strings are ordinary text here
+        assert!(result[1].tokens.len() > 1);
+        assert!(result[1].ids().iter().all(|&id| id < 100_256));
+    }
+
+    #[test]
+    fn test_analyze_stop_sequences() {
+        let state = State::new();
+        from_pretrained(&state, "gpt-4").unwrap();
+        let sequences = vec!["<|endoftext|>".to_string(), "\n\n".to_string()];
+        let reports = analyze_stop_sequences(&state, &sequences).unwrap();
+
+        assert_eq!(reports[0].tokens, vec![100_257]);
+        assert!(reports[0].is_reliable());
+        // Newlines merge with the newlines around them
+        assert!(reports[1].warnings.contains(&StopWarning::MergesBefore("\n".to_string())));
+    }
+
+    #[test]
+    fn test_anthropic_estimates() {
+        let state = State::new();
+        from_pretrained(&state, "claude-sonnet-4-5").unwrap();
+        let text = "fn main() { println!(\"Hello, world!\"); }";
+        let (tokens, num_tokens, _) = encode(&state, text).unwrap();
+        let (base, _, _) = Tiktoken::new("cl100k_base").unwrap().encode(text);
+        assert_eq!(tokens, base);
+        assert!(num_tokens > base.len());
+        assert_eq!(decode(&state, &tokens).unwrap(), text);
+        assert!(vocabulary(&state).is_err());
+    }
+
+    #[test]
+    fn test_count_message_parts() {
+        let state = State::new();
+        let image = MessagePart::Image {
+            width: Some(1024),
+            height: Some(1024),
+            detail: ImageDetail::High,
+        };
+        let parts = vec![MessagePart::Text("Describe this image".to_string()), image];
+
+        from_pretrained(&state, "gpt-4o").unwrap();
+        let (_, text_tokens, _) = encode(&state, "Describe this image").unwrap();
+        assert_eq!(count_message_parts(&state, &parts).unwrap(), vec![text_tokens, 765]);
+
+        from_pretrained(&state, "claude-sonnet-4-5").unwrap();
+        let counts = count_message_parts(&state, &parts).unwrap();
+        assert_eq!(counts[1], 1399);
+    }
+
+    #[test]
+    fn test_count_chat_tokens() {
+        let state = State::new();
+        from_pretrained(&state, "gpt-4o").unwrap();
+        let messages = vec![
+            ChatMessage::text("system", "You are a helpful assistant."),
+            ChatMessage::text("user", "Hello!"),
+        ];
+        let content: usize = ["system", "You are a helpful assistant.", "user", "Hello!"]
+            .iter()
+            .map(|text| encode(&state, text).unwrap().1)
+            .sum();
+        assert_eq!(count_chat_tokens(&state, &messages).unwrap(), content + 2 * 3 + 3);
+    }
+
+    #[test]
+    fn test_apply_chat_template() {
+        let state = State::new();
+        from_pretrained(&state, "gpt-4o").unwrap();
+        let messages = vec![ChatMessage::text("user", "Hello!")];
+        let error = apply_chat_template(&state, &messages, None, true).unwrap_err();
+        assert!(matches!(error, TokenizerError::ChatTemplate(_)));
+
+        let template = ChatTemplate::new(
+            "{% for message in messages %}<|im_start|>{{ message.role }}\n\
+             {{ message.content }}<|im_end|>\n{% endfor %}\
+             {% if add_generation_prompt %}<|im_start|>assistant\n{% endif %}",
+        );
+        let (prompt, num_tokens) =
+            apply_chat_template(&state, &messages, Some(&template), true).unwrap();
+        assert_eq!(prompt, "<|im_start|>user\nHello!<|im_end|>\n<|im_start|>assistant\n");
+        assert_eq!(num_tokens, encode(&state, &prompt).unwrap().1);
+    }
+
+    #[test]
+    fn test_count_json() {
+        let state = State::new();
+        from_pretrained(&state, "gpt-4o").unwrap();
+        let schema = serde_json::json!({
+            "name": "read_file",
+            "parameters": { "type": "object", "required": ["path"] },
+            "description": "Read <|endoftext|>",
+        });
+        let (num_tokens, json) = count_json(&state, &schema).unwrap();
+        assert!(json.contains("\"required\":[\"path\"]"));
+        assert!(!json.contains(": ") && !json.contains(", "));
+        let (_, expected, _) = encode_with_special(&state, &json, SpecialTokens::Ordinary).unwrap();
+        assert_eq!(num_tokens, expected);
+    }
+
+    #[test]
+    fn test_token_lookup() {
+        let state = State::new();
+        assert!(token_to_id(&state, "Hello").is_err());
+        from_pretrained(&state, "claude-sonnet-4-5").unwrap();
+        let (tokens, _, _) = encode(&state, "Hello").unwrap();
+        assert_eq!(token_to_id(&state, "Hello").unwrap(), Some(tokens[0]));
+        assert_eq!(id_to_token(&state, tokens[0]).unwrap().as_deref(), Some("Hello"));
+    }
+
+    #[test]
+    fn test_network_disabled() {
+        let state = State::with_settings(Settings::for_tests());
+        let url = "https://huggingface.co/neopilot/never-cached/resolve/main/offline-test.json";
+        assert!(matches!(
+            from_pretrained(&state, url),
+            Err(TokenizerError::NetworkDisabled(_))
+        ));
+        // Built-in encodings do not need the network
+        assert!(from_pretrained(&state, "gpt-4o").is_ok());
+
+        apply_settings(&state, Settings::default()).unwrap();
+        assert!(state.network_enabled.load(Ordering::Relaxed));
+        assert!(encode(&state, "hello").is_ok());
+    }
+
+    #[test]
+    fn test_set_hf_token() -> Result<()> {
+        let state = State::with_settings(Settings::for_tests());
+        set_hf_token(&state, Some(" hf_secret\n".to_string()))?;
+        assert_eq!(state.hf_token.read().unwrap().as_deref(), Some("hf_secret"));
+        set_hf_token(&state, Some(String::new()))?;
+        assert_eq!(*state.hf_token.read().unwrap(), None);
+
+        let settings = Settings {
+            hf_token: Some("hf_secret".to_string()),
+            ..Settings::default()
+        };
+        assert!(!format!("{settings:?}").contains("hf_secret"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_download_headers() -> Result<()> {
+        let state = State::with_settings(Settings::for_tests());
+        let settings = Settings {
+            user_agent: Some("corp-neopilot/1.0".to_string()),
+            headers: vec![("X-Mirror-Token".to_string(), "mirror_secret".to_string())],
+            ..Settings::for_tests()
+        };
+        assert!(!format!("{settings:?}").contains("mirror_secret"));
+        apply_settings(&state, Settings { max_retries: 7, ..settings })?;
+        assert_eq!(state.max_retries.load(Ordering::Relaxed), 7);
+        let headers = state.download_headers.read().unwrap().clone();
+        assert_eq!(headers.user_agent.as_deref(), Some("corp-neopilot/1.0"));
+        assert!(!format!("{headers:?}").contains("mirror_secret"));
+
+        let invalid = DownloadHeaders {
+            user_agent: None,
+            headers: vec![("X-Token".to_string(), "a\r\nInjected: 1".to_string())],
+        };
+        assert!(matches!(
+            set_download_headers(&state, invalid),
+            Err(TokenizerError::InvalidArgument(_))
+        ));
+        assert_eq!(*state.download_headers.read().unwrap(), headers);
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_input_bytes() -> Result<()> {
+        let state = State::with_settings(Settings {
+            max_input_bytes: 8,
+            ..Settings::for_tests()
+        });
+        from_pretrained(&state, "gpt-4o")?;
+        assert!(encode(&state, "12345678").is_ok());
+        let error = encode(&state, "123456789").unwrap_err();
+        assert!(matches!(error, TokenizerError::InputTooLarge { size: 9, max_size: 8 }));
+        assert_eq!(error.name(), "input_too_large");
+        assert!(encode_batch(&state, &["short".to_string(), "too long!".to_string()]).is_err());
+        assert!(encode_batch_parallel(&state, &["too long!".to_string()], 2).is_err());
+        let too_large = |result: Result<_>| {
+            matches!(result.map(|_| ()), Err(TokenizerError::InputTooLarge { .. }))
+        };
+        assert!(too_large(encode_guarded(&state, "123456789", LongLineMode::default())));
+        assert!(too_large(truncate(&state, "123456789", 1, TruncateStrategy::Prefix)));
+        assert!(too_large(chunk(&state, "123456789", 1, 0)));
+
+        set_max_input_bytes(&state, 0);
+        assert!(encode(&state, "123456789").is_ok());
+        Ok(())
+    }
+
+    /// Panic on another thread while holding the write lock of `lock`
+    fn poison<T: Send + Sync + 'static>(lock: &Arc<RwLock<T>>) {
+        let lock = Arc::clone(lock);
+        let result = std::thread::spawn(move || {
+            let _guard = lock.write().unwrap();
+            panic!("encode failed");
+        })
+        .join();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_recovers_from_panics() -> Result<()> {
+        let state = State::with_settings(Settings::for_tests());
+        from_pretrained(&state, "gpt-4o")?;
+        poison(&state.tokenizer);
+        poison(&state.registry);
+        poison(&state.loaded);
+        assert!(state.tokenizer.is_poisoned());
+
+        assert_eq!(encode(&state, "hello world")?.1, 2);
+        assert!(!state.tokenizer.is_poisoned());
+        assert!(registered(&state)?.is_empty());
+        // The poisoned cache is emptied, then filled again
+        from_pretrained(&state, "gpt-4")?;
+        assert_eq!(state.loaded.read().unwrap().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_counting_paths_agree() -> Result<()> {
+        let state = State::with_settings(Settings::for_tests());
+        assert_eq!(state.cross_check.load(Ordering::Relaxed), cfg!(debug_assertions));
+        set_cross_check(&state, true);
+        let text = "fn main() {\n    let x = 1;\n\n    println!(\"{x}\");\n}\n";
+        for model in ["gpt-4o", "gpt-3.5-turbo", "claude-3-5-sonnet"] {
+            from_pretrained(&state, model)?;
+            assert_eq!(check_consistency(&state, text)?, Vec::new(), "{model}");
+
+            let (_, expected, _) = encode(&state, text)?;
+            let texts = vec![text.to_string(); 3];
+            for (_, num_tokens, _) in encode_batch_parallel(&state, &texts, 2)? {
+                assert_eq!(num_tokens, expected, "{model}");
+            }
+            let mut buffer = tokenized_buffer(&state, text)?;
+            let start = text.find("1;").unwrap();
+            let num_tokens = buffer.edit(start, start + 1, "42")?;
+            assert_eq!(num_tokens, encode(&state, buffer.text())?.1, "{model}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_cross_check_batch_logs_mismatch() {
+        let state = State::with_settings(Settings::for_tests());
+        let tokenizer = TokenizerType::Tiktoken(Tiktoken::new("gpt-4o").unwrap());
+        let texts = vec!["hello world".to_string()];
+        set_cross_check(&state, false);
+        assert_eq!(cross_check_batch(&state, &tokenizer, &texts, &[(vec![], 3, 11)]), 0);
+        // A mismatch is reported without panicking
+        set_cross_check(&state, true);
+        assert_eq!(cross_check_batch(&state, &tokenizer, &texts, &[(vec![], 3, 11)]), 1);
+        assert_eq!(cross_check_batch(&state, &tokenizer, &texts, &[(vec![], 2, 11)]), 0);
+    }
+
+    #[test]
+    fn test_set_mirrors() -> Result<()> {
+        let state = State::with_settings(Settings::for_tests());
+        let mirrors = vec!["https://hf-mirror.com".to_string()];
+        set_mirrors(&state, "meta-llama/Llama-3.1-8B", mirrors.clone())?;
+        assert_eq!(state.mirrors.read().unwrap()["meta-llama/Llama-3.1-8B"], mirrors);
+        assert!(matches!(
+            set_mirrors(&state, "gpt2", vec!["http://mirror.local".to_string()]),
+            Err(TokenizerError::InvalidArgument(_))
+        ));
+        set_mirrors(&state, "meta-llama/Llama-3.1-8B", Vec::new())?;
+        assert!(state.mirrors.read().unwrap().is_empty());
+
+        let settings = Settings {
+            mirrors: vec![("gpt2".to_string(), mirrors)],
+            ..Settings::for_tests()
+        };
+        apply_settings(&state, settings)?;
+        assert!(state.mirrors.read().unwrap().contains_key("gpt2"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_encode() {
+        let state = State::new();
+        from_pretrained(&state, "gpt-4").unwrap();
+        let expected = encode(&state, "hello world").unwrap();
+
+        let results: Vec<_> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| encode(&state, "hello world").unwrap()))
+                .collect();
+            // Loading a model waits for the readers and does not poison the lock
+            from_pretrained(&state, "gpt-4").unwrap();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+        assert!(results.iter().all(|result| *result == expected));
+    }
+
+    #[test]
+    fn test_load_returns_independent_handles() {
+        let state = State::new();
+        let gpt4 = load(&state, "gpt-4").unwrap();
+        let gpt4o = load(&state, "gpt-4o").unwrap();
+        // Loading a handle does not change the current tokenizer
+        assert!(encode(&state, "hello").is_err());
+
+        let (tokens, _, _) = gpt4.encode("Hello, world!").unwrap();
+        assert_eq!(gpt4.decode(&tokens).unwrap(), "Hello, world!");
+        assert_ne!(gpt4o.encode("Hello, world!").unwrap().0, tokens);
+        assert!(Arc::ptr_eq(&gpt4, &load(&state, "gpt-4").unwrap()));
+    }
+
+    #[test]
+    fn test_list_supported_models() {
+        let state = State::new();
+        set_encoding(&state, "my-proxy", Encoding::O200kBase).unwrap();
+        set_encoding(&state, "my-proxy-*", Encoding::O200kBase).unwrap();
+        // Fine-tuned names are looked up by their base model, so this never matches
+        set_encoding(&state, "ft:other:org", Encoding::O200kBase).unwrap();
+        let supported = list_supported_models(&state).unwrap();
+        assert!(supported.models.iter().all(|model| model != "ft:other:org"));
+        for model in &supported.models {
+            assert!(load(&state, model).is_ok(), "{model}");
+        }
+        assert!(supported.models.iter().any(|model| model == "gpt-4o"));
+        assert!(supported.models.iter().any(|model| model == "my-proxy"));
+        assert!(supported.models.iter().all(|model| !model.ends_with('*')));
+        assert!(supported.models.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(supported.encodings.len(), Encoding::ALL.len());
+        assert!(supported.encodings.iter().all(|name| name.parse::<Encoding>().is_ok()));
+    }
+
+    #[test]
+    fn test_current_model() {
+        let state = State::new();
+        assert_eq!(current_model(&state).unwrap(), None);
+        assert!(!is_loaded(&state, None).unwrap());
+
+        from_pretrained(&state, "gpt-4o").unwrap();
+        let current = current_model(&state).unwrap().unwrap();
+        assert_eq!(current.model, "gpt-4o");
+        assert_eq!(current.backend, "tiktoken");
+        assert_eq!(current.encoding, Some("o200k_base"));
+        assert!(is_loaded(&state, None).unwrap());
+        assert!(is_loaded(&state, Some("gpt-4o")).unwrap());
+        assert!(!is_loaded(&state, Some("gpt-4")).unwrap());
+
+        from_pretrained(&state, "claude-3-5-sonnet").unwrap();
+        let current = current_model(&state).unwrap().unwrap();
+        assert_eq!((current.backend, current.encoding), ("anthropic", None));
+    }
+
+    #[test]
+    fn test_from_pretrained_async() {
+        let state = State::new();
+        let (tokenizer, current) =
+            from_pretrained_async(&state, "gpt-4o".to_string()).unwrap().join().unwrap().unwrap();
+        assert!(current);
+        assert_eq!(tokenizer.backend(), "tiktoken");
+        assert!(is_loaded(&state, Some("gpt-4o")).unwrap());
+        assert!(from_pretrained_async(&state, "no-such/model@bad..rev".to_string())
+            .unwrap()
+            .join()
+            .unwrap()
+            .is_err());
+
+        // A load overtaken by another switch is only cached
+        let started = state.switches.load(Ordering::Acquire);
+        from_pretrained(&state, "gpt-4").unwrap();
+        let tokenizer = load(&state, "gpt-3.5-turbo").unwrap();
+        assert!(!make_current(&state, "gpt-3.5-turbo", tokenizer, Some(started)).unwrap());
+        assert!(is_loaded(&state, Some("gpt-4")).unwrap());
+    }
+
+    #[test]
+    fn test_unload() {
+        let state = State::new();
+        assert!(!unload(&state).unwrap());
+        from_pretrained(&state, "gpt-4").unwrap();
+        let handle = load(&state, "gpt-4o").unwrap();
+        assert!(unload(&state).unwrap());
+        assert!(encode(&state, "hello").is_err());
+        assert!(state.loaded.read().unwrap().is_empty());
+        assert!(state.model.read().unwrap().is_none());
+        // Handles keep working, and loading again starts from scratch
+        assert!(handle.encode("hello").is_ok());
+        from_pretrained(&state, "gpt-4").unwrap();
+        assert!(encode(&state, "hello").is_ok());
+    }
+
+    #[test]
+    fn test_registry() {
+        let state = State::new();
+        register(&state, "openai", "gpt-4").unwrap();
+        register(&state, "legacy", "gpt-3.5-turbo").unwrap();
+        assert_eq!(registered(&state).unwrap(), vec!["legacy", "openai"]);
+        // Registering does not change the current tokenizer
+        assert!(encode(&state, "hello").is_err());
+
+        let (tokens, num_tokens, _) = encode_with(&state, "openai", "Hello, world!").unwrap();
+        assert_eq!(num_tokens, tokens.len());
+        assert_eq!(decode_with(&state, "openai", &tokens).unwrap(), "Hello, world!");
+        from_pretrained(&state, "gpt-4").unwrap();
+        assert_eq!(encode(&state, "Hello, world!").unwrap().0, tokens);
+
+        assert!(unregister(&state, "legacy").unwrap());
+        assert!(!unregister(&state, "legacy").unwrap());
+        assert!(matches!(
+            encode_with(&state, "legacy", "hello"),
+            Err(TokenizerError::UnknownTokenizer(_))
+        ));
+    }
+
+    #[test]
+    fn test_encoding() {
+        let state = State::new();
+        from_pretrained(&state, "gpt-4").unwrap();
+        let (tokens, num_tokens, num_chars) = encode(&state, "Hello, world!").unwrap();
+        assert!(!tokens.is_empty());
+        assert!(num_tokens > 0);
+        assert!(num_chars > 0);
+    }
+
+    #[test]
+    fn test_encode_with_special() {
+        let state = State::new();
+        from_pretrained(&state, "gpt-4").unwrap();
+        let text = "a<|endoftext|>b";
+        let (special, _, _) = encode_with_special(&state, text, SpecialTokens::Special).unwrap();
+        assert!(special.contains(&100_257));
+        assert_eq!(encode(&state, text).unwrap().0, special);
+
+        let (ordinary, _, _) = encode_with_special(&state, text, SpecialTokens::Ordinary).unwrap();
+        assert!(!ordinary.contains(&100_257));
+        assert_eq!(decode(&state, &ordinary).unwrap(), text);
+    }
+
+    #[test]
+    fn test_encode_with_policy() {
+        let state = State::new();
+        from_pretrained(&state, "gpt-4").unwrap();
+        let text = "a<|endoftext|>b";
+        let result = encode_with_policy(&state, text, &SpecialTokenPolicy::default());
+        assert!(matches!(result, Err(TokenizerError::DisallowedSpecialToken(_))));
+
+        let escape = SpecialTokenPolicy {
+            allowed: SpecialSet::None,
+            disallowed: SpecialSet::None,
+        };
+        let (tokens, _, _) = encode_with_policy(&state, text, &escape).unwrap();
+        assert_eq!(tokens, encode_with_special(&state, text, SpecialTokens::Ordinary).unwrap().0);
+
+        let allow = SpecialTokenPolicy {
+            allowed: SpecialSet::Only(["<|endoftext|>".to_string()].into()),
+            disallowed: SpecialSet::All,
+        };
+        let (tokens, _, _) = encode_with_policy(&state, text, &allow).unwrap();
+        assert_eq!(tokens, encode(&state, text).unwrap().0);
+    }
+
+    #[test]
+    fn test_encode_with_offsets() {
+        let state = State::new();
+        from_pretrained(&state, "gpt-4").unwrap();
+        let text = "naïve café";
+        let special = SpecialTokens::Special;
+        let result = encode_with_offsets(&state, text, OffsetUnit::Char, special).unwrap();
+        assert_eq!(result.offsets.len(), result.tokens.len());
+        assert_eq!(result.offsets.first().map(|span| span.0), Some(0));
+        assert_eq!(result.offsets.last().map(|span| span.1), Some(result.num_chars));
+        assert!(result.offsets.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+
+        let result = encode_with_offsets(&state, text, OffsetUnit::Byte, special).unwrap();
+        assert!(result.byte_offsets);
+        assert_eq!(result.offsets.last().map(|span| span.1), Some(text.len()));
+        let covered: usize = result.offsets.iter().map(|(start, end)| end - start).sum();
+        assert_eq!(covered, text.len());
+    }
+
+    #[test]
+    fn test_token_ranges() {
+        let state = State::new();
+        from_pretrained(&state, "gpt-4").unwrap();
+        let text = "fn main() {\n    println!(\"café\");\n}\n";
+        let ranges = token_ranges(&state, text, SpecialTokens::Special).unwrap();
+        assert_eq!(ranges.len(), encode(&state, text).unwrap().1);
+        assert_eq!(ranges.first().map(|range| range.start), Some((0, 0)));
+        assert_eq!(ranges.last().map(|range| range.end), Some((3, 0)));
+        assert!(ranges.windows(2).all(|pair| pair[0].start <= pair[1].start));
+    }
+
+    #[test]
+    fn test_encode_batch() {
+        let state = State::new();
+        assert!(encode_batch(&state, &["hello".to_string()]).is_err());
+
+        from_pretrained(&state, "gpt-4").unwrap();
+        let texts = vec!["Hello, world!".to_string(), String::new(), "fn main() {}".to_string()];
+        let results = encode_batch(&state, &texts).unwrap();
+        assert_eq!(results.len(), 3);
+        for (text, result) in texts.iter().zip(results) {
+            assert_eq!(result, encode(&state, text).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_encode_batch_parallel_keeps_order() {
+        let state = State::new();
+        from_pretrained(&state, "gpt-4").unwrap();
+        let texts: Vec<String> = (0..64).map(|i| "word ".repeat(i)).collect();
+        let parallel = encode_batch_parallel(&state, &texts, 4).unwrap();
+        assert_eq!(parallel, encode_batch(&state, &texts).unwrap());
+    }
+
+    #[test]
+    fn test_encode_guarded() {
+        let state = State::new();
+        from_pretrained(&state, "gpt-4").unwrap();
+        let text = format!("let a = 1;\n{}\nlet b = 2;", "x,".repeat(100));
+
+        let strict = encode_guarded(&state, &text, LongLineMode::Strict).unwrap();
+        assert!(!strict.estimated);
+        assert_eq!(strict.tokens, encode(&state, &text).unwrap().0);
+
+        let mode = LongLineMode::Estimate { max_line_len: 50 };
+        let guarded = encode_guarded(&state, &text, mode).unwrap();
+        assert!(guarded.estimated);
+        assert_eq!(guarded.estimated_lines, vec![1]);
+        assert!(guarded.tokens.len() < strict.tokens.len());
+    }
+
+    #[test]
+    fn test_truncate() {
+        let state = State::new();
+        from_pretrained(&state, "gpt-4").unwrap();
+        let text = "The quick brown fox jumps over the lazy dog. Grüße, 世界!";
+        let (tokens, _, _) = encode(&state, text).unwrap();
+
+        let whole = truncate(&state, text, tokens.len(), TruncateStrategy::Prefix).unwrap();
+        assert!(!whole.truncated);
+        assert_eq!(whole.text, text);
+
+        for max_tokens in 1..tokens.len() {
+            let prefix = truncate(&state, text, max_tokens, TruncateStrategy::Prefix).unwrap();
+            assert!(prefix.truncated && text.starts_with(&prefix.text));
+            assert!(prefix.num_tokens <= max_tokens);
+            assert_eq!(prefix.num_tokens, encode(&state, &prefix.text).unwrap().1);
+
+            let suffix = truncate(&state, text, max_tokens, TruncateStrategy::Suffix).unwrap();
+            assert!(suffix.truncated && text.ends_with(&suffix.text));
+            assert!(suffix.num_tokens <= max_tokens);
+        }
+
+        let middle = truncate(&state, text, 12, TruncateStrategy::Middle).unwrap();
+        assert!(middle.truncated && middle.num_tokens <= 12);
+        assert!(middle.text.contains(truncate::DEFAULT_MARKER));
+        assert!(text.starts_with(middle.text.split(truncate::DEFAULT_MARKER).next().unwrap()));
+        assert!(text.ends_with(middle.text.rsplit(truncate::DEFAULT_MARKER).next().unwrap()));
+    }
+
+    #[test]
+    fn test_chunk() {
+        let state = State::new();
+        from_pretrained(&state, "gpt-4").unwrap();
+        let text = "word ".repeat(50);
+        let (tokens, _, _) = encode(&state, &text).unwrap();
+
+        let chunks = chunk(&state, &text, 16, 4).unwrap();
+        assert!(chunks.iter().all(|chunk| chunk.num_tokens() <= 16));
+        assert_eq!(chunks.first().map(|chunk| chunk.start), Some(0));
+        assert_eq!(chunks.last().map(|chunk| chunk.token_end), Some(tokens.len()));
+        for pair in chunks.windows(2) {
+            assert_eq!(pair[1].token_start, pair[0].token_end - 4);
+        }
+        assert!(chunk(&state, &text, 4, 4).is_err());
+    }
+
+    #[test]
+    fn test_encode_lossy_counts_replacements() {
+        let state = State::new();
+        from_pretrained(&state, "gpt-4").unwrap();
+        let text = "caf\u{FFFD} au lait";
+        let result = encode_lossy(&state, text, ReplacementMode::CountAsOneToken).unwrap();
+        let (stripped, _, _) = encode(&state, "caf au lait").unwrap();
+        assert_eq!(result.replacement_positions, vec![3]);
+        assert_eq!(result.num_tokens, stripped.len() + 1);
+    }
+
+    #[test]
+    fn test_stream_decoding_matches_decode() {
+        let state = State::new();
+        from_pretrained(&state, "gpt-4").unwrap();
+        let text = "Grüße, 世界! 👋";
+        let (tokens, _, _) = encode(&state, text).unwrap();
+
+        let mut decoder = decode_stream(&state).unwrap();
+        let mut streamed = String::new();
+        for &token in &tokens {
+            if let Some(fragment) = decoder.push(token).unwrap() {
+                assert!(!fragment.contains(replacement::REPLACEMENT_CHAR));
+                streamed.push_str(&fragment);
+            }
+        }
+        assert_eq!(decoder.finish().unwrap(), None);
+        assert_eq!(streamed, text);
+        assert_eq!(decode(&state, &tokens).unwrap(), text);
+    }
+
+    #[test]
+    fn test_decode_with_special() -> Result<()> {
+        let state = State::with_settings(Settings::for_tests());
+        from_pretrained(&state, "claude-3-5-sonnet")?;
+        let text = "Hi<|endoftext|>";
+        let (tokens, _, _) = encode_with_special(&state, text, SpecialTokens::Ordinary)?;
+        // Special token strings encoded as text are kept
+        assert_eq!(decode_with_special(&state, &tokens, true)?, text);
+
+        from_pretrained(&state, "gpt-4o")?;
+        let (tokens, _, _) = encode(&state, "Hi<|endoftext|>")?;
+        assert_eq!(decode(&state, &tokens)?, "Hi<|endoftext|>");
+        assert_eq!(decode_with_special(&state, &tokens, true)?, "Hi");
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_stream_keeps_its_tokenizer() {
+        let state = State::new();
+        assert!(decode_stream(&state).is_err());
+
+        from_pretrained(&state, "gpt-4").unwrap();
+        let text = "Grüße, 世界! 👋";
+        let (tokens, _, _) = encode(&state, text).unwrap();
+        let mut stream = decode_stream(&state).unwrap();
+        let (head, tail) = tokens.split_at(tokens.len() / 2);
+        let mut streamed = stream.extend(head).unwrap().unwrap_or_default();
+
+        // Switching models mid-stream does not affect the stream
+        from_pretrained(&state, "gpt-4o").unwrap();
+        streamed.push_str(&stream.extend(tail).unwrap().unwrap_or_default());
+        assert_eq!(stream.finish().unwrap(), None);
+        assert!(!stream.has_pending());
+        assert_eq!(streamed, text);
+    }
+
+    #[test]
+    fn test_decode_stream_skips_special_tokens() -> Result<()> {
+        let state = State::new();
+        from_pretrained(&state, "gpt-4o")?;
+        let (tokens, _, _) = encode(&state, "Hi<|endoftext|>")?;
+
+        let mut stream = decode_stream(&state)?;
+        assert_eq!(stream.extend(&tokens)?.as_deref(), Some("Hi<|endoftext|>"));
+        let mut stream = decode_stream_with_special(&state, true)?;
+        assert_eq!(stream.extend(&tokens)?.as_deref(), Some("Hi"));
+        assert_eq!(stream.finish()?, None);
+        Ok(())
     }
 }
 
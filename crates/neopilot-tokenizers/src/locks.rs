@@ -0,0 +1,105 @@
+//! Locks of the state that survive a panic
+//!
+//! A thread panicking while it holds a write lock poisons the lock, after
+//! which the std locks refuse every access: every encode would then fail
+//! with [`crate::TokenizerError::LockError`] until Neovim restarts. The
+//! values of [`crate::State`] are replaced whole under their locks, never
+//! left half-updated, so a poisoned lock is recovered instead: the poison is
+//! cleared and the value kept. Caches, which can always be rebuilt, are
+//! emptied instead of trusted.
+
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Access to a lock of the state, recovering it from poisoning
+pub(crate) trait RecoverLock<T> {
+    /// Shared access, keeping the value if the lock was poisoned
+    fn read_recovered(&self) -> RwLockReadGuard<'_, T>;
+
+    /// Exclusive access, keeping the value if the lock was poisoned
+    fn write_recovered(&self) -> RwLockWriteGuard<'_, T>;
+
+    /// Shared access to a cache, emptied if the lock was poisoned
+    fn read_or_reset(&self) -> RwLockReadGuard<'_, T>
+    where
+        T: Default;
+
+    /// Exclusive access to a cache, emptied if the lock was poisoned
+    fn write_or_reset(&self) -> RwLockWriteGuard<'_, T>
+    where
+        T: Default;
+}
+
+impl<T> RecoverLock<T> for RwLock<T> {
+    fn read_recovered(&self) -> RwLockReadGuard<'_, T> {
+        self.read().unwrap_or_else(|poisoned| {
+            log::warn!("Recovered a tokenizer lock poisoned by a panic");
+            self.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+
+    fn write_recovered(&self) -> RwLockWriteGuard<'_, T> {
+        self.write().unwrap_or_else(|poisoned| {
+            log::warn!("Recovered a tokenizer lock poisoned by a panic");
+            self.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+
+    fn read_or_reset(&self) -> RwLockReadGuard<'_, T>
+    where
+        T: Default,
+    {
+        if self.is_poisoned() {
+            // Taking the write lock resets the cache and clears the poison
+            drop(self.write_or_reset());
+        }
+        self.read_recovered()
+    }
+
+    fn write_or_reset(&self) -> RwLockWriteGuard<'_, T>
+    where
+        T: Default,
+    {
+        self.write().unwrap_or_else(|poisoned| {
+            log::warn!("Emptied a tokenizer cache poisoned by a panic");
+            self.clear_poison();
+            let mut cache = poisoned.into_inner();
+            *cache = T::default();
+            cache
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic;
+    use std::sync::PoisonError;
+
+    fn poison(lock: &RwLock<Vec<u32>>) {
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let mut value = lock.write().unwrap_or_else(PoisonError::into_inner);
+            value.push(2);
+            panic!("encode failed");
+        }));
+        assert!(result.is_err());
+        assert!(lock.is_poisoned());
+    }
+
+    #[test]
+    fn test_recovers_poisoned_locks() {
+        let lock = RwLock::new(vec![1]);
+        poison(&lock);
+        assert_eq!(*lock.read_recovered(), [1, 2]);
+        assert!(!lock.is_poisoned());
+        lock.write_recovered().push(3);
+        assert_eq!(*lock.read().unwrap(), [1, 2, 3]);
+
+        poison(&lock);
+        assert!(lock.read_or_reset().is_empty());
+        assert!(!lock.is_poisoned());
+        poison(&lock);
+        assert!(lock.write_or_reset().is_empty());
+    }
+}
@@ -0,0 +1,96 @@
+//! Token IDs of words, for building `logit_bias` maps
+//!
+//! OpenAI-compatible APIs bias tokens, not words, and BPE vocabularies
+//! usually encode a word differently at the start of a text and after a
+//! space (`"Paris"` and `" Paris"`). [`tokens_for_words`] returns both
+//! variants so a ban or boost applies wherever the word shows up.
+
+use crate::error::{Result, TokenizerError};
+
+/// Token IDs of a word
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct WordTokens {
+    pub word: String,
+    /// Tokens of the word on its own, e.g. at the start of a line
+    pub tokens: Vec<u32>,
+    /// Tokens of the word after a space, as in running text
+    pub spaced_tokens: Vec<u32>,
+}
+
+impl WordTokens {
+    /// Whether both variants are a single token
+    ///
+    /// Biasing a word split into several tokens also biases every other word
+    /// sharing those tokens.
+    pub fn is_single_token(&self) -> bool {
+        self.tokens.len() == 1 && self.spaced_tokens.len() == 1
+    }
+
+    /// Distinct token IDs of both variants, sorted
+    pub fn ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self.tokens.iter().chain(&self.spaced_tokens).copied().collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+}
+
+/// Tokens of every word of `words`, with and without a leading space
+///
+/// Surrounding whitespace is trimmed from the words. `encode` returns the
+/// tokens of a text; special token strings should be encoded as ordinary
+/// text.
+pub(crate) fn tokens_for_words<F>(words: &[String], encode: F) -> Result<Vec<WordTokens>>
+where
+    F: Fn(&str) -> Result<Vec<u32>>,
+{
+    words
+        .iter()
+        .map(|word| {
+            let word = word.trim();
+            if word.is_empty() {
+                return Err(TokenizerError::InvalidArgument(
+                    "Cannot look up the tokens of an empty word".to_string(),
+                ));
+            }
+            Ok(WordTokens {
+                word: word.to_string(),
+                tokens: encode(word)?,
+                spaced_tokens: encode(&format!(" {word}"))?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Byte-level encoder where every token is a single byte
+    fn encode_bytes(text: &str) -> Result<Vec<u32>> {
+        Ok(text.bytes().map(u32::from).collect())
+    }
+
+    #[test]
+    fn test_both_variants() -> Result<()> {
+        let words = vec!["ab".to_string(), " c ".to_string()];
+        let result = tokens_for_words(&words, encode_bytes)?;
+        assert_eq!(result[0].word, "ab");
+        assert_eq!(result[0].tokens, vec![97, 98]);
+        assert_eq!(result[0].spaced_tokens, vec![32, 97, 98]);
+        assert_eq!(result[0].ids(), vec![32, 97, 98]);
+        assert!(!result[0].is_single_token());
+        assert_eq!(result[1].word, "c");
+        assert_eq!(result[1].tokens, vec![99]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_word() {
+        let words = vec!["  ".to_string()];
+        assert!(matches!(
+            tokens_for_words(&words, encode_bytes),
+            Err(TokenizerError::InvalidArgument(_))
+        ));
+    }
+}
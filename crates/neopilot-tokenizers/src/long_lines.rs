@@ -0,0 +1,142 @@
+//! Guard against pathologically long lines
+//!
+//! Minified JavaScript and generated files can contain single lines of several
+//! megabytes, and BPE merging time grows much faster than linearly on them.
+//! In [`LongLineMode::Estimate`] such lines are not encoded; their token count
+//! is estimated from their length instead and the result is flagged.
+
+/// Lines longer than this many bytes are estimated by default
+pub const DEFAULT_MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Average number of bytes per token used for the estimate
+const BYTES_PER_TOKEN: usize = 4;
+
+/// How lines longer than a threshold are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LongLineMode {
+    /// Encode every line, however long
+    Strict,
+    /// Estimate the token count of lines longer than `max_line_len` bytes
+    Estimate { max_line_len: usize },
+}
+
+impl Default for LongLineMode {
+    fn default() -> Self {
+        Self::Estimate {
+            max_line_len: DEFAULT_MAX_LINE_LEN,
+        }
+    }
+}
+
+/// Result of encoding text with a [`LongLineMode`]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct GuardedEncoding {
+    /// Token IDs of the encoded lines; estimated lines contribute none
+    pub tokens: Vec<u32>,
+    /// Number of tokens, including the estimate for skipped lines
+    pub num_tokens: usize,
+    /// Number of characters in the input text
+    pub num_chars: usize,
+    /// Whether `num_tokens` includes an estimate
+    pub estimated: bool,
+    /// 0-based indices of the lines whose tokens were estimated
+    pub estimated_lines: Vec<usize>,
+}
+
+/// Estimated token count of a line that was not encoded
+pub fn estimate_tokens(line: &str) -> usize {
+    line.len().div_ceil(BYTES_PER_TOKEN)
+}
+
+/// Encode `text` with `encode`, estimating overly long lines per `mode`
+///
+/// Consecutive regular lines are encoded together so tokens spanning line
+/// breaks are counted as usual.
+pub(crate) fn encode_guarded<F>(
+    text: &str,
+    mode: LongLineMode,
+    mut encode: F,
+) -> crate::Result<GuardedEncoding>
+where
+    F: FnMut(&str) -> crate::Result<Vec<u32>>,
+{
+    let num_chars = text.chars().count();
+    let max_line_len = match mode {
+        LongLineMode::Estimate { max_line_len } if text.len() > max_line_len => max_line_len,
+        _ => {
+            let tokens = encode(text)?;
+            return Ok(GuardedEncoding {
+                num_tokens: tokens.len(),
+                tokens,
+                num_chars,
+                estimated: false,
+                estimated_lines: Vec::new(),
+            });
+        }
+    };
+
+    let mut tokens = Vec::new();
+    let mut estimated_tokens = 0;
+    let mut estimated_lines = Vec::new();
+    // Byte offset where the current run of regular lines starts
+    let mut run_start = 0;
+    let mut offset = 0;
+    for (index, line) in text.split_inclusive('\n').enumerate() {
+        if line.len() > max_line_len {
+            if run_start < offset {
+                tokens.extend(encode(&text[run_start..offset])?);
+            }
+            estimated_tokens += estimate_tokens(line);
+            estimated_lines.push(index);
+            run_start = offset + line.len();
+        }
+        offset += line.len();
+    }
+    if run_start < text.len() {
+        tokens.extend(encode(&text[run_start..])?);
+    }
+
+    Ok(GuardedEncoding {
+        num_tokens: tokens.len() + estimated_tokens,
+        tokens,
+        num_chars,
+        estimated: !estimated_lines.is_empty(),
+        estimated_lines,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fake_encode(text: &str) -> crate::Result<Vec<u32>> {
+        // One token per byte, like a byte-level BPE without merges
+        Ok(text.bytes().map(u32::from).collect())
+    }
+
+    #[test]
+    fn test_short_text_is_encoded() {
+        let mode = LongLineMode::Estimate { max_line_len: 8 };
+        let result = encode_guarded("ab\ncd", mode, fake_encode).unwrap();
+        assert_eq!(result.num_tokens, 5);
+        assert!(!result.estimated);
+    }
+
+    #[test]
+    fn test_long_lines_are_estimated() {
+        let long_line = "x".repeat(40);
+        let text = format!("ab\n{long_line}\ncd\nef");
+        let mode = LongLineMode::Estimate { max_line_len: 8 };
+
+        let result = encode_guarded(&text, mode, fake_encode).unwrap();
+        assert!(result.estimated);
+        assert_eq!(result.estimated_lines, vec![1]);
+        assert_eq!(result.tokens, fake_encode("ab\ncd\nef").unwrap());
+        assert_eq!(result.num_tokens, 8 + estimate_tokens(&format!("{long_line}\n")));
+        assert_eq!(result.num_chars, text.len());
+
+        let result = encode_guarded(&text, LongLineMode::Strict, fake_encode).unwrap();
+        assert!(!result.estimated);
+        assert_eq!(result.num_tokens, text.len());
+    }
+}
@@ -0,0 +1,171 @@
+//! Mapping of tokens back to positions in the encoded text
+//!
+//! Spans are half-open `(start, end)` indices into the input text, so the
+//! plugin can highlight tokens or decide where to truncate a buffer. They are
+//! character indices by default, or byte offsets for Neovim extmarks. With
+//! character indices, a token that covers only part of a multi-byte character
+//! is widened to the whole character.
+//!
+//! For highlighting a buffer, spans are also given as [`TokenRange`]s of
+//! (row, byte column) positions, the coordinates of Neovim extmarks.
+
+/// Unit of token spans
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OffsetUnit {
+    #[default]
+    Char,
+    Byte,
+}
+
+impl std::str::FromStr for OffsetUnit {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "char" => Ok(Self::Char),
+            "byte" => Ok(Self::Byte),
+            _ => Err(format!("Unknown offset unit '{s}', expected char or byte")),
+        }
+    }
+}
+
+/// Result of encoding with per-token spans
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct EncodingWithOffsets {
+    pub tokens: Vec<u32>,
+    /// Span of each token, in the same order as `tokens`
+    pub offsets: Vec<(usize, usize)>,
+    /// Whether `offsets` are byte offsets rather than character indices
+    pub byte_offsets: bool,
+    pub num_tokens: usize,
+    pub num_chars: usize,
+}
+
+/// A token as a range of buffer positions
+///
+/// Positions are 0-based `(row, column)` pairs with byte columns. `end` is
+/// exclusive: a token ending with a newline ends at column 0 of the next row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct TokenRange {
+    pub token: u32,
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+}
+
+/// Byte spans of consecutive tokens, given the byte length of each token
+pub(crate) fn byte_spans(token_lengths: impl IntoIterator<Item = usize>) -> Vec<(usize, usize)> {
+    let mut start = 0;
+    token_lengths
+        .into_iter()
+        .map(|len| {
+            let span = (start, start + len);
+            start += len;
+            span
+        })
+        .collect()
+}
+
+/// Convert byte spans into character spans
+pub(crate) fn byte_to_char_spans(text: &str, spans: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    let char_starts: Vec<usize> = text.char_indices().map(|(i, _)| i).collect();
+    let char_at = |byte: usize| {
+        if byte >= text.len() {
+            char_starts.len()
+        } else {
+            char_starts.partition_point(|&start| start <= byte) - 1
+        }
+    };
+    spans
+        .iter()
+        .map(|&(start, end)| {
+            let start = char_at(start);
+            let end = char_starts.partition_point(|&char_start| char_start < end);
+            (start, end.max(start))
+        })
+        .collect()
+}
+
+/// Convert the byte spans of `tokens` in `text` into [`TokenRange`]s
+///
+/// Rows are split at `\n`, so `text` is the buffer lines joined with `\n`.
+/// A token covering part of a multi-byte character is widened to the whole
+/// character, since extmarks cannot split one.
+pub(crate) fn token_ranges(
+    text: &str,
+    tokens: &[u32],
+    spans: &[(usize, usize)],
+) -> Vec<TokenRange> {
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+        .collect();
+    let position = |byte: usize| {
+        let row = line_starts.partition_point(|&start| start <= byte) - 1;
+        (row, byte - line_starts[row])
+    };
+    let floor = |mut byte: usize| {
+        byte = byte.min(text.len());
+        while !text.is_char_boundary(byte) {
+            byte -= 1;
+        }
+        byte
+    };
+    let ceil = |mut byte: usize| {
+        byte = byte.min(text.len());
+        while !text.is_char_boundary(byte) {
+            byte += 1;
+        }
+        byte
+    };
+    tokens
+        .iter()
+        .zip(spans)
+        .map(|(&token, &(start, end))| {
+            let start = floor(start);
+            TokenRange {
+                token,
+                start: position(start),
+                end: position(ceil(end).max(start)),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_unit() {
+        assert_eq!("char".parse(), Ok(OffsetUnit::Char));
+        assert_eq!("byte".parse(), Ok(OffsetUnit::Byte));
+        assert!("utf16".parse::<OffsetUnit>().is_err());
+    }
+
+    #[test]
+    fn test_byte_spans() {
+        assert_eq!(byte_spans([2, 0, 3]), vec![(0, 2), (2, 2), (2, 5)]);
+    }
+
+    #[test]
+    fn test_byte_to_char_spans() {
+        // "a€b": '€' is three bytes, split over two tokens
+        let text = "a€b";
+        let spans = byte_spans([1, 2, 1, 1]);
+        assert_eq!(byte_to_char_spans(text, &spans), vec![(0, 1), (1, 2), (1, 2), (2, 3)]);
+        assert!(byte_to_char_spans("", &[]).is_empty());
+    }
+
+    #[test]
+    fn test_token_ranges() {
+        let text = "ab\n€\n";
+        let spans = byte_spans([2, 1, 2, 2]);
+        let ranges = token_ranges(text, &[1, 2, 3, 4], &spans);
+        let positions: Vec<_> = ranges.iter().map(|range| (range.start, range.end)).collect();
+        assert_eq!(
+            positions,
+            vec![((0, 0), (0, 2)), ((0, 2), (1, 0)), ((1, 0), (1, 3)), ((1, 0), (2, 0))]
+        );
+        assert_eq!(ranges[3].token, 4);
+        assert!(token_ranges("", &[], &[]).is_empty());
+    }
+}
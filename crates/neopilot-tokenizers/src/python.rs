@@ -0,0 +1,236 @@
+//! Python bindings, enabled with the `python` feature
+//!
+//! Exposes the same loading and counting logic as the Lua module so tooling
+//! such as pre-commit hooks and CI budget checks agrees with the plugin:
+//!
+//! ```python
+//! from neopilot_tokenizers import Tokenizer
+//!
+//! tokenizer = Tokenizer("gpt-4o")
+//! assert tokenizer.count("hello world") == 2
+//! ```
+
+use std::collections::{HashMap, HashSet};
+
+use pyo3::prelude::*;
+
+use crate::{
+    analyze_stop_sequences, decode_stream_with_special, detect_family, encode_batch,
+    encode_batch_parallel, encode_lossy, encode_with_policy, encode_with_special, from_pretrained,
+    set_encoding, set_hf_token, set_network_enabled, tokens_for_words, truncate_with_marker,
+    ReplacementMode, SpecialSet, SpecialTokenPolicy, SpecialTokens, State, StreamDecoder,
+    TruncateStrategy,
+};
+use crate::tiktoken::Encoding;
+use crate::truncate::DEFAULT_MARKER;
+
+/// Special token set from Python: `"all"` or a collection of token strings
+fn special_set(value: &Bound<'_, PyAny>) -> PyResult<SpecialSet> {
+    if let Ok(set) = value.extract::<&str>() {
+        return match set {
+            "all" => Ok(SpecialSet::All),
+            _ => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Invalid special token set '{set}', expected \"all\" or a collection"
+            ))),
+        };
+    }
+    Ok(SpecialSet::Only(value.extract::<HashSet<String>>()?))
+}
+
+/// A loaded tokenizer
+#[pyclass(name = "Tokenizer")]
+struct PyTokenizer {
+    state: State,
+}
+
+#[pymethods]
+impl PyTokenizer {
+    /// Load the tokenizer for a model name, URL or tokenizer.json path
+    ///
+    /// `encoding` names the tiktoken encoding of a model tiktoken does not
+    /// know, e.g. `Tokenizer("my-proxy-model", encoding="o200k_base")`.
+    /// With `network=False` tokenizers are never downloaded, only loaded
+    /// from disk or the download cache. `hf_token` is the access token for
+    /// gated Hugging Face repositories and defaults to `HF_TOKEN`.
+    #[new]
+    #[pyo3(signature = (model, encoding = None, network = true, hf_token = None))]
+    fn new(
+        model: &str,
+        encoding: Option<&str>,
+        network: bool,
+        hf_token: Option<String>,
+    ) -> PyResult<Self> {
+        let state = State::new();
+        if !network {
+            set_network_enabled(&state, false);
+        }
+        if hf_token.is_some() {
+            set_hf_token(&state, hf_token)?;
+        }
+        if let Some(encoding) = encoding {
+            let encoding: Encoding = encoding
+                .parse()
+                .map_err(pyo3::exceptions::PyValueError::new_err)?;
+            set_encoding(&state, model, encoding)?;
+        }
+        from_pretrained(&state, model)?;
+        Ok(Self { state })
+    }
+
+    /// Token IDs for `text`
+    ///
+    /// With `special_tokens=False`, strings such as `<|endoftext|>` are
+    /// encoded as ordinary text. Passing `allowed_special` or
+    /// `disallowed_special` instead behaves like tiktoken: allowed tokens are
+    /// special, disallowed ones (all by default) raise, others are text.
+    #[pyo3(signature = (
+        text,
+        special_tokens = true,
+        allowed_special = None,
+        disallowed_special = None,
+    ))]
+    fn encode(
+        &self,
+        text: &str,
+        special_tokens: bool,
+        allowed_special: Option<&Bound<'_, PyAny>>,
+        disallowed_special: Option<&Bound<'_, PyAny>>,
+    ) -> PyResult<Vec<u32>> {
+        if allowed_special.is_none() && disallowed_special.is_none() {
+            let special = SpecialTokens::from(special_tokens);
+            let (tokens, _, _) = encode_with_special(&self.state, text, special)?;
+            return Ok(tokens);
+        }
+        let mut policy = SpecialTokenPolicy::default();
+        if let Some(allowed) = allowed_special {
+            policy.allowed = special_set(allowed)?;
+        }
+        if let Some(disallowed) = disallowed_special {
+            policy.disallowed = special_set(disallowed)?;
+        }
+        let (tokens, _, _) = encode_with_policy(&self.state, text, &policy)?;
+        Ok(tokens)
+    }
+
+    /// Token IDs for each of `texts`, encoded on `worker_threads` threads if given
+    #[pyo3(signature = (texts, worker_threads = None))]
+    fn encode_batch(
+        &self,
+        py: Python<'_>,
+        texts: Vec<String>,
+        worker_threads: Option<usize>,
+    ) -> PyResult<Vec<Vec<u32>>> {
+        let results = py.allow_threads(|| match worker_threads {
+            Some(worker_threads) => encode_batch_parallel(&self.state, &texts, worker_threads),
+            None => encode_batch(&self.state, &texts),
+        })?;
+        Ok(results.into_iter().map(|(tokens, _, _)| tokens).collect())
+    }
+
+    /// Number of tokens in `text`
+    ///
+    /// With `replacement_as_one_token`, every U+FFFD counts as exactly one
+    /// token, matching how the plugin budgets buffers that are not valid UTF-8.
+    #[pyo3(signature = (text, replacement_as_one_token = false))]
+    fn count(&self, text: &str, replacement_as_one_token: bool) -> PyResult<usize> {
+        let mode = if replacement_as_one_token {
+            ReplacementMode::CountAsOneToken
+        } else {
+            ReplacementMode::Encode
+        };
+        Ok(encode_lossy(&self.state, text, mode)?.num_tokens)
+    }
+
+    /// Largest part of `text` within `max_tokens`
+    ///
+    /// `strategy` is `"prefix"`, `"suffix"` or `"middle"`, which keeps both
+    /// ends and puts `marker` in between.
+    #[pyo3(signature = (text, max_tokens, strategy = "prefix", marker = DEFAULT_MARKER))]
+    fn truncate(
+        &self,
+        text: &str,
+        max_tokens: usize,
+        strategy: &str,
+        marker: &str,
+    ) -> PyResult<String> {
+        let strategy: TruncateStrategy = strategy
+            .parse()
+            .map_err(pyo3::exceptions::PyValueError::new_err)?;
+        Ok(truncate_with_marker(&self.state, text, max_tokens, strategy, marker)?.text)
+    }
+
+    /// Token IDs of each word, on its own and after a space, for `logit_bias`
+    ///
+    /// Returns `{word: (tokens, spaced_tokens)}`.
+    fn tokens_for_words(
+        &self,
+        words: Vec<String>,
+    ) -> PyResult<HashMap<String, (Vec<u32>, Vec<u32>)>> {
+        Ok(tokens_for_words(&self.state, &words)?
+            .into_iter()
+            .map(|word| (word.word, (word.tokens, word.spaced_tokens)))
+            .collect())
+    }
+
+    /// How each stop sequence tokenizes
+    ///
+    /// Returns `(sequence, tokens, warnings)` per sequence; warnings name the
+    /// contexts where a token spans the start or end of the sequence, which
+    /// servers matching stop sequences token by token can miss.
+    fn analyze_stop_sequences(
+        &self,
+        sequences: Vec<String>,
+    ) -> PyResult<Vec<(String, Vec<u32>, Vec<String>)>> {
+        Ok(analyze_stop_sequences(&self.state, &sequences)?
+            .into_iter()
+            .map(|report| {
+                let warnings = report.warnings.iter().map(ToString::to_string).collect();
+                (report.sequence, report.tokens, warnings)
+            })
+            .collect())
+    }
+
+    /// A decoder for token IDs of a streamed completion, see `StreamDecoder`
+    ///
+    /// With `skip_special_tokens`, markers such as `<|im_end|>` are left out.
+    #[pyo3(signature = (skip_special_tokens = false))]
+    fn decode_stream(&self, skip_special_tokens: bool) -> PyResult<PyDecodeStream> {
+        Ok(PyDecodeStream(decode_stream_with_special(&self.state, skip_special_tokens)?))
+    }
+}
+
+/// Decodes streamed token IDs into text, holding back incomplete characters
+#[pyclass(name = "DecodeStream")]
+struct PyDecodeStream(StreamDecoder);
+
+#[pymethods]
+impl PyDecodeStream {
+    /// Text completed by `token`, or `None` while a character is incomplete
+    fn push(&mut self, token: u32) -> PyResult<Option<String>> {
+        Ok(self.0.push(token)?)
+    }
+
+    /// Text completed by a chunk of tokens
+    fn extend(&mut self, tokens: Vec<u32>) -> PyResult<Option<String>> {
+        Ok(self.0.extend(&tokens)?)
+    }
+
+    /// Remaining buffered text at the end of the stream
+    fn finish(&mut self) -> PyResult<Option<String>> {
+        Ok(self.0.finish()?)
+    }
+}
+
+/// Model family of a model name, URL or path, e.g. `"llama"`
+#[pyfunction(name = "detect_family")]
+fn py_detect_family(model: &str) -> &'static str {
+    detect_family(model).as_str()
+}
+
+#[pymodule]
+fn neopilot_tokenizers(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyTokenizer>()?;
+    m.add_class::<PyDecodeStream>()?;
+    m.add_function(wrap_pyfunction!(py_detect_family, m)?)?;
+    Ok(())
+}
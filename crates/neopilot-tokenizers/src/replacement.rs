@@ -0,0 +1,137 @@
+//! Consistent handling of U+FFFD replacement characters
+//!
+//! Buffers that are not valid UTF-8 reach the tokenizer with invalid sequences
+//! replaced by U+FFFD. Depending on the surrounding text a replacement
+//! character can encode to a different number of byte-level tokens, which
+//! makes token budgets drift. This module reports where replacement
+//! characters are and can count each of them as exactly one token.
+
+/// The Unicode replacement character
+pub const REPLACEMENT_CHAR: char = '\u{FFFD}';
+
+/// How replacement characters are treated when encoding
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReplacementMode {
+    /// Encode replacement characters like any other text
+    #[default]
+    Encode,
+    /// Remove replacement characters before encoding and count each as one token
+    CountAsOneToken,
+}
+
+impl std::str::FromStr for ReplacementMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "encode" => Ok(Self::Encode),
+            "count_as_one_token" => Ok(Self::CountAsOneToken),
+            _ => Err(format!("Unknown replacement mode: {s}")),
+        }
+    }
+}
+
+/// Result of encoding text that may contain replacement characters
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct LossyEncoding {
+    /// Token IDs; with [`ReplacementMode::CountAsOneToken`] these do not
+    /// include the replacement characters
+    pub tokens: Vec<u32>,
+    /// Number of tokens, including replacement characters when they are
+    /// counted separately
+    pub num_tokens: usize,
+    /// Number of characters in the input text
+    pub num_chars: usize,
+    /// Character indices of every replacement character in the input text
+    pub replacement_positions: Vec<usize>,
+}
+
+/// Character indices of every replacement character in `text`
+pub fn replacement_positions(text: &str) -> Vec<usize> {
+    text.chars()
+        .enumerate()
+        .filter(|(_, c)| *c == REPLACEMENT_CHAR)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Encode `text` with `encode`, handling replacement characters per `mode`
+pub(crate) fn encode_lossy<F>(
+    text: &str,
+    mode: ReplacementMode,
+    encode: F,
+) -> crate::Result<LossyEncoding>
+where
+    F: FnOnce(&str) -> crate::Result<Vec<u32>>,
+{
+    let replacement_positions = replacement_positions(text);
+    let num_chars = text.chars().count();
+
+    let (tokens, num_tokens) = match mode {
+        ReplacementMode::Encode => {
+            let tokens = encode(text)?;
+            let num_tokens = tokens.len();
+            (tokens, num_tokens)
+        }
+        ReplacementMode::CountAsOneToken => {
+            if replacement_positions.is_empty() {
+                let tokens = encode(text)?;
+                let num_tokens = tokens.len();
+                (tokens, num_tokens)
+            } else {
+                let stripped: String = text.chars().filter(|c| *c != REPLACEMENT_CHAR).collect();
+                let tokens = encode(&stripped)?;
+                let num_tokens = tokens.len() + replacement_positions.len();
+                (tokens, num_tokens)
+            }
+        }
+    };
+
+    Ok(LossyEncoding {
+        tokens,
+        num_tokens,
+        num_chars,
+        replacement_positions,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn fake_encode(text: &str) -> crate::Result<Vec<u32>> {
+        // One token per byte, like a byte-level BPE without merges
+        Ok(text.bytes().map(u32::from).collect())
+    }
+
+    #[test]
+    fn test_replacement_positions() {
+        assert_eq!(replacement_positions("a\u{FFFD}b\u{FFFD}"), vec![1, 3]);
+        assert!(replacement_positions("plain text").is_empty());
+    }
+
+    #[test]
+    fn test_encode_mode_keeps_characters() {
+        let result = encode_lossy("a\u{FFFD}", ReplacementMode::Encode, fake_encode).unwrap();
+        assert_eq!(result.num_tokens, 4);
+        assert_eq!(result.num_chars, 2);
+        assert_eq!(result.replacement_positions, vec![1]);
+    }
+
+    #[test]
+    fn test_count_as_one_token() {
+        let text = "ab\u{FFFD}\u{FFFD}c";
+        let result = encode_lossy(text, ReplacementMode::CountAsOneToken, fake_encode).unwrap();
+        assert_eq!(result.tokens, vec![97, 98, 99]);
+        assert_eq!(result.num_tokens, 5);
+        assert_eq!(result.num_chars, 5);
+        assert_eq!(result.replacement_positions, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!("encode".parse(), Ok(ReplacementMode::Encode));
+        assert_eq!("count_as_one_token".parse(), Ok(ReplacementMode::CountAsOneToken));
+        assert!("other".parse::<ReplacementMode>().is_err());
+    }
+}
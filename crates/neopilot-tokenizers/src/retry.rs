@@ -0,0 +1,139 @@
+//! Retrying of transient failures
+//!
+//! Operations are retried with exponential backoff as long as they fail with
+//! an error whose [`TokenizerError::retryable`] returns `true`.
+
+use std::time::Duration;
+
+use crate::error::{Result, TokenizerError};
+
+/// How often and how long to wait between attempts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts after the first one, matching `network.max_retries`
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for every further one
+    pub initial_backoff: Duration,
+    /// Upper bound for the delay between attempts
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Policy that never retries
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Self::default()
+        }
+    }
+
+    /// Delay before retry number `retry` (0-based)
+    pub fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff)
+    }
+}
+
+/// Run `operation` until it succeeds, fails permanently or retries run out
+pub fn with_retries<T, F>(policy: &RetryPolicy, mut operation: F) -> Result<T>
+where
+    F: FnMut() -> Result<T>,
+{
+    let mut retry = 0;
+    loop {
+        match operation() {
+            Err(err) if err.retryable() && retry < policy.max_retries => {
+                let delay = policy.backoff(retry);
+                log::debug!(
+                    target: "network",
+                    "Retrying after {} ({}), attempt {} of {} in {:?}",
+                    err,
+                    err.name(),
+                    retry + 1,
+                    policy.max_retries,
+                    delay
+                );
+                std::thread::sleep(delay);
+                retry += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Convenience for building retryable network errors
+pub(crate) fn network_error<E: std::fmt::Display>(err: E) -> TokenizerError {
+    TokenizerError::NetworkError(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instant(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn test_retries_transient_errors() {
+        let mut attempts = 0;
+        let result = with_retries(&instant(3), || {
+            attempts += 1;
+            if attempts < 3 {
+                Err(network_error("connection reset"))
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[test]
+    fn test_gives_up_after_max_retries() {
+        let mut attempts = 0;
+        let result: Result<()> = with_retries(&instant(2), || {
+            attempts += 1;
+            Err(TokenizerError::HttpStatus {
+                url: "https://huggingface.co".to_string(),
+                status: 503,
+            })
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn test_permanent_errors_are_not_retried() {
+        let mut attempts = 0;
+        let result: Result<()> = with_retries(&instant(5), || {
+            attempts += 1;
+            Err(TokenizerError::InsecureProtocol("http://example.com".to_string()))
+        });
+        assert!(matches!(result, Err(TokenizerError::InsecureProtocol(_))));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(0), Duration::from_millis(200));
+        assert_eq!(policy.backoff(1), Duration::from_millis(400));
+        assert_eq!(policy.backoff(10), policy.max_backoff);
+        assert_eq!(RetryPolicy::none().max_retries, 0);
+    }
+}
@@ -0,0 +1,133 @@
+//! File system checks for downloaded tokenizer files
+//!
+//! Downloaded files are only ever written below the cache directory, and on
+//! Unix they are made readable and writable by the current user only.
+
+use std::path::{Path, PathBuf};
+
+use crate::error::{Result, TokenizerError};
+
+/// Resolve `path` and make sure it stays inside `base`
+///
+/// `base` must be absolute and exist. `path` itself may not exist yet, in
+/// which case its parent directory is resolved instead, so symlinks and `..`
+/// components cannot be used to escape `base`.
+pub fn ensure_within(path: &Path, base: &Path) -> Result<PathBuf> {
+    if !base.is_absolute() {
+        return Err(TokenizerError::PathNotAbsolute(base.to_path_buf()));
+    }
+    let base = base.canonicalize()?;
+    let resolved = match path.canonicalize() {
+        Ok(resolved) => resolved,
+        Err(_) => {
+            let parent = path.parent().unwrap_or(path).canonicalize()?;
+            match path.file_name() {
+                Some(name) => parent.join(name),
+                None => parent,
+            }
+        }
+    };
+    if resolved == base || !resolved.starts_with(&base) {
+        return Err(TokenizerError::PathTraversalAttempt {
+            path: path.to_path_buf(),
+            base,
+        });
+    }
+    Ok(resolved)
+}
+
+/// Restrict a downloaded file to the current user
+#[cfg(unix)]
+pub fn secure_file(path: &Path) -> Result<()> {
+    set_mode(path, 0o600)
+}
+
+/// Restrict a downloaded file to the current user
+#[cfg(not(unix))]
+pub fn secure_file(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Restrict the cache directory to the current user
+#[cfg(unix)]
+pub fn secure_dir(path: &Path) -> Result<()> {
+    set_mode(path, 0o700)
+}
+
+/// Restrict the cache directory to the current user
+#[cfg(not(unix))]
+pub fn secure_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Fail if a cached file can be modified by other users
+///
+/// Such a file may have been tampered with and must not be loaded.
+#[cfg(unix)]
+pub fn check_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = std::fs::metadata(path)?.permissions().mode();
+    if mode & 0o022 != 0 {
+        return Err(TokenizerError::InsecurePermissions(path.to_path_buf()));
+    }
+    Ok(())
+}
+
+/// Fail if a cached file can be modified by other users
+#[cfg(not(unix))]
+pub fn check_permissions(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .map_err(|_| TokenizerError::InsecurePermissions(path.to_path_buf()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ensure_within() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+
+        let inside = ensure_within(&base.join("tokenizer.json"), base).unwrap();
+        assert!(inside.starts_with(base.canonicalize().unwrap()));
+
+        assert!(matches!(
+            ensure_within(&base.join("..").join("escape.json"), base),
+            Err(TokenizerError::PathTraversalAttempt { .. })
+        ));
+        assert!(matches!(
+            ensure_within(Path::new("tokenizer.json"), Path::new("relative")),
+            Err(TokenizerError::PathNotAbsolute(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokenizer.json");
+        std::fs::write(&path, "{}").unwrap();
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o666)).unwrap();
+        assert!(matches!(
+            check_permissions(&path),
+            Err(TokenizerError::InsecurePermissions(_))
+        ));
+
+        secure_file(&path).unwrap();
+        check_permissions(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,242 @@
+//! Handling of special token strings such as `<|endoftext|>`
+//!
+//! Chat formats rely on special tokens to delimit messages. Text read from
+//! files or typed by users can contain the same strings, and encoding them as
+//! special tokens lets that text forge message boundaries. Callers choose
+//! explicitly how such strings are encoded, either all at once with
+//! [`SpecialTokens`] or per token with a [`SpecialTokenPolicy`].
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+
+use regex::Regex;
+
+use crate::error::{Result, TokenizerError};
+
+/// How strings of special tokens in the input are encoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SpecialTokens {
+    /// Encode them as the special token they spell
+    #[default]
+    Special,
+    /// Encode them as ordinary text, like any other string
+    Ordinary,
+}
+
+impl From<bool> for SpecialTokens {
+    /// `true` for [`SpecialTokens::Special`]
+    fn from(special: bool) -> Self {
+        if special {
+            Self::Special
+        } else {
+            Self::Ordinary
+        }
+    }
+}
+
+impl std::str::FromStr for SpecialTokens {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "special" => Ok(Self::Special),
+            "ordinary" => Ok(Self::Ordinary),
+            _ => Err(format!(
+                "Unknown special token handling '{s}', expected special or ordinary"
+            )),
+        }
+    }
+}
+
+/// A set of special token strings
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum SpecialSet {
+    /// No special token
+    #[default]
+    None,
+    /// Every special token of the tokenizer
+    All,
+    /// The listed special tokens
+    Only(HashSet<String>),
+}
+
+impl SpecialSet {
+    pub fn contains(&self, token: &str) -> bool {
+        match self {
+            SpecialSet::None => false,
+            SpecialSet::All => true,
+            SpecialSet::Only(tokens) => tokens.contains(token),
+        }
+    }
+}
+
+/// Which special token strings may appear in the text, as in tiktoken
+///
+/// Allowed strings are encoded as their special token. Disallowed strings
+/// make encoding fail, so untrusted text cannot smuggle them in. Any other
+/// special token string is escaped, i.e. encoded as ordinary text. A token in
+/// both sets is allowed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpecialTokenPolicy {
+    pub allowed: SpecialSet,
+    pub disallowed: SpecialSet,
+}
+
+impl Default for SpecialTokenPolicy {
+    /// Nothing allowed and everything disallowed, like tiktoken's `encode`
+    fn default() -> Self {
+        Self {
+            allowed: SpecialSet::None,
+            disallowed: SpecialSet::All,
+        }
+    }
+}
+
+impl From<SpecialTokens> for SpecialTokenPolicy {
+    fn from(special: SpecialTokens) -> Self {
+        let allowed = match special {
+            SpecialTokens::Special => SpecialSet::All,
+            SpecialTokens::Ordinary => SpecialSet::None,
+        };
+        Self {
+            allowed,
+            disallowed: SpecialSet::None,
+        }
+    }
+}
+
+/// Matches every special token of `specials` in a single pass, the longest
+/// one if several start at the same position
+fn special_matcher(specials: &[(String, u32)]) -> Result<Option<Regex>> {
+    let mut contents: Vec<&str> = specials
+        .iter()
+        .map(|(content, _)| content.as_str())
+        .filter(|content| !content.is_empty())
+        .collect();
+    if contents.is_empty() {
+        return Ok(None);
+    }
+    // Alternatives are tried in order, so longer tokens go first
+    contents.sort_by_key(|content| Reverse(content.len()));
+    let pattern = contents.iter().map(|content| regex::escape(content)).collect::<Vec<_>>();
+    Regex::new(&pattern.join("|"))
+        .map(Some)
+        .map_err(|e| TokenizerError::TokenizerError(e.to_string()))
+}
+
+/// Encode `text` according to `policy`
+///
+/// `specials` lists the special tokens of the tokenizer and their IDs, and
+/// `encode_ordinary` encodes text without recognizing any of them.
+pub(crate) fn encode_with_policy<F>(
+    text: &str,
+    policy: &SpecialTokenPolicy,
+    specials: &[(String, u32)],
+    mut encode_ordinary: F,
+) -> Result<Vec<u32>>
+where
+    F: FnMut(&str) -> Result<Vec<u32>>,
+{
+    let mut tokens = Vec::new();
+    // Start of the text not encoded yet, escaped special tokens included
+    let mut pending = 0;
+    if let Some(matcher) = special_matcher(specials)? {
+        let mut ids = HashMap::new();
+        for (content, id) in specials {
+            ids.entry(content.as_str()).or_insert(*id);
+        }
+        for found in matcher.find_iter(text) {
+            let content = found.as_str();
+            if policy.allowed.contains(content) {
+                if pending < found.start() {
+                    tokens.extend(encode_ordinary(&text[pending..found.start()])?);
+                }
+                tokens.push(ids[content]);
+                pending = found.end();
+            } else if policy.disallowed.contains(content) {
+                return Err(TokenizerError::DisallowedSpecialToken(content.to_string()));
+            }
+        }
+    }
+    if pending < text.len() {
+        tokens.extend(encode_ordinary(&text[pending..])?);
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replacement::tests::fake_encode;
+
+    fn specials() -> Vec<(String, u32)> {
+        vec![
+            ("<|end|>".to_string(), 1000),
+            ("<|endoftext|>".to_string(), 1001),
+        ]
+    }
+
+    fn only(tokens: &[&str]) -> SpecialSet {
+        SpecialSet::Only(tokens.iter().map(|token| token.to_string()).collect())
+    }
+
+    #[test]
+    fn test_allowed_tokens_are_special() -> Result<()> {
+        let policy = SpecialTokenPolicy {
+            allowed: only(&["<|endoftext|>"]),
+            disallowed: SpecialSet::None,
+        };
+        let tokens = encode_with_policy("a<|endoftext|>b", &policy, &specials(), fake_encode)?;
+        assert_eq!(tokens, vec![97, 1001, 98]);
+
+        // Not allowed and not disallowed: encoded as text
+        let tokens = encode_with_policy("<|end|>", &policy, &specials(), fake_encode)?;
+        assert_eq!(tokens, fake_encode("<|end|>")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_disallowed_tokens_are_rejected() {
+        let policy = SpecialTokenPolicy::default();
+        let result = encode_with_policy("x<|end|>", &policy, &specials(), fake_encode);
+        assert!(matches!(
+            result,
+            Err(TokenizerError::DisallowedSpecialToken(token)) if token == "<|end|>"
+        ));
+        assert!(encode_with_policy("plain text", &policy, &specials(), fake_encode).is_ok());
+
+        // Allowed wins over disallowed
+        let policy = SpecialTokenPolicy {
+            allowed: only(&["<|end|>"]),
+            disallowed: SpecialSet::All,
+        };
+        let tokens = encode_with_policy("<|end|>", &policy, &specials(), fake_encode).unwrap();
+        assert_eq!(tokens, vec![1000]);
+    }
+
+    #[test]
+    fn test_longest_token_wins() -> Result<()> {
+        let mut specials = specials();
+        specials.push(("<|end".to_string(), 1002));
+        let policy = SpecialTokenPolicy::from(SpecialTokens::Special);
+        let text = "<|end|><|end<|endoftext|>";
+        let tokens = encode_with_policy(text, &policy, &specials, fake_encode)?;
+        assert_eq!(tokens, vec![1000, 1002, 1001]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_special_tokens() {
+        let policy = SpecialTokenPolicy::from(SpecialTokens::Ordinary);
+        assert!(!policy.allowed.contains("<|end|>") && !policy.disallowed.contains("<|end|>"));
+        assert_eq!(SpecialTokenPolicy::from(SpecialTokens::Special).allowed, SpecialSet::All);
+    }
+
+    #[test]
+    fn test_parse_special_tokens() {
+        assert_eq!("special".parse(), Ok(SpecialTokens::Special));
+        assert_eq!("ordinary".parse(), Ok(SpecialTokens::Ordinary));
+        assert!("none".parse::<SpecialTokens>().is_err());
+        assert_eq!(SpecialTokens::from(false), SpecialTokens::Ordinary);
+    }
+}
@@ -0,0 +1,136 @@
+//! Timing and statistics of encode calls
+//!
+//! When token counting feels slow, the first questions are which backend did
+//! the counting and how fast it went. Every encode with the current tokenizer
+//! reports a [`Timing`] and adds it to per-backend [`Counters`] kept in the
+//! state, so both single calls and the whole session can be inspected.
+
+use std::collections::BTreeMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+/// Tokens per second of `num_tokens` encoded in `elapsed`, 0 when no time passed
+fn tokens_per_sec(num_tokens: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs > 0.0 {
+        num_tokens as f64 / secs
+    } else {
+        0.0
+    }
+}
+
+/// Wall time and size of one encode call
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timing {
+    /// Backend of the tokenizer, see `TokenizerType::backend`
+    pub backend: &'static str,
+    pub elapsed: Duration,
+    pub num_tokens: usize,
+    /// Bytes of text encoded
+    pub bytes: usize,
+}
+
+impl Timing {
+    pub fn tokens_per_sec(&self) -> f64 {
+        tokens_per_sec(self.num_tokens as u64, self.elapsed)
+    }
+}
+
+/// Totals of the encode calls of one backend
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counters {
+    pub calls: u64,
+    pub tokens: u64,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl Counters {
+    fn add(&mut self, other: &Counters) {
+        self.calls += other.calls;
+        self.tokens += other.tokens;
+        self.bytes += other.bytes;
+        self.elapsed += other.elapsed;
+    }
+
+    pub fn tokens_per_sec(&self) -> f64 {
+        tokens_per_sec(self.tokens, self.elapsed)
+    }
+}
+
+/// Cumulative counters of encode calls, by backend
+#[derive(Debug, Default)]
+pub struct Stats {
+    by_backend: Mutex<BTreeMap<&'static str, Counters>>,
+}
+
+impl Stats {
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<&'static str, Counters>> {
+        // Counters are plain numbers, valid even if a thread panicked
+        self.by_backend.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Add one call to the counters of its backend
+    pub fn record(&self, timing: &Timing) {
+        self.lock().entry(timing.backend).or_default().add(&Counters {
+            calls: 1,
+            tokens: timing.num_tokens as u64,
+            bytes: timing.bytes as u64,
+            elapsed: timing.elapsed,
+        });
+    }
+
+    /// Counters of every backend used so far
+    pub fn by_backend(&self) -> BTreeMap<&'static str, Counters> {
+        self.lock().clone()
+    }
+
+    /// Counters of all backends together
+    pub fn total(&self) -> Counters {
+        let mut total = Counters::default();
+        for counters in self.lock().values() {
+            total.add(counters);
+        }
+        total
+    }
+
+    /// Start counting from zero again
+    pub fn reset(&self) {
+        self.lock().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timing(backend: &'static str, millis: u64, num_tokens: usize) -> Timing {
+        Timing {
+            backend,
+            elapsed: Duration::from_millis(millis),
+            num_tokens,
+            bytes: num_tokens * 4,
+        }
+    }
+
+    #[test]
+    fn test_counters() {
+        let stats = Stats::default();
+        stats.record(&timing("tiktoken", 10, 100));
+        stats.record(&timing("tiktoken", 30, 300));
+        stats.record(&timing("huggingface", 60, 200));
+
+        let tiktoken = stats.by_backend()["tiktoken"];
+        assert_eq!(tiktoken.calls, 2);
+        assert_eq!(tiktoken.tokens, 400);
+        assert_eq!(tiktoken.tokens_per_sec(), 10_000.0);
+
+        let total = stats.total();
+        assert_eq!((total.calls, total.bytes), (3, 2400));
+        assert_eq!(total.elapsed, Duration::from_millis(100));
+
+        stats.reset();
+        assert_eq!(stats.total(), Counters::default());
+        assert_eq!(timing("tiktoken", 0, 5).tokens_per_sec(), 0.0);
+    }
+}
@@ -0,0 +1,156 @@
+//! How stop sequences tokenize under the loaded model
+//!
+//! Servers that check stop sequences token by token only stop when the
+//! sequence starts and ends on token boundaries. BPE often merges the first
+//! or last characters of a stop string with the text around it (`"\n\n"`
+//! after another newline, `"END"` after a letter), so such a stop sequence is
+//! missed or cuts the output in the middle of a token.
+//! [`analyze_stop_sequences`] encodes every sequence in a few typical
+//! contexts and reports where that happens.
+
+use std::fmt;
+
+use crate::error::Result;
+
+/// Text placed before and after a stop sequence when analyzing it
+pub const CONTEXTS: &[&str] = &["a", " ", "\n", "."];
+
+/// A reason a stop sequence may not be detected reliably
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopWarning {
+    /// The sequence is empty and never matches
+    Empty,
+    /// After `context`, a token spans the start of the sequence
+    MergesBefore(String),
+    /// Before `context`, a token spans the end of the sequence
+    MergesAfter(String),
+}
+
+impl fmt::Display for StopWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "empty stop sequence"),
+            Self::MergesBefore(context) => {
+                write!(f, "a token spans the start of the sequence after {context:?}")
+            },
+            Self::MergesAfter(context) => {
+                write!(f, "a token spans the end of the sequence before {context:?}")
+            },
+        }
+    }
+}
+
+/// How a stop sequence tokenizes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StopSequenceReport {
+    pub sequence: String,
+    /// Tokens of the sequence on its own
+    pub tokens: Vec<u32>,
+    pub warnings: Vec<StopWarning>,
+}
+
+impl StopSequenceReport {
+    /// Whether the sequence starts and ends on token boundaries in every context
+    pub fn is_reliable(&self) -> bool {
+        self.warnings.is_empty()
+    }
+}
+
+/// Whether a token boundary falls at byte `offset` of the encoded text
+fn is_boundary(spans: &[(usize, usize)], offset: usize) -> bool {
+    !spans.iter().any(|&(start, end)| start < offset && offset < end)
+}
+
+/// Analyze every sequence of `sequences` in each of [`CONTEXTS`]
+///
+/// `encode` returns the tokens of a text and the byte span of each token.
+pub(crate) fn analyze_stop_sequences<F>(
+    sequences: &[String],
+    encode: F,
+) -> Result<Vec<StopSequenceReport>>
+where
+    F: Fn(&str) -> Result<(Vec<u32>, Vec<(usize, usize)>)>,
+{
+    sequences
+        .iter()
+        .map(|sequence| {
+            if sequence.is_empty() {
+                return Ok(StopSequenceReport {
+                    sequence: String::new(),
+                    tokens: Vec::new(),
+                    warnings: vec![StopWarning::Empty],
+                });
+            }
+            let (tokens, _) = encode(sequence)?;
+            let mut warnings = Vec::new();
+            for context in CONTEXTS {
+                let (_, spans) = encode(&format!("{context}{sequence}"))?;
+                if !is_boundary(&spans, context.len()) {
+                    warnings.push(StopWarning::MergesBefore(context.to_string()));
+                }
+            }
+            for context in CONTEXTS {
+                let (_, spans) = encode(&format!("{sequence}{context}"))?;
+                if !is_boundary(&spans, sequence.len()) {
+                    warnings.push(StopWarning::MergesAfter(context.to_string()));
+                }
+            }
+            Ok(StopSequenceReport {
+                sequence: sequence.clone(),
+                tokens,
+                warnings,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encoder merging runs of the same character into one token
+    fn encode_runs(text: &str) -> Result<(Vec<u32>, Vec<(usize, usize)>)> {
+        let mut tokens = Vec::new();
+        let mut spans: Vec<(usize, usize)> = Vec::new();
+        for (i, c) in text.char_indices() {
+            match spans.last_mut() {
+                Some(span) if text[span.0..].starts_with(c) => span.1 = i + c.len_utf8(),
+                _ => {
+                    tokens.push(c as u32);
+                    spans.push((i, i + c.len_utf8()));
+                },
+            }
+        }
+        Ok((tokens, spans))
+    }
+
+    #[test]
+    fn test_reliable_sequence() -> Result<()> {
+        let reports = analyze_stop_sequences(&["END".to_string()], encode_runs)?;
+        assert_eq!(reports[0].tokens, vec!['E' as u32, 'N' as u32, 'D' as u32]);
+        assert!(reports[0].is_reliable());
+        Ok(())
+    }
+
+    #[test]
+    fn test_merging_sequences() -> Result<()> {
+        let sequences = vec!["\n\n".to_string(), "a.".to_string(), String::new()];
+        let reports = analyze_stop_sequences(&sequences, encode_runs)?;
+        assert_eq!(
+            reports[0].warnings,
+            vec![
+                StopWarning::MergesBefore("\n".to_string()),
+                StopWarning::MergesAfter("\n".to_string()),
+            ]
+        );
+        assert_eq!(
+            reports[1].warnings,
+            vec![
+                StopWarning::MergesBefore("a".to_string()),
+                StopWarning::MergesAfter(".".to_string()),
+            ]
+        );
+        assert_eq!(reports[2].warnings, vec![StopWarning::Empty]);
+        Ok(())
+    }
+}
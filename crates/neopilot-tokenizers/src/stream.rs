@@ -0,0 +1,185 @@
+//! Incremental decoding of streamed LLM output
+//!
+//! A multi-byte character is often split across several byte-level tokens, so
+//! decoding tokens one at a time produces U+FFFD garbage. [`StreamDecoder`]
+//! keeps the tokens that have not been emitted yet and only returns text once
+//! it ends on a complete character.
+
+use std::sync::Arc;
+
+use crate::error::Result;
+use crate::replacement::REPLACEMENT_CHAR;
+use crate::TokenizerType;
+
+/// Decodes token IDs as they arrive into valid UTF-8 fragments
+///
+/// Decoding is done on a small window of recent tokens rather than each token
+/// alone, so tokenizers that merge whitespace across tokens produce the same
+/// text as decoding the whole sequence. The tokenizer is fixed when the
+/// decoder is created, so loading another model while a response is
+/// streaming does not garble it.
+#[derive(Clone)]
+pub struct StreamDecoder {
+    tokenizer: Arc<TokenizerType>,
+    skip_special_tokens: bool,
+    tokens: Vec<u32>,
+    /// Start of the context window used to decode the next fragment
+    prefix_offset: usize,
+    /// Tokens before this offset have been emitted
+    read_offset: usize,
+}
+
+impl StreamDecoder {
+    pub fn new(tokenizer: Arc<TokenizerType>) -> Self {
+        Self::with_special(tokenizer, false)
+    }
+
+    /// A decoder that leaves out special tokens such as `<|im_end|>` if
+    /// `skip_special_tokens` is set, see [`crate::decode_with_special`]
+    pub fn with_special(tokenizer: Arc<TokenizerType>, skip_special_tokens: bool) -> Self {
+        Self {
+            tokenizer,
+            skip_special_tokens,
+            tokens: Vec::new(),
+            prefix_offset: 0,
+            read_offset: 0,
+        }
+    }
+
+    fn decode(&self, tokens: &[u32]) -> Result<String> {
+        self.tokenizer.decode_with_special(tokens, self.skip_special_tokens)
+    }
+
+    /// Add `token` and return the text it completes, if any
+    pub fn push(&mut self, token: u32) -> Result<Option<String>> {
+        let (tokenizer, skip) = (Arc::clone(&self.tokenizer), self.skip_special_tokens);
+        self.push_with(token, |tokens| tokenizer.decode_with_special(tokens, skip))
+    }
+
+    /// Add the tokens of a streamed chunk and return the text they complete
+    ///
+    /// Equivalent to pushing them one by one and joining the fragments.
+    pub fn extend(&mut self, tokens: &[u32]) -> Result<Option<String>> {
+        let mut text: Option<String> = None;
+        for &token in tokens {
+            if let Some(fragment) = self.push(token)? {
+                text.get_or_insert_with(String::new).push_str(&fragment);
+            }
+        }
+        Ok(text)
+    }
+
+    /// Flush the buffered tokens at the end of the stream
+    ///
+    /// Returns `None` when everything was already emitted. Incomplete
+    /// sequences left in the buffer are decoded lossily. The decoder is then
+    /// empty and can decode the next response.
+    pub fn finish(&mut self) -> Result<Option<String>> {
+        let text = self.finish_with(|tokens| self.decode(tokens));
+        self.tokens.clear();
+        self.prefix_offset = 0;
+        self.read_offset = 0;
+        text
+    }
+
+    /// Whether tokens are buffered waiting for the rest of a character
+    pub fn has_pending(&self) -> bool {
+        self.read_offset < self.tokens.len()
+    }
+
+    /// [`Self::push`] with `decode` turning a slice of tokens into text,
+    /// replacing incomplete UTF-8 sequences with U+FFFD
+    fn push_with<F>(&mut self, token: u32, decode: F) -> Result<Option<String>>
+    where
+        F: Fn(&[u32]) -> Result<String>,
+    {
+        self.tokens.push(token);
+        let prefix = decode(&self.tokens[self.prefix_offset..self.read_offset])?;
+        let text = decode(&self.tokens[self.prefix_offset..])?;
+        if text.len() <= prefix.len() || text.ends_with(REPLACEMENT_CHAR) {
+            return Ok(None);
+        }
+        let Some(fragment) = text.get(prefix.len()..) else {
+            return Ok(None);
+        };
+        self.prefix_offset = self.read_offset;
+        self.read_offset = self.tokens.len();
+        Ok(Some(fragment.to_string()))
+    }
+
+    /// [`Self::finish`] with `decode`, leaving the buffer as it is
+    fn finish_with<F>(&self, decode: F) -> Result<Option<String>>
+    where
+        F: Fn(&[u32]) -> Result<String>,
+    {
+        if self.read_offset == self.tokens.len() {
+            return Ok(None);
+        }
+        let prefix = decode(&self.tokens[self.prefix_offset..self.read_offset])?;
+        let text = decode(&self.tokens[self.prefix_offset..])?;
+        // Skipped special tokens stay buffered but decode to nothing
+        Ok(text.get(prefix.len()..).filter(|text| !text.is_empty()).map(str::to_string))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiktoken::Tiktoken;
+
+    fn decoder() -> Result<StreamDecoder> {
+        Ok(StreamDecoder::new(Arc::new(TokenizerType::Tiktoken(Tiktoken::new("gpt-4o")?))))
+    }
+
+    /// Byte-level decoder where every token is a single byte
+    fn decode_bytes(tokens: &[u32]) -> Result<String> {
+        let bytes: Vec<u8> = tokens.iter().map(|&token| token as u8).collect();
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    #[test]
+    fn test_multibyte_characters_are_buffered() -> Result<()> {
+        let mut decoder = decoder()?;
+        let mut output = Vec::new();
+        for byte in "a€b".bytes() {
+            output.push(decoder.push_with(u32::from(byte), decode_bytes)?);
+        }
+        assert_eq!(
+            output,
+            vec![Some("a".to_string()), None, None, Some("€".to_string()), Some("b".to_string())]
+        );
+        assert!(!decoder.has_pending());
+        assert_eq!(decoder.finish_with(decode_bytes)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_extend_joins_fragments() -> Result<()> {
+        let mut decoder = decoder()?;
+        let text = "a€b 世界";
+        let (tokens, _, _) = decoder.tokenizer.encode(text)?;
+        let mut streamed = String::new();
+        for chunk in tokens.chunks(2) {
+            streamed.push_str(&decoder.extend(chunk)?.unwrap_or_default());
+        }
+        assert_eq!(decoder.finish()?, None);
+        assert_eq!(streamed, text);
+        Ok(())
+    }
+
+    #[test]
+    fn test_finish_flushes_incomplete_sequence() -> Result<()> {
+        let mut decoder = decoder()?;
+        let bytes = "é".as_bytes();
+        assert_eq!(decoder.push_with(u32::from(bytes[0]), decode_bytes)?, None);
+        assert!(decoder.has_pending());
+        assert_eq!(decoder.finish_with(decode_bytes)?, Some(REPLACEMENT_CHAR.to_string()));
+
+        // Finishing empties the decoder for the next response
+        decoder.finish()?;
+        assert!(!decoder.has_pending());
+        let (tokens, _, _) = decoder.tokenizer.encode("next")?;
+        assert_eq!(decoder.extend(&tokens)?.as_deref(), Some("next"));
+        Ok(())
+    }
+}
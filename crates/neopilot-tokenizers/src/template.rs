@@ -0,0 +1,253 @@
+//! Chat templates of Hugging Face models
+//!
+//! Models on the Hub ship a Jinja `chat_template` in their
+//! `tokenizer_config.json`, and GGUF files under `tokenizer.chat_template`,
+//! that turns a list of messages into the prompt the model was trained on.
+//! [`ChatTemplate`] renders it the way `transformers` does: with trimmed
+//! blocks, `raise_exception`, and the Python string methods templates call.
+
+use std::path::Path;
+
+use minijinja::{context, Environment, ErrorKind};
+use serde_json::{json, Value};
+
+use crate::chat::{ChatMessage, MessagePart};
+use crate::error::{Result, TokenizerError};
+
+/// Instructions a template may run, so a broken template cannot hang the editor
+const FUEL: u64 = 50_000_000;
+
+/// A Jinja chat template with the special tokens it refers to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatTemplate {
+    pub source: String,
+    pub bos_token: Option<String>,
+    pub eos_token: Option<String>,
+}
+
+/// Content of a special token entry, written as a string or as an added token
+fn token_content(value: &Value) -> Option<String> {
+    match value {
+        Value::String(token) => Some(token.clone()),
+        Value::Object(token) => token.get("content")?.as_str().map(str::to_string),
+        _ => None,
+    }
+}
+
+impl ChatTemplate {
+    pub fn new(source: &str) -> Self {
+        Self {
+            source: source.to_string(),
+            bos_token: None,
+            eos_token: None,
+        }
+    }
+
+    /// The chat template of a `tokenizer_config.json`, `None` if it has none
+    ///
+    /// Configurations with several named templates use the one named
+    /// `default`.
+    pub fn from_tokenizer_config(json: &str) -> Result<Option<Self>> {
+        let config: Value = serde_json::from_str(json)?;
+        let source = match &config["chat_template"] {
+            Value::String(source) => Some(source.clone()),
+            Value::Array(templates) => templates
+                .iter()
+                .find(|template| template["name"] == "default")
+                .or_else(|| templates.first())
+                .and_then(|template| template["template"].as_str())
+                .map(str::to_string),
+            _ => None,
+        };
+        Ok(source.map(|source| Self {
+            source,
+            bos_token: token_content(&config["bos_token"]),
+            eos_token: token_content(&config["eos_token"]),
+        }))
+    }
+
+    /// The chat template next to a tokenizer in `dir`, if any
+    ///
+    /// A `chat_template.jinja` file replaces the template of
+    /// `tokenizer_config.json`, as in `transformers`. Unreadable files are
+    /// logged and skipped: the tokenizer works without its template.
+    pub(crate) fn from_directory(dir: &Path) -> Option<Self> {
+        let config = dir.join("tokenizer_config.json");
+        let mut template = match std::fs::read_to_string(&config) {
+            Ok(json) => Self::from_tokenizer_config(&json).unwrap_or_else(|e| {
+                log::warn!("Ignoring the chat template of {}: {e}", config.display());
+                None
+            }),
+            Err(_) => None,
+        };
+        if let Ok(source) = std::fs::read_to_string(dir.join("chat_template.jinja")) {
+            template.get_or_insert_with(|| Self::new("")).source = source;
+        }
+        template
+    }
+
+    /// Render `messages` into a prompt
+    ///
+    /// With `add_generation_prompt`, the prompt ends with the start of an
+    /// assistant message for the model to complete.
+    pub fn render(&self, messages: &[ChatMessage], add_generation_prompt: bool) -> Result<String> {
+        let messages = messages.iter().map(message_json).collect::<Result<Vec<_>>>()?;
+
+        let mut env = Environment::new();
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env.set_fuel(Some(FUEL));
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function(
+            "raise_exception",
+            |message: String| -> std::result::Result<(), minijinja::Error> {
+                Err(minijinja::Error::new(ErrorKind::InvalidOperation, message))
+            },
+        );
+        let template = env.template_from_str(&self.source).map_err(template_error)?;
+        template
+            .render(context! {
+                messages => messages,
+                add_generation_prompt => add_generation_prompt,
+                bos_token => self.bos_token,
+                eos_token => self.eos_token,
+                tools => (),
+            })
+            .map_err(template_error)
+    }
+}
+
+fn template_error(e: minijinja::Error) -> TokenizerError {
+    let message = match e.detail() {
+        Some(detail) if e.kind() == ErrorKind::InvalidOperation => detail.to_string(),
+        _ => e.to_string(),
+    };
+    TokenizerError::ChatTemplate(message)
+}
+
+/// A message as templates expect it, following the OpenAI format
+///
+/// Content that is only text is a string; content with images is a list of
+/// `{ type = "text" | "image" }` parts. Tool calls go to `tool_calls`, with
+/// their arguments parsed.
+fn message_json(message: &ChatMessage) -> Result<Value> {
+    let mut text = String::new();
+    let mut parts = Vec::new();
+    let mut tool_calls = Vec::new();
+    let mut has_images = false;
+    for part in &message.content {
+        match part {
+            MessagePart::Text(part) => {
+                text.push_str(part);
+                parts.push(json!({ "type": "text", "text": part }));
+            },
+            MessagePart::Image { .. } => {
+                has_images = true;
+                parts.push(json!({ "type": "image" }));
+            },
+            MessagePart::ToolCall { name, arguments } => {
+                let arguments: Value = serde_json::from_str(arguments).map_err(|e| {
+                    TokenizerError::InvalidArgument(format!(
+                        "Arguments of tool call '{name}' are not valid JSON: {e}"
+                    ))
+                })?;
+                tool_calls.push(json!({
+                    "type": "function",
+                    "function": { "name": name, "arguments": arguments },
+                }));
+            },
+        }
+    }
+
+    let mut result = json!({
+        "role": message.role,
+        "content": if has_images { Value::from(parts) } else { Value::from(text) },
+    });
+    if let Some(name) = &message.name {
+        result["name"] = json!(name);
+    }
+    if !tool_calls.is_empty() {
+        result["tool_calls"] = Value::from(tool_calls);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHATML: &str = "{% for message in messages %}\
+        {{ '<|im_start|>' + message['role'] + '\\n' + message['content'] + '<|im_end|>\\n' }}\
+        {% endfor %}\
+        {% if add_generation_prompt %}{{ '<|im_start|>assistant\\n' }}{% endif %}";
+
+    /// Abridged Llama 2 template, with its role check and `.strip()` calls
+    const LLAMA2: &str = "{{ bos_token }}{% for message in messages %}\
+        {% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}\
+        {{ raise_exception('Conversation roles must alternate user/assistant') }}\
+        {% endif %}\
+        {% if message['role'] == 'user' %}\
+        {{ '[INST] ' + message['content'].strip() + ' [/INST]' }}\
+        {% else %}{{ ' ' + message['content'].strip() + ' ' + eos_token }}{% endif %}\
+        {% endfor %}";
+
+    #[test]
+    fn test_render() -> Result<()> {
+        let messages = vec![
+            ChatMessage::text("system", "You are helpful."),
+            ChatMessage::text("user", "Hi"),
+        ];
+        let template = ChatTemplate::new(CHATML);
+        assert_eq!(
+            template.render(&messages, true)?,
+            "<|im_start|>system\nYou are helpful.<|im_end|>\n\
+             <|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+        );
+        assert!(!template.render(&messages, false)?.ends_with("assistant\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tokenizer_config() -> Result<()> {
+        let config = json!({
+            "bos_token": { "content": "<s>", "special": true },
+            "eos_token": "</s>",
+            "chat_template": [
+                { "name": "tool_use", "template": "tools" },
+                { "name": "default", "template": LLAMA2 },
+            ],
+        });
+        let template = ChatTemplate::from_tokenizer_config(&config.to_string())?.unwrap();
+        assert_eq!(template.source, LLAMA2);
+
+        let mut messages =
+            vec![ChatMessage::text("user", " Hello "), ChatMessage::text("assistant", "Hi!")];
+        assert_eq!(template.render(&messages, false)?, "<s>[INST] Hello [/INST] Hi! </s>");
+
+        messages.swap(0, 1);
+        let error = template.render(&messages, false).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Chat template error: Conversation roles must alternate user/assistant"
+        );
+
+        assert_eq!(ChatTemplate::from_tokenizer_config("{\"eos_token\": \"</s>\"}")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_json() -> Result<()> {
+        let message = ChatMessage {
+            role: "assistant".to_string(),
+            name: None,
+            content: vec![MessagePart::ToolCall {
+                name: "get_weather".to_string(),
+                arguments: "{\"city\": \"Paris\"}".to_string(),
+            }],
+        };
+        let value = message_json(&message)?;
+        assert_eq!(value["content"], "");
+        assert_eq!(value["tool_calls"][0]["function"]["arguments"]["city"], "Paris");
+        Ok(())
+    }
+}
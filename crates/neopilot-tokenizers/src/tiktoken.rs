@@ -1,25 +1,340 @@
 //! Tiktoken tokenizer implementation for OpenAI models
+//!
+//! Model names are mapped to encodings with [`MODEL_ENCODINGS`], the same
+//! table tiktoken uses, kept here so new models do not wait for a
+//! tiktoken-rs release. Callers extend it with their own patterns through
+//! [`lookup_encoding`], see `set_encoding` in the crate root.
+//!
+//! The ranks of every encoding, o200k_base included, are compiled into
+//! tiktoken-rs, so these tokenizers never need the network. With the
+//! `embedded-o200k` feature, o200k_base is instead parsed from the upstream
+//! `o200k_base.tiktoken` file compiled into this crate, see [`embedded`].
+//! Parsing the ranks still takes a noticeable moment for the large
+//! encodings, so the crate root keeps each parsed encoding in a [`RankCache`]
+//! of its state, shared by all its tokenizers until they are unloaded.
 
 use crate::error::{Result, TokenizerError};
+use crate::offsets::byte_spans;
+use crate::special::SpecialTokens;
+use crate::vocab::{token_text, VocabToken, Vocabulary};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
 use tiktoken_rs::CoreBPE;
 
+/// Text encoded by [`Encoding::warm_up`], mixing prose, code and non-ASCII
+/// text to go through every branch of the splitting pattern
+pub(crate) const WARMUP_TEXT: &str =
+    "Hello, world! fn main() { let x = 42; }\n\tcafé 東京 ½ 🙂  end";
+
+/// A tiktoken encoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    R50kBase,
+    P50kBase,
+    P50kEdit,
+    Cl100kBase,
+    O200kBase,
+}
+
+impl Encoding {
+    /// Every encoding, oldest first
+    pub const ALL: [Encoding; 5] = [
+        Self::R50kBase,
+        Self::P50kBase,
+        Self::P50kEdit,
+        Self::Cl100kBase,
+        Self::O200kBase,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::R50kBase => "r50k_base",
+            Self::P50kBase => "p50k_base",
+            Self::P50kEdit => "p50k_edit",
+            Self::Cl100kBase => "cl100k_base",
+            Self::O200kBase => "o200k_base",
+        }
+    }
+
+    fn parse(self) -> Result<CoreBPE> {
+        let bpe = match self {
+            Self::R50kBase => tiktoken_rs::r50k_base(),
+            Self::P50kBase => tiktoken_rs::p50k_base(),
+            Self::P50kEdit => tiktoken_rs::p50k_edit(),
+            Self::Cl100kBase => tiktoken_rs::cl100k_base(),
+            #[cfg(feature = "embedded-o200k")]
+            Self::O200kBase => embedded::o200k_base(),
+            #[cfg(not(feature = "embedded-o200k"))]
+            Self::O200kBase => tiktoken_rs::o200k_base(),
+        };
+        bpe.map_err(|e| TokenizerError::ModelLoadError(e.to_string()))
+    }
+
+    /// Parse the ranks into `ranks` and run a first encode ahead of time
+    ///
+    /// Besides parsing, the first encode of an encoding is slower than the
+    /// following ones, so a warmed encoding makes the first interactive count
+    /// as fast as any other. Cheap once the encoding is warm.
+    pub(crate) fn warm_up(self, ranks: &RankCache) -> Result<()> {
+        ranks.get(self)?.encode_ordinary(WARMUP_TEXT);
+        Ok(())
+    }
+
+    /// Number of regular (non-special) tokens
+    fn vocab_size(self) -> u32 {
+        match self {
+            Self::R50kBase => 50_256,
+            Self::P50kBase | Self::P50kEdit => 50_281,
+            Self::Cl100kBase => 100_256,
+            Self::O200kBase => 199_998,
+        }
+    }
+}
+
+impl std::str::FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            // gpt2 is r50k_base under another name
+            "r50k_base" | "gpt2" => Ok(Self::R50kBase),
+            "p50k_base" => Ok(Self::P50kBase),
+            "p50k_edit" => Ok(Self::P50kEdit),
+            "cl100k_base" => Ok(Self::Cl100kBase),
+            "o200k_base" => Ok(Self::O200kBase),
+            _ => Err(format!(
+                "Unknown encoding '{s}', expected r50k_base, p50k_base, p50k_edit, \
+                 cl100k_base or o200k_base"
+            )),
+        }
+    }
+}
+
+/// Built-in model name patterns and their encodings
+///
+/// A pattern ending in `*` matches every name starting with the rest; the
+/// longest matching pattern wins and exact names beat patterns.
+pub const MODEL_ENCODINGS: &[(&str, Encoding)] = &[
+    // Reasoning models
+    ("o1", Encoding::O200kBase),
+    ("o1-*", Encoding::O200kBase),
+    ("o3", Encoding::O200kBase),
+    ("o3-*", Encoding::O200kBase),
+    ("o4-mini", Encoding::O200kBase),
+    ("o4-mini-*", Encoding::O200kBase),
+    // Chat models
+    ("gpt-5", Encoding::O200kBase),
+    ("gpt-5-*", Encoding::O200kBase),
+    ("gpt-4.1", Encoding::O200kBase),
+    ("gpt-4.1-*", Encoding::O200kBase),
+    ("gpt-4.5-*", Encoding::O200kBase),
+    ("gpt-4o", Encoding::O200kBase),
+    ("gpt-4o-*", Encoding::O200kBase),
+    ("chatgpt-4o-*", Encoding::O200kBase),
+    ("gpt-4", Encoding::Cl100kBase),
+    ("gpt-4-*", Encoding::Cl100kBase),
+    ("gpt-3.5-turbo", Encoding::Cl100kBase),
+    ("gpt-3.5-turbo-*", Encoding::Cl100kBase),
+    ("gpt-3.5", Encoding::Cl100kBase),
+    ("gpt-35-turbo", Encoding::Cl100kBase),
+    ("gpt-35-turbo-*", Encoding::Cl100kBase),
+    // Base models
+    ("davinci-002", Encoding::Cl100kBase),
+    ("babbage-002", Encoding::Cl100kBase),
+    // Embeddings
+    ("text-embedding-ada-002", Encoding::Cl100kBase),
+    ("text-embedding-3-small", Encoding::Cl100kBase),
+    ("text-embedding-3-large", Encoding::Cl100kBase),
+    // Legacy completion, code and edit models
+    ("text-davinci-003", Encoding::P50kBase),
+    ("text-davinci-002", Encoding::P50kBase),
+    ("text-davinci-001", Encoding::R50kBase),
+    ("text-curie-001", Encoding::R50kBase),
+    ("text-babbage-001", Encoding::R50kBase),
+    ("text-ada-001", Encoding::R50kBase),
+    ("davinci", Encoding::R50kBase),
+    ("curie", Encoding::R50kBase),
+    ("babbage", Encoding::R50kBase),
+    ("ada", Encoding::R50kBase),
+    ("code-davinci-002", Encoding::P50kBase),
+    ("code-davinci-001", Encoding::P50kBase),
+    ("code-cushman-002", Encoding::P50kBase),
+    ("code-cushman-001", Encoding::P50kBase),
+    ("davinci-codex", Encoding::P50kBase),
+    ("cushman-codex", Encoding::P50kBase),
+    ("text-davinci-edit-001", Encoding::P50kEdit),
+    ("code-davinci-edit-001", Encoding::P50kEdit),
+    ("text-similarity-*", Encoding::R50kBase),
+    ("text-search-*", Encoding::R50kBase),
+    ("code-search-*", Encoding::R50kBase),
+    ("gpt2", Encoding::R50kBase),
+];
+
+/// Whether `pattern` matches the model name `model`, see [`MODEL_ENCODINGS`]
+pub fn pattern_matches(pattern: &str, model: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => model.starts_with(prefix),
+        None => model == pattern,
+    }
+}
+
+/// Encoding of `model` according to `patterns`, see [`MODEL_ENCODINGS`]
+///
+/// Names are compared in lowercase. Fine-tuned models such as
+/// `ft:gpt-4o-mini:org::id` use the encoding of their base model.
+pub fn lookup_encoding<'a, I>(model: &str, patterns: I) -> Option<Encoding>
+where
+    I: IntoIterator<Item = (&'a str, Encoding)>,
+{
+    let model = model.to_lowercase();
+    let model = match model.strip_prefix("ft:") {
+        Some(fine_tuned) => fine_tuned.split(':').next().unwrap_or(fine_tuned),
+        None => &model,
+    };
+    patterns
+        .into_iter()
+        .filter(|(pattern, _)| pattern_matches(pattern, model))
+        // Exact names rank above any pattern, then longer patterns first
+        .max_by_key(|(pattern, _)| (!pattern.ends_with('*'), pattern.len()))
+        .map(|(_, encoding)| encoding)
+}
+
+/// Ranks compiled into this crate by the `embedded-o200k` feature
+///
+/// `assets/o200k_base.tiktoken` is the file tiktoken publishes, one
+/// base64-encoded token and its rank per line, downloaded and checked against
+/// its published hash by `scripts/fetch-o200k.sh` before building.
+#[cfg(feature = "embedded-o200k")]
+pub(crate) mod embedded {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine as _;
+    use tiktoken_rs::CoreBPE;
+
+    const O200K_BASE: &str =
+        include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/o200k_base.tiktoken"));
+
+    /// The splitting pattern of o200k_base, as in tiktoken
+    const O200K_BASE_PATTERN: &[&str] = &[
+        concat!(
+            r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+",
+            r"(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+        ),
+        concat!(
+            r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*",
+            r"(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+        ),
+        r"\p{N}{1,3}",
+        r" ?[^\s\p{L}\p{N}]+[\r\n/]*",
+        r"\s*[\r\n]+",
+        r"\s+(?!\S)",
+        r"\s+",
+    ];
+
+    /// Parse the embedded o200k_base ranks
+    pub(crate) fn o200k_base() -> anyhow::Result<CoreBPE> {
+        let encoder = O200K_BASE
+            .lines()
+            .map(|line| {
+                let (token, rank) = line
+                    .split_once(' ')
+                    .ok_or_else(|| anyhow::anyhow!("Malformed o200k_base line: {line:?}"))?;
+                let rank: usize = rank.parse()?;
+                Ok((STANDARD.decode(token)?, rank))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let special_tokens = [("<|endoftext|>", 199_999), ("<|endofprompt|>", 200_018)]
+            .into_iter()
+            .map(|(token, rank)| (token.to_string(), rank))
+            .collect();
+        CoreBPE::new(encoder, special_tokens, &O200K_BASE_PATTERN.join("|"))
+    }
+}
+
+/// Parsed ranks of each encoding, kept for the tokenizers of one state
+///
+/// Unlike a process-wide cache, the ranks are freed once the state is cleared
+/// and the tokenizers built from them are dropped.
+#[derive(Default)]
+pub(crate) struct RankCache([Mutex<Option<Arc<CoreBPE>>>; Encoding::ALL.len()]);
+
+impl RankCache {
+    /// The parsed ranks of `encoding`, parsing them on first use
+    pub(crate) fn get(&self, encoding: Encoding) -> Result<Arc<CoreBPE>> {
+        let mut parsed = self.0[encoding as usize].lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(bpe) = parsed.as_ref() {
+            return Ok(Arc::clone(bpe));
+        }
+        let bpe = Arc::new(encoding.parse()?);
+        *parsed = Some(Arc::clone(&bpe));
+        Ok(bpe)
+    }
+
+    /// Drop every parsed encoding
+    pub(crate) fn clear(&self) {
+        for parsed in &self.0 {
+            parsed.lock().unwrap_or_else(PoisonError::into_inner).take();
+        }
+    }
+}
+
+/// The encoding of `model`, a model name looked up in [`MODEL_ENCODINGS`] or
+/// an encoding name
+pub fn model_encoding(model: &str) -> Result<Encoding> {
+    if let Ok(encoding) = model.to_lowercase().parse() {
+        return Ok(encoding);
+    }
+    lookup_encoding(model, MODEL_ENCODINGS.iter().copied()).ok_or_else(|| {
+        TokenizerError::ModelLoadError(format!("No tiktoken encoding for model '{model}'"))
+    })
+}
+
 /// Wrapper around the Tiktoken tokenizer
 pub struct Tiktoken {
-    bpe: CoreBPE,
+    bpe: Arc<CoreBPE>,
+    encoding: Encoding,
+    /// IDs of the vocabulary entries, built on the first
+    /// [`Tiktoken::token_to_id`] that needs it
+    ids: OnceLock<HashMap<String, u32>>,
 }
 
 impl Tiktoken {
     /// Create a new Tiktoken tokenizer for the specified model
     ///
     /// # Arguments
-    /// * `model` - The model name (e.g., "gpt-4")
+    /// * `model` - The model name (e.g., "gpt-4"), looked up in [`MODEL_ENCODINGS`],
+    ///   or an encoding name (e.g., "cl100k_base") for providers that only
+    ///   document the encoding
     pub fn new(model: &str) -> Result<Self> {
-        let bpe = tiktoken_rs::get_bpe_from_model(model)
-            .map_err(|e| TokenizerError::ModelLoadError(e.to_string()))?;
-        Ok(Self { bpe })
+        Self::with_encoding(model_encoding(model)?)
     }
 
-    /// Encode text into tokens
+    /// Create a Tiktoken tokenizer for `encoding`
+    ///
+    /// The ranks are parsed for this tokenizer alone; see
+    /// [`Tiktoken::with_ranks`] to share them.
+    pub fn with_encoding(encoding: Encoding) -> Result<Self> {
+        Ok(Self::from_bpe(encoding, Arc::new(encoding.parse()?)))
+    }
+
+    /// Create a Tiktoken tokenizer for `encoding` with the ranks in `ranks`
+    pub(crate) fn with_ranks(encoding: Encoding, ranks: &RankCache) -> Result<Self> {
+        Ok(Self::from_bpe(encoding, ranks.get(encoding)?))
+    }
+
+    fn from_bpe(encoding: Encoding, bpe: Arc<CoreBPE>) -> Self {
+        Self {
+            bpe,
+            encoding,
+            ids: OnceLock::new(),
+        }
+    }
+
+    /// The encoding of this tokenizer
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Encode text into tokens, treating special token strings as special
     ///
     /// # Arguments
     /// * `text` - The text to encode
@@ -30,11 +345,126 @@ impl Tiktoken {
     /// - The number of tokens
     /// - The number of characters in the input text
     pub fn encode(&self, text: &str) -> (Vec<u32>, usize, usize) {
-        let tokens: Vec<u32> = self.bpe.encode_with_special_tokens(text).iter().map(|&x| x as u32).collect();
+        self.encode_with_special(text, SpecialTokens::Special)
+    }
+
+    /// Encode text into tokens, handling special token strings as `special` says
+    pub fn encode_with_special(
+        &self,
+        text: &str,
+        special: SpecialTokens,
+    ) -> (Vec<u32>, usize, usize) {
+        let tokens = match special {
+            SpecialTokens::Special => self.bpe.encode_with_special_tokens(text),
+            SpecialTokens::Ordinary => self.bpe.encode_ordinary(text),
+        };
+        let tokens: Vec<u32> = tokens.iter().map(|&x| x as u32).collect();
         let num_tokens = tokens.len();
         let num_chars = text.chars().count();
         (tokens, num_tokens, num_chars)
     }
+
+    /// Special tokens of the encoding and their IDs
+    pub fn special_tokens(&self) -> Vec<(String, u32)> {
+        self.bpe
+            .special_tokens()
+            .into_iter()
+            .filter_map(|token| match self.bpe.encode_with_special_tokens(token).as_slice() {
+                [id] => Some((token.to_string(), *id as u32)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Byte span of each token in the text it was encoded from
+    pub fn byte_spans(&self, tokens: &[u32]) -> Vec<(usize, usize)> {
+        byte_spans(tokens.iter().map(|&token| self.bpe._decode_native(&[token as usize]).len()))
+    }
+
+    /// Regular tokens of the encoding; tiktoken has no merge list
+    pub fn vocabulary(&self) -> Vocabulary {
+        let tokens = (0..self.encoding.vocab_size())
+            .map(|id| VocabToken {
+                id,
+                token: token_text(&self.bpe._decode_native(&[id as usize])),
+            })
+            .collect();
+        Vocabulary {
+            tokens,
+            merges: Vec::new(),
+        }
+    }
+
+    /// Text of token `id` as [`Tiktoken::vocabulary`] writes it, including
+    /// special tokens, or `None` for IDs the encoding does not use
+    pub fn id_to_token(&self, id: u32) -> Option<String> {
+        if id < self.encoding.vocab_size() {
+            return Some(token_text(&self.bpe._decode_native(&[id as usize])));
+        }
+        self.special_tokens()
+            .into_iter()
+            .find(|(_, special)| *special == id)
+            .map(|(token, _)| token)
+    }
+
+    /// ID of the token written as `token` by [`Tiktoken::id_to_token`]
+    pub fn token_to_id(&self, token: &str) -> Option<u32> {
+        let specials = self.special_tokens();
+        if let Some((_, id)) = specials.into_iter().find(|(special, _)| special == token) {
+            return Some(id);
+        }
+        // Most tokens encode to themselves; byte tokens and the few that BPE
+        // splits differently are looked up in the vocabulary
+        if let [id] = self.bpe.encode_ordinary(token).as_slice() {
+            let id = *id as u32;
+            if self.id_to_token(id).as_deref() == Some(token) {
+                return Some(id);
+            }
+        }
+        let ids = self.ids.get_or_init(|| {
+            let mut ids = HashMap::with_capacity(self.encoding.vocab_size() as usize);
+            for token in self.vocabulary().tokens {
+                // The lowest ID wins, as the first match of a scan would
+                ids.entry(token.token).or_insert(token.id);
+            }
+            ids
+        });
+        ids.get(token).copied()
+    }
+
+    /// Decode tokens into text, replacing incomplete UTF-8 sequences with U+FFFD
+    ///
+    /// Fails with [`TokenizerError::InvalidArgument`] for IDs the encoding
+    /// does not use, which tiktoken-rs would panic on.
+    pub fn decode(&self, tokens: &[u32]) -> Result<String> {
+        self.decode_with_special(tokens, false)
+    }
+
+    /// Decode tokens like [`Tiktoken::decode`], leaving out special tokens
+    /// such as `<|endoftext|>` if `skip_special_tokens` is set
+    pub fn decode_with_special(&self, tokens: &[u32], skip_special_tokens: bool) -> Result<String> {
+        let vocab_size = self.encoding.vocab_size();
+        let needs_specials = skip_special_tokens || tokens.iter().any(|&id| id >= vocab_size);
+        let specials: Vec<u32> = if needs_specials {
+            self.special_tokens().into_iter().map(|(_, id)| id).collect()
+        } else {
+            Vec::new()
+        };
+        let mut ids = Vec::with_capacity(tokens.len());
+        for &id in tokens {
+            let special = specials.contains(&id);
+            if id >= vocab_size && !special {
+                return Err(TokenizerError::InvalidArgument(format!(
+                    "Token {id} is not in the {} vocabulary",
+                    self.encoding.as_str()
+                )));
+            }
+            if !(special && skip_special_tokens) {
+                ids.push(id as usize);
+            }
+        }
+        Ok(String::from_utf8_lossy(&self.bpe._decode_native(&ids)).into_owned())
+    }
 }
 
 #[cfg(test)]
@@ -56,6 +486,158 @@ mod tests {
         assert_eq!(num_chars, 13);
     }
 
+    #[test]
+    fn test_tiktoken_decode() {
+        let tokenizer = Tiktoken::new("gpt-4").unwrap();
+        let (tokens, _, _) = tokenizer.encode("Hello, world!");
+        assert_eq!(tokenizer.decode(&tokens).unwrap(), "Hello, world!");
+    }
+
+    #[test]
+    fn test_tiktoken_decode_unknown_ids() {
+        let tokenizer = Tiktoken::new("gpt-4").unwrap();
+        for id in [100_256, 100_300, u32::MAX] {
+            assert!(matches!(
+                tokenizer.decode(&[9906, id]),
+                Err(TokenizerError::InvalidArgument(_))
+            ));
+        }
+        assert_eq!(tokenizer.decode(&[100_257]).unwrap(), "<|endoftext|>");
+    }
+
+    #[test]
+    fn test_tiktoken_special_tokens() {
+        let tokenizer = Tiktoken::new("gpt-4").unwrap();
+        let text = "<|endoftext|>";
+        let (tokens, _, _) = tokenizer.encode_with_special(text, SpecialTokens::Special);
+        assert_eq!(tokens, vec![100_257]);
+        let (tokens, _, _) = tokenizer.encode_with_special(text, SpecialTokens::Ordinary);
+        assert!(tokens.len() > 1);
+        assert!(tokens.iter().all(|&token| token < 100_256));
+        assert_eq!(tokenizer.decode(&tokens).unwrap(), text);
+    }
+
+    #[test]
+    fn test_tiktoken_skip_special_tokens() {
+        let tokenizer = Tiktoken::new("gpt-4o").unwrap();
+        let text = "Done.<|endoftext|>";
+        let (tokens, _, _) = tokenizer.encode_with_special(text, SpecialTokens::Special);
+        assert_eq!(tokenizer.decode_with_special(&tokens, false).unwrap(), text);
+        assert_eq!(tokenizer.decode_with_special(&tokens, true).unwrap(), "Done.");
+    }
+
+    #[test]
+    fn test_tiktoken_special_token_list() {
+        let tokenizer = Tiktoken::new("gpt-4").unwrap();
+        let specials = tokenizer.special_tokens();
+        assert!(specials.contains(&("<|endoftext|>".to_string(), 100_257)));
+        assert!(specials.iter().all(|(_, id)| *id >= 100_256));
+    }
+
+    #[test]
+    fn test_tiktoken_byte_spans() {
+        let tokenizer = Tiktoken::new("gpt-4").unwrap();
+        let text = "Hello, wörld!";
+        let (tokens, _, _) = tokenizer.encode(text);
+        let spans = tokenizer.byte_spans(&tokens);
+        assert_eq!(spans.len(), tokens.len());
+        assert_eq!(spans.first().map(|span| span.0), Some(0));
+        assert_eq!(spans.last().map(|span| span.1), Some(text.len()));
+    }
+
+    #[test]
+    fn test_tiktoken_vocabulary() {
+        let tokenizer = Tiktoken::new("gpt-4").unwrap();
+        let vocab = tokenizer.vocabulary();
+        assert_eq!(vocab.tokens.len(), 100_256);
+        assert_eq!(vocab.tokens[0].token, "!");
+        assert!(vocab.merges.is_empty());
+    }
+
+    #[test]
+    fn test_tiktoken_token_lookup() {
+        let tokenizer = Tiktoken::new("gpt-4").unwrap();
+        let (tokens, _, _) = tokenizer.encode("Hello");
+        assert_eq!(tokenizer.id_to_token(tokens[0]).as_deref(), Some("Hello"));
+        assert_eq!(tokenizer.token_to_id("Hello"), Some(tokens[0]));
+
+        assert_eq!(tokenizer.id_to_token(100_257).as_deref(), Some("<|endoftext|>"));
+        assert_eq!(tokenizer.token_to_id("<|endoftext|>"), Some(100_257));
+        assert_eq!(tokenizer.id_to_token(100_256), None);
+        assert_eq!(tokenizer.token_to_id("not a single token"), None);
+
+        // Byte tokens are written as <0xNN> and only found in the vocabulary
+        let (id, token) = (0..256)
+            .filter_map(|id| Some((id, tokenizer.id_to_token(id)?)))
+            .find(|(_, token)| token.starts_with("<0x"))
+            .unwrap();
+        assert_eq!(tokenizer.token_to_id(&token), Some(id));
+    }
+
+    #[test]
+    fn test_model_encodings() {
+        let builtin = || MODEL_ENCODINGS.iter().copied();
+        let cases = [
+            ("gpt-4o", Some(Encoding::O200kBase)),
+            ("gpt-4o-mini", Some(Encoding::O200kBase)),
+            ("chatgpt-4o-latest", Some(Encoding::O200kBase)),
+            ("o1", Some(Encoding::O200kBase)),
+            ("o3-mini", Some(Encoding::O200kBase)),
+            ("gpt-4.1-nano", Some(Encoding::O200kBase)),
+            ("GPT-4", Some(Encoding::Cl100kBase)),
+            ("gpt-4-turbo", Some(Encoding::Cl100kBase)),
+            ("gpt-3.5-turbo-0125", Some(Encoding::Cl100kBase)),
+            ("text-embedding-3-small", Some(Encoding::Cl100kBase)),
+            ("text-davinci-003", Some(Encoding::P50kBase)),
+            ("ft:gpt-4o-mini-2024-07-18:org::abc123", Some(Encoding::O200kBase)),
+            ("ft:gpt-3.5-turbo:org::abc123", Some(Encoding::Cl100kBase)),
+            ("gpt-4oops", None),
+            ("invalid-model", None),
+        ];
+        for (model, expected) in cases {
+            assert_eq!(lookup_encoding(model, builtin()), expected, "{model}");
+        }
+    }
+
+    #[test]
+    fn test_custom_patterns() {
+        let patterns = [
+            ("my-proxy-*", Encoding::O200kBase),
+            ("my-proxy-legacy", Encoding::P50kBase),
+        ];
+        let lookup = |model| lookup_encoding(model, patterns.iter().copied());
+        assert_eq!(lookup("my-proxy-fast"), Some(Encoding::O200kBase));
+        assert_eq!(lookup("my-proxy-legacy"), Some(Encoding::P50kBase));
+        assert_eq!(lookup("other"), None);
+        assert_eq!("o200k_base".parse(), Ok(Encoding::O200kBase));
+        assert!("o100k_base".parse::<Encoding>().is_err());
+    }
+
+    #[test]
+    fn test_encoding_of_newer_models() {
+        let tokenizer = Tiktoken::new("o3-mini").unwrap();
+        assert_eq!(tokenizer.encoding(), Encoding::O200kBase);
+        assert_eq!(tokenizer.vocabulary().tokens.len(), 199_998);
+    }
+
+    #[test]
+    fn test_encoding_names() {
+        let tokenizer = Tiktoken::new("cl100k_base").unwrap();
+        assert_eq!(tokenizer.encoding(), Encoding::Cl100kBase);
+        let tokenizer = Tiktoken::new("O200K_BASE").unwrap();
+        assert_eq!(tokenizer.encoding(), Encoding::O200kBase);
+        let (tokens, _, _) = tokenizer.encode("Hello, world!");
+        assert_eq!(tokens, Tiktoken::new("gpt-4o").unwrap().encode("Hello, world!").0);
+    }
+
+    #[test]
+    fn test_encodings_are_parsed_once() {
+        let first = Tiktoken::new("gpt-4o").unwrap();
+        let second = Tiktoken::with_encoding(Encoding::O200kBase).unwrap();
+        assert!(Arc::ptr_eq(&first.bpe, &second.bpe));
+        assert!(!Arc::ptr_eq(&first.bpe, &Tiktoken::new("gpt-4").unwrap().bpe));
+    }
+
     #[test]
     fn test_invalid_model() {
         let tokenizer = Tiktoken::new("invalid-model");
@@ -64,4 +646,17 @@ mod tests {
             Err(TokenizerError::ModelLoadError(_))
         ));
     }
+
+    #[test]
+    #[cfg(feature = "embedded-o200k")]
+    fn test_embedded_o200k_base() {
+        let embedded = embedded::o200k_base().unwrap();
+        let bundled = tiktoken_rs::o200k_base().unwrap();
+        for text in [WARMUP_TEXT, "<|endoftext|>", ""] {
+            assert_eq!(
+                embedded.encode_with_special_tokens(text),
+                bundled.encode_with_special_tokens(text)
+            );
+        }
+    }
 }
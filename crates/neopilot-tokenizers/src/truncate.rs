@@ -0,0 +1,246 @@
+//! Truncation of text to a token budget
+//!
+//! Cutting text by a character estimate either wastes budget or overshoots
+//! it. Here the cut is placed on a real token boundary of the loaded
+//! tokenizer, moved to the nearest character boundary, and the kept text is
+//! re-encoded to make sure it fits: merges across the cut can change the
+//! count of the last token.
+//!
+//! [`TruncateStrategy::Middle`] keeps both ends of the text and replaces the
+//! middle with a marker, the usual way to fit a large file into a prompt.
+
+use crate::error::Result;
+
+/// Marker inserted in place of the text elided by [`TruncateStrategy::Middle`]
+pub const DEFAULT_MARKER: &str = "\n…\n";
+
+/// Which part of the text is kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TruncateStrategy {
+    /// Keep the beginning of the text
+    #[default]
+    Prefix,
+    /// Keep the end of the text
+    Suffix,
+    /// Keep both ends of the text and elide the middle with a marker
+    Middle,
+}
+
+impl std::str::FromStr for TruncateStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "prefix" => Ok(Self::Prefix),
+            "suffix" => Ok(Self::Suffix),
+            "middle" => Ok(Self::Middle),
+            _ => Err(format!(
+                "Unknown truncation strategy '{s}', expected prefix, suffix or middle"
+            )),
+        }
+    }
+}
+
+/// Result of truncating text to a token budget
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Truncation {
+    pub text: String,
+    /// Number of tokens in `text`
+    pub num_tokens: usize,
+    /// Whether anything was cut
+    pub truncated: bool,
+}
+
+/// Largest character boundary of `text` at or before byte `index`
+pub(crate) fn floor_char_boundary(text: &str, index: usize) -> usize {
+    let mut index = index.min(text.len());
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+/// Smallest character boundary of `text` at or after byte `index`
+pub(crate) fn ceil_char_boundary(text: &str, index: usize) -> usize {
+    let mut index = index.min(text.len());
+    while !text.is_char_boundary(index) {
+        index += 1;
+    }
+    index
+}
+
+/// Largest prefix or suffix of `text`, whose token spans are `spans`, that
+/// fits in `max_tokens`; returns the kept text and its token count
+fn fit<'a, F>(
+    text: &'a str,
+    spans: &[(usize, usize)],
+    max_tokens: usize,
+    from_start: bool,
+    encode: &F,
+) -> Result<(&'a str, usize)>
+where
+    F: Fn(&str) -> Result<(Vec<u32>, Vec<(usize, usize)>)>,
+{
+    let mut keep = max_tokens.min(spans.len());
+    while keep > 0 {
+        let candidate = if from_start {
+            let end = spans[..keep].iter().map(|span| span.1).max().unwrap_or(0);
+            &text[..floor_char_boundary(text, end)]
+        } else {
+            let kept = &spans[spans.len() - keep..];
+            let start = kept.iter().map(|span| span.0).min().unwrap_or(text.len());
+            &text[ceil_char_boundary(text, start)..]
+        };
+        let num_tokens = encode(candidate)?.0.len();
+        if num_tokens <= max_tokens {
+            return Ok((candidate, num_tokens));
+        }
+        keep -= (num_tokens - max_tokens).min(keep);
+    }
+    Ok(("", 0))
+}
+
+/// Head and tail of `text` joined by `marker`, within `max_tokens`
+///
+/// The budget left after the marker is split evenly between head and tail.
+/// Falls back to a plain prefix when the marker alone does not fit.
+fn fit_middle<F>(
+    text: &str,
+    spans: &[(usize, usize)],
+    max_tokens: usize,
+    marker: &str,
+    encode: &F,
+) -> Result<(String, usize)>
+where
+    F: Fn(&str) -> Result<(Vec<u32>, Vec<(usize, usize)>)>,
+{
+    let marker_tokens = encode(marker)?.0.len();
+    let mut budget = max_tokens.saturating_sub(marker_tokens);
+    while budget > 0 {
+        let (head, _) = fit(text, spans, budget - budget / 2, true, encode)?;
+        let (tail, _) = fit(text, spans, budget / 2, false, encode)?;
+        let joined = format!("{head}{marker}{tail}");
+        // Tokens can merge across the marker, so count the joined text again
+        let num_tokens = encode(&joined)?.0.len();
+        if num_tokens <= max_tokens {
+            return Ok((joined, num_tokens));
+        }
+        budget -= (num_tokens - max_tokens).min(budget);
+    }
+    let (head, num_tokens) = fit(text, spans, max_tokens, true, encode)?;
+    Ok((head.to_string(), num_tokens))
+}
+
+/// Largest part of `text` selected by `strategy` that fits in `max_tokens`
+///
+/// `encode` returns the tokens of a text and the byte span of each token.
+/// `marker` is only used by [`TruncateStrategy::Middle`].
+pub(crate) fn truncate<F>(
+    text: &str,
+    max_tokens: usize,
+    strategy: TruncateStrategy,
+    marker: &str,
+    encode: F,
+) -> Result<Truncation>
+where
+    F: Fn(&str) -> Result<(Vec<u32>, Vec<(usize, usize)>)>,
+{
+    let (tokens, spans) = encode(text)?;
+    if tokens.len() <= max_tokens {
+        return Ok(Truncation {
+            text: text.to_string(),
+            num_tokens: tokens.len(),
+            truncated: false,
+        });
+    }
+
+    let (text, num_tokens) = match strategy {
+        TruncateStrategy::Prefix => {
+            let (head, num_tokens) = fit(text, &spans, max_tokens, true, &encode)?;
+            (head.to_string(), num_tokens)
+        }
+        TruncateStrategy::Suffix => {
+            let (tail, num_tokens) = fit(text, &spans, max_tokens, false, &encode)?;
+            (tail.to_string(), num_tokens)
+        }
+        TruncateStrategy::Middle => fit_middle(text, &spans, max_tokens, marker, &encode)?,
+    };
+    Ok(Truncation {
+        text,
+        num_tokens,
+        truncated: true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One token per byte, like a byte-level BPE without merges
+    fn encode_bytes(text: &str) -> Result<(Vec<u32>, Vec<(usize, usize)>)> {
+        let tokens = text.bytes().map(u32::from).collect();
+        let spans = (0..text.len()).map(|i| (i, i + 1)).collect();
+        Ok((tokens, spans))
+    }
+
+    #[test]
+    fn test_parse_strategy() {
+        assert_eq!("prefix".parse(), Ok(TruncateStrategy::Prefix));
+        assert_eq!("suffix".parse(), Ok(TruncateStrategy::Suffix));
+        assert_eq!("middle".parse(), Ok(TruncateStrategy::Middle));
+        assert!("start".parse::<TruncateStrategy>().is_err());
+    }
+
+    #[test]
+    fn test_fits_without_truncation() -> Result<()> {
+        let result = truncate("hello", 5, TruncateStrategy::Prefix, "", encode_bytes)?;
+        assert_eq!((result.text.as_str(), result.num_tokens), ("hello", 5));
+        assert!(!result.truncated);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefix_and_suffix() -> Result<()> {
+        let result = truncate("hello world", 5, TruncateStrategy::Prefix, "", encode_bytes)?;
+        assert_eq!(result.text, "hello");
+        assert!(result.truncated);
+
+        let result = truncate("hello world", 5, TruncateStrategy::Suffix, "", encode_bytes)?;
+        assert_eq!(result.text, "world");
+
+        let result = truncate("hello", 0, TruncateStrategy::Suffix, "", encode_bytes)?;
+        assert_eq!((result.text.as_str(), result.num_tokens), ("", 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_never_splits_characters() -> Result<()> {
+        // '€' is three byte-level tokens; a cut inside it drops the whole character
+        let result = truncate("a€b", 3, TruncateStrategy::Prefix, "", encode_bytes)?;
+        assert_eq!((result.text.as_str(), result.num_tokens), ("a", 1));
+
+        let result = truncate("a€b", 3, TruncateStrategy::Suffix, "", encode_bytes)?;
+        assert_eq!((result.text.as_str(), result.num_tokens), ("b", 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_middle_keeps_both_ends() -> Result<()> {
+        let text = "head-0123456789-tail";
+        let result = truncate(text, 11, TruncateStrategy::Middle, "...", encode_bytes)?;
+        assert_eq!(result.text, "head...tail");
+        assert_eq!(result.num_tokens, 11);
+
+        // An odd budget favours the head
+        let result = truncate(text, 12, TruncateStrategy::Middle, "...", encode_bytes)?;
+        assert_eq!(result.text, "head-...tail");
+        Ok(())
+    }
+
+    #[test]
+    fn test_middle_without_room_for_marker() -> Result<()> {
+        let result = truncate("hello world", 2, TruncateStrategy::Middle, "...", encode_bytes)?;
+        assert_eq!(result.text, "he");
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+//! Export of the loaded vocabulary for debugging
+//!
+//! Dumping the vocabulary (and BPE merges, where the tokenizer has them) lets
+//! users diff two tokenizers or find out why a string costs more tokens than
+//! expected. Tiktoken encodings have no merge list; their token IDs are the
+//! merge ranks.
+
+use std::fmt::Write as _;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::error::{Result, TokenizerError};
+
+/// File format for [`write_vocab`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VocabFormat {
+    #[default]
+    Json,
+    /// One `token` or `merge` row per line, tab separated
+    Tsv,
+}
+
+impl std::str::FromStr for VocabFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "tsv" => Ok(Self::Tsv),
+            _ => Err(format!("Unknown vocabulary format '{s}', expected json or tsv")),
+        }
+    }
+}
+
+/// A vocabulary entry
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct VocabToken {
+    pub id: u32,
+    /// Token text; bytes that are not valid UTF-8 are written as `<0xNN>`
+    pub token: String,
+}
+
+/// Vocabulary and merges of a tokenizer, sorted by token ID
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Vocabulary {
+    pub tokens: Vec<VocabToken>,
+    /// BPE merges in priority order, empty for tokenizers without a merge list
+    pub merges: Vec<(String, String)>,
+}
+
+/// Readable form of raw token bytes
+pub fn token_text(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in bytes.utf8_chunks() {
+        text.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            let _ = write!(text, "<0x{byte:02X}>");
+        }
+    }
+    text
+}
+
+/// Escape tabs, newlines and backslashes so a value fits in one TSV cell
+fn escape_tsv(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Render `vocab` in `format`
+pub fn to_string(vocab: &Vocabulary, format: VocabFormat) -> Result<String> {
+    match format {
+        VocabFormat::Json => Ok(serde_json::to_string_pretty(vocab)?),
+        VocabFormat::Tsv => {
+            let mut out = String::new();
+            for token in &vocab.tokens {
+                let _ = writeln!(out, "token\t{}\t{}", token.id, escape_tsv(&token.token));
+            }
+            for (rank, (left, right)) in vocab.merges.iter().enumerate() {
+                let _ = writeln!(out, "merge\t{rank}\t{}\t{}", escape_tsv(left), escape_tsv(right));
+            }
+            Ok(out)
+        }
+    }
+}
+
+/// Write `vocab` to `path` in `format`
+pub fn write_vocab(vocab: &Vocabulary, path: &Path, format: VocabFormat) -> Result<()> {
+    let contents = to_string(vocab, format)?;
+    std::fs::write(path, contents).map_err(TokenizerError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Vocabulary {
+        Vocabulary {
+            tokens: vec![
+                VocabToken {
+                    id: 0,
+                    token: "a\tb".to_string(),
+                },
+                VocabToken {
+                    id: 1,
+                    token: token_text(&[b'x', 0xE2, 0x82]),
+                },
+            ],
+            merges: vec![("a".to_string(), "b".to_string())],
+        }
+    }
+
+    #[test]
+    fn test_token_text() {
+        assert_eq!(token_text("€".as_bytes()), "€");
+        assert_eq!(token_text(&[b'x', 0xE2, 0x82]), "x<0xE2><0x82>");
+    }
+
+    #[test]
+    fn test_tsv() {
+        let tsv = to_string(&sample(), VocabFormat::Tsv).unwrap();
+        assert_eq!(tsv, "token\t0\ta\\tb\ntoken\t1\tx<0xE2><0x82>\nmerge\t0\ta\tb\n");
+    }
+
+    #[test]
+    fn test_write_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vocab.json");
+        write_vocab(&sample(), &path, VocabFormat::Json).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["tokens"][1]["token"], "x<0xE2><0x82>");
+        assert_eq!(json["merges"][0][1], "b");
+    }
+}
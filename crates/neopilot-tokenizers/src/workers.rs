@@ -0,0 +1,245 @@
+//! Encoders of worker threads
+//!
+//! Batch and directory counts encode on many threads at once. Tiktoken
+//! encoders are made for that: their ranks are immutable and shared by all
+//! threads. A Hugging Face tokenizer is not, see
+//! [`crate::huggingface::HuggingFaceTokenizer::worker_copy`], so every worker encodes with its
+//! own copy, made the first time it needs one. The copies are kept in
+//! [`crate::State`] for the next batch with the same tokenizer; none of this
+//! goes through the state once the batch started. So is the thread pool of
+//! batches with a fixed number of threads, since starting threads costs more
+//! than encoding a small batch. Preloading tokenizers in the background is
+//! bounded by [`LoadSlots`], so it does not compete with the foreground for
+//! every core and download slot.
+
+use std::sync::{Arc, Condvar, Mutex, OnceLock, PoisonError};
+
+use rayon::ThreadPool;
+
+use crate::error::{Result, TokenizerError};
+use crate::TokenizerType;
+
+/// The tokenizer of a batch, with the copies of its workers
+pub(crate) struct WorkerEncoders {
+    shared: Arc<TokenizerType>,
+    /// Copies by rayon worker index, for tokenizers that are not shared
+    copies: Vec<OnceLock<TokenizerType>>,
+}
+
+impl WorkerEncoders {
+    /// Encoders for the `workers` threads of the rayon pool the batch runs on
+    pub(crate) fn new(tokenizer: Arc<TokenizerType>, workers: usize) -> Self {
+        let copies = match tokenizer.as_ref() {
+            TokenizerType::HuggingFace(_) => (0..workers).map(|_| OnceLock::new()).collect(),
+            _ => Vec::new(),
+        };
+        Self {
+            shared: tokenizer,
+            copies,
+        }
+    }
+
+    /// Encoders like [`WorkerEncoders::new`], reusing the copies `cache`
+    /// holds for `tokenizer` when there are enough of them
+    pub(crate) fn cached(
+        cache: &WorkerCache,
+        tokenizer: Arc<TokenizerType>,
+        workers: usize,
+    ) -> Arc<Self> {
+        if !matches!(tokenizer.as_ref(), TokenizerType::HuggingFace(_)) {
+            return Arc::new(Self::new(tokenizer, workers));
+        }
+        // A poisoned lock only means another thread panicked; the slot is still valid
+        let mut cached = cache.0.lock().unwrap_or_else(PoisonError::into_inner);
+        match cached.as_ref() {
+            Some(encoders)
+                if Arc::ptr_eq(&encoders.shared, &tokenizer) && encoders.copies.len() >= workers =>
+            {
+                Arc::clone(encoders)
+            },
+            _ => {
+                let encoders = Arc::new(Self::new(tokenizer, workers));
+                *cached = Some(Arc::clone(&encoders));
+                encoders
+            },
+        }
+    }
+
+    /// The encoder of the calling thread
+    ///
+    /// Threads outside the pool use the shared tokenizer.
+    pub(crate) fn get(&self) -> &TokenizerType {
+        let copy = rayon::current_thread_index().and_then(|index| self.copies.get(index));
+        match (copy, self.shared.as_ref()) {
+            (Some(copy), TokenizerType::HuggingFace(tokenizer)) => {
+                copy.get_or_init(|| TokenizerType::HuggingFace(Box::new(tokenizer.worker_copy())))
+            },
+            _ => &self.shared,
+        }
+    }
+}
+
+/// The worker copies of the last batch with a Hugging Face tokenizer
+#[derive(Default)]
+pub(crate) struct WorkerCache(Mutex<Option<Arc<WorkerEncoders>>>);
+
+impl WorkerCache {
+    /// Drop the copies, e.g. once their tokenizer is unloaded
+    pub(crate) fn clear(&self) {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).take();
+    }
+}
+
+/// The thread pool of the last batch with a fixed number of threads
+#[derive(Default)]
+pub(crate) struct PoolCache(Mutex<Option<Arc<ThreadPool>>>);
+
+impl PoolCache {
+    /// A pool of `threads` threads, reusing the cached one if it has as many
+    pub(crate) fn get(&self, threads: usize) -> Result<Arc<ThreadPool>> {
+        let mut cached = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(pool) = cached.as_ref().filter(|pool| pool.current_num_threads() == threads) {
+            return Ok(Arc::clone(pool));
+        }
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().map_err(|e| {
+            TokenizerError::TokenizerError(format!("Failed to start thread pool: {e}"))
+        })?;
+        let pool = Arc::new(pool);
+        *cached = Some(Arc::clone(&pool));
+        Ok(pool)
+    }
+
+    /// The cached pool whatever its size, or a new one of `threads` threads
+    pub(crate) fn any(&self, threads: usize) -> Result<Arc<ThreadPool>> {
+        let cached = self.0.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        match cached {
+            Some(pool) => Ok(pool),
+            None => self.get(threads),
+        }
+    }
+}
+
+/// Bounds how many background loads run at once, across calls
+#[derive(Default)]
+pub(crate) struct LoadSlots {
+    busy: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// A taken slot of [`LoadSlots`], freed when dropped
+pub(crate) struct LoadSlot<'a>(&'a LoadSlots);
+
+impl LoadSlots {
+    /// Wait until fewer than `max` slots are taken and take one
+    pub(crate) fn acquire(&self, max: usize) -> LoadSlot<'_> {
+        let busy = self.busy.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut busy = self
+            .freed
+            .wait_while(busy, |busy| *busy >= max.max(1))
+            .unwrap_or_else(PoisonError::into_inner);
+        *busy += 1;
+        LoadSlot(self)
+    }
+}
+
+impl Drop for LoadSlot<'_> {
+    fn drop(&mut self) {
+        *self.0.busy.lock().unwrap_or_else(PoisonError::into_inner) -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::huggingface::HuggingFaceTokenizer;
+    use crate::tiktoken::Tiktoken;
+    use rayon::prelude::*;
+
+    fn word_level() -> Arc<TokenizerType> {
+        let json = serde_json::json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [],
+            "normalizer": null,
+            "pre_tokenizer": {"type": "Whitespace"},
+            "post_processor": null,
+            "decoder": null,
+            "model": {
+                "type": "WordLevel",
+                "vocab": {"[UNK]": 0, "hello": 1, "world": 2},
+                "unk_token": "[UNK]",
+            },
+        });
+        let tokenizer = HuggingFaceTokenizer::from_json(&json.to_string()).unwrap();
+        Arc::new(TokenizerType::HuggingFace(Box::new(tokenizer)))
+    }
+
+    #[test]
+    fn test_workers_use_their_own_copy() {
+        let encoders = WorkerEncoders::new(word_level(), 2);
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let texts = vec!["hello world".to_string(); 64];
+        let results: Vec<_> = pool.install(|| {
+            texts
+                .par_iter()
+                .map(|text| {
+                    let encoder = encoders.get();
+                    assert!(!std::ptr::eq(encoder, encoders.shared.as_ref()));
+                    encoder.encode(text).unwrap().0
+                })
+                .collect()
+        });
+        assert!(results.iter().all(|tokens| tokens == &[1, 2]));
+        assert!(encoders.copies.iter().any(|copy| copy.get().is_some()));
+        // Outside the pool the shared tokenizer encodes
+        assert!(std::ptr::eq(encoders.get(), encoders.shared.as_ref()));
+    }
+
+    #[test]
+    fn test_cached_copies() {
+        let cache = WorkerCache::default();
+        let tokenizer = word_level();
+        let first = WorkerEncoders::cached(&cache, Arc::clone(&tokenizer), 4);
+        assert!(Arc::ptr_eq(&first, &WorkerEncoders::cached(&cache, Arc::clone(&tokenizer), 2)));
+        // More workers or another tokenizer need new copies
+        let more = WorkerEncoders::cached(&cache, Arc::clone(&tokenizer), 8);
+        assert!(!Arc::ptr_eq(&first, &more));
+        assert!(!Arc::ptr_eq(&more, &WorkerEncoders::cached(&cache, word_level(), 8)));
+        cache.clear();
+        assert!(cache.0.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_cached_pool() {
+        let pools = PoolCache::default();
+        let pool = pools.get(2).unwrap();
+        assert_eq!(pool.current_num_threads(), 2);
+        assert!(Arc::ptr_eq(&pool, &pools.get(2).unwrap()));
+        assert_eq!(pools.get(3).unwrap().current_num_threads(), 3);
+    }
+
+    #[test]
+    fn test_load_slots() {
+        let slots = Arc::new(LoadSlots::default());
+        let first = slots.acquire(1);
+        let waiting = {
+            let slots = Arc::clone(&slots);
+            std::thread::spawn(move || drop(slots.acquire(1)))
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!waiting.is_finished());
+        drop(first);
+        waiting.join().unwrap();
+        assert_eq!(*slots.busy.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_tiktoken_is_shared() {
+        let tokenizer = Arc::new(TokenizerType::Tiktoken(Tiktoken::new("gpt-4o").unwrap()));
+        let encoders = WorkerEncoders::new(tokenizer, 4);
+        assert!(encoders.copies.is_empty());
+        assert!(std::ptr::eq(encoders.get(), encoders.shared.as_ref()));
+    }
+}
@@ -55,3 +55,27 @@ fn test_large_file_download() {
     // The actual error might vary, but we're checking that it doesn't panic
     assert!(result.is_err());
 }
+
+#[test]
+fn test_error_codes() {
+    let error = TokenizerError::InsecureProtocol("http://example.com".to_string());
+    assert_eq!(error.code(), neopilot_error::ErrorCode::Security);
+
+    let shared: neopilot_error::Error = TokenizerError::LockError("poisoned".to_string()).into();
+    assert_eq!(shared.code(), neopilot_error::ErrorCode::Lock);
+    assert_eq!(shared.to_string(), "Failed to acquire lock: poisoned");
+}
+
+#[test]
+fn test_error_identifiers_and_retryability() {
+    let insecure = TokenizerError::InsecureProtocol("http://example.com".to_string());
+    assert_eq!(insecure.name(), "insecure_protocol");
+    assert_eq!(insecure.number(), 1010);
+    assert!(!insecure.retryable());
+
+    assert!(TokenizerError::NetworkError("timed out".to_string()).retryable());
+    let server_error = TokenizerError::HttpStatus { url: "https://huggingface.co".to_string(), status: 502 };
+    assert!(server_error.retryable());
+    let not_found = TokenizerError::HttpStatus { url: "https://huggingface.co".to_string(), status: 404 };
+    assert!(!not_found.retryable());
+}
@@ -8,8 +8,149 @@ local filetype_map = {
   ["cs"] = "csharp",
 }
 
+---@class NeopilotPositionContext
+---@field language string
+---@field enclosing_kind string | nil
+---@field start_line integer 0-based
+---@field end_line integer 0-based, inclusive
+---@field source string
+---@field definitions { path: string, name: string, text: string }[]
+---@field tokens integer
+
+---@class NeopilotDefinitionFunc
+---@field name string
+---@field params? string
+---@field return_type? string
+---@field modifier? string visibility, e.g. "pub" or "export"
+
+---@class NeopilotDefinitionVariable
+---@field name string
+---@field value_type? string
+
+---A definition extracted for the repo map; `kind` says which fields it has
+---@class NeopilotDefinition
+---@field kind "func" | "class" | "module" | "enum" | "union" | "variable"
+---@field name string
+---@field params? string func
+---@field return_type? string func
+---@field modifier? string func, class and module visibility, e.g. "pub" or "export"
+---@field type_name? string class and module, e.g. "class", "module" or a Bazel rule
+---@field methods? NeopilotDefinitionFunc[] class and module
+---@field properties? NeopilotDefinitionVariable[] class and module
+---@field items? NeopilotDefinitionVariable[] enum and union
+---@field value_type? string variable
+
+---@class NeopilotReference
+---@field start_line integer 0-based
+---@field start_col integer 0-based, in bytes
+---@field end_line integer 0-based
+---@field end_col integer 0-based, in bytes, exclusive
+---@field start_byte integer
+---@field end_byte integer exclusive
+
+---@class NeopilotSymbolLocation
+---@field path string relative to the index root
+---@field language string
+---@field definition NeopilotDefinition
+---@field text string the definition as rendered in the repo map
+---@field start_line? integer 0-based; nil when the file no longer has the definition
+---@field end_line? integer 0-based, inclusive
+---@field snippet? string first lines of the definition's source
+
+---@class NeopilotRepoMapDiff
+---@field unchanged boolean
+---@field text string human-readable summary of the delta
+---@field added_files string[]
+---@field removed_files string[]
+---@field changed_files { path: string, added: string[], removed: string[], changed: string[] }[]
+---@field rank_changes { path: string, old_rank: integer, new_rank: integer }[]
+
+---@class NeopilotFunctionMetrics
+---@field name string
+---@field start_line integer 0-based
+---@field body_lines integer
+---@field branches integer conditionals, loops, match arms and short-circuit operators
+
+---@class NeopilotLanguageSupport
+---@field language string tree-sitter language name
+---@field classes boolean
+---@field functions boolean
+---@field methods boolean
+---@field enums boolean
+---@field unions boolean
+---@field variables boolean
+---@field modules boolean
+---@field imports boolean
+---@field docstrings boolean
+---@field targets boolean build targets and rules, e.g. in Bazel files
+
+---@class NeopilotScanOptions
+---@field sandboxed? boolean do not follow symlinks out of the root and cap bytes read
+---@field max_bytes? integer stop reading files after this many bytes
+---@field include_vendored? boolean also scan vendor/, third_party/, node_modules/ and similar (defaults to `repo_map.include_vendored`)
+
+---@class NeopilotScanTimings
+---@field parse_ms number
+---@field query_ms number running the definitions query and building the definitions
+---@field metrics_ms number
+---@field total_ms number
+
+---@class NeopilotScanProfile
+---@field languages table<string, NeopilotScanTimings | { files: integer, bytes: integer }>
+---@field slowest_files (NeopilotScanTimings | { path: string, language: string, bytes: integer })[] slowest first
+---@field text string both as a table, for :messages or a bug report
+
+---Event queued by a Rust module; fields besides `name` depend on the event
+---@class NeopilotEvent
+---@field name "scan_progress" | "config_reloaded" | "download_finished" | "cache_evicted"
+---@field phase? string scan_progress: "discovering", "parsing" or "done"
+---@field files_done? integer scan_progress: files parsed or skipped
+---@field files_total? integer scan_progress: files discovered so far
+---@field url? string download_finished
+---@field error? string download_finished: why the download failed
+---@field cache? string cache_evicted: which cache, e.g. "tokenizers"
+---@field entries? integer cache_evicted: entries dropped
+
+---Order of the files in the map; "dependencies" lists files before the files that use them
+---@alias NeopilotRepoMapOrder "rank" | "path" | "recent" | "dependencies"
+---Encoding a file was transcoded from before parsing
+---@alias NeopilotSourceEncoding "utf8" | "utf16le" | "utf16be" | "latin1"
+
 ---@class NeopilotRepoMap
----@field stringify_definitions fun(lang: string, source: string): string
+---@field stringify_definitions fun(lang: string, source: string, opts?: { max_output_bytes?: integer, transform?: fun(defs: NeopilotDefinition[], lang: string): NeopilotDefinition[] | nil }): string with max_output_bytes, cut at a definition boundary and ended by "… N more symbols omitted" (only this listing is capped, maps are bounded by the budget of render_repo_map); transform filters or rewrites the definitions before they are rendered and returns them, or nil to render the table it was given
+---@field export_list fun(lang: string, source: string): string[] names listed by `__all__`, `module.exports`, `export` statements or `defdelegate`, sorted; exported top-level functions are kept in the repo map and marked `export`
+---@field local_references fun(source: string, lang: string, position: { line: integer, col: integer }): NeopilotReference[] every identifier in the file with the same name as the one at the 0-based position (col in bytes), for rename and edit context; scopes are not resolved, empty if the position is not on an identifier
+---@field sexp fun(lang: string, source: string, opts?: { max_depth?: integer, max_len?: integer }): string syntax tree as an S-expression, for writing queries and bug reports
+---@field supported_languages fun(): NeopilotLanguageSupport[] symbol kinds extracted per language
+---@field health fun(): { ok: boolean, disabled_languages: { language: string, message: string }[] } languages disabled for the session because their grammar or query failed to load
+---@field profile fun(): NeopilotScanProfile time spent scanning since the session started or reset_profile, to find the grammar, query or files that make scans slow
+---@field reset_profile fun(): nil
+---@field disable_language fun(lang: string): nil skip files of lang for the rest of the session, e.g. when profile shows its grammar is too slow
+---@field init_logging fun(): nil
+---@field recent_logs fun(limit?: integer): { timestamp: string, level: string, target: string, message: string, trace_id?: string }[]
+---@field set_config fun(config: table | nil): nil use this configuration, e.g. `{ repo_map = { include_vendored = true } }`, instead of files and `NEOPILOT_` variables; unset keys take their defaults and nil goes back to loading
+---@field poll_events fun(): NeopilotEvent[] take the events queued by this module, oldest first; scans report each phase and every percent of files
+---@field on_event fun(name: string, callback: fun(event: NeopilotEvent)): fun(): boolean call callback from dispatch_events for events named name, or all events for "*"; returns a function that detaches it
+---@field dispatch_events fun(): integer take the queued events and call the attached callbacks, e.g. from a timer; returns how many events there were
+---@field traced fun(trace_id: string): NeopilotRepoMap the same functions, run with trace_id attached to logs and errors
+---@field scan_directory fun(root: string, opts?: NeopilotScanOptions): { path: string, lang: string, defs: string, encoding: NeopilotSourceEncoding }[]
+---@field start_scan fun(root: string, opts?: NeopilotScanOptions): nil
+---@field take_scan_result fun(): { path: string, lang: string, defs: string, encoding: NeopilotSourceEncoding }[] | nil
+---@field build_index fun(root: string, opts?: NeopilotScanOptions): integer
+---@field save_index fun(path: string): nil
+---@field load_index fun(path: string): integer
+---@field export_index fun(path: string): nil write the index as sorted JSON Lines, one line per file, to commit so teammates and CI skip the first scan
+---@field import_index fun(path: string, root: string): integer read an index written by export_index for the checkout at root, rescanning files changed since; returns the number of files
+---@field update_file fun(path: string): boolean rescan one changed file and update references and rankings incrementally; false if the file left the index
+---@field set_buffer_overlay fun(path: string, contents: string | nil): boolean use unsaved buffer contents instead of the file on disk for scans and context_for_position, and update the index if built; nil goes back to the file on disk. Returns whether the file is in the index
+---@field get_repo_map fun(focus_files?: string[], order?: NeopilotRepoMapOrder): { path: string, lang: string, defs: string, encoding: NeopilotSourceEncoding, score: number, focus: boolean, metrics?: NeopilotFunctionMetrics[] }[] metrics are included when `repo_map.include_metrics` is set, private definitions of the focus files when `repo_map.include_private_in_focus` is
+---@field get_repo_map_encoded fun(format: "json" | "msgpack" | "cbor", focus_files?: string[], order?: NeopilotRepoMapOrder): string
+---@field diff_index fun(path: string): NeopilotRepoMapDiff compare a saved index with the current one
+---@field update_token_costs fun(fingerprint?: string, count?: fun(text: string): integer): integer cache the token cost of every definition, returns how many were counted
+---@field render_repo_map fun(budget_tokens: integer, focus_files?: string[], summarize?: fun(path: string, defs: string, names: string[]): string | nil, order?: NeopilotRepoMapOrder): { files: { path: string, lang: string, defs: string, summarized: boolean, score: number, focus: boolean, tokens: integer }[], tokens: integer, omitted: integer } focus files list their private definitions too when `repo_map.include_private_in_focus` is set
+---@field lookup fun(qualified_name: string): NeopilotSymbolLocation | nil definition of a symbol in the index, e.g. "Engine" or the method "Engine::new" / "Engine.new", for @mention completion; the highest ranked file wins when several define it
+---@field context_for_position fun(path: string, line: integer, col: integer, budget_tokens: integer): NeopilotPositionContext
+---@field scan_progress fun(): { phase: string, files_discovered: integer, files_parsed: integer, files_skipped: integer, bytes_total: integer, bytes_processed: integer, percent: number }
 local repo_map_lib = nil
 
 local RepoMap = {}
@@ -18,7 +159,7 @@ local RepoMap = {}
 function RepoMap._init_repo_map_lib()
   if repo_map_lib ~= nil then return repo_map_lib end
 
-  local ok, core = pcall(require, "neopilot_repo_map")
+  local ok, core = require("neopilot_lib").require("repo_map")
   if not ok then return nil end
 
   repo_map_lib = core
@@ -1,31 +1,211 @@
 local Utils = require("neopilot.utils")
 
+---@class NeopilotStreamDecoder
+---@field push fun(self: NeopilotStreamDecoder, token: integer): string | nil text completed by this token
+---@field extend fun(self: NeopilotStreamDecoder, tokens: integer[]): string | nil text completed by a streamed chunk of tokens
+---@field finish fun(self: NeopilotStreamDecoder): string | nil remaining buffered text; the decoder can then be reused
+---@field has_pending fun(self: NeopilotStreamDecoder): boolean whether tokens wait for the rest of a character
+
+---@class NeopilotTokenizedBuffer
+---@field edit fun(self: NeopilotTokenizedBuffer, start_byte: integer, old_end_byte: integer, new_text: string): integer replace the 0-based byte range start_byte..old_end_byte, as reported by `on_bytes`, and return the new token count; only the lines around the edit are encoded again
+---@field set_text fun(self: NeopilotTokenizedBuffer, text: string): nil replace the whole text
+---@field tokens fun(self: NeopilotTokenizedBuffer): integer[]
+---@field num_tokens fun(self: NeopilotTokenizedBuffer): integer
+
+---@class NeopilotIncrementalEncoder
+---@field append fun(self: NeopilotIncrementalEncoder, text: string): integer append text and return the token count of everything appended; only the text after the last line start is encoded again
+---@field num_tokens fun(self: NeopilotIncrementalEncoder): integer
+---@field num_chars fun(self: NeopilotIncrementalEncoder): integer
+---@field reset fun(self: NeopilotIncrementalEncoder): nil
+
+---@class NeopilotTokenBudget
+---@field try_reserve fun(self: NeopilotTokenBudget, text: string): boolean, integer | nil reserve the tokens of text if they fit, returning its token count; nothing is reserved otherwise, so offer pieces in priority order
+---@field try_reserve_tokens fun(self: NeopilotTokenBudget, tokens: integer): boolean reserve tokens counted elsewhere (chat framing, images) if they fit
+---@field release fun(self: NeopilotTokenBudget, tokens: integer): nil give back tokens of a dropped piece
+---@field remaining fun(self: NeopilotTokenBudget): integer
+---@field used fun(self: NeopilotTokenBudget): integer
+---@field max_tokens fun(self: NeopilotTokenBudget): integer
+
+---@class NeopilotTokenizerError
+---@field number integer stable number of the error, e.g. 1023 for an input over max_input_bytes
+---@field name string e.g. "input_too_large"
+---@field message string
+
+---@class NeopilotTokenizerHandle
+---@field encode fun(self: NeopilotTokenizerHandle, text: string, special_tokens?: boolean | { allowed_special?: "all" | string[], disallowed_special?: "all" | string[] }): integer[], integer, integer
+---@field decode fun(self: NeopilotTokenizerHandle, tokens: integer[], skip_special_tokens?: boolean): string
+---@field count fun(self: NeopilotTokenizerHandle, text: string): integer
+---@field token_to_id fun(self: NeopilotTokenizerHandle, token: string): integer | nil
+---@field id_to_token fun(self: NeopilotTokenizerHandle, id: integer): string | nil
+---@field decode_stream fun(self: NeopilotTokenizerHandle, skip_special_tokens?: boolean): NeopilotStreamDecoder
+---@field budget fun(self: NeopilotTokenizerHandle, max_tokens: integer): NeopilotTokenBudget
+---@field incremental_encoder fun(self: NeopilotTokenizerHandle): NeopilotIncrementalEncoder
+---@field buffer fun(self: NeopilotTokenizerHandle, text: string): NeopilotTokenizedBuffer
+
+---@class NeopilotEncodeTiming
+---@field backend "tiktoken" | "huggingface" | "anthropic"
+---@field elapsed_ms number
+---@field tokens_per_sec number
+
+---A token as 0-based buffer positions with byte columns, as taken by extmarks; the end is exclusive
+---@class NeopilotTokenRange
+---@field token integer
+---@field start_row integer
+---@field start_col integer
+---@field end_row integer
+---@field end_col integer
+
+---@class NeopilotEncodeStats
+---@field calls integer
+---@field tokens integer
+---@field bytes integer
+---@field elapsed_ms number
+---@field tokens_per_sec number
+
 ---@class NeopilotTokenizer
----@field from_pretrained fun(model: string): nil
----@field encode fun(string): integer[]
+---@field from_pretrained fun(model: string): NeopilotTokenizerHandle make model the current tokenizer and return a handle to it; model can also be a tiktoken encoding such as "cl100k_base" or "o200k_base", a Hugging Face repository such as "meta-llama/Llama-3.1-8B" (optionally "@revision"), or the path of a GGUF model file (llama.cpp, ollama) to use its embedded tokenizer
+---@field load fun(model: string): NeopilotTokenizerHandle a handle with its own encode/decode; the current tokenizer is left unchanged
+---@field from_json fun(json: string): NeopilotTokenizerHandle a handle for the tokenizer described by the contents of a tokenizer.json file, without touching the filesystem; the current tokenizer is left unchanged
+---@field set_encoding fun(pattern: string, encoding: "o200k_base" | "cl100k_base" | "p50k_base" | "p50k_edit" | "r50k_base"): nil count models matching pattern (a name, or a prefix ending in "*") with a tiktoken encoding
+---@field set_network_enabled fun(enabled: boolean): nil mirror network.enabled; while false, tokenizers are only loaded from disk or the download cache
+---@field set_hf_token fun(token: string | nil): nil Hugging Face access token for gated repositories such as Llama, only sent to Hugging Face; defaults to HF_TOKEN
+---@field set_max_input_bytes fun(max_bytes: integer): nil encoding larger texts raises an "Input too large" error instead of taking seconds (default 10 MiB, 0 disables the limit)
+---@field set_cross_check fun(enabled: boolean): nil compare batch and tokenized buffer counts with encoding each text whole, logging any disagreement as an error; on by default in debug builds
+---@field check_consistency fun(text: string): { path: "per_line" | "buffer" | "batch" | "parts" | "chat", expected: integer, actual: integer }[] count text with the current tokenizer through every counting path; lists the paths disagreeing with encode, empty when all agree
+---@field set_config fun(config: { tokenizer?: { max_input_bytes?: integer }, network?: { enabled?: boolean, hf_token?: string, user_agent?: string, max_retries?: integer, headers?: table<string, string>, mirrors?: table<string, string[]> } } | nil): nil use these settings instead of `NEOPILOT_` variables, as repo_map.set_config does; nil reads the environment again
+---@field preload fun(models: string[]): nil load tokenizers as background tasks of the scheduler, at most 8 per call and 2 at a time; models already loaded are skipped
+---@field register fun(name: string, model: string): nil keep the tokenizer for model under name, next to the current one
+---@field current_model fun(): { model: string, backend: "tiktoken" | "huggingface" | "anthropic", encoding?: string } | nil the current tokenizer, nil before from_pretrained and after unload
+---@field is_loaded fun(model?: string): boolean whether a tokenizer is current, or with model, whether it is the one loaded for model
+---@field list_supported_models fun(): { models: string[], encodings: string[], cached: string[] } names for model completion: OpenAI models with a built-in encoding and exact set_encoding names that resolve, tiktoken encodings, and Hugging Face repositories downloaded before
+---@field from_pretrained_async fun(model: string, callback: NeopilotTokenizerLoadCallback) like from_pretrained as a background task; callback runs from poll_loads once done, with current false if from_pretrained or unload was called meanwhile
+---@field warmup fun(encodings?: string[]): nil parse the given tiktoken encodings, e.g. "o200k_base", and run a first encode with them and the current tokenizer as a background task
+---@field poll_loads fun(): integer call the callbacks of finished from_pretrained_async loads; returns how many are still loading, or raises the errors of failed callbacks once all were called
+---@field unload fun(): boolean drop the current tokenizer and the cached loads to free their memory; registered tokenizers and handles keep theirs. Returns whether a tokenizer was current
+---@field unregister fun(name: string): boolean
+---@field registered fun(): string[] names of the registered tokenizers
+---@field encode_with fun(name: string, text: string): integer[], integer, integer encode with a registered tokenizer
+---@field decode_with fun(name: string, tokens: integer[], skip_special_tokens?: boolean): string decode with a registered tokenizer
+---@field encode fun(text: string, with_offsets?: boolean | "char" | "byte", special_tokens?: boolean | { allowed_special?: "all" | string[], disallowed_special?: "all" | string[] }, with_timing?: boolean): integer[], integer, integer, integer[][] | nil, NeopilotEncodeTiming | nil tokens, num_tokens, num_chars, the 0-based { start, end } span of each token (true means "char"; use "byte" for extmarks) and, with with_timing, how long the call took; with special_tokens = false, strings like "<|endoftext|>" are encoded as ordinary text; a table applies tiktoken rules: allowed strings are special, disallowed ones (all by default) raise an error, others are encoded as text
+---@field try_encode fun(text: string): integer[] | nil, integer | NeopilotTokenizerError, integer | nil like encode, but a failure returns nil and the error instead of raising it
+---@field token_ranges fun(text: string, special_tokens?: boolean): NeopilotTokenRange[] the range of every token of text, the buffer lines joined with "\n", e.g. to highlight where tokens split; a token covering part of a multi-byte character covers all of it
+---@field stats fun(): NeopilotEncodeStats | { by_backend: table<string, NeopilotEncodeStats> } totals of the encodes with the current tokenizer since the library loaded or reset_stats, to find out why counting is slow
+---@field reset_stats fun(): nil
+---@field encode_batch fun(texts: string[], worker_threads?: integer): { tokens: integer[], num_tokens: integer, num_chars: integer }[]
+---@field decode fun(tokens: integer[], skip_special_tokens?: boolean): string with skip_special_tokens, special tokens such as "<|endoftext|>" or "<|im_end|>" are left out, e.g. for completions shown to the user
+---@field token_to_id fun(token: string): integer | nil id of the single token written as token in the vocabulary (raw entries such as "Ġhello" for Hugging Face tokenizers, text with <0xNN> for partial bytes for tiktoken), nil if no token is
+---@field id_to_token fun(id: integer): string | nil vocabulary entry of a token id, e.g. to check what a stop token decodes to; nil for unused ids
+---@field decode_stream fun(skip_special_tokens?: boolean): NeopilotStreamDecoder decode a streamed completion with the current tokenizer, which the decoder keeps even if the model changes; with skip_special_tokens, markers like "<|im_end|>" are left out
+---@field stream_decoder fun(skip_special_tokens?: boolean): NeopilotStreamDecoder older name of decode_stream
+---@field buffer fun(text: string): NeopilotTokenizedBuffer tokens of a buffer kept up to date edit by edit, with the current tokenizer
+---@field incremental_encoder fun(): NeopilotIncrementalEncoder running token count of text appended in fragments, e.g. a growing buffer, with the current tokenizer
+---@field budget fun(max_tokens: integer): NeopilotTokenBudget a budget counted with the current tokenizer, which it keeps even if the model changes
+---@field count_files fun(paths: string[]): table<string, { encoding?: "utf8" | "utf16le" | "utf16be" | "latin1" | "binary", num_tokens?: integer, num_chars?: integer, lossy?: boolean, estimated?: boolean, error?: string }> token counts keyed by path
+---@field export_vocab fun(path: string, format?: "json" | "tsv"): nil dump the vocabulary and merges of the loaded tokenizer
+---@field poll_events fun(): NeopilotEvent[] take the events queued by this module (download_finished, cache_evicted), oldest first
+---@field on_event fun(name: string, callback: fun(event: NeopilotEvent)): fun(): boolean call callback from dispatch_events for events named name, or all events for "*"; returns a function that detaches it
+---@field dispatch_events fun(): integer take the queued events and call the attached callbacks; returns how many events there were
+---@field traced fun(trace_id: string): NeopilotTokenizer the same functions, run with trace_id attached to logs and errors
+---@field detect_family fun(model: string): { family: "openai" | "llama" | "mistral" | "qwen" | "cohere" | "gemma" | "anthropic" | "unknown", source: "tiktoken" | "huggingface" | "anthropic", location?: string } Claude models use an approximation that estimates counts from cl100k_base
+---@field encode_lossy fun(text: string, mode?: "encode" | "count_as_one_token"): { tokens: integer[], num_tokens: integer, num_chars: integer, replacement_positions: integer[] }
+---@field encode_guarded fun(text: string, max_line_len?: integer): { tokens: integer[], num_tokens: integer, num_chars: integer, estimated: boolean, estimated_lines: integer[] } lines longer than max_line_len bytes (default 65536, 0 encodes everything) are estimated
+---@field truncate fun(text: string, max_tokens: integer, strategy?: "prefix" | "suffix" | "middle", marker?: string): { text: string, num_tokens: integer, truncated: boolean } largest prefix (default) or suffix within max_tokens, cut on token boundaries; "middle" keeps both ends around marker (default "\n…\n")
+---@field chunk fun(text: string, max_tokens: integer, overlap_tokens?: integer): { text: string, start: integer, ["end"]: integer, token_start: integer, token_end: integer, num_tokens: integer }[] overlapping chunks of max_tokens tokens; start/end are 0-based character offsets, end exclusive
+---@field tokens_for_words fun(words: string[]): { word: string, tokens: integer[], spaced_tokens: integer[], ids: integer[], single_token: boolean }[] token IDs of each word alone and after a space, for logit_bias maps; ids are both variants, deduplicated
+---@field analyze_stop_sequences fun(sequences: string[]): { sequence: string, tokens: integer[], reliable: boolean, warnings: string[] }[] how each stop sequence tokenizes; warnings name the text around it that a token merges with, which token-level stop matching can miss
+---@field count_chat_tokens fun(messages: { role: string, content: string | table[], name?: string }[]): integer tokens of a conversation as the API reports them, with the per-message, per-name and reply framing of the current model (OpenAI's 3 tokens per message); content can be a list of parts as in count_message_parts
+---@field apply_chat_template fun(messages: { role: string, content: string | table[], name?: string }[], opts?: { add_generation_prompt?: boolean, template?: string, bos_token?: string, eos_token?: string }): string, integer prompt rendered with the model's Jinja chat_template (or opts.template) and its token count; errors if the model has none
+---@field count_message_parts fun(parts: ({ type: "text", text: string } | { type: "image", width?: integer, height?: integer, detail?: "low" | "high" | "auto" } | { type: "tool_call", name: string, arguments: string })[]): integer, integer[] total and per-part token counts; images and tool calls (arguments as JSON) follow the billing rules of the current model, images of unknown size count as the largest accepted
+---@field count_json fun(value: any): integer, string tokens of value serialized as compact JSON (keys sorted, "/" unescaped, empty tables as arrays unless vim.empty_dict(), self-referencing tables rejected), the way tool schemas are sent, and that JSON text
+---@field encode_as fun(text: string, format?: "json" | "msgpack" | "cbor"): string serialized { tokens, num_tokens, num_chars }
 local tokenizers = nil
 
 ---@type "gpt-4o" | string
 local current_model = "gpt-4o"
 
+---Whether a tokenizer finished loading
+local loaded = false
+
+---@type uv_timer_t | nil
+local poll_timer = nil
+
 local M = {}
 
+local function stop_polling()
+  if poll_timer == nil then return end
+  poll_timer:stop()
+  poll_timer:close()
+  poll_timer = nil
+end
+
+---Poll the background loads until none is left; their callbacks run on the main loop
+local function poll_loads()
+  if poll_timer ~= nil then return end
+  poll_timer = vim.uv.new_timer()
+  poll_timer:start(
+    50,
+    50,
+    vim.schedule_wrap(function()
+      if poll_timer == nil or tokenizers == nil then return end
+      local ok, running = pcall(tokenizers.poll_loads)
+      if not ok then
+        Utils.warn("Tokenizer load callback failed: " .. tostring(running))
+        return
+      end
+      if running == 0 then stop_polling() end
+    end)
+  )
+end
+
+---@alias NeopilotTokenizerLoadCallback fun(err: string | nil, handle: NeopilotTokenizerHandle | nil, current: boolean | nil)
+
+---@param model string
+---@param callback? NeopilotTokenizerLoadCallback
+local function start_load(model, callback)
+  tokenizers.from_pretrained_async(model, function(err, handle, current)
+    if err == nil and current then
+      loaded = true
+      -- Run the first encode now, so the first count the user waits for is not the slow one
+      tokenizers.warmup()
+    end
+    if callback then
+      callback(err, handle, current)
+    elseif err ~= nil then
+      Utils.warn("Failed to load the tokenizer for " .. model .. ": " .. err, { once = true })
+    end
+  end)
+  poll_loads()
+end
+
+---Require the library and start loading model in the background
 ---@param model "gpt-4o" | string
+---@param callback? NeopilotTokenizerLoadCallback
 ---@return NeopilotTokenizer|nil
-function M._init_tokenizers_lib(model)
+function M._init_tokenizers_lib(model, callback)
   if tokenizers ~= nil then return tokenizers end
 
-  local ok, core = pcall(require, "neopilot_tokenizers")
+  local ok, core = require("neopilot_lib").require("tokenizers")
   if not ok then return nil end
 
   ---@cast core NeopilotTokenizer
   tokenizers = core
 
-  core.from_pretrained(model)
+  start_load(model, callback)
 
   return tokenizers
 end
 
+---Load model as a background task and make it current, without blocking the editor
+---@param model string
+---@param callback? NeopilotTokenizerLoadCallback called on the main loop once loaded; current is false if another model was made current meanwhile
+function M.load_async(model, callback)
+  current_model = model
+  if tokenizers ~= nil then return start_load(model, callback) end
+  if M._init_tokenizers_lib(model, callback) == nil and callback then
+    callback("neopilot_tokenizers is not available")
+  end
+end
+
 ---@param model "gpt-4o" | string
 ---@param warning? boolean
 function M.setup(model, warning)
@@ -44,7 +224,43 @@ function M.setup(model, warning)
   end
 end
 
-function M.available() return M._init_tokenizers_lib(current_model) ~= nil end
+---Load tokenizers for other models in the background so switching is instant
+---@param models string[]
+function M.preload(models)
+  if not M.available() then return end
+  tokenizers.preload(models)
+end
+
+---The current tokenizer, e.g. for the statusline; nil until it has loaded
+---@return { model: string, backend: string, encoding?: string } | nil
+function M.current_model()
+  if tokenizers == nil then return nil end
+  return tokenizers.current_model()
+end
+
+---Model names to offer when picking a tokenizer, sorted within each kind
+---@return string[]
+function M.supported_models()
+  if not M.available() then return {} end
+  local supported = tokenizers.list_supported_models()
+  local names = {}
+  for _, kind in ipairs({ "models", "encodings", "cached" }) do
+    vim.list_extend(names, supported[kind])
+  end
+  return names
+end
+
+---Free the memory of the loaded tokenizers; the next use loads the current model again
+function M.unload()
+  stop_polling()
+  if tokenizers == nil then return end
+  tokenizers.unload()
+  tokenizers = nil
+  loaded = false
+end
+
+---Whether the library is there and a tokenizer finished loading; the first call starts loading
+function M.available() return M._init_tokenizers_lib(current_model) ~= nil and loaded end
 
 ---@param prompt string
 function M.encode(prompt)
@@ -55,12 +271,21 @@ function M.encode(prompt)
   return tokenizers.encode(prompt)
 end
 
+---Number of TokenizerError::InputTooLarge
+local INPUT_TOO_LARGE = 1023
+
 ---@param prompt string
 function M.count(prompt)
   if not M.available() then return math.ceil(#prompt * 0.5) end
+  if not prompt or prompt == "" then return 0 end
+  if type(prompt) ~= "string" then error("Prompt is not type string", 2) end
 
-  local tokens = M.encode(prompt)
-  if not tokens then return 0 end
+  local tokens, err = tokenizers.try_encode(prompt)
+  if not tokens then
+    -- Too large to encode quickly, e.g. a huge log file: estimate instead
+    if err.number == INPUT_TOO_LARGE then return math.ceil(#prompt * 0.5) end
+    error(err.message, 0)
+  end
   return #tokens
 end
 
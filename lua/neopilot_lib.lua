@@ -17,4 +17,15 @@ function M.load()
   end
 end
 
+---Require the native module neopilot_<name>, preferring the table of the same name in
+---neopilot_core, whose modules share one engine and configuration
+---@param name "tokenizers" | "repo_map"
+---@return boolean ok
+---@return any module_or_error
+function M.require(name)
+  local ok, core = pcall(require, "neopilot_core")
+  if ok and type(core) == "table" and core[name] ~= nil then return true, core[name] end
+  return pcall(require, "neopilot_" .. name)
+end
+
 return M
@@ -0,0 +1,32 @@
+#!/bin/bash
+
+# Download the o200k_base ranks compiled in by the embedded-o200k feature of
+# neopilot-tokenizers, checking them against the hash tiktoken pins
+
+set -e
+
+URL=${URL:-https://openaipublic.blob.core.windows.net/encodings/o200k_base.tiktoken}
+SHA256=446a9538cb6c348e3516120d7c08b09f57c36495e2acfffe59a5bf8b0cfb1a2d
+DEST=${DEST:-crates/neopilot-tokenizers/assets/o200k_base.tiktoken}
+
+if [ -f "$DEST" ] && echo "$SHA256  $DEST" | sha256sum -c --status; then
+    echo "$DEST is up to date"
+    exit 0
+fi
+
+if ! command -v curl &> /dev/null; then
+    echo "Error: curl is required but not installed"
+    exit 1
+fi
+
+mkdir -p "$(dirname "$DEST")"
+curl -sSfL --proto '=https' --tlsv1.2 -o "$DEST.tmp" "$URL"
+
+if ! echo "$SHA256  $DEST.tmp" | sha256sum -c --status; then
+    rm -f "$DEST.tmp"
+    echo "Error: $URL does not match the expected hash"
+    exit 1
+fi
+
+mv "$DEST.tmp" "$DEST"
+echo "Downloaded $DEST"
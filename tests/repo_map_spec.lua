@@ -0,0 +1,70 @@
+local ok, repo_map = pcall(require, "neopilot_repo_map")
+
+---Render `source` with `transform` applied to its definitions
+local function stringify(lang, source, transform)
+  return repo_map.stringify_definitions(lang, source, { transform = transform })
+end
+
+describe("neopilot_repo_map definitions", function()
+  if not ok then
+    pending("neopilot_repo_map is not built")
+    return
+  end
+
+  it("round-trips extracted definitions through Lua tables", function()
+    local sources = {
+      python = '__all__ = ["run", "Visible"]\n\ndef run(x):\n    pass\n\nclass Visible:\n    pass\n',
+      elixir = "defmodule MyApp do\n  defdelegate fetch(id), to: MyApp.Repo\nend\n",
+      starlark = 'cc_library(name = "lib")\n\ndef helper(ctx):\n    pass\n',
+    }
+    local kinds = {}
+    for lang, source in pairs(sources) do
+      local plain = repo_map.stringify_definitions(lang, source)
+      assert.are_not.equal("", plain)
+      local copied = stringify(lang, source, function(defs)
+        for _, def in ipairs(defs) do
+          kinds[def.kind] = true
+        end
+        -- Fresh tables, so nothing survives from the tables Rust wrote
+        return vim.deepcopy(defs)
+      end)
+      assert.equals(plain, copied)
+    end
+    -- Modules are only written by callers, so the next test covers them
+    assert.same({ func = true, class = true }, kinds)
+  end)
+
+  it("reads every definition kind", function()
+    local cases = {
+      {
+        { kind = "func", name = "run", params = "(x: u32)", return_type = "u32", modifier = "pub" },
+        "pub func run(x: u32) -> u32;",
+      },
+      { { kind = "variable", name = "LIMIT", value_type = "usize" }, "var LIMIT:usize;" },
+      {
+        {
+          kind = "class",
+          name = "Engine",
+          methods = { { name = "start" } },
+          properties = { { name = "speed", value_type = "u8" } },
+        },
+        "class Engine{func start();var speed:u8;};",
+      },
+      { { kind = "module", name = "MyApp" }, "module MyApp{};" },
+      {
+        { kind = "enum", name = "Mode", items = { { name = "Fast" }, { name = "Slow", value_type = "u8" } } },
+        "enum Mode{Fast;Slow:u8;};",
+      },
+      {
+        { kind = "union", name = "Value", items = { { name = "int", value_type = "i32" } } },
+        "union Value{int:i32;};",
+      },
+    }
+    for _, case in ipairs(cases) do
+      local def, expected = case[1], case[2]
+      assert.equals(expected, stringify("rust", "", function() return { def } end))
+    end
+    local unknown = { kind = "trait", name = "T" }
+    assert.has_error(function() stringify("rust", "", function() return { unknown } end) end)
+  end)
+end)